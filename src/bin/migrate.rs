@@ -0,0 +1,46 @@
+// src/bin/migrate.rs
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (c) 2025 Canmi
+
+use librfs::common::config::MetadataBackend;
+use librfs::metadata::{format, migrate, store};
+use rfs_utils::{log, set_log_level, LogLevel};
+
+const USAGE: &str = "Usage: rfs-migrate <pool_root> <json|db|upgrade>";
+
+// Converts a pool's metadata between the JSON tree and the embedded database,
+// or upgrades it to the current metadata format ahead of its first use.
+// Stop rfsd (or unmount the pool) before running this.
+#[tokio::main]
+async fn main() {
+    set_log_level(LogLevel::Info);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [pool_root, backend] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let target = match backend.as_str() {
+        "json" => MetadataBackend::Json,
+        "db" => MetadataBackend::Db,
+        "upgrade" => return upgrade(pool_root).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = migrate::migrate(pool_root, target).await {
+        log(LogLevel::Error, &format!("Migration of '{}' failed: {}", pool_root, e));
+        std::process::exit(1);
+    }
+}
+
+// Opening the pool's metadata store upgrades it, resuming an interrupted upgrade.
+async fn upgrade(pool_root: &str) {
+    if let Err(e) = store::get_metadata_store(pool_root).await {
+        log(LogLevel::Error, &format!("Upgrade of '{}' failed: {}", pool_root, e));
+        std::process::exit(1);
+    }
+    log(LogLevel::Info, &format!("Metadata of '{}' is in format {}.", pool_root, format::FORMAT_VERSION));
+}
@@ -0,0 +1,115 @@
+// src/block/backend.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::store::RwError;
+use crate::block::{local::LocalStore, memory::MemoryStore, s3::S3Store};
+use crate::common::config::{self, BlockBackend};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// Block stores in use, one per pool root.
+static BLOCK_STORES: Lazy<Mutex<HashMap<String, Arc<dyn BlockStore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Identifies a stored block: its XXH3 hash and collision index `n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockKey {
+    pub xxh3: u128,
+    pub index: u32,
+}
+
+impl BlockKey {
+    pub fn new(xxh3: u128, index: u32) -> Self {
+        BlockKey { xxh3, index }
+    }
+
+    // Parses the `{xxh3:032x}-{n}` form produced by `Display`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (hash, n) = name.split_once('-')?;
+        if hash.len() != 32 {
+            return None;
+        }
+        Some(BlockKey::new(u128::from_str_radix(hash, 16).ok()?, n.parse().ok()?))
+    }
+}
+
+impl fmt::Display for BlockKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}-{}", self.xxh3, self.index)
+    }
+}
+
+// Raw storage for block contents. Deduplication and reference counting happen
+// above this trait, in `store`, so backends only move bytes around.
+pub trait BlockStore: Send + Sync {
+    // Stores a block, replacing any previous content under the same key.
+    fn put<'a>(&'a self, key: BlockKey, data: &'a [u8]) -> BoxFuture<'a, Result<(), RwError>>;
+
+    // Reads a block. Fails with `RwError::NotFound` if it does not exist.
+    fn get(&self, key: BlockKey) -> BoxFuture<'_, Result<Vec<u8>, RwError>>;
+
+    fn exists(&self, key: BlockKey) -> BoxFuture<'_, Result<bool, RwError>>;
+
+    // Deletes a block. Deleting a missing block is not an error.
+    fn delete(&self, key: BlockKey) -> BoxFuture<'_, Result<(), RwError>>;
+
+    // Lists the keys of all stored blocks.
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlockKey>, RwError>>;
+}
+
+// Returns the block store of a pool, creating it from the pool's config on first use.
+pub async fn get_block_store(root_path: &str) -> Result<Arc<dyn BlockStore>, RwError> {
+    if let Some(store) = BLOCK_STORES.lock().unwrap().get(root_path) {
+        return Ok(store.clone());
+    }
+
+    let pool_config = config::get_pool_config(root_path).await?;
+    let created: Arc<dyn BlockStore> = match &pool_config.block_backend {
+        BlockBackend::Local => Arc::new(LocalStore::new(root_path, &pool_config)),
+        BlockBackend::Memory => Arc::new(MemoryStore::new()),
+        BlockBackend::S3(s3_config) => Arc::new(S3Store::new(s3_config.clone())?),
+    };
+    let mut stores = BLOCK_STORES.lock().unwrap();
+    Ok(stores.entry(root_path.to_string()).or_insert(created).clone())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Runs the operations every backend must support against `store`, which must start empty.
+    pub(crate) async fn exercise(store: &dyn BlockStore) {
+        let a = BlockKey::new(0xabc, 1);
+        let b = BlockKey::new(0xabc, 2);
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(!store.exists(a).await.unwrap());
+        assert!(matches!(store.get(a).await, Err(RwError::NotFound(key)) if key == a));
+
+        store.put(a, b"first").await.unwrap();
+        store.put(b, b"second").await.unwrap();
+        store.put(a, b"replaced").await.unwrap();
+        assert!(store.exists(a).await.unwrap());
+        assert_eq!(store.get(a).await.unwrap(), b"replaced");
+        assert_eq!(store.get(b).await.unwrap(), b"second");
+        let mut keys = store.list().await.unwrap();
+        keys.sort_by_key(|k| k.index);
+        assert_eq!(keys, vec![a, b]);
+
+        store.delete(a).await.unwrap();
+        store.delete(a).await.unwrap();
+        assert!(!store.exists(a).await.unwrap());
+        assert_eq!(store.list().await.unwrap(), vec![b]);
+    }
+
+    #[test]
+    fn keys_round_trip_through_their_names() {
+        let key = BlockKey::new(u128::MAX - 5, 3);
+        assert_eq!(BlockKey::parse(&key.to_string()), Some(key));
+        assert_eq!(BlockKey::parse(&format!("{}.1234abcd.tmp", key)), None);
+        assert_eq!(BlockKey::parse("abc-1"), None);
+    }
+}
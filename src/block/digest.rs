@@ -2,9 +2,15 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
+use sha2::{Digest, Sha256};
 use xxhash_rust::xxh3::xxh3_128;
 
 // Calculates the 128-bit XXH3 hash of a byte slice.
 pub fn calculate_xxh3_128(data: &[u8]) -> u128 {
     xxh3_128(data)
 }
+
+// Calculates the SHA-256 hash of a byte slice, used to confirm XXH3 matches.
+pub fn calculate_sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
@@ -0,0 +1,158 @@
+// src/block/export.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::{sparse, store, CHUNK_SIZE};
+use crate::common;
+use crate::metadata::{error::MetadataError, manager, model::Entry};
+use rfs_utils::{log, LogLevel};
+use std::io::SeekFrom;
+use thiserror::Error;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Pool with ID {0} not found.")]
+    PoolNotFound(u64),
+    #[error("I/O error during file export: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Block storage error: {0}")]
+    Store(#[from] store::RwError),
+    #[error("Metadata error: {0}")]
+    Metadata(#[from] MetadataError),
+}
+
+// Exports a file from the RFS to the OS.
+// Holes in the block map are punched rather than written, so sparse files stay sparse.
+pub async fn export_file(
+    rfs_file_path: &str,
+    os_file_path: &str,
+    pool_id: u64,
+) -> Result<(), ExportError> {
+    let pool_root_path = common::pool::get_pool_path_by_id(pool_id)
+        .ok_or(ExportError::PoolNotFound(pool_id))?;
+    export_from(&pool_root_path, rfs_file_path, os_file_path).await
+}
+
+// Exports a file of the pool at `pool_root_path`.
+async fn export_from(pool_root_path: &str, rfs_file_path: &str, os_file_path: &str) -> Result<(), ExportError> {
+    if let Entry::Symlink(link) = manager::get_entry(pool_root_path, rfs_file_path).await? {
+        return export_symlink(rfs_file_path, &link.target, os_file_path).await;
+    }
+    let metadata = manager::get_file_metadata(pool_root_path, rfs_file_path).await?;
+
+    // The target may already exist with other content, so it is sized first and
+    // every hole is punched explicitly instead of relying on a fresh file. A
+    // symlink at the target is refused rather than written through.
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(os_file_path)
+        .await?;
+    file.set_len(metadata.size).await?;
+
+    let chunk_size = CHUNK_SIZE as u64;
+    let mut pending_hole: Option<(u64, u64)> = None;
+    for sequence in 0..metadata.size.div_ceil(chunk_size) {
+        let offset = sequence * chunk_size;
+        let chunk_len = chunk_size.min(metadata.size - offset);
+        match metadata.blocks.get(&sequence) {
+            Some(block) if !block.hole => {
+                if let Some((start, len)) = pending_hole.take() {
+                    punch_or_zero(&mut file, start, len).await?;
+                }
+                let data = store::read_block(pool_root_path, block.xxh3, block.index).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(&data).await?;
+            }
+            _ => {
+                // Merge consecutive holes into a single range.
+                pending_hole = match pending_hole {
+                    Some((start, len)) => Some((start, len + chunk_len)),
+                    None => Some((offset, chunk_len)),
+                };
+            }
+        }
+    }
+    if let Some((start, len)) = pending_hole {
+        punch_or_zero(&mut file, start, len).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+
+    log(LogLevel::Info, &format!("Successfully exported '{}' to '{}'", rfs_file_path, os_file_path));
+    Ok(())
+}
+
+// Recreates a symlink on the OS, replacing any file or symlink at `os_file_path`.
+async fn export_symlink(rfs_file_path: &str, target: &str, os_file_path: &str) -> Result<(), ExportError> {
+    match tokio::fs::symlink_metadata(os_file_path).await {
+        Ok(existing) if !existing.is_dir() => tokio::fs::remove_file(os_file_path).await?,
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    tokio::fs::symlink(target, os_file_path).await?;
+
+    log(LogLevel::Info, &format!("Exported symlink '{}' to '{}' -> '{}'", rfs_file_path, os_file_path, target));
+    Ok(())
+}
+
+// Turns a byte range of the target into a hole, writing zeros if punching is unsupported.
+async fn punch_or_zero(file: &mut tokio::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+    // Pending writes must land before the range is deallocated underneath them.
+    file.flush().await?;
+    if sparse::punch_hole(file, offset, len)? {
+        return Ok(());
+    }
+    let zeros = vec![0u8; CHUNK_SIZE];
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n]).await?;
+        written += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ingest::{self, IngestControl};
+    use crate::metadata::test_util::TestPool;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    #[tokio::test]
+    async fn sparse_files_export_byte_identical_with_their_holes() {
+        let pool = TestPool::new("export-sparse", "{}");
+        let root = pool.root.as_str();
+        // Holes in chunks 0, 2 and 5; data in 1, across 3 and 4, and in the short tail.
+        let source = Path::new(root).join("source.bin");
+        let file = std::fs::File::create(&source).unwrap();
+        file.set_len(6 * CHUNK + 100).unwrap();
+        let pattern: Vec<u8> = (0..CHUNK as u32).map(|i| (i % 251) as u8 + 1).collect();
+        file.write_all_at(&pattern, CHUNK).unwrap();
+        file.write_all_at(&pattern, 3 * CHUNK + 7).unwrap();
+        file.write_all_at(&pattern[..100], 6 * CHUNK).unwrap();
+        drop(file);
+        let source_path = source.to_str().unwrap();
+        ingest::ingest_into(root, source_path, "/", "f", &IngestControl::new(), None).await.unwrap();
+
+        // The target holds other data at first, which the holes must replace.
+        let target = Path::new(root).join("target.bin");
+        std::fs::write(&target, vec![9; 8 * CHUNK_SIZE]).unwrap();
+        export_from(root, "/f", target.to_str().unwrap()).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), std::fs::read(&source).unwrap());
+
+        let target = std::fs::File::open(&target).unwrap();
+        assert_eq!(sparse::next_data_offset(&target, 0).unwrap(), Some(CHUNK));
+        assert_eq!(sparse::next_data_offset(&target, 2 * CHUNK).unwrap(), Some(3 * CHUNK));
+        assert_eq!(sparse::next_data_offset(&target, 5 * CHUNK).unwrap(), Some(6 * CHUNK));
+    }
+}
@@ -0,0 +1,390 @@
+// src/block/index.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::backend;
+use crate::block::digest;
+use crate::block::log_file::{self, Commit, LogSync};
+use crate::block::store::RwError;
+use crate::common::config::{self, BlockBackend};
+use crate::metadata::manager;
+use once_cell::sync::Lazy;
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+const INDEX_FILE: &str = "index.log";
+
+// Open indexes, one per pool root. The index is loaded once and then kept
+// fully resident, so both dedup hits and misses are answered from memory.
+static INDEXES: Lazy<StdMutex<HashMap<String, Arc<Mutex<BlockIndex>>>>> =
+    Lazy::new(|| StdMutex::new(HashMap::new()));
+
+// What the index knows about one stored `{xxh3}-{n}` block.
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub index: u32,
+    pub len: u64,
+    pub sha256: [u8; 32],
+    // Number of file block maps referencing this block.
+    pub refs: u64,
+}
+
+// One line of the append-only index log. Replaying the log in order rebuilds the index.
+// Externally tagged, because serde cannot buffer u128 fields of internally tagged enums.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum IndexRecord {
+    Put { xxh3: u128, index: u32, len: u64, sha256: String, refs: u64 },
+    Ref { xxh3: u128, index: u32, delta: i64 },
+    Remove { xxh3: u128, index: u32 },
+}
+
+// Persistent per-pool index mapping `xxh3 -> [(n, len, sha256, refs)]`.
+pub struct BlockIndex {
+    entries: HashMap<u128, Vec<IndexEntry>>,
+    log_path: PathBuf,
+    // None for pools whose blocks do not outlive the process either.
+    log: Option<fs::File>,
+    sync: Option<Arc<LogSync>>,
+    log_records: usize,
+    // Collision indexes held by blocks that are being written or deleted
+    // outside the index lock, so that `next_index` does not hand them out.
+    reserved: HashMap<u128, Vec<u32>>,
+}
+
+// Returns the index of a pool, loading it from disk on first use.
+pub async fn open(root_path: &str) -> Result<Arc<Mutex<BlockIndex>>, RwError> {
+    if let Some(index) = INDEXES.lock().unwrap().get(root_path) {
+        return Ok(index.clone());
+    }
+
+    let loaded = Arc::new(Mutex::new(BlockIndex::load(root_path).await?));
+    // Another task may have loaded the same pool meanwhile; keep the first one.
+    let mut indexes = INDEXES.lock().unwrap();
+    Ok(indexes.entry(root_path.to_string()).or_insert(loaded).clone())
+}
+
+impl BlockIndex {
+    async fn load(root_path: &str) -> Result<Self, RwError> {
+        let blocks_dir = Path::new(root_path).join("blocks");
+        let log_path = blocks_dir.join(INDEX_FILE);
+        // Blocks of the memory backend are gone after a restart, so a persisted
+        // index would make ingests dedup against blocks that no longer exist.
+        if matches!(config::get_pool_config(root_path).await?.block_backend, BlockBackend::Memory) {
+            return Ok(BlockIndex {
+                entries: HashMap::new(),
+                log_path,
+                log: None,
+                sync: None,
+                log_records: 0,
+                reserved: HashMap::new(),
+            });
+        }
+        fs::create_dir_all(&blocks_dir).await?;
+
+        let (entries, log_records) = if log_path.exists() {
+            replay(&log_path).await?
+        } else {
+            log(LogLevel::Info, &format!("No block index found in '{}', rebuilding it.", root_path));
+            (rebuild(root_path).await?, 0)
+        };
+
+        let log = fs::OpenOptions::new().create(true).append(true).open(&log_path).await?;
+        let mut index = BlockIndex {
+            entries,
+            sync: Some(LogSync::new(&log).await?),
+            log: Some(log),
+            log_path,
+            log_records,
+            reserved: HashMap::new(),
+        };
+
+        // Rewrite the log when it is mostly superseded reference updates.
+        let live = index.entries.values().map(Vec::len).sum::<usize>();
+        if log_records == 0 || index.log_records > 2 * live + 1024 {
+            index.compact().await?;
+        }
+        Ok(index)
+    }
+
+    // Finds a stored block with identical content.
+    pub fn find(&self, xxh3: u128, len: u64, sha256: &[u8; 32]) -> Option<u32> {
+        self.entries
+            .get(&xxh3)?
+            .iter()
+            .find(|e| e.len == len && &e.sha256 == sha256)
+            .map(|e| e.index)
+    }
+
+    pub fn get(&self, xxh3: u128, index: u32) -> Option<&IndexEntry> {
+        self.entries.get(&xxh3)?.iter().find(|e| e.index == index)
+    }
+
+    // Returns the next unused collision index for an XXH3 value.
+    pub fn next_index(&self, xxh3: u128) -> u32 {
+        let indexed = self.entries.get(&xxh3).into_iter().flatten().map(|e| e.index);
+        let reserved = self.reserved.get(&xxh3).into_iter().flatten().copied();
+        indexed.chain(reserved).max().unwrap_or(0) + 1
+    }
+
+    // Holds a collision index while its block is written or deleted without the
+    // index lock. `insert` and `unreserve` give it back.
+    pub fn reserve(&mut self, xxh3: u128, index: u32) {
+        self.reserved.entry(xxh3).or_default().push(index);
+    }
+
+    pub fn unreserve(&mut self, xxh3: u128, index: u32) {
+        if let Some(list) = self.reserved.get_mut(&xxh3) {
+            list.retain(|&n| n != index);
+            if list.is_empty() {
+                self.reserved.remove(&xxh3);
+            }
+        }
+    }
+
+    // The changes made so far, to be made durable once the index lock is released.
+    pub fn commit(&self) -> Commit {
+        Commit::of(self.sync.as_ref())
+    }
+
+    pub async fn insert(&mut self, xxh3: u128, entry: IndexEntry) -> std::io::Result<()> {
+        self.unreserve(xxh3, entry.index);
+        self.append(&IndexRecord::Put {
+            xxh3,
+            index: entry.index,
+            len: entry.len,
+            sha256: hex::encode(entry.sha256),
+            refs: entry.refs,
+        })
+        .await?;
+        self.entries.entry(xxh3).or_default().push(entry);
+        Ok(())
+    }
+
+    // Adjusts the reference count of a block and returns the new count.
+    pub async fn add_ref(&mut self, xxh3: u128, index: u32, delta: i64) -> std::io::Result<u64> {
+        let Some(entry) = self.entries.get_mut(&xxh3).and_then(|v| v.iter_mut().find(|e| e.index == index)) else {
+            return Ok(0);
+        };
+        entry.refs = entry.refs.saturating_add_signed(delta);
+        let refs = entry.refs;
+        self.append(&IndexRecord::Ref { xxh3, index, delta }).await?;
+        Ok(refs)
+    }
+
+    pub async fn remove(&mut self, xxh3: u128, index: u32) -> std::io::Result<()> {
+        if let Some(list) = self.entries.get_mut(&xxh3) {
+            list.retain(|e| e.index != index);
+            if list.is_empty() {
+                self.entries.remove(&xxh3);
+            }
+        }
+        self.append(&IndexRecord::Remove { xxh3, index }).await
+    }
+
+    // Iterates over every indexed block.
+    pub fn iter(&self) -> impl Iterator<Item = (u128, &IndexEntry)> {
+        self.entries.iter().flat_map(|(xxh3, list)| list.iter().map(move |e| (*xxh3, e)))
+    }
+
+    // Records a change, which only becomes durable through `commit`: a lost
+    // `Ref` would let a block still in use be released.
+    async fn append(&mut self, record: &IndexRecord) -> std::io::Result<()> {
+        let (Some(log), Some(sync)) = (&mut self.log, &self.sync) else {
+            return Ok(());
+        };
+        log_file::write_record(log, record).await?;
+        sync.wrote();
+        self.log_records += 1;
+        Ok(())
+    }
+
+    // Rewrites the log as one `Put` record per live block.
+    async fn compact(&mut self) -> std::io::Result<()> {
+        if self.log.is_none() {
+            return Ok(());
+        }
+        let mut content = Vec::new();
+        for (xxh3, e) in self.iter() {
+            let record = IndexRecord::Put {
+                xxh3,
+                index: e.index,
+                len: e.len,
+                sha256: hex::encode(e.sha256),
+                refs: e.refs,
+            };
+            content.extend(serde_json::to_vec(&record)?);
+            content.push(b'\n');
+        }
+        log_file::replace(&self.log_path, &content).await?;
+
+        // The new log was synced as a whole, so pending commits of the old one
+        // only sync a file that is gone.
+        let log = fs::OpenOptions::new().append(true).open(&self.log_path).await?;
+        self.sync = Some(LogSync::new(&log).await?);
+        self.log = Some(log);
+        self.log_records = self.entries.values().map(Vec::len).sum();
+        Ok(())
+    }
+}
+
+// Loads the index by replaying its log. A torn final line from a crash is cut
+// off; any other unreadable record fails the load, as the index would be
+// missing references and let blocks still in use be released.
+async fn replay(log_path: &Path) -> std::io::Result<(HashMap<u128, Vec<IndexEntry>>, usize)> {
+    let content = log_file::read_records(log_path).await?;
+    let mut entries: HashMap<u128, Vec<IndexEntry>> = HashMap::new();
+    let mut records = 0;
+
+    for (n, line) in content.split(|&b| b == b'\n').enumerate().filter(|(_, l)| !l.is_empty()) {
+        let unreadable = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unreadable record on line {} of {}", n + 1, log_path.display()),
+            )
+        };
+        let record = serde_json::from_slice::<IndexRecord>(line).map_err(|_| unreadable())?;
+        records += 1;
+        match record {
+            IndexRecord::Put { xxh3, index, len, sha256, refs } => {
+                let mut hash = [0u8; 32];
+                hex::decode_to_slice(&sha256, &mut hash).map_err(|_| unreadable())?;
+                let list = entries.entry(xxh3).or_default();
+                list.retain(|e| e.index != index);
+                list.push(IndexEntry { index, len, sha256: hash, refs });
+            }
+            IndexRecord::Ref { xxh3, index, delta } => {
+                if let Some(e) = entries.get_mut(&xxh3).and_then(|v| v.iter_mut().find(|e| e.index == index)) {
+                    e.refs = e.refs.saturating_add_signed(delta);
+                }
+            }
+            IndexRecord::Remove { xxh3, index } => {
+                if let Some(list) = entries.get_mut(&xxh3) {
+                    list.retain(|e| e.index != index);
+                }
+            }
+        }
+    }
+    entries.retain(|_, list| !list.is_empty());
+    Ok((entries, records))
+}
+
+// Builds the index of a pool created before the index existed: every stored
+// block is hashed once, and reference counts are recovered from the file
+// block maps.
+async fn rebuild(root_path: &str) -> Result<HashMap<u128, Vec<IndexEntry>>, RwError> {
+    let mut entries: HashMap<u128, Vec<IndexEntry>> = HashMap::new();
+
+    let store = backend::get_block_store(root_path).await?;
+    for key in store.list().await? {
+        let data = store.get(key).await?;
+        entries.entry(key.xxh3).or_default().push(IndexEntry {
+            index: key.index,
+            len: data.len() as u64,
+            sha256: digest::calculate_sha256(&data),
+            refs: 0,
+        });
+    }
+
+    manager::visit_block_refs(root_path, |block| {
+        if let Some(e) = entries.get_mut(&block.xxh3).and_then(|v| v.iter_mut().find(|e| e.index == block.index)) {
+            e.refs += 1;
+        }
+    })
+    .await
+    .map_err(|e| RwError::Backend(format!("Failed to walk file metadata: {}", e)))?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_util::TestPool;
+
+    fn entry(index: u32, refs: u64) -> IndexEntry {
+        IndexEntry { index, len: 10, sha256: [7; 32], refs }
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_truncated_before_appending() {
+        let pool = TestPool::new("index-torn", "{}");
+        let root = pool.root.as_str();
+        let log_path = Path::new(root).join("blocks").join(INDEX_FILE);
+        std::fs::create_dir_all(log_path.parent().unwrap()).unwrap();
+        let mut content = Vec::new();
+        for record in [
+            IndexRecord::Put { xxh3: 1, index: 1, len: 10, sha256: hex::encode([7; 32]), refs: 1 },
+            IndexRecord::Ref { xxh3: 1, index: 1, delta: 1 },
+        ] {
+            content.extend(serde_json::to_vec(&record).unwrap());
+            content.push(b'\n');
+        }
+        let whole = content.len();
+        content.extend(br#"{"ref":{"xxh3":1,"ind"#);
+        std::fs::write(&log_path, &content).unwrap();
+
+        let mut index = BlockIndex::load(root).await.unwrap();
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), whole as u64);
+        assert_eq!(index.get(1, 1).unwrap().refs, 2);
+        index.add_ref(1, 1, 1).await.unwrap();
+        index.insert(2, entry(1, 1)).await.unwrap();
+        drop(index);
+
+        let (entries, records) = replay(&log_path).await.unwrap();
+        assert_eq!(records, 4);
+        assert_eq!(entries[&1][0].refs, 3);
+        assert_eq!(entries[&2][0].refs, 1);
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_record_fails_the_load() {
+        let pool = TestPool::new("index-corrupt", "{}");
+        let log_path = Path::new(&pool.root).join("blocks").join(INDEX_FILE);
+        std::fs::create_dir_all(log_path.parent().unwrap()).unwrap();
+        let put = IndexRecord::Put { xxh3: 1, index: 1, len: 10, sha256: hex::encode([7; 32]), refs: 1 };
+        let mut content = serde_json::to_vec(&put).unwrap();
+        content.extend(b"\n{\"ref\":{\"xxh3\":1,\"ind\n");
+        content.extend(serde_json::to_vec(&IndexRecord::Ref { xxh3: 1, index: 1, delta: 1 }).unwrap());
+        content.push(b'\n');
+        std::fs::write(&log_path, &content).unwrap();
+
+        let err = BlockIndex::load(&pool.root).await.err().unwrap();
+        assert!(matches!(err, RwError::Io(e) if e.kind() == std::io::ErrorKind::InvalidData));
+        assert_eq!(std::fs::read(&log_path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn reserved_indexes_are_not_handed_out() {
+        let pool = TestPool::new("index-reserve", "{}");
+        let mut index = BlockIndex::load(&pool.root).await.unwrap();
+        index.insert(5, entry(1, 1)).await.unwrap();
+        index.reserve(5, 2);
+        assert_eq!(index.next_index(5), 3);
+        index.insert(5, entry(2, 1)).await.unwrap();
+        index.reserve(5, 3);
+        index.unreserve(5, 3);
+        assert_eq!(index.next_index(5), 3);
+        index.commit().wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_applies_refs_and_removals() {
+        let pool = TestPool::new("index-replay", "{}");
+        let mut index = BlockIndex::load(&pool.root).await.unwrap();
+        index.insert(5, entry(1, 1)).await.unwrap();
+        index.insert(5, entry(2, 1)).await.unwrap();
+        assert_eq!(index.add_ref(5, 2, -1).await.unwrap(), 0);
+        index.remove(5, 2).await.unwrap();
+        assert_eq!(index.next_index(5), 2);
+        drop(index);
+
+        let index = BlockIndex::load(&pool.root).await.unwrap();
+        assert_eq!(index.find(5, 10, &[7; 32]), Some(1));
+        assert!(index.get(5, 2).is_none());
+    }
+}
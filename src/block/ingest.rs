@@ -2,20 +2,23 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
-use crate::block::{digest, store};
+use crate::block::{digest, sparse, store, CHUNK_SIZE};
 use crate::common;
 use crate::metadata::{
-    error::MetadataError, manager, model::{BlockInfo, FileMetadata}, path_utils,
+    error::MetadataError, manager, model::{BlockInfo, FileMetadata}, naming, precondition::Precondition,
 };
 use chrono::Utc;
 use rfs_utils::{log, LogLevel};
+use serde::Serialize;
 use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::time::Instant;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
 
 const BUFFER_SIZE: usize = 64 * 1024 * 1024; // 64 MB
-const CHUNK_SIZE: usize = 128 * 1024; // 128KB
 
 #[derive(Error, Debug)]
 pub enum IngestError {
@@ -27,6 +30,69 @@ pub enum IngestError {
     Store(#[from] store::RwError),
     #[error("Metadata error: {0}")]
     Metadata(#[from] MetadataError),
+    #[error("Ingest was cancelled.")]
+    Cancelled,
+}
+
+// A snapshot of how far an ingest has progressed.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestProgress {
+    // Logical bytes processed so far, including holes that were skipped rather than read.
+    pub bytes_read: u64,
+    pub blocks_written: u64,
+    pub blocks_deduplicated: u64,
+    // All-zero chunks recorded as holes instead of being stored.
+    pub holes: u64,
+    pub elapsed_ms: u64,
+    // Average throughput since the start of the ingest, in bytes per second.
+    pub throughput: u64,
+}
+
+// Lets a caller observe and abort a running ingest.
+// Progress is published on a watch channel; cancelling the token stops the
+// ingest at the next chunk and releases any blocks it referenced.
+#[derive(Clone)]
+pub struct IngestControl {
+    progress: watch::Sender<IngestProgress>,
+    cancel: CancellationToken,
+}
+
+impl IngestControl {
+    pub fn new() -> Self {
+        let (progress, _) = watch::channel(IngestProgress::default());
+        IngestControl { progress, cancel: CancellationToken::new() }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<IngestProgress> {
+        self.progress.subscribe()
+    }
+
+    pub fn progress(&self) -> IngestProgress {
+        self.progress.borrow().clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+impl Default for IngestControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A piece of the source file handed from the reader task to the chunker.
+enum Segment {
+    // A buffer and the number of bytes filled in it.
+    Data(Vec<u8>, usize),
+    // A run of bytes the filesystem reports as a hole; it is never read.
+    Hole(u64),
 }
 
 // Ingests a file from the OS into the RFS.
@@ -36,29 +102,186 @@ pub async fn ingest_file(
     rfs_dir_path: &str,
     filename: &str,
     pool_id: u64,
+) -> Result<(), IngestError> {
+    ingest_file_with_control(os_file_path, rfs_dir_path, filename, pool_id, &IngestControl::new()).await
+}
+
+// Same as `ingest_file`, but reports progress to and honours cancellation from `control`.
+pub async fn ingest_file_with_control(
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    pool_id: u64,
+    control: &IngestControl,
+) -> Result<(), IngestError> {
+    ingest(os_file_path, rfs_dir_path, filename, pool_id, control, None).await
+}
+
+// Ingests a file over an existing one of the same name. The replaced contents
+// are kept as a prior version if the pool's retention policy allows.
+pub async fn reingest_file(
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    pool_id: u64,
+    control: &IngestControl,
+) -> Result<(), IngestError> {
+    reingest_file_if(os_file_path, rfs_dir_path, filename, pool_id, control, &Precondition::default()).await
+}
+
+// Like `reingest_file`, but the file is only replaced, or created, if its
+// current entry satisfies `precondition` when the ingest completes.
+pub async fn reingest_file_if(
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    pool_id: u64,
+    control: &IngestControl,
+    precondition: &Precondition,
+) -> Result<(), IngestError> {
+    ingest(os_file_path, rfs_dir_path, filename, pool_id, control, Some(precondition)).await
+}
+
+async fn ingest(
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    pool_id: u64,
+    control: &IngestControl,
+    // Set to replace an existing file, under the given precondition.
+    replace: Option<&Precondition>,
 ) -> Result<(), IngestError> {
     // 1. Validate paths and get the pool root.
-    path_utils::validate_component(filename)?;
     let pool_root_path = common::pool::get_pool_path_by_id(pool_id)
         .ok_or(IngestError::PoolNotFound(pool_id))?;
+    ingest_into(&pool_root_path, os_file_path, rfs_dir_path, filename, control, replace).await
+}
 
+// Ingests into the pool at `pool_root_path`.
+pub(crate) async fn ingest_into(
+    pool_root_path: &str,
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    control: &IngestControl,
+    replace: Option<&Precondition>,
+) -> Result<(), IngestError> {
+    let filename = &naming::normalize_name(pool_root_path, filename).await?;
+
+    // A symlink is stored as a symlink instead of being followed to its target.
+    if tokio::fs::symlink_metadata(os_file_path).await?.file_type().is_symlink() {
+        let target = tokio::fs::read_link(os_file_path).await?;
+        let target = target.to_str().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "symlink target is not valid UTF-8")
+        })?;
+        manager::create_symlink(pool_root_path, rfs_dir_path, filename, target).await?;
+        log(LogLevel::Info, &format!("Ingested symlink '{}' -> '{}'", os_file_path, target));
+        return Ok(());
+    }
+
+    // Blocks referenced by this ingest; released again if it fails.
+    let mut stored_blocks = Vec::new();
+    let result = ingest_blocks(
+        os_file_path,
+        rfs_dir_path,
+        filename,
+        pool_root_path,
+        control,
+        replace,
+        &mut stored_blocks,
+    )
+    .await;
+
+    if let Err(e) = &result {
+        log(
+            LogLevel::Warn,
+            &format!("Ingest of '{}' aborted: {}. Releasing {} blocks.", os_file_path, e, stored_blocks.len()),
+        );
+        for block in &stored_blocks {
+            if let Err(e) = store::release_block(pool_root_path, block.xxh3, block.index).await {
+                log(LogLevel::Error, &format!("Failed to release block {:032x}-{}: {}", block.xxh3, block.index, e));
+            }
+        }
+    }
+    result
+}
+
+enum ChunkKind {
+    Written,
+    Deduplicated,
+    Hole,
+}
+
+async fn ingest_blocks(
+    os_file_path: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    pool_root_path: &str,
+    control: &IngestControl,
+    replace: Option<&Precondition>,
+    stored_blocks: &mut Vec<BlockInfo>,
+) -> Result<(), IngestError> {
     // 2. Set up the async block processing pipeline to gather block info.
-    let (full_buf_tx, mut full_buf_rx) = mpsc::channel::<(Vec<u8>, usize)>(2);
+    // O_NOFOLLOW: a symlink swapped in since the check above is not followed either.
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(os_file_path)
+        .await?;
+    let file_len = file.metadata().await?.len();
+    // Refuse a file that cannot fit before storing any of its blocks.
+    manager::check_quota(pool_root_path, rfs_dir_path, filename, file_len).await?;
+    let (full_buf_tx, mut full_buf_rx) = mpsc::channel::<std::io::Result<Segment>>(2);
     let (empty_buf_tx, mut empty_buf_rx) = mpsc::channel::<Vec<u8>>(2);
     empty_buf_tx.send(vec![0; BUFFER_SIZE]).await.unwrap();
     empty_buf_tx.send(vec![0; BUFFER_SIZE]).await.unwrap();
 
-    let os_file_path_owned = os_file_path.to_string();
+    let cancel = control.cancel.clone();
     let reader_handle = tokio::spawn(async move {
-        let mut file = match tokio::fs::File::open(&os_file_path_owned).await {
-            Ok(f) => f,
-            Err(_) => return,
-        };
+        let mut pos: u64 = 0;
         while let Some(mut buffer) = empty_buf_rx.recv().await {
-            match file.read(&mut buffer).await {
-                Ok(0) => break,
-                Ok(n) => if full_buf_tx.send((buffer, n)).await.is_err() { break; },
-                Err(_) => break,
+            // Skip whole chunks inside a filesystem hole without reading them.
+            let hole_len = match sparse::next_data_offset(&file, pos) {
+                Ok(Some(data_at)) => (data_at.min(file_len).saturating_sub(pos)) / CHUNK_SIZE as u64 * CHUNK_SIZE as u64,
+                Ok(None) => file_len.saturating_sub(pos),
+                Err(e) => {
+                    let _ = full_buf_tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            if hole_len > 0 {
+                pos += hole_len;
+                if full_buf_tx.send(Ok(Segment::Hole(hole_len))).await.is_err() {
+                    return;
+                }
+                if let Err(e) = file.seek(SeekFrom::Start(pos)).await {
+                    let _ = full_buf_tx.send(Err(e)).await;
+                    return;
+                }
+            }
+
+            // Fill the whole buffer so that chunks stay aligned to CHUNK_SIZE.
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let read = tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    read = file.read(&mut buffer[filled..]) => read,
+                };
+                match read {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) => {
+                        let _ = full_buf_tx.send(Err(e)).await;
+                        return;
+                    }
+                }
+            }
+            pos += filled as u64;
+            if filled == 0 || full_buf_tx.send(Ok(Segment::Data(buffer, filled))).await.is_err() {
+                break;
+            }
+            if filled < BUFFER_SIZE {
+                break; // Reached end of file.
             }
         }
     });
@@ -67,19 +290,72 @@ pub async fn ingest_file(
     let mut blocks = BTreeMap::new();
     let mut total_size: u64 = 0;
     let mut chunk_sequence: u64 = 0;
+    let started = Instant::now();
+    let report = |kind: ChunkKind, total_size: u64| {
+        control.progress.send_modify(|p| {
+            p.bytes_read = total_size;
+            match kind {
+                ChunkKind::Written => p.blocks_written += 1,
+                ChunkKind::Deduplicated => p.blocks_deduplicated += 1,
+                ChunkKind::Hole => p.holes += 1,
+            }
+            let elapsed = started.elapsed();
+            p.elapsed_ms = elapsed.as_millis() as u64;
+            if elapsed.as_secs_f64() > 0.0 {
+                p.throughput = (total_size as f64 / elapsed.as_secs_f64()) as u64;
+            }
+        });
+    };
+
+    while let Some(segment) = full_buf_rx.recv().await {
+        if control.is_cancelled() {
+            return Err(IngestError::Cancelled);
+        }
+        let (buffer, bytes_in_buffer) = match segment? {
+            Segment::Data(buffer, n) => (buffer, n),
+            Segment::Hole(mut remaining) => {
+                while remaining > 0 {
+                    let chunk_len = remaining.min(CHUNK_SIZE as u64);
+                    blocks.insert(chunk_sequence, BlockInfo::hole());
+                    total_size += chunk_len;
+                    remaining -= chunk_len;
+                    chunk_sequence += 1;
+                    report(ChunkKind::Hole, total_size);
+                }
+                continue;
+            }
+        };
 
-    while let Some((buffer, bytes_in_buffer)) = full_buf_rx.recv().await {
         for chunk_data in buffer[..bytes_in_buffer].chunks(CHUNK_SIZE) {
             if chunk_data.is_empty() { continue; }
-            let xxh3_hash = digest::calculate_xxh3_128(chunk_data);
-            let collision_index = store::write_block(&pool_root_path, xxh3_hash, chunk_data).await?;
-            blocks.insert(chunk_sequence, BlockInfo { xxh3: xxh3_hash, index: collision_index });
+            if control.is_cancelled() {
+                return Err(IngestError::Cancelled);
+            }
+            // All-zero chunks are recorded as holes without hashing or storing them.
+            let (block, kind) = if sparse::is_zero(chunk_data) {
+                (BlockInfo::hole(), ChunkKind::Hole)
+            } else {
+                let xxh3_hash = digest::calculate_xxh3_128(chunk_data);
+                let stored = store::write_block(pool_root_path, xxh3_hash, chunk_data).await?;
+                let block = BlockInfo::stored(xxh3_hash, stored.index);
+                stored_blocks.push(block.clone());
+                if stored.is_new {
+                    (block, ChunkKind::Written)
+                } else {
+                    (block, ChunkKind::Deduplicated)
+                }
+            };
+            blocks.insert(chunk_sequence, block);
             total_size += chunk_data.len() as u64;
             chunk_sequence += 1;
+            report(kind, total_size);
         }
         let _ = empty_buf_tx.send(buffer).await;
     }
     reader_handle.await.unwrap();
+    if control.is_cancelled() {
+        return Err(IngestError::Cancelled);
+    }
 
     let now = Utc::now();
     let final_file_metadata = FileMetadata {
@@ -88,12 +364,90 @@ pub async fn ingest_file(
         created_at: now,
         modified_at: now,
         blocks,
+        versions: Vec::new(),
+        links: 1,
+        link_dirs: Vec::new(),
+        xattrs: BTreeMap::new(),
     };
 
     // 4. Call the metadata manager to create the file entry atomically.
-    manager::create_file(&pool_root_path, rfs_dir_path, filename, final_file_metadata).await?;
+    match replace {
+        Some(precondition) => {
+            manager::replace_file_if(pool_root_path, rfs_dir_path, filename, final_file_metadata, precondition).await?
+        }
+        None => manager::create_file(pool_root_path, rfs_dir_path, filename, final_file_metadata).await?,
+    }
     let final_rfs_path = format!("{}/{}", rfs_dir_path.trim_end_matches('/'), filename);
     log(LogLevel::Info, &format!("Successfully ingested '{}' into rfs at '{}'", os_file_path, final_rfs_path));
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::index;
+    use crate::metadata::test_util::TestPool;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    // Writes a source file of `len` bytes into the pool's directory, with
+    // each listed chunk filled with its byte and the rest left a hole.
+    fn source_file(pool_root: &str, len: u64, chunks: &[(u64, u8)]) -> String {
+        let path = Path::new(pool_root).join("source.bin");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(len).unwrap();
+        for &(chunk, byte) in chunks {
+            let end = ((chunk + 1) * CHUNK).min(len);
+            file.write_all_at(&vec![byte; (end - chunk * CHUNK) as usize], chunk * CHUNK).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn progress_counts_written_deduplicated_and_hole_chunks() {
+        let pool = TestPool::new("ingest-progress", "{}");
+        let root = pool.root.as_str();
+        let len = 4 * CHUNK + 10;
+        let source = source_file(root, len, &[(0, 1), (1, 2), (3, 1), (4, 3)]);
+
+        let control = IngestControl::new();
+        ingest_into(root, &source, "/", "f", &control, None).await.unwrap();
+        let progress = control.progress();
+        assert_eq!(progress.bytes_read, len);
+        assert_eq!((progress.blocks_written, progress.blocks_deduplicated, progress.holes), (3, 1, 1));
+        let metadata = manager::get_file_metadata(root, "/f").await.unwrap();
+        assert_eq!(metadata.size, len);
+        assert!(metadata.blocks[&2].hole);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_ingest_releases_the_blocks_it_stored() {
+        let pool = TestPool::new("ingest-cancel", "{}");
+        let root = pool.root.clone();
+        let chunks: Vec<(u64, u8)> = (0..16).map(|chunk| (chunk, chunk as u8 + 1)).collect();
+        let source = source_file(&root, 16 * CHUNK, &chunks);
+
+        let control = IngestControl::new();
+        let mut progress = control.subscribe();
+        let ingest = tokio::spawn({
+            let (root, source, control) = (root.clone(), source.clone(), control.clone());
+            async move { ingest_into(&root, &source, "/", "f", &control, None).await }
+        });
+        while progress.borrow_and_update().blocks_written == 0 {
+            progress.changed().await.unwrap();
+        }
+        control.cancel();
+        assert!(matches!(ingest.await.unwrap(), Err(IngestError::Cancelled)));
+        assert!(manager::get_entry(&root, "/f").await.is_err());
+        let index = index::open(&root).await.unwrap();
+        assert!(index.lock().await.iter().all(|(_, entry)| entry.refs == 0));
+
+        // Nothing is left to deduplicate against.
+        let control = IngestControl::new();
+        ingest_into(&root, &source, "/", "f", &control, None).await.unwrap();
+        assert_eq!((control.progress().blocks_written, control.progress().blocks_deduplicated), (16, 0));
+    }
+}
@@ -0,0 +1,167 @@
+// src/block/local.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::backend::{BlockKey, BlockStore};
+use crate::block::pack;
+use crate::block::store::RwError;
+use crate::common::config::{BlockLayout, PoolConfig};
+use futures::future::BoxFuture;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+// Stores blocks on the local filesystem under the pool root, either one file
+// per block or appended to packfiles. Reads and deletes work across both
+// layouts, so a pool can switch layouts without migrating existing blocks.
+pub struct LocalStore {
+    root_path: String,
+    layout: BlockLayout,
+    pack_size: u64,
+}
+
+impl LocalStore {
+    pub fn new(root_path: &str, pool_config: &PoolConfig) -> Self {
+        LocalStore {
+            root_path: root_path.to_string(),
+            layout: pool_config.block_layout,
+            pack_size: pool_config.pack_size,
+        }
+    }
+
+    // Constructs the full path to a block's directory based on its XXH3 hash.
+    // The structure is /blocks/{:2}/{:2}/{:2}/
+    fn block_dir(&self, xxh3: u128) -> PathBuf {
+        let xxh3_hex = format!("{:032x}", xxh3);
+        Path::new(&self.root_path)
+            .join("blocks")
+            .join(&xxh3_hex[0..2])
+            .join(&xxh3_hex[2..4])
+            .join(&xxh3_hex[4..6])
+    }
+
+    fn block_path(&self, key: BlockKey) -> PathBuf {
+        self.block_dir(key.xxh3).join(key.to_string())
+    }
+}
+
+impl BlockStore for LocalStore {
+    fn put<'a>(&'a self, key: BlockKey, data: &'a [u8]) -> BoxFuture<'a, Result<(), RwError>> {
+        Box::pin(async move {
+            if self.layout == BlockLayout::Packs {
+                let packs = pack::open(&self.root_path).await?;
+                packs.lock().await.append(key.xxh3, key.index, data, self.pack_size).await?;
+                return Ok(());
+            }
+
+            // Write next to the block and rename it into place, so that a crash
+            // while an existing block is overwritten cannot leave it torn.
+            fs::create_dir_all(self.block_dir(key.xxh3)).await?;
+            let path = self.block_path(key);
+            let tmp_path = path.with_extension(format!("{:08x}.tmp", rand::random::<u32>()));
+            let mut file = fs::File::create(&tmp_path).await?;
+            let written = async {
+                file.write_all(data).await?;
+                file.sync_all().await?;
+                fs::rename(&tmp_path, &path).await
+            };
+            if let Err(e) = written.await {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(e.into());
+            }
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: BlockKey) -> BoxFuture<'_, Result<Vec<u8>, RwError>> {
+        Box::pin(async move {
+            if let Some(data) = pack::read(&self.root_path, key.xxh3, key.index).await? {
+                return Ok(data);
+            }
+            match fs::read(self.block_path(key)).await {
+                Ok(data) => Ok(data),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(RwError::NotFound(key)),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn exists(&self, key: BlockKey) -> BoxFuture<'_, Result<bool, RwError>> {
+        Box::pin(async move {
+            let packs = pack::open(&self.root_path).await?;
+            if packs.lock().await.location(key.xxh3, key.index).is_some() {
+                return Ok(true);
+            }
+            Ok(fs::try_exists(self.block_path(key)).await?)
+        })
+    }
+
+    fn delete(&self, key: BlockKey) -> BoxFuture<'_, Result<(), RwError>> {
+        Box::pin(async move {
+            // Packed blocks only become dead space here; compaction reclaims it later.
+            let packs = pack::open(&self.root_path).await?;
+            if packs.lock().await.remove(key.xxh3, key.index).await? {
+                return Ok(());
+            }
+            match fs::remove_file(self.block_path(key)).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlockKey>, RwError>> {
+        Box::pin(async move {
+            let packs = pack::open(&self.root_path).await?;
+            let mut keys: Vec<BlockKey> = packs
+                .lock()
+                .await
+                .keys()
+                .map(|(xxh3, index)| BlockKey::new(xxh3, index))
+                .collect();
+
+            let mut pending = vec![Path::new(&self.root_path).join("blocks")];
+            while let Some(dir) = pending.pop() {
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_dir() {
+                        pending.push(entry.path());
+                    } else if let Some(key) = entry.file_name().to_str().and_then(BlockKey::parse) {
+                        keys.push(key);
+                    }
+                }
+            }
+            Ok(keys)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::backend::tests::exercise;
+    use crate::metadata::test_util::TestPool;
+
+    #[tokio::test]
+    async fn stores_block_files() {
+        let pool = TestPool::new("local-files", "{}");
+        let store = LocalStore::new(&pool.root, &PoolConfig::default());
+        exercise(&store).await;
+        // A leftover temporary file from an interrupted put is not a block.
+        let key = BlockKey::new(0xabc, 2);
+        std::fs::write(store.block_path(key).with_extension("0badf00d.tmp"), b"torn").unwrap();
+        assert_eq!(store.list().await.unwrap(), vec![key]);
+    }
+
+    #[tokio::test]
+    async fn stores_packed_blocks() {
+        let pool = TestPool::new("local-packs", "{}");
+        let pool_config = PoolConfig { block_layout: BlockLayout::Packs, pack_size: 8, ..PoolConfig::default() };
+        exercise(&LocalStore::new(&pool.root, &pool_config)).await;
+    }
+}
@@ -0,0 +1,100 @@
+// src/block/log_file.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use rfs_utils::{log, LogLevel};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// Reads an append-only log of newline-terminated JSON records. A crash can
+// leave the last record torn; it is cut off the file here, as the next append
+// would otherwise be glued onto it and become unreadable as well.
+pub(crate) async fn read_records(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut content = fs::read(path).await?;
+    let end = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    if end < content.len() {
+        log(LogLevel::Warn, &format!("Truncating torn record at the end of {}", path.display()));
+        fs::OpenOptions::new().write(true).open(path).await?.set_len(end as u64).await?;
+        content.truncate(end);
+    }
+    Ok(content)
+}
+
+// Appends one record to a log opened for appending, and waits until it is on disk.
+pub(crate) async fn append_record<T: Serialize>(file: &mut fs::File, record: &T) -> std::io::Result<()> {
+    write_record(file, record).await?;
+    file.sync_data().await
+}
+
+// Appends one record to a log opened for appending without syncing it. The
+// record has reached the kernel when this returns, so a sync through any
+// handle of the file makes it durable.
+pub(crate) async fn write_record<T: Serialize>(file: &mut fs::File, record: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    file.flush().await
+}
+
+// Replaces a log with `content` through a temporary file, which is synced
+// before it is renamed into place.
+pub(crate) async fn replace(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("log.tmp");
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp_path, path).await
+}
+
+// Syncs of an append-only log, shared between writers. Records are written
+// under the owner's lock but synced after it is released, so writers that
+// queue up behind one sync are all covered by the next one.
+pub(crate) struct LogSync {
+    file: fs::File,
+    written: AtomicU64,
+    synced: Mutex<u64>,
+}
+
+impl LogSync {
+    pub(crate) async fn new(log: &fs::File) -> std::io::Result<Arc<Self>> {
+        Ok(Arc::new(LogSync { file: log.try_clone().await?, written: AtomicU64::new(0), synced: Mutex::new(0) }))
+    }
+
+    // Counts a record appended with `write_record`.
+    pub(crate) fn wrote(&self) {
+        self.written.fetch_add(1, Ordering::Release);
+    }
+}
+
+// A log up to some record, which `wait` makes durable.
+#[must_use]
+pub struct Commit {
+    sync: Option<Arc<LogSync>>,
+    seq: u64,
+}
+
+impl Commit {
+    // The records written to the log so far; none for a pool without a log.
+    pub(crate) fn of(sync: Option<&Arc<LogSync>>) -> Self {
+        Commit { sync: sync.cloned(), seq: sync.map_or(0, |s| s.written.load(Ordering::Acquire)) }
+    }
+
+    pub async fn wait(self) -> std::io::Result<()> {
+        let Some(sync) = self.sync else {
+            return Ok(());
+        };
+        let mut synced = sync.synced.lock().await;
+        if *synced >= self.seq {
+            return Ok(());
+        }
+        let written = sync.written.load(Ordering::Acquire);
+        sync.file.sync_data().await?;
+        *synced = written;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// src/block/memory.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::backend::{BlockKey, BlockStore};
+use crate::block::store::RwError;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Keeps blocks in process memory. Contents are lost when the process exits,
+// which makes this backend suitable for tests and scratch pools only.
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: Mutex<HashMap<BlockKey, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn put<'a>(&'a self, key: BlockKey, data: &'a [u8]) -> BoxFuture<'a, Result<(), RwError>> {
+        self.blocks.lock().unwrap().insert(key, data.to_vec());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn get(&self, key: BlockKey) -> BoxFuture<'_, Result<Vec<u8>, RwError>> {
+        let data = self.blocks.lock().unwrap().get(&key).cloned();
+        Box::pin(async move { data.ok_or(RwError::NotFound(key)) })
+    }
+
+    fn exists(&self, key: BlockKey) -> BoxFuture<'_, Result<bool, RwError>> {
+        let exists = self.blocks.lock().unwrap().contains_key(&key);
+        Box::pin(async move { Ok(exists) })
+    }
+
+    fn delete(&self, key: BlockKey) -> BoxFuture<'_, Result<(), RwError>> {
+        self.blocks.lock().unwrap().remove(&key);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<BlockKey>, RwError>> {
+        let keys = self.blocks.lock().unwrap().keys().copied().collect();
+        Box::pin(async move { Ok(keys) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::backend::tests::exercise;
+    use crate::block::index;
+
+    #[tokio::test]
+    async fn stores_blocks() {
+        exercise(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn index_is_not_persisted() {
+        let dir = std::env::temp_dir().join(format!("rfs-memory-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), r#"{"blockBackend":{"type":"memory"}}"#).unwrap();
+        let root = dir.to_string_lossy().into_owned();
+
+        let index = index::open(&root).await.unwrap();
+        let entry = index::IndexEntry { index: 1, len: 3, sha256: [1; 32], refs: 1 };
+        index.lock().await.insert(9, entry).await.unwrap();
+        assert_eq!(index.lock().await.find(9, 3, &[1; 32]), Some(1));
+        assert!(!dir.join("blocks").join("index.log").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
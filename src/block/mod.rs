@@ -2,6 +2,20 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
+pub mod backend;
 pub mod digest;
+pub mod export;
+pub mod index;
+pub mod local;
+pub mod log_file;
+pub mod memory;
+pub mod pack;
+pub mod read;
+pub mod s3;
+pub mod sparse;
 pub mod store;
 pub mod ingest;
+pub mod write;
+
+// Files are split into fixed-size chunks; chunk `n` covers bytes [n * CHUNK_SIZE, (n + 1) * CHUNK_SIZE).
+pub const CHUNK_SIZE: usize = 128 * 1024; // 128KB
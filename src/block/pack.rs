@@ -0,0 +1,344 @@
+// src/block/pack.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::log_file;
+use once_cell::sync::Lazy;
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+const PACK_DIR: &str = "packs";
+const PACK_INDEX_FILE: &str = "index.log";
+
+// Open pack stores, one per pool root.
+static PACK_STORES: Lazy<StdMutex<HashMap<String, Arc<Mutex<PackStore>>>>> =
+    Lazy::new(|| StdMutex::new(HashMap::new()));
+
+// Where a packed block lives: `len` bytes at `offset` in `blocks/packs/{pack}.pack`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct PackLocation {
+    pub pack: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+// One line of the append-only pack index log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum PackRecord {
+    Put { xxh3: u128, index: u32, location: PackLocation },
+    Remove { xxh3: u128, index: u32 },
+}
+
+// Space accounting for one packfile.
+#[derive(Debug, Clone, Copy, Default)]
+struct PackStats {
+    size: u64,
+    live: u64,
+}
+
+// The packed blocks of one pool: where each block is, and the pack being appended to.
+pub struct PackStore {
+    dir: PathBuf,
+    locations: HashMap<(u128, u32), PackLocation>,
+    packs: BTreeMap<u32, PackStats>,
+    active: Option<(u32, fs::File)>,
+    log: fs::File,
+}
+
+// Returns the pack store of a pool, loading its index on first use.
+pub async fn open(root_path: &str) -> std::io::Result<Arc<Mutex<PackStore>>> {
+    if let Some(store) = PACK_STORES.lock().unwrap().get(root_path) {
+        return Ok(store.clone());
+    }
+
+    let loaded = Arc::new(Mutex::new(PackStore::load(root_path).await?));
+    let mut stores = PACK_STORES.lock().unwrap();
+    Ok(stores.entry(root_path.to_string()).or_insert(loaded).clone())
+}
+
+impl PackStore {
+    async fn load(root_path: &str) -> std::io::Result<Self> {
+        let dir = Path::new(root_path).join("blocks").join(PACK_DIR);
+        fs::create_dir_all(&dir).await?;
+        let log_path = dir.join(PACK_INDEX_FILE);
+
+        let mut locations = HashMap::new();
+        if log_path.exists() {
+            let content = log_file::read_records(&log_path).await?;
+            for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                match serde_json::from_slice::<PackRecord>(line) {
+                    Ok(PackRecord::Put { xxh3, index, location }) => {
+                        locations.insert((xxh3, index), location);
+                    }
+                    Ok(PackRecord::Remove { xxh3, index }) => {
+                        locations.remove(&(xxh3, index));
+                    }
+                    Err(_) => log(LogLevel::Warn, &format!("Skipping unreadable record in {}", log_path.display())),
+                }
+            }
+        }
+
+        // Pack sizes come from the files themselves, so bytes appended without a
+        // matching index record (e.g. after a crash) count as dead space.
+        let mut packs = BTreeMap::new();
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(id) = parse_pack_name(&entry.file_name().to_string_lossy()) {
+                let size = entry.metadata().await?.len();
+                packs.insert(id, PackStats { size, live: 0 });
+            }
+        }
+        for location in locations.values() {
+            packs.entry(location.pack).or_default().live += location.len;
+        }
+
+        Ok(PackStore {
+            log: fs::OpenOptions::new().create(true).append(true).open(&log_path).await?,
+            dir,
+            locations,
+            packs,
+            active: None,
+        })
+    }
+
+    pub fn location(&self, xxh3: u128, index: u32) -> Option<PackLocation> {
+        self.locations.get(&(xxh3, index)).copied()
+    }
+
+    // Iterates over every packed block.
+    pub fn keys(&self) -> impl Iterator<Item = (u128, u32)> + '_ {
+        self.locations.keys().copied()
+    }
+
+    // Appends a block to the active pack, starting a new pack once it reaches `pack_size`.
+    pub async fn append(&mut self, xxh3: u128, index: u32, data: &[u8], pack_size: u64) -> std::io::Result<()> {
+        let location = self.write_data(data, pack_size).await?;
+        self.put_location(xxh3, index, location).await
+    }
+
+    // Forgets a packed block. Its bytes become dead space until the pack is compacted.
+    pub async fn remove(&mut self, xxh3: u128, index: u32) -> std::io::Result<bool> {
+        let Some(location) = self.locations.remove(&(xxh3, index)) else {
+            return Ok(false);
+        };
+        if let Some(stats) = self.packs.get_mut(&location.pack) {
+            stats.live = stats.live.saturating_sub(location.len);
+        }
+        self.append_record(&PackRecord::Remove { xxh3, index }).await?;
+        Ok(true)
+    }
+
+    async fn write_data(&mut self, data: &[u8], pack_size: u64) -> std::io::Result<PackLocation> {
+        let full = match &self.active {
+            Some((id, _)) => self.packs.get(id).is_some_and(|s| s.size + data.len() as u64 > pack_size),
+            None => true,
+        };
+        if full {
+            let id = self.packs.keys().next_back().map_or(1, |id| id + 1);
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.pack_path(id))
+                .await?;
+            self.packs.insert(id, PackStats::default());
+            self.active = Some((id, file));
+        }
+
+        let (id, file) = self.active.as_mut().unwrap();
+        let stats = self.packs.get_mut(id).unwrap();
+        file.write_all(data).await?;
+        // The data must be on disk before the index record pointing at it.
+        file.sync_data().await?;
+        let location = PackLocation { pack: *id, offset: stats.size, len: data.len() as u64 };
+        stats.size += location.len;
+        stats.live += location.len;
+        Ok(location)
+    }
+
+    async fn put_location(&mut self, xxh3: u128, index: u32, location: PackLocation) -> std::io::Result<()> {
+        self.append_record(&PackRecord::Put { xxh3, index, location }).await?;
+        if let Some(old) = self.locations.insert((xxh3, index), location)
+            && let Some(stats) = self.packs.get_mut(&old.pack)
+        {
+            stats.live = stats.live.saturating_sub(old.len);
+        }
+        Ok(())
+    }
+
+    async fn append_record(&mut self, record: &PackRecord) -> std::io::Result<()> {
+        log_file::append_record(&mut self.log, record).await
+    }
+
+    // Replaces the index log with one `Put` record per packed block.
+    async fn rewrite_log(&mut self) -> std::io::Result<()> {
+        let log_path = self.dir.join(PACK_INDEX_FILE);
+        let mut content = Vec::new();
+        for (&(xxh3, index), &location) in &self.locations {
+            content.extend(serde_json::to_vec(&PackRecord::Put { xxh3, index, location })?);
+            content.push(b'\n');
+        }
+        log_file::replace(&log_path, &content).await?;
+        self.log = fs::OpenOptions::new().append(true).open(&log_path).await?;
+        Ok(())
+    }
+
+    // Flushes the active pack and the index log to disk.
+    async fn sync(&mut self) -> std::io::Result<()> {
+        if let Some((_, file)) = &mut self.active {
+            file.sync_all().await?;
+        }
+        self.log.sync_all().await
+    }
+
+    fn pack_path(&self, id: u32) -> PathBuf {
+        self.dir.join(format!("{:08}.pack", id))
+    }
+}
+
+// Reads a packed block, or returns `None` if the block is not in a pack.
+pub async fn read(root_path: &str, xxh3: u128, index: u32) -> std::io::Result<Option<Vec<u8>>> {
+    let store = open(root_path).await?;
+    // Compaction may move the block between the lookup and the read; retry once.
+    for _ in 0..2 {
+        let (location, path) = {
+            let store = store.lock().await;
+            match store.location(xxh3, index) {
+                Some(location) => (location, store.pack_path(location.pack)),
+                None => return Ok(None),
+            }
+        };
+        match read_at(&path, location).await {
+            Ok(data) => return Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("Packed block {:032x}-{} disappeared during read", xxh3, index),
+    ))
+}
+
+async fn read_at(path: &Path, location: PackLocation) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(location.offset)).await?;
+    let mut data = vec![0; location.len as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+// Rewrites every sealed pack whose dead space has reached `compact_ratio`,
+// moving its live blocks to the active pack and deleting the old file.
+// Returns the number of bytes reclaimed.
+pub async fn compact(root_path: &str, pack_size: u64, compact_ratio: f64) -> std::io::Result<u64> {
+    let store = open(root_path).await?;
+    let mut store = store.lock().await;
+
+    let active_id = store.active.as_ref().map(|(id, _)| *id);
+    let candidates: Vec<u32> = store
+        .packs
+        .iter()
+        .filter(|(id, s)| Some(**id) != active_id && s.size > 0)
+        .filter(|(_, s)| (s.size - s.live.min(s.size)) as f64 / s.size as f64 >= compact_ratio)
+        .map(|(id, _)| *id)
+        .collect();
+
+    let mut reclaimed = 0;
+    for pack in &candidates {
+        let pack = *pack;
+        let path = store.pack_path(pack);
+        let stats = store.packs.get(&pack).copied().unwrap_or_default();
+        let live: Vec<((u128, u32), PackLocation)> = store
+            .locations
+            .iter()
+            .filter(|(_, l)| l.pack == pack)
+            .map(|(k, l)| (*k, *l))
+            .collect();
+
+        for ((xxh3, index), location) in live {
+            let data = read_at(&path, location).await?;
+            let new_location = store.write_data(&data, pack_size).await?;
+            store.put_location(xxh3, index, new_location).await?;
+        }
+
+        // The moved blocks and their records are synced as they are written,
+        // so nothing refers to the old pack any more once it is unlinked.
+        store.sync().await?;
+        store.packs.remove(&pack);
+        fs::remove_file(&path).await?;
+        reclaimed += stats.size - stats.live.min(stats.size);
+        log(LogLevel::Info, &format!("Compacted pack {} in '{}'", path.display(), root_path));
+    }
+    if !candidates.is_empty() {
+        store.rewrite_log().await?;
+    }
+    Ok(reclaimed)
+}
+
+fn parse_pack_name(name: &str) -> Option<u32> {
+    name.strip_suffix(".pack")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_util::TestPool;
+
+    async fn read_back(store: &PackStore, xxh3: u128) -> Vec<u8> {
+        let location = store.location(xxh3, 1).unwrap();
+        read_at(&store.pack_path(location.pack), location).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_truncated_before_appending() {
+        let pool = TestPool::new("pack-torn", "{}");
+        let root = pool.root.as_str();
+        let mut store = PackStore::load(root).await.unwrap();
+        store.append(1, 1, b"first", 1024).await.unwrap();
+        drop(store);
+        let log_path = Path::new(&root).join("blocks").join(PACK_DIR).join(PACK_INDEX_FILE);
+        let mut log = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        std::io::Write::write_all(&mut log, br#"{"put":{"xxh3":2,"#).unwrap();
+
+        let mut store = PackStore::load(root).await.unwrap();
+        store.append(3, 1, b"third", 1024).await.unwrap();
+        drop(store);
+        let store = PackStore::load(root).await.unwrap();
+        assert_eq!(read_back(&store, 1).await, b"first");
+        assert_eq!(read_back(&store, 3).await, b"third");
+        assert!(store.location(2, 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn compaction_moves_live_blocks_durably() {
+        let pool = TestPool::new("pack-compact", "{}");
+        let root = pool.root.as_str();
+        {
+            let store = open(root).await.unwrap();
+            let mut store = store.lock().await;
+            store.append(1, 1, b"deadbeef", 20).await.unwrap();
+            store.append(2, 1, b"livedata", 20).await.unwrap();
+            store.append(3, 1, b"activeXX", 20).await.unwrap();
+            assert_eq!(store.location(2, 1).unwrap().pack, 1);
+            store.remove(1, 1).await.unwrap();
+        }
+        assert_eq!(compact(root, 20, 0.5).await.unwrap(), 8);
+        PACK_STORES.lock().unwrap().remove(root);
+
+        let store = PackStore::load(root).await.unwrap();
+        assert!(!store.pack_path(1).exists());
+        assert_eq!(store.location(2, 1).unwrap().pack, 2);
+        assert_eq!(read_back(&store, 2).await, b"livedata");
+        assert_eq!(read_back(&store, 3).await, b"activeXX");
+        assert!(store.location(1, 1).is_none());
+    }
+}
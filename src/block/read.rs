@@ -0,0 +1,77 @@
+// src/block/read.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::{store, CHUNK_SIZE};
+use crate::metadata::model::FileMetadata;
+
+// Reads up to `len` bytes starting at `offset` from a file's block map.
+// Holes, and chunks missing from the map, read back as zeros.
+pub async fn read_range(
+    root_path: &str,
+    metadata: &FileMetadata,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, store::RwError> {
+    let end = offset.saturating_add(len).min(metadata.size);
+    if offset >= end {
+        return Ok(Vec::new());
+    }
+
+    let chunk_size = CHUNK_SIZE as u64;
+    let mut data = Vec::with_capacity((end - offset) as usize);
+    for sequence in offset / chunk_size..end.div_ceil(chunk_size) {
+        let chunk_start = sequence * chunk_size;
+        let chunk_len = chunk_size.min(metadata.size - chunk_start);
+        let chunk = match metadata.blocks.get(&sequence) {
+            Some(block) if !block.hole => store::read_block(root_path, block.xxh3, block.index).await?,
+            _ => vec![0; chunk_len as usize],
+        };
+
+        // Copy only the part of this chunk that overlaps the requested range.
+        let from = offset.saturating_sub(chunk_start) as usize;
+        let to = ((end - chunk_start).min(chunk_len) as usize).min(chunk.len());
+        if from < to {
+            data.extend_from_slice(&chunk[from..to]);
+        }
+    }
+    Ok(data)
+}
+
+// Reads the whole content of a file.
+pub async fn read_all(root_path: &str, metadata: &FileMetadata) -> Result<Vec<u8>, store::RwError> {
+    read_range(root_path, metadata, 0, metadata.size).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::digest;
+    use crate::metadata::model::BlockInfo;
+    use crate::metadata::test_util::{self, TestPool};
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    #[tokio::test]
+    async fn holes_and_missing_chunks_read_as_zeros() {
+        let pool = TestPool::new("read-holes", "{}");
+        let root = pool.root.as_str();
+        let data = vec![5; CHUNK_SIZE];
+        let xxh3 = digest::calculate_xxh3_128(&data);
+        let stored = store::write_block(root, xxh3, &data).await.unwrap();
+
+        // Data, a hole, a chunk missing from the map, and data cut short by the file's end.
+        let mut metadata = test_util::file_metadata("f", 3 * CHUNK + 10);
+        metadata.blocks.insert(0, BlockInfo::stored(xxh3, stored.index));
+        metadata.blocks.insert(1, BlockInfo::hole());
+        metadata.blocks.insert(3, BlockInfo::stored(xxh3, stored.index));
+
+        let mut expected = vec![5; CHUNK_SIZE];
+        expected.resize(3 * CHUNK_SIZE, 0);
+        expected.extend_from_slice(&[5; 10]);
+        assert_eq!(read_all(root, &metadata).await.unwrap(), expected);
+        assert_eq!(read_range(root, &metadata, CHUNK - 2, 4).await.unwrap(), [5, 5, 0, 0]);
+        assert_eq!(read_range(root, &metadata, 3 * CHUNK - 1, 100).await.unwrap(), &expected[3 * CHUNK_SIZE - 1..]);
+        assert!(read_range(root, &metadata, 3 * CHUNK + 10, 5).await.unwrap().is_empty());
+    }
+}
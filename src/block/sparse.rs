@@ -0,0 +1,107 @@
+// src/block/sparse.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// Returns true if every byte of the chunk is zero.
+pub fn is_zero(data: &[u8]) -> bool {
+    // Compare 16 bytes at a time; this is much faster than a byte loop on large chunks.
+    let words = data.chunks_exact(16);
+    let tail = words.remainder();
+    words.fold(0u128, |acc, w| acc | u128::from_ne_bytes(w.try_into().unwrap())) == 0
+        && tail.iter().all(|&b| b == 0)
+}
+
+// Finds the start of the next data region at or after `pos` using SEEK_DATA.
+// Returns `None` if there is no more data, i.e. the rest of the file is a hole.
+// Filesystems without hole reporting are treated as fully allocated.
+#[cfg(target_os = "linux")]
+pub fn next_data_offset(file: &impl AsRawFd, pos: u64) -> io::Result<Option<u64>> {
+    let offset = unsafe { libc::lseek(file.as_raw_fd(), pos as libc::off_t, libc::SEEK_DATA) };
+    if offset >= 0 {
+        return Ok(Some(offset as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        Some(libc::EINVAL) => Ok(Some(pos)),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn next_data_offset(_file: &impl AsRawFd, pos: u64) -> io::Result<Option<u64>> {
+    Ok(Some(pos))
+}
+
+// Deallocates a byte range of a file so that it reads back as zeros without using space.
+// Returns false if the platform or filesystem cannot punch holes; the caller
+// must then write the zeros itself.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &impl AsRawFd, offset: u64, len: u64) -> io::Result<bool> {
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let ret = unsafe {
+        libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t)
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &impl AsRawFd, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::CHUNK_SIZE;
+    use crate::metadata::test_util::TestPool;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    #[test]
+    fn zero_chunks_are_told_from_data_anywhere_in_them() {
+        assert!(is_zero(&[]));
+        assert!(is_zero(&[0; 33]));
+        for at in [0, 15, 16, 31, 32] {
+            let mut data = [0; 33];
+            data[at] = 1;
+            assert!(!is_zero(&data));
+        }
+    }
+
+    // Expects the temporary directory on a filesystem that reports holes, as
+    // ext4, xfs, btrfs and tmpfs do.
+    #[test]
+    fn holes_are_found_and_punched() {
+        let pool = TestPool::new("sparse-holes", "{}");
+        let path = Path::new(&pool.root).join("sparse.bin");
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).unwrap();
+        file.set_len(5 * CHUNK).unwrap();
+        file.write_all_at(&vec![1; CHUNK_SIZE], CHUNK).unwrap();
+        file.write_all_at(&vec![2; CHUNK_SIZE], 3 * CHUNK).unwrap();
+
+        assert_eq!(next_data_offset(&file, 0).unwrap(), Some(CHUNK));
+        assert_eq!(next_data_offset(&file, CHUNK + 5).unwrap(), Some(CHUNK + 5));
+        assert_eq!(next_data_offset(&file, 2 * CHUNK).unwrap(), Some(3 * CHUNK));
+        assert_eq!(next_data_offset(&file, 4 * CHUNK).unwrap(), None);
+
+        assert!(punch_hole(&file, CHUNK, CHUNK).unwrap());
+        assert_eq!(next_data_offset(&file, 0).unwrap(), Some(3 * CHUNK));
+        let mut punched = vec![1; CHUNK_SIZE];
+        file.read_exact_at(&mut punched, CHUNK).unwrap();
+        assert!(is_zero(&punched));
+        assert_eq!(file.metadata().unwrap().len(), 5 * CHUNK);
+    }
+}
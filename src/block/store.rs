@@ -2,83 +2,132 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
-use std::path::{Path, PathBuf};
+use crate::block::backend::{self, BlockKey};
+use crate::block::digest;
+use crate::block::index::{self, IndexEntry};
 use thiserror::Error;
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
 
 #[derive(Error, Debug)]
 pub enum RwError {
     // I/O error during block read/write operations.
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+
+    // The requested block does not exist in the pool's backend.
+    #[error("Block {0} not found")]
+    NotFound(BlockKey),
+
+    // A non-filesystem backend (e.g. an object store) reported an error.
+    #[error("Block backend error: {0}")]
+    Backend(String),
 }
 
-// Constructs the full path to a block's directory based on its XXH3 hash.
-// The structure is /blocks/{:2}/{:2}/{:2}/
-fn get_block_dir(root_path: &str, xxh3: u128) -> PathBuf {
-    let xxh3_hex = format!("{:032x}", xxh3);
-    Path::new(root_path)
-        .join("blocks")
-        .join(&xxh3_hex[0..2])
-        .join(&xxh3_hex[2..4])
-        .join(&xxh3_hex[4..6])
+// The outcome of a block write: where the block lives and whether it was newly stored.
+#[derive(Debug, Clone, Copy)]
+pub struct StoredBlock {
+    pub index: u32,
+    // False when an identical block already existed and was reused.
+    pub is_new: bool,
 }
 
 // Writes a data block to the storage pool, using only XXH3 for pathing and naming.
-// Returns the collision index `n` of the `{xxh3}-{n}` file that was written or matched.
+// Returns the collision index `n` of the `{xxh3}-{n}` block that was written or matched.
+// Every call adds one reference to the block; release it with `release_block`.
 pub async fn write_block(
     root_path: &str,
     xxh3: u128,
     data: &[u8],
-) -> Result<u32, RwError> {
-    let block_dir = get_block_dir(root_path, xxh3);
-    fs::create_dir_all(&block_dir).await?;
+) -> Result<StoredBlock, RwError> {
+    let sha256 = digest::calculate_sha256(data);
+    let len = data.len() as u64;
+    let index = index::open(root_path).await?;
+    let mut guard = index.lock().await;
 
-    // Asynchronously read the directory to find all potential collision files at once.
-    let mut max_n = 0;
-    let mut matching_paths = Vec::new();
+    // The index answers both hits and misses without touching the block backend.
+    if let Some(n) = guard.find(xxh3, len, &sha256) {
+        guard.add_ref(xxh3, n, 1).await?;
+        let commit = guard.commit();
+        drop(guard);
+        commit.wait().await?;
+        return Ok(StoredBlock { index: n, is_new: false });
+    }
 
-    match fs::read_dir(&block_dir).await {
-        Ok(mut entries) => {
-            let prefix = format!("{:032x}-", xxh3);
-            while let Some(entry) = entries.next_entry().await? {
-                if let Some(name_str) = entry.file_name().to_str() {
-                    if name_str.starts_with(&prefix) {
-                        if let Some(n_str) = name_str.strip_prefix(&prefix) {
-                            if let Ok(n) = n_str.parse::<u32>() {
-                                if n > max_n {
-                                    max_n = n;
-                                }
-                                matching_paths.push((n, entry.path()));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-        Err(e) => return Err(e.into()),
+    // If no match was found, write a new block at the end of the chain. The
+    // write happens without the index lock, so a concurrent write of the same
+    // content stores a second copy; later writes dedup against the first.
+    let new_index = guard.next_index(xxh3);
+    guard.reserve(xxh3, new_index);
+    drop(guard);
+    let put = match backend::get_block_store(root_path).await {
+        Ok(store) => store.put(BlockKey::new(xxh3, new_index), data).await,
+        Err(e) => Err(e),
     };
 
-    // Iterate through the discovered files and compare their content.
-    for (n, path) in matching_paths {
-        let existing_data = fs::read(path).await?;
-        if existing_data == data {
-            // Found an exact match. Return its index.
-            return Ok(n);
-        }
+    let mut guard = index.lock().await;
+    if let Err(e) = put {
+        guard.unreserve(xxh3, new_index);
+        return Err(e);
     }
+    guard
+        .insert(xxh3, IndexEntry { index: new_index, len, sha256, refs: 1 })
+        .await?;
+    let commit = guard.commit();
+    drop(guard);
+    commit.wait().await?;
+    Ok(StoredBlock { index: new_index, is_new: true })
+}
 
-    // If no match was found, write a new file at the end of the chain.
-    let new_index = max_n + 1;
-    let new_block_filename = format!("{:032x}-{}", xxh3, new_index);
-    let new_block_path = block_dir.join(new_block_filename);
+// Adds one reference to an already stored block, e.g. when a block map is cloned.
+pub async fn retain_block(
+    root_path: &str,
+    xxh3: u128,
+    collision_index: u32,
+) -> Result<(), RwError> {
+    let index = index::open(root_path).await?;
+    let mut guard = index.lock().await;
+    if guard.get(xxh3, collision_index).is_none() {
+        return Err(RwError::NotFound(BlockKey::new(xxh3, collision_index)));
+    }
+    guard.add_ref(xxh3, collision_index, 1).await?;
+    let commit = guard.commit();
+    drop(guard);
+    Ok(commit.wait().await?)
+}
 
-    let mut file = fs::File::create(&new_block_path).await?;
-    file.write_all(data).await?;
+// Drops one reference to a block, deleting it once nothing references it anymore.
+pub async fn release_block(
+    root_path: &str,
+    xxh3: u128,
+    collision_index: u32,
+) -> Result<(), RwError> {
+    let index = index::open(root_path).await?;
+    let mut guard = index.lock().await;
+    if guard.get(xxh3, collision_index).is_none() {
+        return Ok(());
+    }
+    let refs = guard.add_ref(xxh3, collision_index, -1).await?;
+    if refs > 0 {
+        let commit = guard.commit();
+        drop(guard);
+        return Ok(commit.wait().await?);
+    }
 
-    Ok(new_index)
+    // The block leaves the index durably before it is deleted: a crash in
+    // between leaves an orphaned block, never an entry for a missing one. Its
+    // collision index stays reserved until the delete is done.
+    guard.remove(xxh3, collision_index).await?;
+    guard.reserve(xxh3, collision_index);
+    let commit = guard.commit();
+    drop(guard);
+    let deleted = match commit.wait().await {
+        Ok(()) => match backend::get_block_store(root_path).await {
+            Ok(store) => store.delete(BlockKey::new(xxh3, collision_index)).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e.into()),
+    };
+    index.lock().await.unreserve(xxh3, collision_index);
+    deleted
 }
 
 // Reads a data block from the storage pool.
@@ -87,9 +136,6 @@ pub async fn read_block(
     xxh3: u128,
     collision_index: u32,
 ) -> Result<Vec<u8>, RwError> {
-    let block_dir = get_block_dir(root_path, xxh3);
-    let block_filename = format!("{:032x}-{}", xxh3, collision_index);
-    let block_path = block_dir.join(block_filename);
-    let data = fs::read(block_path).await?;
-    Ok(data)
+    let store = backend::get_block_store(root_path).await?;
+    store.get(BlockKey::new(xxh3, collision_index)).await
 }
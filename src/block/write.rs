@@ -0,0 +1,253 @@
+// src/block/write.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::{digest, read, sparse, store, CHUNK_SIZE};
+use crate::metadata::{error::MetadataError, manager, model::{BlockInfo, FileMetadata}, precondition::Precondition};
+use chrono::Utc;
+use rfs_utils::{log, LogLevel};
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum WriteError {
+    #[error("Block storage error: {0}")]
+    Store(#[from] store::RwError),
+    #[error("Metadata error: {0}")]
+    Metadata(#[from] MetadataError),
+    #[error("Write at offset {0} would overflow the file size")]
+    Overflow(u64),
+}
+
+// Writes `data` into the file at `rfs_file_path`, starting at `offset`.
+// Writing past the end grows the file; any gap before `offset` reads as zeros.
+pub async fn pwrite(pool_root: &str, rfs_file_path: &str, offset: u64, data: &[u8]) -> Result<(), WriteError> {
+    pwrite_if(pool_root, rfs_file_path, offset, data, &Precondition::default()).await
+}
+
+// Like `pwrite`, but only if the file satisfies `precondition`.
+pub async fn pwrite_if(
+    pool_root: &str,
+    rfs_file_path: &str,
+    offset: u64,
+    data: &[u8],
+    precondition: &Precondition,
+) -> Result<(), WriteError> {
+    let update = manager::open_file_for_update(pool_root, rfs_file_path, precondition).await?;
+    let end = offset.checked_add(data.len() as u64).ok_or(WriteError::Overflow(offset))?;
+    // An empty write never grows the file.
+    let size = if data.is_empty() { update.metadata.size } else { update.metadata.size.max(end) };
+    rewrite(pool_root, update, size, Some((offset, data))).await
+}
+
+// Appends `data` to the end of the file and returns the offset it was written at.
+pub async fn append(pool_root: &str, rfs_file_path: &str, data: &[u8]) -> Result<u64, WriteError> {
+    append_if(pool_root, rfs_file_path, data, &Precondition::default()).await
+}
+
+// Like `append`, but only if the file satisfies `precondition`.
+pub async fn append_if(
+    pool_root: &str,
+    rfs_file_path: &str,
+    data: &[u8],
+    precondition: &Precondition,
+) -> Result<u64, WriteError> {
+    let update = manager::open_file_for_update(pool_root, rfs_file_path, precondition).await?;
+    let offset = update.metadata.size;
+    let size = offset.checked_add(data.len() as u64).ok_or(WriteError::Overflow(offset))?;
+    rewrite(pool_root, update, size, Some((offset, data))).await?;
+    Ok(offset)
+}
+
+// Shrinks or grows the file to `size` bytes. Growing it appends zeros.
+pub async fn truncate(pool_root: &str, rfs_file_path: &str, size: u64) -> Result<(), WriteError> {
+    truncate_if(pool_root, rfs_file_path, size, &Precondition::default()).await
+}
+
+// Like `truncate`, but only if the file satisfies `precondition`.
+pub async fn truncate_if(
+    pool_root: &str,
+    rfs_file_path: &str,
+    size: u64,
+    precondition: &Precondition,
+) -> Result<(), WriteError> {
+    let update = manager::open_file_for_update(pool_root, rfs_file_path, precondition).await?;
+    rewrite(pool_root, update, size, None).await
+}
+
+// Rewrites only the chunks a change touches: each is rebuilt from its old
+// contents and the new data and stored as a new block, while untouched chunks
+// keep their blocks. Blocks are never modified in place, so snapshots, copies
+// and versions sharing them are unaffected. The new block map and size are
+// swapped in at once; the replaced blocks are released afterwards. Chunks of
+// zeros get no entry in the block map, as missing chunks read as zeros, so a
+// write far past the end does not grow the map by the size of the gap.
+async fn rewrite(
+    pool_root: &str,
+    update: manager::FileUpdate,
+    size: u64,
+    write: Option<(u64, &[u8])>,
+) -> Result<(), WriteError> {
+    update.check_quota(size).await?;
+    let old = update.metadata.clone();
+    let chunk_size = CHUNK_SIZE as u64;
+    let new_chunks = size.div_ceil(chunk_size);
+
+    let mut touched = BTreeSet::new();
+    if let Some((offset, data)) = write
+        && !data.is_empty()
+    {
+        touched.extend(offset / chunk_size..=(offset + data.len() as u64 - 1) / chunk_size);
+    }
+    // The chunk the size boundary cuts through changes length, so it is rebuilt too.
+    let boundary = old.size.min(size);
+    if size != old.size && !boundary.is_multiple_of(chunk_size) {
+        touched.insert(boundary / chunk_size);
+    }
+
+    let mut metadata = FileMetadata {
+        size,
+        modified_at: Utc::now(),
+        blocks: old.blocks.range(..new_chunks).map(|(s, b)| (*s, b.clone())).collect(),
+        ..old.clone()
+    };
+
+    // Blocks referenced by this write; released again if it fails.
+    let mut stored_blocks = Vec::new();
+    let result = store_chunks(pool_root, &old, &mut metadata, &touched, write, &mut stored_blocks).await;
+    let result = match result {
+        Ok(()) => update.commit(metadata).await.map_err(WriteError::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        log(LogLevel::Warn, &format!("Write aborted: {}. Releasing {} blocks.", e, stored_blocks.len()));
+        release_blocks(pool_root, &stored_blocks).await;
+        return result;
+    }
+
+    // Each sequence held one reference, whether its block was replaced or cut off.
+    let replaced: Vec<BlockInfo> = old
+        .blocks
+        .iter()
+        .filter(|(sequence, block)| !block.hole && (**sequence >= new_chunks || touched.contains(*sequence)))
+        .map(|(_, block)| block.clone())
+        .collect();
+    release_blocks(pool_root, &replaced).await;
+    Ok(())
+}
+
+async fn store_chunks(
+    pool_root: &str,
+    old: &FileMetadata,
+    metadata: &mut FileMetadata,
+    touched: &BTreeSet<u64>,
+    write: Option<(u64, &[u8])>,
+    stored_blocks: &mut Vec<BlockInfo>,
+) -> Result<(), WriteError> {
+    let chunk_size = CHUNK_SIZE as u64;
+    for &sequence in touched {
+        let chunk_start = sequence * chunk_size;
+        let chunk_len = chunk_size.min(metadata.size - chunk_start) as usize;
+
+        // Start from the old contents; anything past the old end is zeros.
+        let mut chunk = read::read_range(pool_root, old, chunk_start, chunk_len as u64).await?;
+        chunk.resize(chunk_len, 0);
+        if let Some((offset, data)) = write {
+            let from = offset.max(chunk_start);
+            let to = (offset + data.len() as u64).min(chunk_start + chunk_len as u64);
+            if from < to {
+                chunk[(from - chunk_start) as usize..(to - chunk_start) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+
+        if sparse::is_zero(&chunk) {
+            metadata.blocks.remove(&sequence);
+            continue;
+        }
+        let xxh3_hash = digest::calculate_xxh3_128(&chunk);
+        let stored = store::write_block(pool_root, xxh3_hash, &chunk).await?;
+        let block = BlockInfo::stored(xxh3_hash, stored.index);
+        stored_blocks.push(block.clone());
+        metadata.blocks.insert(sequence, block);
+    }
+    Ok(())
+}
+
+async fn release_blocks(pool_root: &str, blocks: &[BlockInfo]) {
+    for block in blocks {
+        if let Err(e) = store::release_block(pool_root, block.xxh3, block.index).await {
+            log(LogLevel::Error, &format!("Failed to release block {:032x}-{}: {}", block.xxh3, block.index, e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::index;
+    use crate::metadata::test_util::{self, TestPool};
+
+    const CHUNK: usize = CHUNK_SIZE;
+
+    async fn contents(pool_root: &str) -> (FileMetadata, Vec<u8>) {
+        let metadata = manager::get_file_metadata(pool_root, "/f").await.unwrap();
+        let data = read::read_all(pool_root, &metadata).await.unwrap();
+        (metadata, data)
+    }
+
+    #[tokio::test]
+    async fn writes_match_a_plain_buffer() {
+        let pool = TestPool::new("write-model", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "f", test_util::file_metadata("f", 0)).await.unwrap();
+
+        let mut expected = Vec::new();
+        let pattern: Vec<u8> = (0..CHUNK as u32 + 500).map(|i| (i % 251) as u8 + 1).collect();
+        assert_eq!(append(root, "/f", &pattern).await.unwrap(), 0);
+        expected.extend_from_slice(&pattern);
+
+        // Across a chunk boundary, and past the end.
+        pwrite(root, "/f", CHUNK as u64 - 3, b"boundary").await.unwrap();
+        expected[CHUNK - 3..CHUNK + 5].copy_from_slice(b"boundary");
+        pwrite(root, "/f", 3 * CHUNK as u64 + 10, b"tail").await.unwrap();
+        expected.resize(3 * CHUNK + 10, 0);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(append(root, "/f", b"more").await.unwrap(), expected.len() as u64);
+        expected.extend_from_slice(b"more");
+        assert_eq!(contents(root).await.1, expected);
+
+        // Shrinking into a chunk and growing again brings back zeros, not the cut-off data.
+        truncate(root, "/f", CHUNK as u64 + 100).await.unwrap();
+        expected.truncate(CHUNK + 100);
+        truncate(root, "/f", 2 * CHUNK as u64).await.unwrap();
+        expected.resize(2 * CHUNK, 0);
+        let (metadata, data) = contents(root).await;
+        assert_eq!(metadata.size, expected.len() as u64);
+        assert_eq!(data, expected);
+
+        // Every block the file held is released along with it.
+        truncate(root, "/f", 0).await.unwrap();
+        assert!(contents(root).await.0.blocks.is_empty());
+        let index = index::open(root).await.unwrap();
+        assert!(index.lock().await.iter().all(|(_, entry)| entry.refs == 0));
+    }
+
+    #[tokio::test]
+    async fn gaps_take_no_room_in_the_block_map() {
+        let pool = TestPool::new("write-gap", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "f", test_util::file_metadata("f", 0)).await.unwrap();
+
+        let offset = 1 << 40;
+        pwrite(root, "/f", offset, b"x").await.unwrap();
+        let metadata = manager::get_file_metadata(root, "/f").await.unwrap();
+        assert_eq!(metadata.size, offset + 1);
+        assert_eq!(metadata.blocks.keys().collect::<Vec<_>>(), vec![&(offset / CHUNK as u64)]);
+        assert_eq!(read::read_range(root, &metadata, offset - 2, 3).await.unwrap(), b"\0\0x");
+
+        // Zeroing the only chunk with data leaves none.
+        pwrite(root, "/f", offset, b"\0").await.unwrap();
+        assert!(manager::get_file_metadata(root, "/f").await.unwrap().blocks.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+// src/common/config.rs
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (c) 2025 Canmi
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const POOL_CONFIG_FILE: &str = "config.json";
+
+// Loaded pool configurations, keyed by pool root path.
+static POOL_CONFIGS: Lazy<Mutex<HashMap<String, PoolConfig>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Per-pool options, stored as `config.json` in the pool root.
+///
+/// Every field has a default, so a missing file or missing keys behave like
+/// a pool created with the built-in settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PoolConfig {
+    /// Where block contents are stored.
+    pub block_backend: BlockBackend,
+    /// How new blocks are laid out on disk. Existing blocks stay readable
+    /// when this is changed.
+    pub block_layout: BlockLayout,
+    /// Size at which a packfile is sealed and a new one is started.
+    pub pack_size: u64,
+    /// Fraction of dead bytes at which a packfile is rewritten by compaction.
+    pub pack_compact_ratio: f64,
+    /// Where the directory tree and file block maps are stored. Switch with
+    /// `rfs-migrate`, which converts the existing metadata.
+    pub metadata_backend: MetadataBackend,
+    /// Keep a resident index of every entry (`search.log`) so that searches
+    /// do not walk the metadata tree. Delete the file to have it rebuilt.
+    /// Only used with the db metadata backend; other pools are walked.
+    pub search_index: bool,
+    /// How many prior versions of a file are kept when it is replaced, and for how long.
+    pub version_retention: VersionRetention,
+    /// Which names entries may have and how they are looked up.
+    pub naming: NamingConfig,
+    /// Whether deleted entries go to a trash first, and for how long.
+    pub trash: TrashConfig,
+    /// Records kept in the change log (`changes.log`) when the daemon's
+    /// maintenance task trims it. Watchers that fall further behind must rescan.
+    pub change_log_records: u64,
+    /// Keep parsed directory listings and resolved paths in memory.
+    pub listing_cache: ListingCacheConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum BlockBackend {
+    /// The local filesystem under the pool root, laid out per `block_layout`.
+    #[default]
+    Local,
+    /// Process memory. Blocks do not survive a restart; meant for tests.
+    Memory,
+    /// An S3-compatible object store.
+    S3(S3Config),
+}
+
+/// Connection settings for an S3-compatible bucket. Objects are addressed
+/// path-style, which both AWS and MinIO-style servers accept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `http://127.0.0.1:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, so several pools can share a bucket.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum BlockLayout {
+    /// One file per block under `blocks/xx/yy/zz/`.
+    #[default]
+    Files,
+    /// Blocks appended to large segment files under `blocks/packs/`.
+    Packs,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataBackend {
+    /// A tree of JSON files under `metadata/`.
+    #[default]
+    Json,
+    /// An embedded transactional key-value database, `metadata.redb`.
+    Db,
+}
+
+/// Retention policy for file versions. A version is pruned as soon as any
+/// limit is exceeded, oldest first. Age and size limits are also enforced by
+/// the daemon's maintenance task.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VersionRetention {
+    /// Prior versions kept per file. 0 disables versioning.
+    pub max_versions: u32,
+    /// Versions replaced longer ago than this are pruned.
+    pub max_age_days: Option<u64>,
+    /// Cap on the combined size of a file's versions.
+    pub max_bytes: Option<u64>,
+}
+
+/// Naming policy for entries. Names are always stored NFC-normalized, so
+/// differently composed spellings of the same name refer to one entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NamingConfig {
+    pub mode: NamingMode,
+    /// Resolve names regardless of case. Entries keep the case they were
+    /// created with; a lookup that misses goes through a case-folded index.
+    pub case_insensitive: bool,
+}
+
+/// Trash policy. With the trash enabled, a deleted entry can be restored
+/// until the daemon's maintenance task purges it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TrashConfig {
+    pub enabled: bool,
+    /// Days a deleted entry is kept before it is purged and its blocks released.
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig { enabled: false, retention_days: 30 }
+    }
+}
+
+/// Bounds of the in-process cache of directory listings and path resolutions.
+/// Listings written by other processes are noticed by their file stamps, so
+/// a pool with the `json` backend can still be shared.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ListingCacheConfig {
+    pub enabled: bool,
+    /// Directory entries held across all cached listings. Larger listings are not cached.
+    pub max_entries: usize,
+    /// Directory paths whose resolution is remembered.
+    pub max_paths: usize,
+}
+
+impl Default for ListingCacheConfig {
+    fn default() -> Self {
+        ListingCacheConfig { enabled: true, max_entries: 100_000, max_paths: 10_000 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum NamingMode {
+    /// Anything but `/` and NUL, like a POSIX filesystem.
+    Posix,
+    /// Letters, digits and `_-.@~()[]` only.
+    #[default]
+    Safe,
+    /// Names a Windows client can create: no `<>:"/\|?*` or control
+    /// characters, no trailing dot or space, and no device names like `CON`.
+    Windows,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            block_backend: BlockBackend::Local,
+            block_layout: BlockLayout::Files,
+            pack_size: 1024 * 1024 * 1024, // 1 GB
+            pack_compact_ratio: 0.5,
+            metadata_backend: MetadataBackend::Json,
+            search_index: false,
+            version_retention: VersionRetention::default(),
+            naming: NamingConfig::default(),
+            trash: TrashConfig::default(),
+            change_log_records: 100_000,
+            listing_cache: ListingCacheConfig::default(),
+        }
+    }
+}
+
+/// Gets the configuration of the pool at `pool_root`.
+///
+/// The file is read on first use and cached for the lifetime of the process.
+pub async fn get_pool_config(pool_root: &str) -> std::io::Result<PoolConfig> {
+    if let Some(config) = POOL_CONFIGS.lock().unwrap().get(pool_root) {
+        return Ok(config.clone());
+    }
+
+    let config_path = Path::new(pool_root).join(POOL_CONFIG_FILE);
+    let config = match tokio::fs::read(&config_path).await {
+        Ok(content) => serde_json::from_slice(&content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => PoolConfig::default(),
+        Err(e) => return Err(e),
+    };
+    POOL_CONFIGS
+        .lock()
+        .unwrap()
+        .insert(pool_root.to_string(), config.clone());
+    Ok(config)
+}
+
+/// Drops the cached configuration of a pool, so the next
+/// `get_pool_config` call reads `config.json` again.
+pub fn invalidate_pool_config(pool_root: &str) {
+    POOL_CONFIGS.lock().unwrap().remove(pool_root);
+}
@@ -2,4 +2,5 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (c) 2025 Canmi
 
+pub mod config;
 pub mod pool;
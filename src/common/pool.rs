@@ -21,3 +21,9 @@ pub fn get_pool_path_by_id(id: u64) -> Option<String> {
         .find(|p| p.pool_id == id)
         .map(|p| p.path.clone())
 }
+
+/// Gets the storage paths of all loaded pools.
+pub fn get_all_pool_paths() -> Vec<String> {
+    let pools_guard = POOLS.lock().unwrap();
+    pools_guard.iter().map(|p| p.path.clone()).collect()
+}
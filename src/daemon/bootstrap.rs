@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
-use crate::daemon::{router, unixsock};
+use crate::daemon::{maintenance, router, unixsock};
 use rfs_ess::Config; // Import the Config struct
 use rfs_utils::{log, LogLevel};
 
@@ -20,6 +20,9 @@ pub async fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let app = router::create_router();
     log(LogLevel::Debug, "Router created.");
 
+    maintenance::spawn();
+    log(LogLevel::Debug, "Maintenance task started.");
+
     log(LogLevel::Info, &format!("Server listening on {}", socket_path));
 
     axum::serve(listener, app.into_make_service())
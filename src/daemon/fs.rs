@@ -0,0 +1,281 @@
+// src/daemon/fs.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::common::pool::get_pool_path_by_id;
+use crate::metadata::cache;
+use crate::metadata::du::{self, DuOptions};
+use crate::metadata::error::MetadataError;
+use crate::metadata::journal;
+use crate::metadata::listing::{EntryKind, ListOptions, SortKey, SortOrder};
+use crate::metadata::manager;
+use crate::metadata::precondition::Precondition;
+use crate::metadata::search::{self, SearchQuery};
+use crate::metadata::store;
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    pub pool: u64,
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(rename = "type")]
+    pub kind: Option<EntryKind>,
+    pub pattern: Option<String>,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+// Lists one page of a directory, e.g.
+// `GET /list?pool=1&path=/photos&sort=mtime&order=desc&limit=100&type=file&pattern=*.jpg`.
+pub async fn get_list_handler(Query(query): Query<ListQuery>) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    let options = ListOptions {
+        sort: query.sort,
+        order: query.order,
+        cursor: query.cursor,
+        limit: query.limit,
+        kind: query.kind,
+        pattern: query.pattern,
+    };
+    match manager::list_directory_page(&pool_root, &query.path, &options).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EntryQuery {
+    pub pool: u64,
+    pub path: String,
+}
+
+// Reads the entry at a path, with its ETag in the `ETag` header, e.g.
+// `GET /entry?pool=1&path=/photos/a.jpg`. An `If-None-Match` header the entry
+// matches yields 304 Not Modified.
+pub async fn get_entry_handler(Query(query): Query<EntryQuery>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    let entry = match manager::get_entry(&pool_root, &query.path).await {
+        Ok(entry) => entry,
+        Err(e) => return (error_status(&e), e.to_string()).into_response(),
+    };
+    let not_modified = Precondition { if_match: None, ..precondition_from(&headers) }
+        .check(&query.path, Some(&entry))
+        .is_err();
+    let etag = entry.etag().and_then(|etag| HeaderValue::from_str(&format!("\"{}\"", etag)).ok());
+    let mut response = match not_modified {
+        true => StatusCode::NOT_MODIFIED.into_response(),
+        false => (StatusCode::OK, Json(entry)).into_response(),
+    };
+    if let Some(etag) = etag {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+// Deletes the entry at a path, e.g. `DELETE /entry?pool=1&path=/photos/a.jpg`.
+// With `If-Match` or `If-None-Match` the delete only happens if the entry's
+// ETag satisfies them, and fails with 412 Precondition Failed otherwise.
+pub async fn delete_entry_handler(Query(query): Query<EntryQuery>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    match manager::delete_entry_if(&pool_root, &query.path, &precondition_from(&headers)).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+// Reads the `If-Match` and `If-None-Match` headers of a request.
+pub fn precondition_from(headers: &HeaderMap) -> Precondition {
+    let value = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(String::from);
+    Precondition { if_match: value(header::IF_MATCH), if_none_match: value(header::IF_NONE_MATCH) }
+}
+
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    pub pool: u64,
+    #[serde(flatten)]
+    pub query: SearchQuery,
+}
+
+// Searches a pool on the daemon side, e.g.
+// `POST /search {"pool": 1, "path": "/photos", "name": "*.jpg", "minSize": 1048576}`.
+pub async fn post_search_handler(Json(payload): Json<SearchRequest>) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(payload.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", payload.pool)).into_response();
+    };
+
+    match search::search(&pool_root, &payload.query).await {
+        Ok(hits) => (StatusCode::OK, Json(hits)).into_response(),
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QuotaQuery {
+    pub pool: u64,
+    pub path: Option<String>,
+    pub owner: Option<String>,
+}
+
+// Reports the usage of a directory or owner against its quota, e.g.
+// `GET /quota?pool=1&path=/photos` or `GET /quota?pool=1&owner=alice`.
+pub async fn get_quota_handler(Query(query): Query<QuotaQuery>) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    let usage = match (&query.path, &query.owner) {
+        (Some(path), None) => manager::get_dir_quota(&pool_root, path).await,
+        (None, Some(owner)) => manager::get_owner_quota(&pool_root, owner).await,
+        _ => return (StatusCode::BAD_REQUEST, "Expected either a path or an owner").into_response(),
+    };
+    match usage {
+        Ok(usage) => (StatusCode::OK, Json(usage)).into_response(),
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuQuery {
+    pub pool: u64,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub max_depth: usize,
+    #[serde(default)]
+    pub exclusive: bool,
+}
+
+// Reports the space used below a path, or by the whole pool if none is given, e.g.
+// `GET /du?pool=1&path=/photos&maxDepth=1&exclusive=true`.
+pub async fn get_du_handler(Query(query): Query<DuQuery>) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    let Some(path) = &query.path else {
+        return match du::pool_usage(&pool_root).await {
+            Ok(usage) => (StatusCode::OK, Json(usage)).into_response(),
+            Err(e) => (error_status(&e), e.to_string()).into_response(),
+        };
+    };
+    let options = DuOptions { max_depth: query.max_depth, exclusive: query.exclusive };
+    match du::du(&pool_root, path, &options).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CacheQuery {
+    pub pool: u64,
+}
+
+// Reports the hit and miss counters of a pool's listing cache, e.g. `GET /cache?pool=1`.
+pub async fn get_cache_handler(Query(query): Query<CacheQuery>) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    // The cache is set up along with the pool's metadata store.
+    if let Err(e) = store::get_metadata_store(&pool_root).await {
+        return (error_status(&e), e.to_string()).into_response();
+    }
+    match cache::cache_stats(&pool_root) {
+        Some(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Pool {} has no listing cache", query.pool)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    pub pool: u64,
+    #[serde(default = "default_path")]
+    pub path: String,
+    pub since: Option<u64>,
+}
+
+// Streams the changes below a path as server-sent events, e.g.
+// `GET /watch?pool=1&path=/photos&since=120`. Each event carries its sequence
+// number as ID, so a client reconnecting with `Last-Event-ID` resumes where it
+// left off, rather than at the `since` of the URL it reconnects to. Without
+// either, only changes made from now on are sent. A final `error` event
+// reports why the stream ended.
+pub async fn get_watch_handler(Query(query): Query<WatchQuery>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(pool_root) = get_pool_path_by_id(query.pool) else {
+        return (StatusCode::NOT_FOUND, format!("Pool with ID {} not found", query.pool)).into_response();
+    };
+
+    let last_event_id = headers.get("last-event-id").and_then(|v| v.to_str().ok()?.parse().ok());
+    let since = match last_event_id.or(query.since) {
+        Some(since) => since,
+        None => match journal::current_seq(&pool_root).await {
+            Ok(seq) => seq,
+            Err(e) => return (error_status(&e), e.to_string()).into_response(),
+        },
+    };
+    let events = journal::watch(&pool_root, &query.path, since).map(|change| {
+        let event = match change {
+            Ok(change) => Event::default()
+                .id(change.seq.to_string())
+                .json_data(&change)
+                .unwrap_or_else(|e| Event::default().event("error").data(e.to_string())),
+            Err(e) => Event::default().event("error").data(e.to_string()),
+        };
+        Ok::<_, Infallible>(event)
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+// Maps a metadata error to the HTTP status reported to clients.
+pub fn error_status(error: &MetadataError) -> StatusCode {
+    match error {
+        MetadataError::NotFound(_)
+        | MetadataError::SnapshotNotFound(_)
+        | MetadataError::VersionNotFound(_)
+        | MetadataError::TrashItemNotFound(_)
+        | MetadataError::XattrNotFound(_) => StatusCode::NOT_FOUND,
+        MetadataError::EntryAlreadyExists(_) => StatusCode::CONFLICT,
+        MetadataError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        MetadataError::ChangesTrimmed(_) | MetadataError::ChangesLost(_) => StatusCode::GONE,
+        MetadataError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+        MetadataError::InvalidPathComponent(_)
+        | MetadataError::EmptyPathComponent
+        | MetadataError::NotAFile(_)
+        | MetadataError::NotADirectory(_)
+        | MetadataError::NotASymlink(_)
+        | MetadataError::InvalidXattrName(_)
+        | MetadataError::InvalidCursor(_)
+        | MetadataError::InvalidPattern(_)
+        | MetadataError::InvalidLimit(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,214 @@
+// src/daemon/job.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::ingest::{self, IngestControl, IngestError, IngestProgress};
+use crate::daemon::fs::precondition_from;
+use axum::{
+    extract::Path as UrlPath,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Tracks the ingest jobs started through the daemon, keyed by job ID.
+static JOBS: Lazy<Mutex<HashMap<u64, IngestJob>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+// How long the outcome of a finished job can still be queried.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", tag = "state", content = "message")]
+pub enum JobState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed(String),
+}
+
+struct IngestJob {
+    control: IngestControl,
+    state: JobState,
+    finished_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+pub struct IngestJobRequest {
+    pub file: String,
+    pub path: String, // Destination directory inside the pool.
+    pub pool: u64,
+    // Replace an existing file of the same name instead of failing.
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestJobStatus {
+    pub id: u64,
+    #[serde(flatten)]
+    pub state: JobState,
+    pub progress: IngestProgress,
+}
+
+// Starts an ingest in the background and returns its job ID immediately.
+// `If-Match` and `If-None-Match` headers make it a replace that only goes
+// ahead if the existing file satisfies them once the ingest completes;
+// otherwise the job fails.
+pub async fn post_ingest_job_handler(
+    headers: HeaderMap,
+    Json(payload): Json<IngestJobRequest>,
+) -> impl IntoResponse {
+    let precondition = precondition_from(&headers);
+    let filename = match Path::new(&payload.file).file_name().and_then(|n| n.to_str()) {
+        Some(name) => name.to_string(),
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Could not determine filename from: {}", payload.file),
+            )
+                .into_response();
+        }
+    };
+
+    let id = start_job(move |control| async move {
+        if payload.replace || !precondition.is_empty() {
+            ingest::reingest_file_if(&payload.file, &payload.path, &filename, payload.pool, &control, &precondition)
+                .await
+        } else {
+            ingest::ingest_file_with_control(&payload.file, &payload.path, &filename, payload.pool, &control).await
+        }
+    });
+    (StatusCode::ACCEPTED, Json(status_of(id).unwrap())).into_response()
+}
+
+// Reports the state and progress of an ingest job.
+pub async fn get_ingest_job_handler(UrlPath(id): UrlPath<u64>) -> impl IntoResponse {
+    match status_of(id) {
+        Some(status) => (StatusCode::OK, Json(status)).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("No ingest job with ID {}", id)).into_response(),
+    }
+}
+
+// Requests cancellation of a running ingest job.
+pub async fn delete_ingest_job_handler(UrlPath(id): UrlPath<u64>) -> impl IntoResponse {
+    match JOBS.lock().unwrap().get(&id) {
+        Some(job) => {
+            job.control.cancel();
+            (StatusCode::ACCEPTED, format!("Cancellation requested for ingest job {}", id))
+        }
+        None => (StatusCode::NOT_FOUND, format!("No ingest job with ID {}", id)),
+    }
+}
+
+// Registers a job and runs the ingest `run` starts for it in the background,
+// under the job's control. Returns the job's ID.
+fn start_job<F, Fut>(run: F) -> u64
+where
+    F: FnOnce(IngestControl) -> Fut,
+    Fut: Future<Output = Result<(), IngestError>> + Send + 'static,
+{
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+    let control = IngestControl::new();
+    {
+        let mut jobs = JOBS.lock().unwrap();
+        prune_finished(&mut jobs, Instant::now());
+        jobs.insert(id, IngestJob { control: control.clone(), state: JobState::Running, finished_at: None });
+    }
+
+    let ingest = run(control);
+    tokio::spawn(async move {
+        let state = match ingest.await {
+            Ok(()) => JobState::Completed,
+            Err(IngestError::Cancelled) => JobState::Cancelled,
+            Err(e) => JobState::Failed(e.to_string()),
+        };
+        if let Some(job) = JOBS.lock().unwrap().get_mut(&id) {
+            job.state = state;
+            job.finished_at = Some(Instant::now());
+        }
+    });
+    id
+}
+
+fn status_of(id: u64) -> Option<IngestJobStatus> {
+    let jobs = JOBS.lock().unwrap();
+    let job = jobs.get(&id)?;
+    Some(IngestJobStatus { id, state: job.state.clone(), progress: job.control.progress() })
+}
+
+// Forgets jobs that finished more than FINISHED_JOB_TTL ago, so that a
+// long-running daemon does not keep every job it ever ran.
+fn prune_finished(jobs: &mut HashMap<u64, IngestJob>, now: Instant) {
+    jobs.retain(|_, job| job.finished_at.is_none_or(|at| now.duration_since(at) < FINISHED_JOB_TTL));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::CHUNK_SIZE;
+    use crate::metadata::test_util::TestPool;
+
+    async fn finished(id: u64) -> IngestJobStatus {
+        loop {
+            let status = status_of(id).unwrap();
+            if !matches!(status.state, JobState::Running) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_report_the_progress_of_their_ingest() {
+        let pool = TestPool::new("job-progress", "{}");
+        let root = pool.root.clone();
+        let source = Path::new(&root).join("source.bin");
+        std::fs::write(&source, vec![7; 3 * CHUNK_SIZE]).unwrap();
+
+        let id = start_job(move |control| async move {
+            ingest::ingest_into(&root, source.to_str().unwrap(), "/", "f", &control, None).await
+        });
+        let status = finished(id).await;
+        assert!(matches!(status.state, JobState::Completed));
+        let progress = status.progress;
+        assert_eq!(progress.bytes_read, 3 * CHUNK_SIZE as u64);
+        assert_eq!((progress.blocks_written, progress.blocks_deduplicated), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_job_stops_its_ingest() {
+        let id = start_job(|control| async move {
+            while !control.is_cancelled() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Err(IngestError::Cancelled)
+        });
+        let response = delete_ingest_job_handler(UrlPath(id)).await.into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(matches!(finished(id).await.state, JobState::Cancelled));
+    }
+
+    #[test]
+    fn finished_jobs_are_forgotten_after_their_ttl() {
+        let now = Instant::now();
+        let job = |finished_at| IngestJob { control: IngestControl::new(), state: JobState::Completed, finished_at };
+        let mut jobs = HashMap::from([(1, job(None)), (2, job(Some(now))), (3, job(Some(now)))]);
+        prune_finished(&mut jobs, now + FINISHED_JOB_TTL - Duration::from_secs(1));
+        assert_eq!(jobs.len(), 3);
+        jobs.get_mut(&3).unwrap().finished_at = Some(now + Duration::from_secs(2));
+        prune_finished(&mut jobs, now + FINISHED_JOB_TTL + Duration::from_secs(1));
+        let mut left: Vec<u64> = jobs.into_keys().collect();
+        left.sort();
+        assert_eq!(left, [1, 3]);
+    }
+}
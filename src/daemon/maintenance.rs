@@ -0,0 +1,82 @@
+// src/daemon/maintenance.rs
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (c) 2025 Canmi
+
+use crate::block::pack;
+use crate::common::config::{self, BlockBackend};
+use crate::common::pool;
+use crate::metadata::{cache, journal, trash, version};
+use rfs_utils::{log, LogLevel};
+use tokio::time::{interval, Duration};
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Spawns the background task that periodically maintains every pool.
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut ticker = interval(MAINTENANCE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for pool_root in pool::get_all_pool_paths() {
+                run_once(&pool_root).await;
+            }
+        }
+    });
+}
+
+// Runs one maintenance pass over a pool, logging rather than propagating failures.
+async fn run_once(pool_root: &str) {
+    let pool_config = match config::get_pool_config(pool_root).await {
+        Ok(c) => c,
+        Err(e) => {
+            log(LogLevel::Error, &format!("Failed to load config of pool '{}': {}", pool_root, e));
+            return;
+        }
+    };
+
+    // Expire file versions and trashed entries first, so that compaction can reclaim their blocks.
+    match version::prune_pool(pool_root).await {
+        Ok(0) => {}
+        Ok(pruned) => log(LogLevel::Info, &format!("Pruned {} file versions in '{}'", pruned, pool_root)),
+        Err(e) => log(LogLevel::Error, &format!("Version pruning failed in '{}': {}", pool_root, e)),
+    }
+
+    match trash::purge_expired(pool_root).await {
+        Ok(0) => {}
+        Ok(purged) => log(LogLevel::Info, &format!("Purged {} trashed entries in '{}'", purged, pool_root)),
+        Err(e) => log(LogLevel::Error, &format!("Trash purge failed in '{}': {}", pool_root, e)),
+    }
+
+    match journal::trim(pool_root).await {
+        Ok(0) => {}
+        Ok(dropped) => log(LogLevel::Debug, &format!("Trimmed {} changes from the log of '{}'", dropped, pool_root)),
+        Err(e) => log(LogLevel::Error, &format!("Change log trim failed in '{}': {}", pool_root, e)),
+    }
+
+    if let Some(stats) = cache::cache_stats(pool_root) {
+        let message = format!(
+            "Listing cache of '{}': {} listing hits, {} misses ({} stale), {} path hits, {} misses, {} evictions",
+            pool_root,
+            stats.listing_hits,
+            stats.listing_misses,
+            stats.listing_stale,
+            stats.path_hits,
+            stats.path_misses,
+            stats.evictions
+        );
+        log(LogLevel::Debug, &message);
+    }
+
+    // Rewrite packs that blocks released by GC have left mostly empty.
+    if !matches!(pool_config.block_backend, BlockBackend::Local) {
+        return;
+    }
+    match pack::compact(pool_root, pool_config.pack_size, pool_config.pack_compact_ratio).await {
+        Ok(0) => {}
+        Ok(reclaimed) => log(
+            LogLevel::Info,
+            &format!("Pack compaction reclaimed {} bytes in '{}'", reclaimed, pool_root),
+        ),
+        Err(e) => log(LogLevel::Error, &format!("Pack compaction failed in '{}': {}", pool_root, e)),
+    }
+}
@@ -3,5 +3,8 @@
 // Copyright (c) 2025 Canmi
 
 pub mod bootstrap;
+pub mod fs;
+pub mod job;
+pub mod maintenance;
 pub mod router;
 pub mod unixsock;
\ No newline at end of file
@@ -2,6 +2,13 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (c) 2025 Canmi
 
+use crate::daemon::fs::{
+    delete_entry_handler, get_cache_handler, get_du_handler, get_entry_handler, get_list_handler, get_quota_handler,
+    get_watch_handler, post_search_handler,
+};
+use crate::daemon::job::{
+    delete_ingest_job_handler, get_ingest_job_handler, post_ingest_job_handler,
+};
 use crate::test::file::post_test_block_storage_handler;
 use axum::{
     routing::{get, post},
@@ -12,6 +19,18 @@ pub fn create_router() -> Router {
     Router::new()
         .route("/", get(get_root_handler))
         .route("/test/file/block/storage", post(post_test_block_storage_handler))
+        .route("/ingest", post(post_ingest_job_handler))
+        .route(
+            "/ingest/{id}",
+            get(get_ingest_job_handler).delete(delete_ingest_job_handler),
+        )
+        .route("/list", get(get_list_handler))
+        .route("/entry", get(get_entry_handler).delete(delete_entry_handler))
+        .route("/search", post(post_search_handler))
+        .route("/quota", get(get_quota_handler))
+        .route("/du", get(get_du_handler))
+        .route("/watch", get(get_watch_handler))
+        .route("/cache", get(get_cache_handler))
 }
 
 async fn get_root_handler() -> &'static str {
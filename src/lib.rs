@@ -2,8 +2,35 @@
 // SPDX-License-Identifier: AGPL-3.0
 // Copyright (c) 2025 Canmi
 
+pub mod block;
+pub mod common;
 pub mod metadata;
 
+pub use block::export::export_file;
+pub use block::ingest::{
+    ingest_file, ingest_file_with_control, reingest_file, reingest_file_if, IngestControl, IngestProgress,
+};
+pub use block::read::{read_all, read_range};
+pub use block::write::{append, append_if, pwrite, pwrite_if, truncate, truncate_if, WriteError};
+pub use metadata::batch::Batch;
+pub use metadata::cache::{cache_stats, CacheStats};
+pub use metadata::du::{du, pool_usage, DuEntry, DuOptions, SpaceUsage};
 pub use metadata::error::MetadataError;
-pub use metadata::manager::{create_file, list_directory};
+pub use metadata::format::{Superblock, FORMAT_VERSION};
+pub use metadata::journal::{current_seq, watch, Change, ChangeKind};
+pub use metadata::listing::{EntryKind, ListOptions, ListPage, SortKey, SortOrder};
+pub use metadata::manager::{
+    check_quota, copy_entry, create_file, create_hard_link, create_snapshot, create_symlink, delete_entry,
+    delete_entry_if, delete_snapshot, get_dir_quota, get_entry, get_file_metadata, get_owner_quota,
+    get_snapshot_file_metadata, get_version_metadata, get_xattr, list_directory, list_directory_page,
+    list_snapshot_directory, list_snapshots, list_trash, list_versions, list_xattrs, purge_trash, read_symlink,
+    remove_xattr, rename_entry, replace_file, replace_file_if, restore_snapshot, restore_trash, restore_version,
+    set_dir_owner, set_dir_quota, set_owner_quota, set_xattr,
+};
 pub use metadata::model;
+pub use metadata::precondition::Precondition;
+pub use metadata::quota::QuotaUsage;
+pub use metadata::search::{search, SearchHit, SearchQuery};
+pub use metadata::snapshot::SnapshotInfo;
+pub use metadata::trash::TrashItem;
+pub use metadata::walk::{walk, WalkItem, WalkOptions, WalkOrder};
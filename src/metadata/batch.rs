@@ -0,0 +1,651 @@
+// src/metadata/batch.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config;
+use crate::metadata::error::MetadataError;
+use crate::metadata::journal::{self, Change, ChangeKind};
+use crate::metadata::manager;
+use crate::metadata::model::{DirectoryListing, Entry, FileEntry, FileMetadata};
+use crate::metadata::naming;
+use crate::metadata::path_utils;
+use crate::metadata::precondition::Precondition;
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use crate::metadata::trash::{self, TrashRegistry};
+use crate::metadata::tree;
+use rfs_utils::{log, LogLevel};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// An operation as added to a batch.
+enum Op {
+    Create { dir: String, name: String, metadata: FileMetadata },
+    Delete { path: String },
+    Rename { from: String, to: String, precondition: Precondition },
+}
+
+// Creates, deletes and renames committed as a unit. Each operation is checked
+// against the state left by the ones before it, and if any of them cannot be
+// applied, nothing is changed. Every affected directory's listing is written
+// once, and the size changes are propagated once to each ancestor.
+//
+// An entry created or moved by the batch cannot be deleted or moved again by
+// it, and no operation may touch a path below another one's.
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Creates a file like `manager::create_file`.
+    pub fn create_file(&mut self, rfs_dir_path: &str, filename: &str, file_metadata: FileMetadata) -> &mut Self {
+        let op = Op::Create { dir: rfs_dir_path.to_string(), name: filename.to_string(), metadata: file_metadata };
+        self.ops.push(op);
+        self
+    }
+
+    // Deletes an entry like `manager::delete_entry`, to the trash if it is enabled.
+    pub fn delete(&mut self, rfs_path: &str) -> &mut Self {
+        self.ops.push(Op::Delete { path: rfs_path.to_string() });
+        self
+    }
+
+    // Moves the entry at `from` to `to`, which must not exist yet. Missing
+    // directories on the way to `to` are created.
+    pub fn rename(&mut self, from: &str, to: &str) -> &mut Self {
+        self.rename_if(from, to, &Precondition::default())
+    }
+
+    // Like `rename`, but only if the entry at `from` satisfies `precondition`
+    // when the batch is applied.
+    pub fn rename_if(&mut self, from: &str, to: &str, precondition: &Precondition) -> &mut Self {
+        self.ops.push(Op::Rename { from: from.to_string(), to: to.to_string(), precondition: precondition.clone() });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Applies the batch. Directories that the batch needs are created before
+    // the directories it touches are locked, and stay if the batch then fails
+    // because of a concurrent change.
+    pub async fn commit(self, pool_root: &str) -> Result<(), MetadataError> {
+        commit(pool_root, self.ops).await
+    }
+}
+
+// The entry `name` in the directory at `dir`.
+struct Place {
+    dir: Vec<String>,
+    name: String,
+}
+
+impl Place {
+    fn path(&self) -> String {
+        journal::entry_path(&self.dir, &self.name)
+    }
+}
+
+// An operation with its paths resolved.
+enum Step {
+    Create { at: Place, metadata: FileMetadata },
+    Delete { at: Place },
+    Rename { from: Place, to: Place, precondition: Precondition },
+}
+
+impl Step {
+    fn places(&self) -> Vec<&Place> {
+        match self {
+            Step::Create { at, .. } | Step::Delete { at } => vec![at],
+            Step::Rename { from, to, .. } => vec![from, to],
+        }
+    }
+}
+
+// The outcome of a step, as planned against the directory listings.
+enum Applied<'a> {
+    Created { at: &'a Place, entry: Entry, metadata: &'a FileMetadata },
+    Deleted { at: &'a Place, entry: Entry },
+    // `moved` is the entry as linked at `to`, under a new CID if its own is taken there.
+    Renamed { from: &'a Place, to: &'a Place, entry: Entry, moved: Entry },
+}
+
+// A directory touched by the batch, by its path components.
+struct Dir {
+    key: Option<DirKey>,
+    original: DirectoryListing,
+    listing: DirectoryListing,
+    // The CIDs in use in the directory before or during the batch, which an
+    // entry moved in must not take.
+    taken: HashSet<String>,
+    // Names whose entries the batch created or moved here.
+    placed: HashSet<String>,
+    changed: bool,
+}
+
+impl Dir {
+    fn new(key: Option<DirKey>, listing: DirectoryListing) -> Self {
+        let taken = listing.values().filter_map(journal::cid_of).map(String::from).collect();
+        Dir { key, original: listing.clone(), listing, taken, placed: HashSet::new(), changed: false }
+    }
+
+    fn new_cid(&self) -> String {
+        let mut cid = path_utils::generate_cid();
+        while self.taken.contains(&cid) {
+            cid = path_utils::generate_cid();
+        }
+        cid
+    }
+
+    fn take(&mut self, at: &Place) -> Result<Entry, MetadataError> {
+        if self.placed.contains(&at.name) {
+            let message = format!("'{}' is created or moved by the same batch", at.path());
+            return Err(MetadataError::InvalidOperation(message));
+        }
+        let entry = self.listing.remove(&at.name).ok_or_else(|| MetadataError::NotFound(at.path()))?;
+        self.changed = true;
+        Ok(entry)
+    }
+
+    fn put(&mut self, at: &Place, entry: Entry) -> Result<(), MetadataError> {
+        if self.listing.contains_key(&at.name) {
+            return Err(MetadataError::EntryAlreadyExists(at.name.clone()));
+        }
+        self.taken.extend(journal::cid_of(&entry).map(String::from));
+        self.placed.insert(at.name.clone());
+        self.listing.insert(at.name.clone(), entry);
+        self.changed = true;
+        Ok(())
+    }
+
+    fn key(&self) -> &DirKey {
+        self.key.as_ref().expect("directories are resolved before the batch is written")
+    }
+}
+
+type Dirs = BTreeMap<Vec<String>, Dir>;
+
+// Size and entry count changes, by the path components of a directory.
+type Deltas = BTreeMap<Vec<String>, (i64, i64)>;
+
+async fn commit(pool_root: &str, ops: Vec<Op>) -> Result<(), MetadataError> {
+    if ops.is_empty() {
+        return Ok(());
+    }
+    let use_trash = config::get_pool_config(pool_root).await?.trash.enabled;
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+
+    let mut steps = Vec::with_capacity(ops.len());
+    for op in ops {
+        steps.push(resolve(pool_root, store, op).await?);
+    }
+    check_overlaps(&steps)?;
+
+    // Directories the batch takes entries from must exist; those it puts
+    // entries in are created if missing.
+    let mut affected = BTreeMap::new();
+    for step in &steps {
+        let (taken_from, put_in) = match step {
+            Step::Create { at, .. } => (None, Some(at)),
+            Step::Delete { at } => (Some(at), None),
+            Step::Rename { from, to, .. } => (Some(from), Some(to)),
+        };
+        if let Some(at) = put_in {
+            affected.entry(at.dir.clone()).or_insert(false);
+        }
+        if let Some(at) = taken_from {
+            affected.insert(at.dir.clone(), true);
+        }
+    }
+
+    // Check the batch unlocked first, so a batch that cannot apply creates no directories.
+    let mut dirs = Dirs::new();
+    for (components, &must_exist) in &affected {
+        let key = match manager::find_dir_path(store, components).await {
+            Ok(key) => Some(key),
+            Err(MetadataError::NotFound(_)) if !must_exist => None,
+            Err(e) => return Err(e),
+        };
+        let listing = match &key {
+            Some(key) => store.read_listing(key).await?,
+            None => DirectoryListing::new(),
+        };
+        dirs.insert(components.clone(), Dir::new(key, listing));
+    }
+    let applied = plan(&steps, &mut dirs)?;
+    for (components, (bytes, entries)) in totals(&deltas(pool_root, &dirs, &applied).await?) {
+        manager::check_quotas(pool_root, store, &components, bytes, entries).await?;
+    }
+
+    let mut keys = Vec::with_capacity(affected.len());
+    for (components, &must_exist) in &affected {
+        let key = match must_exist {
+            true => manager::find_dir_path(store, components).await?,
+            false => manager::resolve_dir_path(pool_root, store, components).await?,
+        };
+        keys.push((components.clone(), key));
+    }
+
+    let deletes = steps.iter().any(|step| matches!(step, Step::Delete { .. }));
+    let _registry_lock = match use_trash && deletes {
+        true => Some(trash::REGISTRY.lock(pool_root).await?),
+        false => None,
+    };
+    // Locks are taken deepest directory first, the order in which changes
+    // are propagated up the tree. Besides the directories it changes, the
+    // batch locks those it moves to another parent or into the trash, with
+    // everything below them, so that no writer is active in a subtree while
+    // its metadata moves. If the locked listings move other directories, the
+    // batch locks again.
+    let mut moving = moving_dirs(store, &dirs, &applied, use_trash).await?;
+    let (locks, dirs, applied) = loop {
+        let mut order: Vec<&DirKey> = keys.iter().map(|(_, key)| key).chain(&moving).collect();
+        order.sort_by_key(|key| (Reverse(key.cids().len()), key.as_string()));
+        order.dedup();
+        let mut locks = Vec::with_capacity(order.len());
+        for key in order {
+            locks.push(store.lock_dir(key).await?);
+        }
+        let mut dirs = Dirs::new();
+        for (components, key) in &keys {
+            let listing = store.read_listing(key).await?;
+            dirs.insert(components.clone(), Dir::new(Some(key.clone()), listing));
+        }
+        let applied = plan(&steps, &mut dirs)?;
+        let now = moving_dirs(store, &dirs, &applied, use_trash).await?;
+        if now.is_subset(&moving) {
+            break (locks, dirs, applied);
+        }
+        drop(locks);
+        moving = now;
+    };
+    let deltas = deltas(pool_root, &dirs, &applied).await?;
+
+    let mut registry = match use_trash && deletes {
+        true => Some(trash::REGISTRY.read(pool_root).await?),
+        false => None,
+    };
+    let kept = write(pool_root, store, &dirs, &applied, registry.as_mut()).await?;
+
+    // The listings are written, so the batch has been applied: what follows
+    // cannot undo it, and a failure there is only logged. Kept items left
+    // moving are settled when the pool is next opened.
+    if let Some(registry) = &mut registry
+        && let Err(e) = trash::settle(pool_root, registry, &kept).await
+    {
+        log(LogLevel::Error, &format!("Failed to settle {} deleted entries in the trash: {}", kept.len(), e));
+    }
+    let mut changes = Vec::with_capacity(applied.len());
+    for applied in &applied {
+        match applied {
+            Applied::Created { at, entry, .. } => {
+                changes.push(Change::new(ChangeKind::Create, at.path(), journal::cid_of(entry)));
+            }
+            Applied::Deleted { at, entry } => {
+                if registry.is_none()
+                    && let Err(e) = tree::release_entry(pool_root, store, dirs[&at.dir].key(), entry).await
+                {
+                    log(LogLevel::Error, &format!("Failed to release deleted entry '{}': {}", at.path(), e));
+                }
+                changes.push(Change::new(ChangeKind::Delete, at.path(), journal::cid_of(entry)));
+            }
+            Applied::Renamed { from, to, moved, .. } => {
+                let change = Change::new(ChangeKind::Rename, to.path(), journal::cid_of(moved));
+                changes.push(Change { old_path: Some(from.path()), ..change });
+            }
+        }
+    }
+    journal::record_all(pool_root, changes).await;
+    drop(locks);
+
+    // Parents are locked one at a time from here on, deepest first.
+    let mut totals: Vec<_> = totals(&deltas).into_iter().collect();
+    totals.sort_by_key(|(components, _)| Reverse(components.len()));
+    for (components, (bytes, entries)) in totals {
+        if let Err(e) = manager::update_dir_entry(pool_root, store, &components, bytes, entries).await {
+            log(LogLevel::Error, &format!("Failed to update the entry of '/{}': {}", components.join("/"), e));
+        }
+    }
+    log(LogLevel::Info, &format!("Committed a batch of {} changes.", applied.len()));
+    Ok(())
+}
+
+async fn resolve(pool_root: &str, store: &dyn MetadataStore, op: Op) -> Result<Step, MetadataError> {
+    Ok(match op {
+        Op::Create { dir, name, metadata } => {
+            let name = naming::normalize_name(pool_root, &name).await?;
+            let path = format!("{}/{}", dir.trim_end_matches('/'), name);
+            Step::Create { at: place(pool_root, store, &path).await?, metadata }
+        }
+        Op::Delete { path } => Step::Delete { at: place(pool_root, store, &path).await? },
+        Op::Rename { from, to, precondition } => {
+            let (from, to) = (place(pool_root, store, &from).await?, place(pool_root, store, &to).await?);
+            if to.dir.starts_with(&from.dir) && to.dir.get(from.dir.len()) == Some(&from.name) {
+                return Err(MetadataError::InvalidOperation(format!("cannot move '{}' into itself", from.path())));
+            }
+            Step::Rename { from, to, precondition }
+        }
+    })
+}
+
+async fn place(pool_root: &str, store: &dyn MetadataStore, path: &str) -> Result<Place, MetadataError> {
+    let mut dir = naming::split_path(pool_root, store, path).await?;
+    let name =
+        dir.pop().ok_or_else(|| MetadataError::InvalidOperation("a batch cannot change the pool root".to_string()))?;
+    Ok(Place { dir, name })
+}
+
+// Steps are planned against listings read up front, which a step on an
+// ancestor of another step's path would invalidate.
+fn check_overlaps(steps: &[Step]) -> Result<(), MetadataError> {
+    let paths: BTreeSet<String> = steps.iter().flat_map(Step::places).map(Place::path).collect();
+    for path in &paths {
+        let prefix = format!("{}/", path);
+        if let Some(below) = paths.range(prefix.clone()..).next()
+            && below.starts_with(&prefix)
+        {
+            return Err(MetadataError::InvalidOperation(format!(
+                "'{}' and '{}' cannot be changed by the same batch",
+                path, below
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Applies the steps to the listings in `dirs`, each against the state left by the ones before.
+fn plan<'a>(steps: &'a [Step], dirs: &mut Dirs) -> Result<Vec<Applied<'a>>, MetadataError> {
+    let mut applied = Vec::with_capacity(steps.len());
+    for step in steps {
+        match step {
+            Step::Create { at, metadata } => {
+                let dir = dirs.get_mut(&at.dir).expect("affected directories are loaded");
+                let entry = Entry::File(FileEntry {
+                    cid: dir.new_cid(),
+                    size: metadata.size,
+                    created_at: metadata.created_at,
+                    modified_at: metadata.modified_at,
+                    linked: false,
+                    revision: 0,
+                });
+                dir.put(at, entry.clone())?;
+                applied.push(Applied::Created { at, entry, metadata });
+            }
+            Step::Delete { at } => {
+                let entry = dirs.get_mut(&at.dir).expect("affected directories are loaded").take(at)?;
+                applied.push(Applied::Deleted { at, entry });
+            }
+            Step::Rename { from, to, precondition } => {
+                let entry = dirs.get_mut(&from.dir).expect("affected directories are loaded").take(from)?;
+                precondition.check(&from.path(), Some(&entry))?;
+                let dst = dirs.get_mut(&to.dir).expect("affected directories are loaded");
+                // CIDs are unique per directory only; a linked file's is that of its shared block map.
+                let mut moved = entry.clone();
+                let stored_here = !matches!(&entry, Entry::File(f) if f.linked);
+                if from.dir != to.dir
+                    && stored_here
+                    && journal::cid_of(&entry).is_some_and(|cid| dst.taken.contains(cid))
+                {
+                    let cid = dst.new_cid();
+                    match &mut moved {
+                        Entry::File(file_entry) => file_entry.cid = cid,
+                        Entry::Directory(info) => info.cid = cid,
+                        Entry::Symlink(_) => {}
+                    }
+                }
+                dst.put(to, moved.clone())?;
+                applied.push(Applied::Renamed { from, to, entry, moved });
+            }
+        }
+    }
+    Ok(applied)
+}
+
+// The changes of the planned steps to each directory they happen in.
+async fn deltas(pool_root: &str, dirs: &Dirs, applied: &[Applied<'_>]) -> Result<Deltas, MetadataError> {
+    let mut deltas = Deltas::new();
+    for applied in applied {
+        match applied {
+            Applied::Created { at, entry, .. } => add(&mut deltas, &at.dir, tree::entry_size(entry) as i64, 1),
+            Applied::Deleted { at, entry } => {
+                let entries = count(pool_root, dirs[&at.dir].key.as_ref(), entry).await?;
+                add(&mut deltas, &at.dir, -(tree::entry_size(entry) as i64), -entries);
+            }
+            Applied::Renamed { from, to, entry, .. } => {
+                let (size, entries) = match from.dir == to.dir {
+                    true => (0, 0),
+                    false => {
+                        (tree::entry_size(entry) as i64, count(pool_root, dirs[&from.dir].key.as_ref(), entry).await?)
+                    }
+                };
+                add(&mut deltas, &from.dir, -size, -entries);
+                add(&mut deltas, &to.dir, size, entries);
+            }
+        }
+    }
+    Ok(deltas)
+}
+
+fn add(deltas: &mut Deltas, dir: &[String], bytes: i64, entries: i64) {
+    let delta = deltas.entry(dir.to_vec()).or_default();
+    delta.0 += bytes;
+    delta.1 += entries;
+}
+
+// The entries an entry in `dir` stands for, itself and everything below it.
+async fn count(pool_root: &str, dir: Option<&DirKey>, entry: &Entry) -> Result<i64, MetadataError> {
+    Ok(match (dir, entry) {
+        (Some(dir), Entry::Directory(info)) => {
+            1 + manager::count_entries(pool_root, dir.child(&info.cid)).await? as i64
+        }
+        _ => 1,
+    })
+}
+
+// Sums the changes of each directory into every directory above it, up to
+// but excluding the root, which has no entry of its own.
+fn totals(deltas: &Deltas) -> Deltas {
+    let mut totals = Deltas::new();
+    for (dir, &(bytes, entries)) in deltas {
+        for len in 1..=dir.len() {
+            add(&mut totals, &dir[..len], bytes, entries);
+        }
+    }
+    totals
+}
+
+// The directories the planned steps move, to another parent or into the
+// trash, and every directory below them.
+async fn moving_dirs(
+    store: &dyn MetadataStore,
+    dirs: &Dirs,
+    applied: &[Applied<'_>],
+    use_trash: bool,
+) -> Result<HashSet<DirKey>, MetadataError> {
+    let mut pending = Vec::new();
+    for applied in applied {
+        let (at, entry) = match applied {
+            Applied::Deleted { at, entry } if use_trash => (at, entry),
+            Applied::Renamed { from, to, entry, .. } if from.dir != to.dir => (from, entry),
+            _ => continue,
+        };
+        if let (Some(dir), Entry::Directory(info)) = (&dirs[&at.dir].key, entry) {
+            pending.push(dir.child(&info.cid));
+        }
+    }
+    let mut moving = HashSet::new();
+    while let Some(dir) = pending.pop() {
+        for entry in store.read_listing(&dir).await?.values() {
+            if let Entry::Directory(info) = entry {
+                pending.push(dir.child(&info.cid));
+            }
+        }
+        moving.insert(dir);
+    }
+    Ok(moving)
+}
+
+// What has been written so far, to be undone if a later write fails.
+#[derive(Default)]
+struct Undo {
+    created: Vec<(DirKey, String)>,
+    // Source directory, destination directory, the entry as moved and its
+    // original CID. A move is recorded before it starts, as one that failed
+    // part way is undone by moving it back.
+    moved: Vec<(DirKey, DirKey, Entry, String)>,
+    kept: Vec<String>,
+    listings: Vec<(DirKey, DirectoryListing)>,
+}
+
+// Writes the new files' metadata, moves renamed entries between directories,
+// moves deleted ones into the trash if `registry` is given and writes each
+// changed listing once. Returns the IDs of the trash items, which are left
+// moving for the caller to settle. On failure, undoes what it wrote.
+async fn write(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dirs: &Dirs,
+    applied: &[Applied<'_>],
+    mut registry: Option<&mut TrashRegistry>,
+) -> Result<Vec<String>, MetadataError> {
+    let mut undo = Undo::default();
+    let Err(e) = write_all(pool_root, store, dirs, applied, registry.as_deref_mut(), &mut undo).await else {
+        return Ok(undo.kept);
+    };
+    for (dir, listing) in undo.listings.iter().rev() {
+        if let Err(e) = store.write_listing(dir, listing).await {
+            log(LogLevel::Error, &format!("Failed to restore listing '{}': {}", dir.as_string(), e));
+        }
+    }
+    if let Some(registry) = registry {
+        for id in undo.kept.iter().rev() {
+            if let Err(e) = trash::put_back(pool_root, store, registry, id).await {
+                log(LogLevel::Error, &format!("Failed to move trash item '{}' back: {}", id, e));
+            }
+        }
+    }
+    for (src, dst, moved, cid) in undo.moved.iter().rev() {
+        if let Err(e) = tree::move_entry_as(pool_root, store, dst, moved, src, cid).await {
+            log(LogLevel::Error, &format!("Failed to move back entry from '{}': {}", dst.as_string(), e));
+        }
+    }
+    for (dir, cid) in &undo.created {
+        if let Err(e) = store.remove_file_metadata(dir, cid).await {
+            log(LogLevel::Error, &format!("Failed to remove metadata of '{}' in '{}': {}", cid, dir.as_string(), e));
+        }
+    }
+    Err(e)
+}
+
+async fn write_all(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dirs: &Dirs,
+    applied: &[Applied<'_>],
+    mut registry: Option<&mut TrashRegistry>,
+    undo: &mut Undo,
+) -> Result<(), MetadataError> {
+    for applied in applied {
+        match applied {
+            Applied::Created { at, entry, metadata } => {
+                let dir = dirs[&at.dir].key();
+                let cid = journal::cid_of(entry).unwrap_or_default();
+                store.write_file_metadata(dir, cid, metadata).await?;
+                undo.created.push((dir.clone(), cid.to_string()));
+            }
+            Applied::Renamed { from, to, entry, moved } if from.dir != to.dir => {
+                let (src, dst) = (dirs[&from.dir].key(), dirs[&to.dir].key());
+                let Some(cid) = journal::cid_of(moved) else {
+                    continue;
+                };
+                let original = journal::cid_of(entry).unwrap_or_default().to_string();
+                undo.moved.push((src.clone(), dst.clone(), moved.clone(), original));
+                tree::move_entry_as(pool_root, store, src, entry, dst, cid).await?;
+            }
+            // A failed keep is undone by `keep` itself.
+            Applied::Deleted { at, entry } => {
+                if let Some(registry) = registry.as_deref_mut() {
+                    let dir = dirs[&at.dir].key();
+                    undo.kept.push(trash::keep(pool_root, store, registry, dir, &at.name, entry, &at.path()).await?);
+                }
+            }
+            _ => {}
+        }
+    }
+    for dir in dirs.values().filter(|dir| dir.changed) {
+        undo.listings.push((dir.key().clone(), dir.original.clone()));
+        store.write_listing(dir.key(), &dir.listing).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_util::{self, TestPool};
+
+    // Drops the block map of the file at `dir`/`name`, so that moving it fails.
+    async fn lose_metadata(pool_root: &str, dir: &[&str], name: &str) {
+        let store = store::get_metadata_store(pool_root).await.unwrap();
+        let components: Vec<String> = dir.iter().map(|c| c.to_string()).collect();
+        let key = manager::find_dir_path(store.as_ref(), &components).await.unwrap();
+        let entry = store.get_entry(&key, name).await.unwrap().unwrap();
+        store.remove_file_metadata(&key, journal::cid_of(&entry).unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_rename_that_fails_part_way_is_moved_back() {
+        for backend in ["json", "db"] {
+            let config = format!(r#"{{"metadataBackend":"{}"}}"#, backend);
+            let pool = TestPool::new(&format!("batch-rename-{}", backend), &config);
+            let root = pool.root.as_str();
+            for name in ["x1", "x2", "x3", "x4"] {
+                manager::create_file(root, "/a/s", name, test_util::file_metadata(name, 3)).await.unwrap();
+            }
+            lose_metadata(root, &["a", "s"], "x3").await;
+
+            let mut batch = Batch::new();
+            batch.rename("/a/s", "/b/s");
+            assert!(batch.commit(root).await.is_err());
+            assert_eq!(manager::list_directory(root, "/a/s").await.unwrap().len(), 4);
+            for name in ["x1", "x2", "x4"] {
+                assert_eq!(manager::get_file_metadata(root, &format!("/a/s/{}", name)).await.unwrap().size, 3);
+            }
+            assert!(manager::get_entry(root, "/b/s").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_takes_its_deletes_back_out_of_the_trash() {
+        let pool = TestPool::new("batch-trash", r#"{"trash":{"enabled":true}}"#);
+        let root = pool.root.as_str();
+        manager::create_file(root, "/c/d", "f", test_util::file_metadata("f", 3)).await.unwrap();
+        manager::create_file(root, "/c", "g", test_util::file_metadata("g", 5)).await.unwrap();
+        lose_metadata(root, &["c"], "g").await;
+
+        let mut batch = Batch::new();
+        batch.delete("/c/d").rename("/c/g", "/e/g");
+        assert!(batch.commit(root).await.is_err());
+        assert_eq!(manager::get_file_metadata(root, "/c/d/f").await.unwrap().size, 3);
+        assert!(manager::get_entry(root, "/c/g").await.is_ok());
+        assert!(trash::REGISTRY.read(root).await.unwrap().is_empty());
+        assert_eq!(tree::entry_size(&manager::get_entry(root, "/c").await.unwrap()), 8);
+
+        // Without the broken file, the same delete goes through.
+        let mut batch = Batch::new();
+        batch.delete("/c/d");
+        batch.commit(root).await.unwrap();
+        assert_eq!(manager::list_trash(root).await.unwrap().len(), 1);
+        assert_eq!(tree::entry_size(&manager::get_entry(root, "/c").await.unwrap()), 5);
+    }
+}
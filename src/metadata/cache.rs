@@ -0,0 +1,527 @@
+// src/metadata/cache.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config::ListingCacheConfig;
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{DirectoryListing, Entry, FileMetadata};
+use crate::metadata::store::{self, DirKey, DirLock, ListingStamp, MetadataStore};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex as StdMutex};
+
+// Open listing caches, one per pool root, for reporting their counters.
+static CACHES: Lazy<StdMutex<HashMap<String, Arc<ListingCache>>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+// Counters of a pool's listing cache since the pool's store was opened, and
+// what the cache holds now.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub listing_hits: u64,
+    pub listing_misses: u64,
+    // Misses on a cached listing that another process had written since.
+    pub listing_stale: u64,
+    pub path_hits: u64,
+    pub path_misses: u64,
+    pub evictions: u64,
+    pub listings: usize,
+    pub entries: usize,
+    pub paths: usize,
+}
+
+// Reads the counters of a pool's listing cache, if the pool has one open.
+pub fn cache_stats(pool_root: &str) -> Option<CacheStats> {
+    let cache = CACHES.lock().unwrap().get(pool_root).cloned()?;
+    let state = cache.state.lock().unwrap();
+    let mut stats = state.stats;
+    stats.listings = state.listings.len();
+    stats.entries = state.entries;
+    stats.paths = state.paths.len();
+    Some(stats)
+}
+
+// Forgets the cache of a pool whose store is closed.
+pub(crate) fn close(pool_root: &str) {
+    CACHES.lock().unwrap().remove(pool_root);
+}
+
+struct CachedListing {
+    listing: DirectoryListing,
+    stamp: Option<ListingStamp>,
+    // Changes when the listing is read anew, but not when it is updated by a
+    // write that leaves the resolution of paths through it as it was.
+    version: u64,
+    used: u64,
+}
+
+// A resolution of directory names below a base directory.
+struct CachedPath {
+    dir: DirKey,
+    // The versions of the cached listings it went through, from the base
+    // down to the parent of `dir`.
+    versions: Vec<u64>,
+    used: u64,
+}
+
+type PathKey = (DirKey, Vec<String>);
+
+#[derive(Default)]
+struct CacheState {
+    listings: HashMap<DirKey, CachedListing>,
+    // Cached listings by last use, least recent first.
+    listings_by_use: BTreeMap<u64, DirKey>,
+    // Directory entries across all cached listings.
+    entries: usize,
+    paths: HashMap<PathKey, CachedPath>,
+    paths_by_use: BTreeMap<u64, PathKey>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    // Returns the listing of `dir` if it is cached and `stamp` is still its
+    // stamp, counting a hit or a miss. A stale listing is dropped.
+    fn fresh(&mut self, dir: &DirKey, stamp: &Option<ListingStamp>) -> Option<&DirectoryListing> {
+        match self.listings.get(dir) {
+            Some(cached) if cached.stamp == *stamp => {}
+            Some(_) => {
+                self.stats.listing_stale += 1;
+                self.stats.listing_misses += 1;
+                self.remove_listing(dir);
+                return None;
+            }
+            None => {
+                self.stats.listing_misses += 1;
+                return None;
+            }
+        }
+        self.stats.listing_hits += 1;
+        let now = self.tick();
+        let cached = self.listings.get_mut(dir)?;
+        self.listings_by_use.remove(&cached.used);
+        cached.used = now;
+        self.listings_by_use.insert(now, dir.clone());
+        Some(&self.listings[dir].listing)
+    }
+
+    fn insert_listing(&mut self, dir: &DirKey, listing: DirectoryListing, stamp: Option<ListingStamp>, max: usize) {
+        self.remove_listing(dir);
+        if listing.len() > max {
+            return;
+        }
+        let used = self.tick();
+        self.entries += listing.len();
+        self.listings.insert(dir.clone(), CachedListing { listing, stamp, version: used, used });
+        self.listings_by_use.insert(used, dir.clone());
+        self.evict_listings(max);
+    }
+
+    fn remove_listing(&mut self, dir: &DirKey) {
+        if let Some(cached) = self.listings.remove(dir) {
+            self.listings_by_use.remove(&cached.used);
+            self.entries -= cached.listing.len();
+        }
+    }
+
+    fn evict_listings(&mut self, max: usize) {
+        while self.entries > max
+            && let Some((_, dir)) = self.listings_by_use.pop_first()
+        {
+            if let Some(cached) = self.listings.remove(&dir) {
+                self.entries -= cached.listing.len();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    // Applies a change of one entry to a cached listing, made while it was
+    // current, and restamps it.
+    fn update_entry(
+        &mut self,
+        dir: &DirKey,
+        name: &str,
+        entry: Option<&Entry>,
+        stamp: Option<ListingStamp>,
+        max: usize,
+    ) {
+        let Some(cached) = self.listings.get_mut(dir) else {
+            return;
+        };
+        let before = cached.listing.len();
+        match entry {
+            Some(entry) => cached.listing.insert(name.to_string(), entry.clone()),
+            None => cached.listing.remove(name),
+        };
+        cached.stamp = stamp;
+        self.entries = self.entries + cached.listing.len() - before;
+        if cached.listing.len() > max {
+            self.remove_listing(dir);
+        }
+        self.evict_listings(max);
+    }
+
+    // Drops the resolutions that went through the listing of `dir`.
+    fn forget_paths_through(&mut self, dir: &DirKey) {
+        let through = |key: &PathKey, path: &CachedPath| {
+            key.0.cids().len() <= dir.cids().len()
+                && path.dir.cids().len() > dir.cids().len()
+                && path.dir.cids().starts_with(dir.cids())
+        };
+        let forgotten: Vec<PathKey> =
+            self.paths.iter().filter(|(key, path)| through(key, path)).map(|(key, _)| key.clone()).collect();
+        for key in forgotten {
+            self.remove_path(&key);
+        }
+    }
+
+    // Returns a remembered resolution and the listing versions it was made
+    // from, counting a miss if there is none.
+    fn path(&mut self, key: &PathKey) -> Option<(DirKey, Vec<u64>)> {
+        let now = self.tick();
+        let Some(path) = self.paths.get_mut(key) else {
+            self.stats.path_misses += 1;
+            return None;
+        };
+        let used = std::mem::replace(&mut path.used, now);
+        let found = (path.dir.clone(), path.versions.clone());
+        self.paths_by_use.remove(&used);
+        self.paths_by_use.insert(now, key.clone());
+        Some(found)
+    }
+
+    fn remove_path(&mut self, key: &PathKey) {
+        if let Some(path) = self.paths.remove(key) {
+            self.paths_by_use.remove(&path.used);
+        }
+    }
+}
+
+// A bounded cache of parsed listings and path resolutions, least recently
+// used first out.
+pub struct ListingCache {
+    max_entries: usize,
+    max_paths: usize,
+    state: StdMutex<CacheState>,
+}
+
+// Wraps a metadata store and serves listings, and the entries of cached ones,
+// from memory. Every read checks the stamp of the stored listing first, so
+// writes by other processes are picked up; writes through this store update
+// the cache.
+// Like all writers, those of other processes must hold the directory lock.
+pub struct CachedStore {
+    inner: Arc<dyn MetadataStore>,
+    cache: Arc<ListingCache>,
+}
+
+impl CachedStore {
+    pub fn open(pool_root: &str, inner: Arc<dyn MetadataStore>, config: &ListingCacheConfig) -> Self {
+        let cache = Arc::new(ListingCache {
+            max_entries: config.max_entries,
+            max_paths: config.max_paths,
+            state: StdMutex::new(CacheState::default()),
+        });
+        CACHES.lock().unwrap().insert(pool_root.to_string(), cache.clone());
+        CachedStore { inner, cache }
+    }
+
+    // Runs `f` on the current listing of `dir`, reading it into the cache if
+    // needed. The stamp is taken before the read, so a concurrent write is
+    // noticed by the next lookup at the latest.
+    async fn with_listing<R>(&self, dir: &DirKey, f: impl FnOnce(&DirectoryListing) -> R) -> Result<R, MetadataError> {
+        let stamp = self.inner.listing_stamp(dir).await?;
+        if let Some(listing) = self.cache.state.lock().unwrap().fresh(dir, &stamp) {
+            return Ok(f(listing));
+        }
+        let listing = self.inner.read_listing(dir).await?;
+        let result = f(&listing);
+        self.cache.state.lock().unwrap().insert_listing(dir, listing, stamp, self.cache.max_entries);
+        Ok(result)
+    }
+
+    // Runs `f` on the listing of `dir` only if it is cached and current.
+    async fn if_cached<R>(
+        &self,
+        dir: &DirKey,
+        f: impl FnOnce(&DirectoryListing) -> R,
+    ) -> Result<Option<R>, MetadataError> {
+        let stamp = self.inner.listing_stamp(dir).await?;
+        Ok(self.cache.state.lock().unwrap().fresh(dir, &stamp).map(f))
+    }
+
+    // Changes one entry through the inner store. The cached listing is
+    // updated if it was current before the change, and dropped otherwise.
+    async fn change_entry(
+        &self,
+        dir: &DirKey,
+        name: &str,
+        entry: Option<&Entry>,
+    ) -> Result<Option<Entry>, MetadataError> {
+        let before = self.inner.listing_stamp(dir).await?;
+        let removed = match entry {
+            Some(entry) => {
+                self.inner.put_entry(dir, name, entry).await?;
+                None
+            }
+            None => self.inner.remove_entry(dir, name).await?,
+        };
+        let after = self.inner.listing_stamp(dir).await?;
+
+        let mut state = self.cache.state.lock().unwrap();
+        let current = state.listings.get(dir).filter(|cached| cached.stamp == before);
+        let Some(cached) = current else {
+            state.remove_listing(dir);
+            state.forget_paths_through(dir);
+            return Ok(removed);
+        };
+        // Resolutions only change when a directory entry goes or gets another CID.
+        let dir_cid = |entry: Option<&Entry>| match entry {
+            Some(Entry::Directory(info)) => Some(info.cid.clone()),
+            _ => None,
+        };
+        let old_cid = dir_cid(cached.listing.get(name));
+        if old_cid.is_some() && old_cid != dir_cid(entry) {
+            state.forget_paths_through(dir);
+        }
+        state.update_entry(dir, name, entry, after, self.cache.max_entries);
+        Ok(removed)
+    }
+}
+
+impl MetadataStore for CachedStore {
+    fn lock_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirLock, MetadataError>> {
+        self.inner.lock_dir(dir)
+    }
+
+    fn read_listing<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirectoryListing, MetadataError>> {
+        Box::pin(self.with_listing(dir, DirectoryListing::clone))
+    }
+
+    fn write_listing<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        listing: &'a DirectoryListing,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.inner.write_listing(dir, listing).await?;
+            let stamp = self.inner.listing_stamp(dir).await?;
+            let mut state = self.cache.state.lock().unwrap();
+            state.forget_paths_through(dir);
+            state.insert_listing(dir, listing.clone(), stamp, self.cache.max_entries);
+            Ok(())
+        })
+    }
+
+    // A listing that is not cached is left to the inner store's lookup,
+    // which need not read all of it for one entry.
+    fn get_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move {
+            match self.if_cached(dir, |listing| listing.get(name).cloned()).await? {
+                Some(entry) => Ok(entry),
+                None => self.inner.get_entry(dir, name).await,
+            }
+        })
+    }
+
+    fn put_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+        entry: &'a Entry,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.change_entry(dir, name, Some(entry)).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(self.change_entry(dir, name, None))
+    }
+
+    // A directory too large to cache is left to the inner store's lookup.
+    fn find_folded<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        folded: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, Entry)>, MetadataError>> {
+        Box::pin(async move {
+            match self.if_cached(dir, |listing| store::find_folded_in(listing, folded)).await? {
+                Some(found) => Ok(found),
+                None => self.inner.find_folded(dir, folded).await,
+            }
+        })
+    }
+
+    fn read_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+    ) -> BoxFuture<'a, Result<FileMetadata, MetadataError>> {
+        self.inner.read_file_metadata(dir, cid)
+    }
+
+    fn write_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+        metadata: &'a FileMetadata,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.write_file_metadata(dir, cid, metadata)
+    }
+
+    fn remove_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.remove_file_metadata(dir, cid)
+    }
+
+    fn create_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.create_dir(dir)
+    }
+
+    fn remove_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.inner.remove_dir(dir).await?;
+            let mut state = self.cache.state.lock().unwrap();
+            state.remove_listing(dir);
+            state.forget_paths_through(dir);
+            Ok(())
+        })
+    }
+
+    fn listing_stamp<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<Option<ListingStamp>, MetadataError>> {
+        self.inner.listing_stamp(dir)
+    }
+
+    // A resolution is valid while the listings it went through are cached,
+    // current and unchanged as far as the resolution is concerned.
+    fn cached_dir<'a>(
+        &'a self,
+        base: &'a DirKey,
+        components: &'a [String],
+    ) -> BoxFuture<'a, Result<Option<DirKey>, MetadataError>> {
+        Box::pin(async move {
+            let key = (base.clone(), components.to_vec());
+            let Some((dir, versions)) = self.cache.state.lock().unwrap().path(&key) else {
+                return Ok(None);
+            };
+
+            let mut through = base.clone();
+            for (depth, version) in versions.into_iter().enumerate() {
+                let stamp = self.inner.listing_stamp(&through).await?;
+                let mut state = self.cache.state.lock().unwrap();
+                let valid = state.listings.get(&through).is_some_and(|l| l.stamp == stamp && l.version == version);
+                if !valid {
+                    state.remove_path(&key);
+                    state.stats.path_misses += 1;
+                    return Ok(None);
+                }
+                through = through.child(&dir.cids()[base.cids().len() + depth]);
+            }
+            self.cache.state.lock().unwrap().stats.path_hits += 1;
+            Ok(Some(dir))
+        })
+    }
+
+    // Only resolutions made entirely from cached listings are remembered.
+    fn remember_dir(&self, base: &DirKey, components: &[String], dir: &DirKey) {
+        let mut state = self.cache.state.lock().unwrap();
+        let mut versions = Vec::with_capacity(components.len());
+        let mut through = base.clone();
+        for cid in &dir.cids()[base.cids().len()..] {
+            let Some(cached) = state.listings.get(&through) else {
+                return;
+            };
+            versions.push(cached.version);
+            through = through.child(cid);
+        }
+
+        let key = (base.clone(), components.to_vec());
+        state.remove_path(&key);
+        let used = state.tick();
+        state.paths.insert(key.clone(), CachedPath { dir: dir.clone(), versions, used });
+        state.paths_by_use.insert(used, key);
+        while state.paths.len() > self.cache.max_paths
+            && let Some((_, key)) = state.paths_by_use.pop_first()
+        {
+            state.paths.remove(&key);
+            state.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::json::{JsonStore, LISTING_FILE, METADATA_DIR, SHARD_THRESHOLD};
+    use crate::metadata::model::SymlinkInfo;
+    use crate::metadata::test_util::TestPool;
+    use chrono::Utc;
+    use std::path::Path;
+
+    fn symlink(target: &str) -> Entry {
+        Entry::Symlink(SymlinkInfo { target: target.into(), created_at: Utc::now(), modified_at: Utc::now() })
+    }
+
+    fn open(pool_root: &str, inner: Arc<dyn MetadataStore>) -> CachedStore {
+        CachedStore::open(pool_root, inner, &ListingCacheConfig::default())
+    }
+
+    #[tokio::test]
+    async fn entries_of_uncached_listings_are_looked_up_in_the_store() {
+        let pool = TestPool::new("cache-entry", "{}");
+        let root = pool.root.as_str();
+        let json = Arc::new(JsonStore::new(root));
+        let dir = DirKey::root().child("abcde");
+        json.create_dir(&dir).await.unwrap();
+        json.put_entry(&dir, "a", &symlink("a")).await.unwrap();
+        let cached = open(root, json);
+
+        assert!(cached.get_entry(&dir, "a").await.unwrap().is_some());
+        let stats = cache_stats(root).unwrap();
+        assert_eq!((stats.listings, stats.listing_hits), (0, 0));
+
+        cached.read_listing(&dir).await.unwrap();
+        assert!(cached.get_entry(&dir, "b").await.unwrap().is_none());
+        let stats = cache_stats(root).unwrap();
+        assert_eq!((stats.listings, stats.listing_hits), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn writes_by_other_processes_change_the_stamp() {
+        let pool = TestPool::new("cache-stamp", "{}");
+        let root = pool.root.as_str();
+        let cached = open(root, Arc::new(JsonStore::new(root)));
+        let other = JsonStore::new(root);
+
+        for count in [1, SHARD_THRESHOLD + 1] {
+            let dir = DirKey::root().child(&format!("d{}", count));
+            let listing: DirectoryListing = (0..count).map(|i| (format!("e{}", i), symlink("e"))).collect();
+            other.create_dir(&dir).await.unwrap();
+            other.write_listing(&dir, &listing).await.unwrap();
+            assert_eq!(cached.read_listing(&dir).await.unwrap().len(), count);
+
+            let before = other.listing_stamp(&dir).await.unwrap();
+            other.put_entry(&dir, "new", &symlink("new")).await.unwrap();
+            assert_ne!(other.listing_stamp(&dir).await.unwrap(), before);
+            assert!(cached.get_entry(&dir, "new").await.unwrap().is_some());
+            assert_eq!(cached.read_listing(&dir).await.unwrap().len(), count + 1);
+        }
+        assert_eq!(cache_stats(root).unwrap().listing_stale, 2);
+
+        // The count is kept first in metadata.json, where stamps read it.
+        let listing_path = Path::new(root).join(METADATA_DIR).join("d1").join(LISTING_FILE);
+        assert!(std::fs::read_to_string(listing_path).unwrap().starts_with("{\n  \"/writes\": 2,"));
+    }
+}
@@ -0,0 +1,324 @@
+// src/metadata/db.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{DirectoryListing, Entry, FileMetadata};
+use crate::metadata::path_utils;
+use crate::metadata::store::{DirKey, DirLock, MetadataStore};
+use futures::future::BoxFuture;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const DB_FILE: &str = "metadata.redb";
+
+// Directory entries keyed by (directory key, entry name).
+const ENTRIES: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("entries");
+// Entry names by (directory key, case-folded name, name), for lookups that
+// ignore case. Kept in step with ENTRIES by every write. The table is named
+// after the folding it was filled with, see `path_utils::fold_case`.
+const FOLDED: TableDefinition<(&str, &str, &str), ()> = TableDefinition::new("folded_full");
+// FOLDED as filled with lowercased names, before case folding was full.
+const LOWERCASED: TableDefinition<(&str, &str, &str), ()> = TableDefinition::new("folded");
+// File block maps keyed by (directory key, file CID).
+const FILES: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("files");
+
+// redb reports each stage of a transaction with its own error type.
+macro_rules! impl_from_redb {
+    ($($error:ty),*) => {
+        $(impl From<$error> for MetadataError {
+            fn from(e: $error) -> Self {
+                MetadataError::Database(e.to_string())
+            }
+        })*
+    };
+}
+impl_from_redb!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+// Stores metadata in an embedded, transactional key-value database
+// (`{pool}/metadata.redb`). Every entry and block map is its own record, so
+// updating one entry does not rewrite the whole directory.
+pub struct DbStore {
+    db: Arc<Database>,
+    locks: Mutex<HashMap<DirKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl DbStore {
+    pub async fn open(pool_root: &str) -> Result<Self, MetadataError> {
+        let db_path = Path::new(pool_root).join(DB_FILE);
+        let db = tokio::task::spawn_blocking(move || -> Result<Database, MetadataError> {
+            let db = Database::create(db_path)?;
+            // Create the tables up front so that read transactions can always open them.
+            let txn = db.begin_write()?;
+            let entries = txn.open_table(ENTRIES)?;
+            txn.open_table(FILES)?;
+            let mut folded = txn.open_table(FOLDED)?;
+            // Databases written before FOLDED existed, including those with
+            // only LOWERCASED, get it filled in once.
+            if folded.is_empty()? {
+                txn.delete_table(LOWERCASED)?;
+                for item in entries.iter()? {
+                    let (key, _) = item?;
+                    let (dir, name) = key.value();
+                    folded.insert((dir, path_utils::fold_case(name).as_str(), name), ())?;
+                }
+            }
+            drop((entries, folded));
+            txn.commit()?;
+            Ok(db)
+        })
+        .await
+        .map_err(|e| MetadataError::Database(e.to_string()))??;
+
+        Ok(DbStore { db: Arc::new(db), locks: Mutex::new(HashMap::new()) })
+    }
+
+    // Runs a database operation on the blocking thread pool.
+    async fn blocking<T, F>(&self, op: F) -> Result<T, MetadataError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, MetadataError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || op(&db))
+            .await
+            .map_err(|e| MetadataError::Database(e.to_string()))?
+    }
+}
+
+impl MetadataStore for DbStore {
+    // Locks are held in-process; redb itself refuses to open a database twice.
+    fn lock_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirLock, MetadataError>> {
+        let lock = self.locks.lock().unwrap().entry(dir.clone()).or_default().clone();
+        Box::pin(async move { Ok(Box::new(lock.lock_owned().await) as DirLock) })
+    }
+
+    fn read_listing<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirectoryListing, MetadataError>> {
+        let dir = dir.as_string();
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(ENTRIES)?;
+            let mut listing = DirectoryListing::new();
+            for item in table.range((dir.as_str(), "")..)? {
+                let (key, value) = item?;
+                let (entry_dir, name) = key.value();
+                if entry_dir != dir {
+                    break;
+                }
+                listing.insert(name.to_string(), serde_json::from_slice(value.value())?);
+            }
+            Ok(listing)
+        }))
+    }
+
+    fn write_listing<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        listing: &'a DirectoryListing,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        let dir = dir.as_string();
+        let encoded: Result<Vec<(String, Vec<u8>)>, _> = listing
+            .iter()
+            .map(|(name, entry)| serde_json::to_vec(entry).map(|v| (name.clone(), v)))
+            .collect();
+        Box::pin(async move {
+            let encoded = encoded?;
+            self.blocking(move |db| {
+                let txn = db.begin_write()?;
+                {
+                    let mut table = txn.open_table(ENTRIES)?;
+                    let stale: Vec<String> = table
+                        .range((dir.as_str(), "")..)?
+                        .map_while(|item| {
+                            let (key, _) = item.ok()?;
+                            let (entry_dir, name) = key.value();
+                            (entry_dir == dir).then(|| name.to_string())
+                        })
+                        .collect();
+                    let mut folded = txn.open_table(FOLDED)?;
+                    for name in &stale {
+                        table.remove((dir.as_str(), name.as_str()))?;
+                        folded.remove((dir.as_str(), path_utils::fold_case(name).as_str(), name.as_str()))?;
+                    }
+                    for (name, value) in &encoded {
+                        table.insert((dir.as_str(), name.as_str()), value.as_slice())?;
+                        folded.insert((dir.as_str(), path_utils::fold_case(name).as_str(), name.as_str()), ())?;
+                    }
+                }
+                txn.commit()?;
+                Ok(())
+            })
+            .await
+        })
+    }
+
+    fn get_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        let (dir, name) = (dir.as_string(), name.to_string());
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(ENTRIES)?;
+            match table.get((dir.as_str(), name.as_str()))? {
+                Some(value) => Ok(Some(serde_json::from_slice(value.value())?)),
+                None => Ok(None),
+            }
+        }))
+    }
+
+    fn put_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+        entry: &'a Entry,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        let (dir, name) = (dir.as_string(), name.to_string());
+        let encoded = serde_json::to_vec(entry);
+        Box::pin(async move {
+            let encoded = encoded?;
+            self.blocking(move |db| {
+                let txn = db.begin_write()?;
+                txn.open_table(ENTRIES)?
+                    .insert((dir.as_str(), name.as_str()), encoded.as_slice())?;
+                txn.open_table(FOLDED)?
+                    .insert((dir.as_str(), path_utils::fold_case(&name).as_str(), name.as_str()), ())?;
+                txn.commit()?;
+                Ok(())
+            })
+            .await
+        })
+    }
+
+    fn remove_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        let (dir, name) = (dir.as_string(), name.to_string());
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            let removed = {
+                let mut table = txn.open_table(ENTRIES)?;
+                let removed = table.remove((dir.as_str(), name.as_str()))?;
+                txn.open_table(FOLDED)?.remove((dir.as_str(), path_utils::fold_case(&name).as_str(), name.as_str()))?;
+                match removed {
+                    Some(value) => Some(serde_json::from_slice(value.value())?),
+                    None => None,
+                }
+            };
+            txn.commit()?;
+            Ok(removed)
+        }))
+    }
+
+    fn find_folded<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        folded: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, Entry)>, MetadataError>> {
+        let (dir, folded) = (dir.as_string(), folded.to_string());
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_read()?;
+            let names = txn.open_table(FOLDED)?;
+            let Some(item) = names.range((dir.as_str(), folded.as_str(), "")..)?.next() else {
+                return Ok(None);
+            };
+            let (key, _) = item?;
+            let (key_dir, key_folded, name) = key.value();
+            if key_dir != dir || key_folded != folded {
+                return Ok(None);
+            }
+            match txn.open_table(ENTRIES)?.get((dir.as_str(), name))? {
+                Some(value) => Ok(Some((name.to_string(), serde_json::from_slice(value.value())?))),
+                None => Ok(None),
+            }
+        }))
+    }
+
+    fn read_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<FileMetadata, MetadataError>> {
+        let (dir, cid) = (dir.as_string(), cid.to_string());
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(FILES)?;
+            match table.get((dir.as_str(), cid.as_str()))? {
+                Some(value) => Ok(serde_json::from_slice(value.value())?),
+                None => Err(MetadataError::NotFound(format!("{}.json", cid))),
+            }
+        }))
+    }
+
+    fn write_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+        metadata: &'a FileMetadata,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        let (dir, cid) = (dir.as_string(), cid.to_string());
+        let encoded = serde_json::to_vec(metadata);
+        Box::pin(async move {
+            let encoded = encoded?;
+            self.blocking(move |db| {
+                let txn = db.begin_write()?;
+                txn.open_table(FILES)?
+                    .insert((dir.as_str(), cid.as_str()), encoded.as_slice())?;
+                txn.commit()?;
+                Ok(())
+            })
+            .await
+        })
+    }
+
+    fn remove_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<(), MetadataError>> {
+        let (dir, cid) = (dir.as_string(), cid.to_string());
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            txn.open_table(FILES)?.remove((dir.as_str(), cid.as_str()))?;
+            txn.commit()?;
+            Ok(())
+        }))
+    }
+
+    // Directories exist implicitly through their entries.
+    fn create_dir<'a>(&'a self, _dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        let dir = dir.as_string();
+        Box::pin(self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            let mut folded = txn.open_table(FOLDED)?;
+            let keys: Vec<(String, String)> = folded
+                .range((dir.as_str(), "", "")..)?
+                .map_while(|item| {
+                    let (key, _) = item.ok()?;
+                    let (key_dir, folded_name, name) = key.value();
+                    (key_dir == dir).then(|| (folded_name.to_string(), name.to_string()))
+                })
+                .collect();
+            for (folded_name, name) in &keys {
+                folded.remove((dir.as_str(), folded_name.as_str(), name.as_str()))?;
+            }
+            drop(folded);
+            for definition in [ENTRIES, FILES] {
+                let mut table = txn.open_table(definition)?;
+                let keys: Vec<String> = table
+                    .range((dir.as_str(), "")..)?
+                    .map_while(|item| {
+                        let (key, _) = item.ok()?;
+                        let (key_dir, name) = key.value();
+                        (key_dir == dir).then(|| name.to_string())
+                    })
+                    .collect();
+                for name in &keys {
+                    table.remove((dir.as_str(), name.as_str()))?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        }))
+    }
+}
@@ -0,0 +1,306 @@
+// src/metadata/du.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::block::index;
+use crate::metadata::error::MetadataError;
+use crate::metadata::link;
+use crate::metadata::manager;
+use crate::metadata::model::{BlockInfo, Entry, FileEntry};
+use crate::metadata::naming;
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use crate::metadata::walk::{self, WalkOptions};
+use crate::metadata::xattr;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::pin::pin;
+
+// A stored block, as `(xxh3, index)`.
+type BlockKey = (u128, u32);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DuOptions {
+    // Also report the directories down to this many levels below the path, as `du -d` does.
+    pub max_depth: usize,
+    // Report what deleting each entry would free instead of every unique byte it references.
+    pub exclusive: bool,
+}
+
+// Space pinned by a file, a directory tree or a pool. Everything that holds
+// block references counts: current contents, prior versions and extended
+// attribute values. Holes are never stored and count nowhere.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SpaceUsage {
+    // Stored bytes referenced, counting a block once per reference.
+    pub logical_bytes: u64,
+    // Bytes of the distinct blocks referenced.
+    pub physical_bytes: u64,
+    // Physical bytes of blocks referenced from nowhere else, i.e. what
+    // deleting the file or directory would free.
+    pub exclusive_bytes: u64,
+    // Physical bytes of blocks with more than one reference anywhere in the
+    // pool, i.e. deduplicated data.
+    pub shared_bytes: u64,
+    // Logical over physical bytes; 1.0 when nothing is stored.
+    pub dedup_ratio: f64,
+}
+
+// One line of `du` output.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DuEntry {
+    pub path: String,
+    // The exclusive or physical bytes, depending on `DuOptions::exclusive`.
+    pub bytes: u64,
+    #[serde(flatten)]
+    pub usage: SpaceUsage,
+}
+
+// The references one reported entry holds.
+#[derive(Default)]
+struct Scope {
+    refs: HashMap<BlockKey, u64>,
+    // Hard-linked files below the entry, by CID, with how many of their links are below it.
+    linked: HashMap<String, u32>,
+}
+
+// The blocks of a hard-linked file, and how many links it has in total.
+struct LinkedFile {
+    blocks: Vec<BlockKey>,
+    links: u32,
+}
+
+// Reports the space used by the entry at `rfs_path` and, per
+// `options.max_depth`, by the directories below it, in path order.
+pub async fn du(pool_root: &str, rfs_path: &str, options: &DuOptions) -> Result<Vec<DuEntry>, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let mut components = naming::split_path(pool_root, store, rfs_path).await?;
+    let path = format!("/{}", components.join("/"));
+
+    let mut scopes = BTreeMap::from([(path.clone(), Scope::default())]);
+    let mut linked_files = HashMap::new();
+    let start = match components.pop() {
+        None => DirKey::root(),
+        Some(name) => {
+            let parent = manager::find_dir_path(store, &components).await?;
+            let entry = store.get_entry(&parent, &name).await?.ok_or_else(|| MetadataError::NotFound(path.clone()))?;
+            let blocks = entry_blocks(store, &parent, &entry, &mut linked_files).await?;
+            add_refs(scopes.get_mut(&path).unwrap(), &entry, &blocks);
+            match entry {
+                Entry::Directory(info) => parent.child(&info.cid),
+                _ => return summarize(pool_root, scopes, &linked_files, options).await,
+            }
+        }
+    };
+
+    let mut entries = pin!(walk::walk_dir(pool_root, start, &path, WalkOptions::default()));
+    while let Some(item) = entries.next().await {
+        let item = item?;
+        let blocks = entry_blocks(store, &item.dir, &item.entry, &mut linked_files).await?;
+        // The entry counts towards the start path and every reported directory above it.
+        let relative: Vec<&str> = item.path[path.len()..].split('/').filter(|c| !c.is_empty()).collect();
+        let mut deepest = item.depth.saturating_sub(1).min(options.max_depth);
+        if matches!(item.entry, Entry::Directory(_)) && item.depth <= options.max_depth {
+            deepest = item.depth;
+        }
+        for depth in 0..=deepest {
+            let scope_path = match depth {
+                0 => path.clone(),
+                _ => format!("{}/{}", path.trim_end_matches('/'), relative[..depth].join("/")),
+            };
+            add_refs(scopes.entry(scope_path).or_default(), &item.entry, &blocks);
+        }
+    }
+    summarize(pool_root, scopes, &linked_files, options).await
+}
+
+// Reports the space used by the whole pool, from the block index alone:
+// live files, versions, snapshots and attribute values all count.
+pub async fn pool_usage(pool_root: &str) -> Result<SpaceUsage, MetadataError> {
+    let index = index::open(pool_root).await?;
+    let index = index.lock().await;
+    let mut usage = SpaceUsage::default();
+    for (_, entry) in index.iter() {
+        usage.logical_bytes += entry.len * entry.refs;
+        usage.physical_bytes += entry.len;
+        if entry.refs > 1 {
+            usage.shared_bytes += entry.len;
+        }
+    }
+    // Nothing outside the pool references its blocks.
+    usage.exclusive_bytes = usage.physical_bytes;
+    usage.dedup_ratio = dedup_ratio(usage.logical_bytes, usage.physical_bytes);
+    Ok(usage)
+}
+
+// The blocks an entry itself references. Those of a hard-linked file are
+// also recorded in `linked_files`.
+async fn entry_blocks(
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    entry: &Entry,
+    linked_files: &mut HashMap<String, LinkedFile>,
+) -> Result<Vec<BlockKey>, MetadataError> {
+    let file_entry = match entry {
+        Entry::File(file_entry) => file_entry,
+        Entry::Directory(info) => return Ok(xattr::blocks(&info.xattrs).map(block_key).collect()),
+        Entry::Symlink(_) => return Ok(Vec::new()),
+    };
+    if let Some(linked) = linked_files.get(&file_entry.cid).filter(|_| file_entry.linked) {
+        return Ok(linked.blocks.clone());
+    }
+    let mut blocks = Vec::new();
+    let home = link::home(dir, file_entry);
+    let metadata = manager::visit_file_refs(store, &home, &file_entry.cid, |b| blocks.push(block_key(b))).await?;
+    if file_entry.linked {
+        linked_files.insert(file_entry.cid.clone(), LinkedFile { blocks: blocks.clone(), links: metadata.links });
+    }
+    Ok(blocks)
+}
+
+fn add_refs(scope: &mut Scope, entry: &Entry, blocks: &[BlockKey]) {
+    // A hard-linked block map holds one reference per block, however many links reach it.
+    if let Entry::File(FileEntry { cid, linked: true, .. }) = entry {
+        let seen = scope.linked.entry(cid.clone()).or_default();
+        *seen += 1;
+        if *seen > 1 {
+            return;
+        }
+    }
+    for block in blocks {
+        *scope.refs.entry(*block).or_default() += 1;
+    }
+}
+
+async fn summarize(
+    pool_root: &str,
+    scopes: BTreeMap<String, Scope>,
+    linked_files: &HashMap<String, LinkedFile>,
+    options: &DuOptions,
+) -> Result<Vec<DuEntry>, MetadataError> {
+    let index = index::open(pool_root).await?;
+    let index = index.lock().await;
+    let mut report = Vec::with_capacity(scopes.len());
+    for (path, scope) in scopes {
+        // Files with links outside the scope outlive it, and so do their blocks.
+        let pinned: HashSet<BlockKey> = scope
+            .linked
+            .iter()
+            .filter_map(|(cid, seen)| linked_files.get(cid).filter(|f| *seen < f.links))
+            .flat_map(|f| f.blocks.iter().copied())
+            .collect();
+
+        let mut usage = SpaceUsage::default();
+        for (key, refs) in &scope.refs {
+            let Some(entry) = index.get(key.0, key.1) else {
+                continue;
+            };
+            usage.logical_bytes += entry.len * refs;
+            usage.physical_bytes += entry.len;
+            if *refs >= entry.refs && !pinned.contains(key) {
+                usage.exclusive_bytes += entry.len;
+            }
+            if entry.refs > 1 {
+                usage.shared_bytes += entry.len;
+            }
+        }
+        usage.dedup_ratio = dedup_ratio(usage.logical_bytes, usage.physical_bytes);
+        let bytes = if options.exclusive { usage.exclusive_bytes } else { usage.physical_bytes };
+        report.push(DuEntry { path, bytes, usage });
+    }
+    Ok(report)
+}
+
+fn block_key(block: &BlockInfo) -> BlockKey {
+    (block.xxh3, block.index)
+}
+
+fn dedup_ratio(logical_bytes: u64, physical_bytes: u64) -> f64 {
+    if physical_bytes == 0 {
+        return 1.0;
+    }
+    logical_bytes as f64 / physical_bytes as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::write;
+    use crate::metadata::test_util::{self, TestPool};
+
+    const LEN: u64 = 1000;
+
+    // Creates the file `path` holding LEN bytes of `fill`, one block.
+    async fn file(pool_root: &str, path: &str, fill: u8) {
+        let (dir, name) = path.rsplit_once('/').unwrap();
+        let dir = if dir.is_empty() { "/" } else { dir };
+        manager::create_file(pool_root, dir, name, test_util::file_metadata(name, 0)).await.unwrap();
+        write::append(pool_root, path, &[fill; LEN as usize]).await.unwrap();
+    }
+
+    // The reported paths, with their physical and exclusive bytes.
+    async fn report(pool_root: &str, rfs_path: &str, max_depth: usize) -> Vec<(String, u64, u64)> {
+        let options = DuOptions { max_depth, exclusive: false };
+        let entries = du(pool_root, rfs_path, &options).await.unwrap();
+        entries.into_iter().map(|e| (e.path, e.usage.physical_bytes, e.usage.exclusive_bytes)).collect()
+    }
+
+    #[tokio::test]
+    async fn deduplicated_blocks_are_exclusive_only_within_the_scope() {
+        let pool = TestPool::new("du-dedup", "{}");
+        let root = pool.root.as_str();
+        file(root, "/a/x", 1).await;
+        file(root, "/a/y", 1).await;
+        file(root, "/b/z", 2).await;
+        file(root, "/c/w", 2).await;
+
+        let usage = &du(root, "/a", &DuOptions::default()).await.unwrap()[0].usage;
+        assert_eq!((usage.logical_bytes, usage.physical_bytes, usage.exclusive_bytes), (2 * LEN, LEN, LEN));
+        assert_eq!(usage.shared_bytes, LEN);
+        assert_eq!(usage.dedup_ratio, 2.0);
+        // The copy in /c keeps the block alive.
+        assert_eq!(report(root, "/b", 0).await, [("/b".to_string(), LEN, 0)]);
+        assert_eq!(report(root, "/", 0).await, [("/".to_string(), 2 * LEN, 2 * LEN)]);
+    }
+
+    #[tokio::test]
+    async fn hard_links_are_exclusive_only_when_every_link_is_within_the_scope() {
+        let pool = TestPool::new("du-links", "{}");
+        let root = pool.root.as_str();
+        file(root, "/a/x", 1).await;
+        manager::create_hard_link(root, "/a/x", "/a/x2").await.unwrap();
+        file(root, "/b/z", 2).await;
+        file(root, "/c/w", 3).await;
+        manager::create_hard_link(root, "/b/z", "/c/z").await.unwrap();
+
+        // However many links reach it, a block map references its blocks once.
+        let usage = &du(root, "/a", &DuOptions::default()).await.unwrap()[0].usage;
+        assert_eq!((usage.logical_bytes, usage.physical_bytes, usage.exclusive_bytes), (LEN, LEN, LEN));
+        assert_eq!(report(root, "/b", 0).await, [("/b".to_string(), LEN, 0)]);
+        assert_eq!(report(root, "/c", 0).await, [("/c".to_string(), 2 * LEN, LEN)]);
+        assert_eq!(report(root, "/", 0).await, [("/".to_string(), 3 * LEN, 3 * LEN)]);
+    }
+
+    #[tokio::test]
+    async fn directories_down_to_max_depth_count_everything_below_them() {
+        let pool = TestPool::new("du-depth", "{}");
+        let root = pool.root.as_str();
+        file(root, "/d/e/f/g", 1).await;
+        file(root, "/d/k", 2).await;
+        manager::create_hard_link(root, "/d/e/f/g", "/d/l").await.unwrap();
+
+        assert_eq!(report(root, "/d", 0).await, [("/d".to_string(), 2 * LEN, 2 * LEN)]);
+        // The link in /d pins the blocks of /d/e/f/g for /d/e and /d/e/f.
+        assert_eq!(report(root, "/d", 1).await, [("/d".to_string(), 2 * LEN, 2 * LEN), ("/d/e".to_string(), LEN, 0)]);
+        assert_eq!(
+            report(root, "/d", 5).await,
+            [("/d".to_string(), 2 * LEN, 2 * LEN), ("/d/e".to_string(), LEN, 0), ("/d/e/f".to_string(), LEN, 0)]
+        );
+        manager::delete_entry(root, "/d/l").await.unwrap();
+        assert_eq!(report(root, "/d", 1).await[1], ("/d/e".to_string(), LEN, LEN));
+    }
+}
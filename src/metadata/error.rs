@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: AGPL-3.0
 // Copyright (c) 2025 Canmi
 
+use crate::block::store::RwError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -26,7 +27,87 @@ pub enum MetadataError {
     #[error("JSON serialization/deserialization error: {0}")]
     Json(#[from] serde_json::Error),
 
+    // No entry exists at the given path.
+    #[error("No such file or directory: {0}")]
+    NotFound(String),
+
+    // The operation expected a file but found a directory.
+    #[error("The specified path is a directory, not a file: {0}")]
+    NotAFile(String),
+
     // The operation expected a directory but found a file.
     #[error("The specified path is a file, not a directory: {0}")]
     NotADirectory(String),
+
+    // The operation expected a symbolic link.
+    #[error("The specified path is not a symbolic link: {0}")]
+    NotASymlink(String),
+
+    // The embedded metadata database reported an error.
+    #[error("Metadata database error: {0}")]
+    Database(String),
+
+    // A listing cursor is malformed or belongs to a different listing order.
+    #[error("Invalid listing cursor: {0}")]
+    InvalidCursor(String),
+
+    // A glob pattern could not be parsed.
+    #[error("Invalid glob pattern: '{0}'")]
+    InvalidPattern(String),
+
+    // A listing page may not be limited to zero entries.
+    #[error("Invalid listing limit: {0}")]
+    InvalidLimit(usize),
+
+    // No snapshot with the given name exists.
+    #[error("No such snapshot: {0}")]
+    SnapshotNotFound(String),
+
+    // The file or directory has no extended attribute with the given name.
+    #[error("No such extended attribute: {0}")]
+    XattrNotFound(String),
+
+    // An extended attribute name is empty, too long or contains a NUL byte.
+    #[error("Invalid extended attribute name: '{0}'")]
+    InvalidXattrName(String),
+
+    // The trash holds no entry with the given ID.
+    #[error("No such trash item: {0}")]
+    TrashItemNotFound(String),
+
+    // The file has no prior version with the given number.
+    #[error("No such version: {0}")]
+    VersionNotFound(u32),
+
+    // The operation is not valid for the given paths, e.g. copying a directory into itself.
+    #[error("Invalid operation: {0}")]
+    InvalidOperation(String),
+
+    // The entry does not satisfy the precondition of a conditional operation.
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    // A change would exceed the quota of a directory or owner.
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    // The change log no longer holds the changes after the given sequence number.
+    #[error("Changes after sequence {0} have been trimmed from the change log")]
+    ChangesTrimmed(u64),
+
+    // Changes after the given sequence number could not be recorded in the change log.
+    #[error("Changes after sequence {0} are missing from the change log")]
+    ChangesLost(u64),
+
+    // Updating block reference counts failed.
+    #[error("Block storage error: {0}")]
+    Block(#[from] RwError),
+
+    // The pool's metadata cannot be migrated as requested.
+    #[error("Cannot migrate metadata: {0}")]
+    Migration(String),
+
+    // The pool was written in a newer metadata format than this librfs supports.
+    #[error("Pool metadata format {0} is newer than the supported format {1}")]
+    UnsupportedFormat(u32, u32),
 }
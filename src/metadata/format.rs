@@ -0,0 +1,512 @@
+// src/metadata/format.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::link;
+use crate::metadata::lock::FileLock;
+use crate::metadata::migrate;
+use crate::metadata::model::{DirectoryListing, Entry};
+use crate::metadata::path_utils;
+use crate::metadata::search_index;
+use crate::metadata::store::{DirKey, MetadataStore};
+use crate::metadata::version;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+// The pool superblock, in the pool root next to config.json.
+pub const SUPERBLOCK_FILE: &str = "format.json";
+// The directories an interrupted upgrade step has already converted, one
+// `DirKey` per line.
+const PROGRESS_FILE: &str = "format.progress";
+
+// The metadata format this librfs reads and writes:
+// 1. Pools without a superblock. Block maps may lack the file's name.
+// 2. Every block map records `filename`.
+// 3. Hard-linked block maps record the directories of their links.
+// 4. Entry names are NFC-normalized, and sharded JSON listings are bucketed
+//    by case-folded name.
+// 5. JSON listings and shard headers count the writes to them.
+// 6. Case-folded names are fully folded rather than lowercased.
+pub const FORMAT_VERSION: u32 = 6;
+
+// Records which format a pool's metadata is stored in. It covers both
+// backends, as `rfs-migrate` copies the metadata as it is read.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Superblock {
+    pub version: u32,
+    // The version an interrupted upgrade was moving to. Its progress is kept
+    // in format.progress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrading: Option<u32>,
+    // The librfs release that last wrote the superblock.
+    #[serde(default)]
+    pub written_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgraded_at: Option<DateTime<Utc>>,
+}
+
+impl Superblock {
+    // What a pool created before superblocks existed is treated as.
+    fn legacy() -> Self {
+        Superblock { version: 1, upgrading: None, written_by: String::new(), upgraded_at: None }
+    }
+}
+
+// Rewrites one directory of a pool from the previous format version. It gets
+// the directory's listing, read under its lock, and returns how many entries
+// or block maps it changed. Running it again on a converted directory changes
+// nothing.
+type UpgradeDir =
+    for<'a> fn(&'a dyn MetadataStore, &'a DirKey, &'a DirectoryListing) -> BoxFuture<'a, Result<u64, MetadataError>>;
+
+// One step of the upgrade path, from `to - 1` to `to`.
+struct Migration {
+    to: u32,
+    description: &'static str,
+    upgrade_dir: UpgradeDir,
+    // The step rewrites listings, which the search index is not told about.
+    rewrites_listings: bool,
+}
+
+// Every step, in order. A change to the stored format that older readers
+// cannot ignore adds a step here and bumps FORMAT_VERSION.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 2,
+        description: "record file names in block maps",
+        upgrade_dir: name_block_maps,
+        rewrites_listings: false,
+    },
+    Migration {
+        to: 3,
+        description: "record where hard links are",
+        upgrade_dir: record_link_dirs,
+        rewrites_listings: false,
+    },
+    Migration {
+        to: 4,
+        description: "normalize entry names and fold shard buckets",
+        upgrade_dir: normalize_listing,
+        rewrites_listings: true,
+    },
+    Migration { to: 5, description: "count writes to listings", upgrade_dir: count_writes, rewrites_listings: false },
+    Migration { to: 6, description: "fold names fully", upgrade_dir: refold_listing, rewrites_listings: false },
+];
+
+fn superblock_path(pool_root: &str) -> PathBuf {
+    Path::new(pool_root).join(SUPERBLOCK_FILE)
+}
+
+fn progress_path(pool_root: &str) -> PathBuf {
+    Path::new(pool_root).join(PROGRESS_FILE)
+}
+
+// Reads the superblock of a pool, or None for a pool that predates them.
+pub async fn read_superblock(pool_root: &str) -> Result<Option<Superblock>, MetadataError> {
+    match fs::read(superblock_path(pool_root)).await {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaces the superblock through a temporary file, so that it is never seen half written.
+async fn write_superblock(pool_root: &str, superblock: &mut Superblock) -> Result<(), MetadataError> {
+    superblock.written_by = env!("CARGO_PKG_VERSION").to_string();
+    let path = superblock_path(pool_root);
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(superblock)?).await?;
+    fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+// Makes sure a pool's metadata can be used through `store`, its backend as
+// selected by config.json: pools in a newer format are refused, and older
+// ones are upgraded in place first. An upgrade that was interrupted resumes
+// where it stopped.
+pub async fn open(pool_root: &str, store: &dyn MetadataStore) -> Result<(), MetadataError> {
+    let superblock = read_superblock(pool_root).await?.unwrap_or_else(Superblock::legacy);
+    if superblock.version == FORMAT_VERSION {
+        return Ok(());
+    }
+    check_supported(&superblock)?;
+
+    // Another process may be upgrading the pool; wait for it and look again.
+    let _lock = FileLock::acquire(&superblock_path(pool_root)).await?;
+    let mut superblock = read_superblock(pool_root).await?.unwrap_or_else(Superblock::legacy);
+    check_supported(&superblock)?;
+    let from = superblock.version;
+    for migration in MIGRATIONS.iter().filter(|m| m.to > from) {
+        upgrade(pool_root, store, migration, &mut superblock).await?;
+    }
+    Ok(())
+}
+
+fn check_supported(superblock: &Superblock) -> Result<(), MetadataError> {
+    if superblock.version > FORMAT_VERSION {
+        return Err(MetadataError::UnsupportedFormat(superblock.version, FORMAT_VERSION));
+    }
+    Ok(())
+}
+
+// Runs one step over every directory of the pool, recording each one it
+// finishes so that a later run can skip it.
+async fn upgrade(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    migration: &Migration,
+    superblock: &mut Superblock,
+) -> Result<(), MetadataError> {
+    let resumed = superblock.upgrading == Some(migration.to);
+    let done = if resumed { read_progress(pool_root).await? } else { HashSet::new() };
+    log(
+        LogLevel::Info,
+        &format!(
+            "Upgrading metadata of '{}' from format {} to {}: {}{}.",
+            pool_root,
+            superblock.version,
+            migration.to,
+            migration.description,
+            if resumed { format!(", resuming after {} directories", done.len()) } else { String::new() }
+        ),
+    );
+    if !resumed {
+        // Progress left by a step that finished without cleaning up is stale.
+        match fs::remove_file(progress_path(pool_root)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        superblock.upgrading = Some(migration.to);
+        write_superblock(pool_root, superblock).await?;
+    }
+
+    let mut progress = fs::OpenOptions::new().create(true).append(true).open(progress_path(pool_root)).await?;
+    let mut directories = 0;
+    let mut changed = 0;
+    let mut pending = migrate::metadata_roots(pool_root).await?;
+    while let Some(dir) = pending.pop() {
+        let _dir_lock = store.lock_dir(&dir).await?;
+        let listing = store.read_listing(&dir).await?;
+        for entry in listing.values() {
+            if let Entry::Directory(info) = entry {
+                pending.push(dir.child(&info.cid));
+            }
+        }
+        let key = dir.as_string();
+        if done.contains(&key) {
+            continue;
+        }
+        changed += (migration.upgrade_dir)(store, &dir, &listing).await?;
+        progress.write_all(format!("{}\n", key).as_bytes()).await?;
+        directories += 1;
+    }
+    progress.sync_all().await?;
+
+    if migration.rewrites_listings {
+        search_index::discard(pool_root).await?;
+    }
+    superblock.version = migration.to;
+    superblock.upgrading = None;
+    superblock.upgraded_at = Some(Utc::now());
+    write_superblock(pool_root, superblock).await?;
+    fs::remove_file(progress_path(pool_root)).await?;
+    log(
+        LogLevel::Info,
+        &format!(
+            "Upgraded {} directories of '{}' to format {}, making {} changes.",
+            directories, pool_root, migration.to, changed
+        ),
+    );
+    Ok(())
+}
+
+// The directories recorded in format.progress. A line cut short by a crash
+// has no newline yet and is ignored, so its directory is converted again.
+async fn read_progress(pool_root: &str) -> Result<HashSet<String>, MetadataError> {
+    let content = match fs::read_to_string(progress_path(pool_root)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines: Vec<&str> = content.split('\n').collect();
+    lines.pop();
+    Ok(lines.into_iter().map(String::from).collect())
+}
+
+// Format 2: fills in the `filename` of block maps written before it existed,
+// including those of prior versions, from the name of the entry. A hard-linked
+// block map takes the name of the first link converted.
+fn name_block_maps<'a>(
+    store: &'a dyn MetadataStore,
+    dir: &'a DirKey,
+    listing: &'a DirectoryListing,
+) -> BoxFuture<'a, Result<u64, MetadataError>> {
+    Box::pin(async move {
+        let mut changed = 0;
+        for (name, entry) in listing {
+            let Entry::File(file_entry) = entry else {
+                continue;
+            };
+            let _home_lock = link::lock_home(store, file_entry).await?;
+            let home = link::home(dir, file_entry);
+            let metadata = store.read_file_metadata(&home, &file_entry.cid).await?;
+            let mut ids: Vec<String> =
+                metadata.versions.iter().map(|v| version::version_id(&file_entry.cid, v.version)).collect();
+            ids.push(file_entry.cid.clone());
+            for id in &ids {
+                let mut metadata = store.read_file_metadata(&home, id).await?;
+                if metadata.filename.is_empty() {
+                    metadata.filename = name.clone();
+                    store.write_file_metadata(&home, id, &metadata).await?;
+                    changed += 1;
+                }
+            }
+        }
+        Ok(changed)
+    })
+}
+
+// Format 3: records the directory of each hard link in the shared block map.
+// A directory's links are counted and set, rather than added, so converting
+// it again changes nothing.
+fn record_link_dirs<'a>(
+    store: &'a dyn MetadataStore,
+    dir: &'a DirKey,
+    listing: &'a DirectoryListing,
+) -> BoxFuture<'a, Result<u64, MetadataError>> {
+    Box::pin(async move {
+        let mut links: BTreeMap<&str, usize> = BTreeMap::new();
+        for entry in listing.values() {
+            if let Entry::File(file_entry) = entry
+                && file_entry.linked
+            {
+                *links.entry(&file_entry.cid).or_default() += 1;
+            }
+        }
+
+        let key = dir.as_string();
+        let mut changed = 0;
+        for (cid, count) in links {
+            let root = link::links_root();
+            let _home_lock = store.lock_dir(&root).await?;
+            let mut metadata = store.read_file_metadata(&root, cid).await?;
+            if metadata.link_dirs.iter().filter(|d| **d == key).count() == count {
+                continue;
+            }
+            metadata.link_dirs.retain(|d| *d != key);
+            metadata.link_dirs.extend(std::iter::repeat_n(key.clone(), count));
+            store.write_file_metadata(&root, cid, &metadata).await?;
+            changed += 1;
+        }
+        Ok(changed)
+    })
+}
+
+// Format 4: renames entries to the NFC form of their names, which lookups
+// normalize to, and rewrites every listing. Written whole, a large JSON
+// listing is sharded by case-folded name, which case-insensitive lookups
+// rely on. An entry whose normalized name is taken by another gets a free
+// one, e.g. "Résumé (1)", as merging the two is not this step's call; left
+// decomposed, it could not be looked up at all.
+fn normalize_listing<'a>(
+    store: &'a dyn MetadataStore,
+    dir: &'a DirKey,
+    listing: &'a DirectoryListing,
+) -> BoxFuture<'a, Result<u64, MetadataError>> {
+    Box::pin(async move {
+        let mut normalized = listing.clone();
+        let mut changed = 0;
+        for (name, entry) in listing {
+            let mut nfc = path_utils::normalize(name);
+            if nfc == *name {
+                continue;
+            }
+            if normalized.contains_key(&nfc) {
+                let free = (1..).map(|n| format!("{} ({})", nfc, n)).find(|n| !normalized.contains_key(n)).unwrap();
+                log(
+                    LogLevel::Warn,
+                    &format!("Renaming '{}' to '{}', as its normalized name '{}' is taken.", name, free, nfc),
+                );
+                nfc = free;
+            }
+            normalized.remove(name);
+            normalized.insert(nfc, entry.clone());
+            changed += 1;
+        }
+        store.write_listing(dir, &normalized).await?;
+        Ok(changed)
+    })
+}
+
+// Format 5: a JSON listing counts the writes to it under a key that older
+// readers take for an entry. A listing without the count reads as never
+// written, so nothing needs rewriting.
+fn count_writes<'a>(
+    _store: &'a dyn MetadataStore,
+    _dir: &'a DirKey,
+    _listing: &'a DirectoryListing,
+) -> BoxFuture<'a, Result<u64, MetadataError>> {
+    Box::pin(async { Ok(0) })
+}
+
+// Format 6: rewrites each listing holding a name whose full case folding
+// differs from its lowercase, which format 4 bucketed sharded JSON listings by,
+// so that it moves to the bucket lookups look in. The db refills its folded
+// index on open.
+fn refold_listing<'a>(
+    store: &'a dyn MetadataStore,
+    dir: &'a DirKey,
+    listing: &'a DirectoryListing,
+) -> BoxFuture<'a, Result<u64, MetadataError>> {
+    Box::pin(async move {
+        if listing.keys().all(|name| path_utils::fold_case(name) == name.to_lowercase()) {
+            return Ok(0);
+        }
+        store.write_listing(dir, listing).await?;
+        Ok(1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::json::JsonStore;
+    use crate::metadata::model::{DirectoryInfo, SymlinkInfo};
+    use crate::metadata::store;
+    use crate::metadata::test_util::TestPool;
+
+    fn symlink(target: &str) -> Entry {
+        Entry::Symlink(SymlinkInfo { target: target.into(), created_at: Utc::now(), modified_at: Utc::now() })
+    }
+
+    // A format 3 pool whose root holds the directory "Café" and the symlink
+    // "Résumé", and that directory the symlink "Café", all named decomposed.
+    // Returns the pool and the directory's key.
+    async fn format_3_pool(name: &str) -> (TestPool, DirKey) {
+        let pool = TestPool::new(name, r#"{"metadataBackend":"json"}"#);
+        let json = JsonStore::new(&pool.root);
+        let info = DirectoryInfo {
+            cid: "abcde".into(),
+            size: 0,
+            created_at: Utc::now(),
+            modified_at: Utc::now(),
+            xattrs: Default::default(),
+            quota: None,
+            revision: 0,
+        };
+        let root: DirectoryListing = [
+            ("Cafe\u{301}".to_string(), Entry::Directory(info)),
+            ("Re\u{301}sume\u{301}".to_string(), symlink("root")),
+        ]
+        .into_iter()
+        .collect();
+        let child = DirKey::root().child("abcde");
+        json.create_dir(&DirKey::root()).await.unwrap();
+        json.write_listing(&DirKey::root(), &root).await.unwrap();
+        json.create_dir(&child).await.unwrap();
+        json.write_listing(&child, &[("Cafe\u{301}".to_string(), symlink("child"))].into_iter().collect())
+            .await
+            .unwrap();
+        (pool, child)
+    }
+
+    async fn names(store: &dyn MetadataStore, dir: &DirKey) -> Vec<String> {
+        let mut names: Vec<String> = store.read_listing(dir).await.unwrap().into_keys().collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_upgrade_resumes_where_it_stopped() {
+        let (pool, child) = format_3_pool("format-resume").await;
+        // The crash came after the root was converted, while the line of the
+        // subdirectory was being recorded.
+        std::fs::write(superblock_path(&pool.root), r#"{"version":3,"upgrading":4}"#).unwrap();
+        std::fs::write(progress_path(&pool.root), format!("\n{}", child.as_string())).unwrap();
+
+        let store = store::get_metadata_store(&pool.root).await.unwrap();
+        assert_eq!(names(store.as_ref(), &DirKey::root()).await, ["Cafe\u{301}", "Re\u{301}sume\u{301}"]);
+        assert_eq!(names(store.as_ref(), &child).await, ["Caf\u{e9}"]);
+        let superblock = read_superblock(&pool.root).await.unwrap().unwrap();
+        assert_eq!((superblock.version, superblock.upgrading), (FORMAT_VERSION, None));
+        assert!(!progress_path(&pool.root).exists());
+    }
+
+    #[tokio::test]
+    async fn progress_left_without_an_upgrade_under_way_is_ignored() {
+        let (pool, child) = format_3_pool("format-stale-progress").await;
+        std::fs::write(superblock_path(&pool.root), r#"{"version":3}"#).unwrap();
+        std::fs::write(progress_path(&pool.root), format!("\n{}\n", child.as_string())).unwrap();
+
+        let store = store::get_metadata_store(&pool.root).await.unwrap();
+        assert_eq!(names(store.as_ref(), &DirKey::root()).await, ["Caf\u{e9}", "R\u{e9}sum\u{e9}"]);
+        assert_eq!(names(store.as_ref(), &child).await, ["Caf\u{e9}"]);
+        assert!(!progress_path(&pool.root).exists());
+    }
+
+    #[tokio::test]
+    async fn upgrading_to_format_4_normalizes_names() {
+        let pool = TestPool::new("format-nfc", r#"{"metadataBackend":"json"}"#);
+        let listing: DirectoryListing = [
+            ("Cafe\u{301}".to_string(), symlink("decomposed")),
+            ("Re\u{301}sume\u{301}".to_string(), symlink("decomposed")),
+            ("R\u{e9}sum\u{e9}".to_string(), symlink("composed")),
+        ]
+        .into_iter()
+        .collect();
+        let json = JsonStore::new(&pool.root);
+        json.create_dir(&DirKey::root()).await.unwrap();
+        json.write_listing(&DirKey::root(), &listing).await.unwrap();
+        std::fs::write(superblock_path(&pool.root), r#"{"version":3}"#).unwrap();
+
+        let store = store::get_metadata_store(&pool.root).await.unwrap();
+        let listing = store.read_listing(&DirKey::root()).await.unwrap();
+        let mut names: Vec<&str> = listing.keys().map(String::as_str).collect();
+        names.sort();
+        // The decomposed "Résumé" collides with the composed one and is renamed.
+        assert_eq!(names, ["Caf\u{e9}", "R\u{e9}sum\u{e9}", "R\u{e9}sum\u{e9} (1)"]);
+        assert!(matches!(&listing["R\u{e9}sum\u{e9}"], Entry::Symlink(info) if info.target == "composed"));
+        assert!(matches!(&listing["R\u{e9}sum\u{e9} (1)"], Entry::Symlink(info) if info.target == "decomposed"));
+        assert_eq!(read_superblock(&pool.root).await.unwrap().unwrap().version, FORMAT_VERSION);
+    }
+
+    #[tokio::test]
+    async fn upgrading_to_format_6_moves_names_to_their_fully_folded_bucket() {
+        let pool = TestPool::new("format-fold", r#"{"metadataBackend":"json"}"#);
+        let json = JsonStore::new(&pool.root);
+        json.create_dir(&DirKey::root()).await.unwrap();
+        let mut listing: DirectoryListing = (0..3000).map(|i| (format!("Entry{}", i), symlink("t"))).collect();
+        listing.insert("Stra\u{df}e".into(), symlink("t"));
+        json.write_listing(&DirKey::root(), &listing).await.unwrap();
+
+        // Format 5 put the entry in the bucket of its lowercased name.
+        let bucket = |key: &str| {
+            let bucket = xxhash_rust::xxh3::xxh3_64(key.as_bytes()) % 512;
+            Path::new(&pool.root).join("metadata/shards").join(format!("{:04x}.json", bucket))
+        };
+        let read = |path: &Path| -> serde_json::Map<String, serde_json::Value> {
+            std::fs::read(path).map_or_else(|_| Default::default(), |content| serde_json::from_slice(&content).unwrap())
+        };
+        let (folded, lowercased) = (bucket("strasse"), bucket("stra\u{df}e"));
+        let mut from = read(&folded);
+        let mut to = read(&lowercased);
+        to.insert("Stra\u{df}e".into(), from.remove("Stra\u{df}e").unwrap());
+        std::fs::write(&folded, serde_json::to_vec(&from).unwrap()).unwrap();
+        std::fs::write(&lowercased, serde_json::to_vec(&to).unwrap()).unwrap();
+        std::fs::write(superblock_path(&pool.root), r#"{"version":5}"#).unwrap();
+
+        let store = store::get_metadata_store(&pool.root).await.unwrap();
+        let found = store.find_folded(&DirKey::root(), &path_utils::fold_case("STRASSE")).await.unwrap();
+        assert_eq!(found.map(|(name, _)| name).as_deref(), Some("Stra\u{df}e"));
+        assert_eq!(store.read_listing(&DirKey::root()).await.unwrap().len(), 3001);
+        assert_eq!(read_superblock(&pool.root).await.unwrap().unwrap().version, FORMAT_VERSION);
+    }
+}
@@ -0,0 +1,427 @@
+// src/metadata/journal.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config;
+use crate::metadata::error::MetadataError;
+use crate::metadata::lock::FileLock;
+use crate::metadata::model::Entry;
+use crate::metadata::naming;
+use crate::metadata::store;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use once_cell::sync::Lazy;
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration};
+
+const CHANGE_LOG_FILE: &str = "changes.log";
+// How long a watcher waits for a change announced in this process before it
+// re-reads the log anyway, to pick up changes made by other processes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Wakes the watchers of a pool when this process appends to its log, by pool root.
+static NOTIFIERS: Lazy<StdMutex<HashMap<String, Arc<Notify>>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+// How many changes this process failed to record, by pool root. The next
+// append skips their sequence numbers, leaving a gap that watchers report.
+static LOST: Lazy<StdMutex<HashMap<String, u64>>> = Lazy::new(|| StdMutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Create,
+    Delete,
+    // The entry moved from `old_path` to `path`.
+    Rename,
+    // The contents or attributes of the entry changed.
+    Modify,
+}
+
+// One line of the append-only change log, `{pool}/changes.log`. Sequence
+// numbers start at 1 and increase by one per change. A gap stands for changes
+// that were made but could not be recorded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub seq: u64,
+    pub kind: ChangeKind,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    // The CID of the file or directory; symlinks have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl Change {
+    // A change to be recorded; its sequence number is assigned when it is.
+    pub(crate) fn new(kind: ChangeKind, path: String, cid: Option<&str>) -> Self {
+        Change { seq: 0, kind, path, old_path: None, cid: cid.map(String::from), at: Utc::now() }
+    }
+
+    // Whether the change touches `prefix` or anything below it.
+    fn is_below(&self, prefix: &str) -> bool {
+        let below = |path: &str| {
+            prefix == "/" || path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+        };
+        below(&self.path) || self.old_path.as_deref().is_some_and(below)
+    }
+}
+
+fn log_path(pool_root: &str) -> PathBuf {
+    Path::new(pool_root).join(CHANGE_LOG_FILE)
+}
+
+fn notifier(pool_root: &str) -> Arc<Notify> {
+    NOTIFIERS.lock().unwrap().entry(pool_root.to_string()).or_default().clone()
+}
+
+// The virtual path of the entry `name` in the directory at `dir_components`.
+pub(crate) fn entry_path(dir_components: &[String], name: &str) -> String {
+    match dir_components.is_empty() {
+        true => format!("/{}", name),
+        false => format!("/{}/{}", dir_components.join("/"), name),
+    }
+}
+
+// The CID a change to `entry` is recorded with.
+pub(crate) fn cid_of(entry: &Entry) -> Option<&str> {
+    match entry {
+        Entry::File(file_entry) => Some(&file_entry.cid),
+        Entry::Directory(info) => Some(&info.cid),
+        Entry::Symlink(_) => None,
+    }
+}
+
+// Appends a change to the pool's log. Callers record a change while they
+// still hold the lock of the directory it happened in, so changes to one
+// directory are logged in the order they were made.
+pub(crate) async fn record(pool_root: &str, kind: ChangeKind, path: String, cid: Option<&str>) {
+    record_all(pool_root, vec![Change::new(kind, path, cid)]).await
+}
+
+// Appends changes to the pool's log in one write, numbered in order. They have
+// been made by then, and the caller still has to propagate them up the tree,
+// so a log that cannot be written is reported here instead of failing it, and
+// the changes are counted as lost.
+pub(crate) async fn record_all(pool_root: &str, changes: Vec<Change>) {
+    let count = changes.len() as u64;
+    if let Err(e) = append(pool_root, changes).await {
+        log(LogLevel::Error, &format!("Failed to record changes in the log of '{}': {}", pool_root, e));
+        *LOST.lock().unwrap().entry(pool_root.to_string()).or_default() += count;
+    }
+}
+
+async fn append(pool_root: &str, changes: Vec<Change>) -> Result<(), MetadataError> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let path = log_path(pool_root);
+    let lock = FileLock::acquire(&path).await?;
+    let (mut seq, complete) = read_last_seq(&path).await?;
+    let lost = LOST.lock().unwrap().get(pool_root).copied().unwrap_or(0);
+    seq += lost;
+
+    // A record torn by a crash is left on a line of its own and skipped by readers.
+    let mut lines = if complete { Vec::new() } else { vec![b'\n'] };
+    for mut change in changes {
+        seq += 1;
+        change.seq = seq;
+        lines.extend(serde_json::to_vec(&change)?);
+        lines.push(b'\n');
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+    file.write_all(&lines).await?;
+    file.sync_data().await?;
+    drop(lock);
+    if lost > 0 {
+        let mut counts = LOST.lock().unwrap();
+        let count = counts.entry(pool_root.to_string()).or_default();
+        *count -= lost;
+        if *count == 0 {
+            counts.remove(pool_root);
+        }
+    }
+
+    notifier(pool_root).notify_waiters();
+    Ok(())
+}
+
+// Reads the sequence number of the last complete record, 0 if there is none,
+// and whether the log ends with a complete record.
+async fn read_last_seq(path: &Path) -> Result<(u64, bool), MetadataError> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, true)),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata().await?.len();
+    let mut chunk = 4096;
+    loop {
+        let start = len.saturating_sub(chunk);
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await?;
+
+        let complete = tail.last().is_none_or(|&b| b == b'\n');
+        let mut lines = tail.split(|&b| b == b'\n').collect::<Vec<_>>();
+        // The part after the last newline is torn or empty, and the first part
+        // may be cut off by the start of the chunk.
+        lines.pop();
+        let first_whole = if start == 0 { 0 } else { 1 };
+        for line in lines.iter().skip(first_whole).rev() {
+            if let Ok(change) = serde_json::from_slice::<Change>(line) {
+                return Ok((change.seq, complete));
+            }
+        }
+        if start == 0 {
+            return Ok((0, complete));
+        }
+        chunk *= 2;
+    }
+}
+
+// The complete records of the log from byte `offset` on.
+struct LogRead {
+    changes: Vec<Change>,
+    // Where the next read should start.
+    end: u64,
+    // The log is shorter than `offset`, i.e. it has been rewritten.
+    shrunk: bool,
+}
+
+async fn read_from(path: &Path, offset: u64) -> Result<LogRead, MetadataError> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(LogRead { changes: Vec::new(), end: 0, shrunk: offset > 0 });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if file.metadata().await?.len() < offset {
+        return Ok(LogRead { changes: Vec::new(), end: 0, shrunk: true });
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).await?;
+
+    // A record still being written is picked up by the next read.
+    let whole = content.iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos + 1);
+    let mut changes = Vec::new();
+    for line in content[..whole].split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        match serde_json::from_slice::<Change>(line) {
+            Ok(change) => changes.push(change),
+            Err(_) => log(LogLevel::Warn, &format!("Skipping unreadable record in {}", path.display())),
+        }
+    }
+    Ok(LogRead { changes, end: offset + whole as u64, shrunk: false })
+}
+
+// Reads the sequence number of the pool's latest change, 0 if there is none.
+// Watching from it yields only changes made from now on.
+pub async fn current_seq(pool_root: &str) -> Result<u64, MetadataError> {
+    Ok(read_last_seq(&log_path(pool_root)).await?.0)
+}
+
+// Drops all but the pool's latest `change_log_records` changes from its log.
+// The latest change is always kept, so sequence numbers never restart.
+// Returns the number of changes dropped.
+pub async fn trim(pool_root: &str) -> Result<u64, MetadataError> {
+    let keep = config::get_pool_config(pool_root).await?.change_log_records.max(1) as usize;
+    let path = log_path(pool_root);
+    let _lock = FileLock::acquire(&path).await?;
+    let changes = read_from(&path, 0).await?.changes;
+    if changes.len() <= keep {
+        return Ok(0);
+    }
+
+    let dropped = changes.len() - keep;
+    let mut content = Vec::new();
+    for change in &changes[dropped..] {
+        content.extend(serde_json::to_vec(change)?);
+        content.push(b'\n');
+    }
+    let tmp_path = path.with_extension("log.tmp");
+    fs::write(&tmp_path, &content).await?;
+    fs::rename(&tmp_path, &path).await?;
+    Ok(dropped as u64)
+}
+
+// A watcher's position in the log.
+struct WatchState {
+    pool_root: String,
+    rfs_path: String,
+    // The normalized `rfs_path`, once resolved.
+    prefix: Option<String>,
+    last_seq: u64,
+    offset: u64,
+    pending: VecDeque<Change>,
+    failed: bool,
+}
+
+// Streams the changes at or below `rfs_path` made after `since_seq`, waiting
+// for new ones once the log is exhausted; the stream only ends on an error.
+// A consumer that resumes with the sequence number of the last change it saw
+// misses nothing, unless those changes have since been trimmed from the log,
+// which fails with `ChangesTrimmed`, or could not be recorded, which fails with
+// `ChangesLost`. Use `current_seq` to start from now; a
+// `since_seq` past it is refused.
+pub fn watch(
+    pool_root: &str,
+    rfs_path: &str,
+    since_seq: u64,
+) -> impl Stream<Item = Result<Change, MetadataError>> + Send + 'static {
+    let state = WatchState {
+        pool_root: pool_root.to_string(),
+        rfs_path: rfs_path.to_string(),
+        prefix: None,
+        last_seq: since_seq,
+        offset: 0,
+        pending: VecDeque::new(),
+        failed: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        if state.failed {
+            return None;
+        }
+        match state.next_change().await {
+            Ok(change) => Some((Ok(change), state)),
+            Err(e) => {
+                state.failed = true;
+                Some((Err(e), state))
+            }
+        }
+    })
+}
+
+impl WatchState {
+    async fn next_change(&mut self) -> Result<Change, MetadataError> {
+        if self.prefix.is_none() {
+            let store = store::get_metadata_store(&self.pool_root).await?;
+            let components = naming::split_path(&self.pool_root, store.as_ref(), &self.rfs_path).await?;
+            let current = current_seq(&self.pool_root).await?;
+            if self.last_seq > current {
+                return Err(MetadataError::InvalidOperation(format!(
+                    "cannot watch from change {}, the latest is {}",
+                    self.last_seq, current
+                )));
+            }
+            self.prefix = Some(format!("/{}", components.join("/")));
+        }
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Ok(change);
+            }
+            // Listen before reading, so an append in between is not missed.
+            let notify = notifier(&self.pool_root);
+            let mut notified = pin!(notify.notified());
+            notified.as_mut().enable();
+            self.read_new().await?;
+            if self.pending.is_empty() {
+                let _ = timeout(POLL_INTERVAL, notified).await;
+            }
+        }
+    }
+
+    async fn read_new(&mut self) -> Result<(), MetadataError> {
+        let path = log_path(&self.pool_root);
+        let mut read = read_from(&path, self.offset).await?;
+        // Records follow each other without gaps, unless changes were lost or
+        // the log was trimmed since the last read and has to be scanned again.
+        let moved = match read.changes.first() {
+            Some(first) => first.seq != self.last_seq + 1,
+            None => read.shrunk,
+        };
+        if self.offset > 0 && moved {
+            self.offset = 0;
+            read = read_from(&path, 0).await?;
+        }
+
+        let prefix = self.prefix.as_deref().unwrap_or("/");
+        for (i, change) in read.changes.into_iter().enumerate() {
+            if change.seq <= self.last_seq {
+                continue;
+            }
+            // Changes missing at the head of the log were trimmed, those
+            // missing further on were never recorded. The changes before the
+            // gap are passed on first; the next read finds it again.
+            if change.seq > self.last_seq + 1 {
+                if !self.pending.is_empty() {
+                    return Ok(());
+                }
+                return Err(match self.offset == 0 && i == 0 {
+                    true => MetadataError::ChangesTrimmed(self.last_seq),
+                    false => MetadataError::ChangesLost(self.last_seq),
+                });
+            }
+            self.last_seq = change.seq;
+            if change.is_below(prefix) {
+                self.pending.push_back(change);
+            }
+        }
+        self.offset = read.end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn quota_changes_are_logged_and_watchers_cannot_start_ahead() {
+        let pool = TestPool::new("journal-quota", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d", "f", test_util::file_metadata("f", 1)).await.unwrap();
+        let since = current_seq(root).await.unwrap();
+
+        manager::set_dir_quota(root, "/d", Some(10), None).await.unwrap();
+        manager::set_dir_owner(root, "/d", Some("u")).await.unwrap();
+        let mut changes = pin!(watch(root, "/d", since));
+        for _ in 0..2 {
+            let change = changes.next().await.unwrap().unwrap();
+            assert_eq!((change.kind, change.path.as_str()), (ChangeKind::Modify, "/d"));
+        }
+
+        let ahead = current_seq(root).await.unwrap() + 1;
+        let mut changes = pin!(watch(root, "/", ahead));
+        assert!(matches!(changes.next().await, Some(Err(MetadataError::InvalidOperation(_)))));
+    }
+
+    #[tokio::test]
+    async fn watchers_crossing_changes_that_were_not_recorded_are_told() {
+        let pool = TestPool::new("journal-lost", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "a", test_util::file_metadata("a", 1)).await.unwrap();
+
+        // A directory in the log's place makes appending to it fail.
+        let path = log_path(root);
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        manager::create_file(root, "/", "b", test_util::file_metadata("b", 1)).await.unwrap();
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::write(&path, content).unwrap();
+        manager::create_file(root, "/", "c", test_util::file_metadata("c", 1)).await.unwrap();
+        assert_eq!(current_seq(root).await.unwrap(), 3);
+
+        let mut changes = pin!(watch(root, "/", 0));
+        assert_eq!(changes.next().await.unwrap().unwrap().path, "/a");
+        assert!(matches!(changes.next().await, Some(Err(MetadataError::ChangesLost(1)))));
+        // Watching from after the gap misses nothing.
+        let mut changes = pin!(watch(root, "/", 2));
+        assert_eq!(changes.next().await.unwrap().unwrap().path, "/c");
+    }
+}
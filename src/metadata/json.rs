@@ -0,0 +1,472 @@
+// src/metadata/json.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::lock::FileLock;
+use crate::metadata::model::{DirectoryListing, Entry, FileMetadata};
+use crate::metadata::path_utils;
+use crate::metadata::store::{self, DirKey, DirLock, ListingStamp, MetadataStore};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use xxhash_rust::xxh3::xxh3_64;
+
+pub const METADATA_DIR: &str = "metadata";
+pub const LISTING_FILE: &str = "metadata.json";
+const SHARD_HEADER_FILE: &str = "shards.json";
+const SHARD_DIR: &str = "shards";
+
+// A directory whose listing grows past this many entries is split into buckets.
+pub(crate) const SHARD_THRESHOLD: usize = 2048;
+// Number of buckets a directory is split into.
+const SHARD_BUCKETS: u32 = 512;
+
+// Marks a sharded directory. Entries live in `shards/{bucket:04x}.json`, and
+// the bucket of an entry is the xxh3 of its case-folded name modulo
+// `buckets`, so that names differing only in case share a bucket. Pools whose
+// buckets were not folded yet are resharded by the format 4 upgrade, and those
+// bucketed by lowercased name by format 6. The header is rewritten after every
+// bucket write, so its stamp tracks the whole listing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct ShardHeader {
+    buckets: u32,
+    // A full rewrite of the listing fills a new set of buckets, kept in
+    // `shards.{generation}/`, and replacing the header switches over to it.
+    // Generation 0 is `shards/`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    generation: u64,
+    // Counts the writes to the listing, for its stamp.
+    #[serde(default)]
+    writes: u64,
+}
+
+impl ShardHeader {
+    fn shard_dir(&self, dir_path: &Path) -> PathBuf {
+        match self.generation {
+            0 => dir_path.join(SHARD_DIR),
+            generation => dir_path.join(format!("{}.{}", SHARD_DIR, generation)),
+        }
+    }
+
+    fn bucket_path(&self, dir_path: &Path, name: &str) -> PathBuf {
+        let key = path_utils::fold_case(name);
+        bucket_file(&self.shard_dir(dir_path), (xxh3_64(key.as_bytes()) % self.buckets as u64) as u32)
+    }
+}
+
+fn is_zero(generation: &u64) -> bool {
+    *generation == 0
+}
+
+// Counts the writes to an unsharded listing in its metadata.json, under a key
+// no entry name can take. It is serialized first, so that a stamp reads it
+// from the head of the file.
+const WRITES_HEAD: &str = "{\n  \"/writes\": ";
+
+#[derive(Deserialize, Default)]
+struct ListingFile {
+    #[serde(rename = "/writes", default)]
+    writes: u64,
+    #[serde(flatten)]
+    entries: DirectoryListing,
+}
+
+#[derive(Serialize)]
+struct ListingFileRef<'a> {
+    #[serde(rename = "/writes", skip_serializing_if = "is_zero")]
+    writes: u64,
+    #[serde(flatten)]
+    entries: &'a DirectoryListing,
+}
+
+// Stores metadata as a tree of JSON files under `{pool}/metadata/`.
+// Each directory is a physical directory named after its CID, holding a
+// `metadata.json` listing and one `{cid}.json` block map per file.
+//
+// Small directories keep their whole listing in `metadata.json`. Once a
+// directory grows past `SHARD_THRESHOLD` entries it is converted to the
+// sharded format, where adding, updating or removing an entry only rewrites
+// the bucket that holds it. Existing directories are converted on their next
+// write, so no separate upgrade step is needed.
+pub struct JsonStore {
+    metadata_root: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new(pool_root: &str) -> Self {
+        JsonStore { metadata_root: Path::new(pool_root).join(METADATA_DIR) }
+    }
+
+    // Resolves a directory key to its physical metadata directory.
+    fn dir_path(&self, dir: &DirKey) -> PathBuf {
+        let mut path = self.metadata_root.clone();
+        path.extend(dir.cids());
+        path
+    }
+
+    // Returns the shard header of a directory, or None if it is not sharded.
+    async fn shard_header(&self, dir_path: &Path) -> Result<Option<ShardHeader>, MetadataError> {
+        match fs::read(dir_path.join(SHARD_HEADER_FILE)).await {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Replaces the listing of a directory with a sharded one. The new buckets
+    // only become visible with the header, so a crash part way through leaves
+    // the previous listing in place.
+    async fn write_sharded(
+        &self,
+        dir_path: &Path,
+        previous: Option<ShardHeader>,
+        listing: &DirectoryListing,
+        writes: u64,
+    ) -> Result<(), MetadataError> {
+        let generation = previous.map_or(0, |header| header.generation + 1);
+        let header = ShardHeader { buckets: SHARD_BUCKETS, generation, writes };
+        let mut buckets: HashMap<PathBuf, DirectoryListing> = HashMap::new();
+        for (name, entry) in listing {
+            buckets
+                .entry(header.bucket_path(dir_path, name))
+                .or_default()
+                .insert(name.clone(), entry.clone());
+        }
+
+        // Buckets left behind by an interrupted rewrite were never switched to.
+        let shard_dir = header.shard_dir(dir_path);
+        remove_dir_if_exists(&shard_dir).await?;
+        fs::create_dir_all(&shard_dir).await?;
+        for (path, bucket_listing) in &buckets {
+            write_map(path, 0, bucket_listing).await?;
+        }
+        write_header(dir_path, &header).await?;
+        if let Some(previous) = previous {
+            remove_dir_if_exists(&previous.shard_dir(dir_path)).await?;
+        }
+        remove_if_exists(&dir_path.join(LISTING_FILE)).await
+    }
+}
+
+impl MetadataStore for JsonStore {
+    fn lock_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirLock, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            fs::create_dir_all(&dir_path).await?;
+            let lock = FileLock::acquire(&dir_path.join(LISTING_FILE)).await?;
+            Ok(Box::new(lock) as DirLock)
+        })
+    }
+
+    // Reads and parses a metadata.json file.
+    fn read_listing<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirectoryListing, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let Some(header) = self.shard_header(&dir_path).await? else {
+                return Ok(read_map(&dir_path.join(LISTING_FILE)).await?.entries);
+            };
+
+            let mut listing = DirectoryListing::new();
+            let mut buckets = fs::read_dir(header.shard_dir(&dir_path)).await?;
+            while let Some(bucket) = buckets.next_entry().await? {
+                if bucket.path().extension().is_some_and(|ext| ext == "json") {
+                    listing.extend(read_map(&bucket.path()).await?.entries);
+                }
+            }
+            Ok(listing)
+        })
+    }
+
+    // Writes a DirectoryListing to a metadata.json file.
+    fn write_listing<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        listing: &'a DirectoryListing,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let header = self.shard_header(&dir_path).await?;
+            let writes = match &header {
+                Some(header) => header.writes,
+                None => read_writes(&dir_path.join(LISTING_FILE)).await?,
+            } + 1;
+            if listing.len() > SHARD_THRESHOLD {
+                return self.write_sharded(&dir_path, header, listing, writes).await;
+            }
+
+            // Reads keep going to the buckets until the header is removed, by
+            // which time metadata.json holds the whole listing.
+            write_map(&dir_path.join(LISTING_FILE), writes, listing).await?;
+            if let Some(header) = header {
+                remove_if_exists(&dir_path.join(SHARD_HEADER_FILE)).await?;
+                remove_dir_if_exists(&header.shard_dir(&dir_path)).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let path = match self.shard_header(&dir_path).await? {
+                Some(header) => header.bucket_path(&dir_path, name),
+                None => dir_path.join(LISTING_FILE),
+            };
+            Ok(read_map(&path).await?.entries.remove(name))
+        })
+    }
+
+    // Only the bucket holding the entry is rewritten. An unsharded directory
+    // is converted once it outgrows `SHARD_THRESHOLD`.
+    fn put_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+        entry: &'a Entry,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let Some(header) = self.shard_header(&dir_path).await? else {
+                let mut listing = self.read_listing(dir).await?;
+                listing.insert(name.to_string(), entry.clone());
+                return self.write_listing(dir, &listing).await;
+            };
+
+            let path = header.bucket_path(&dir_path, name);
+            let mut bucket = read_map(&path).await?.entries;
+            bucket.insert(name.to_string(), entry.clone());
+            write_map(&path, 0, &bucket).await?;
+            write_header(&dir_path, &ShardHeader { writes: header.writes + 1, ..header }).await
+        })
+    }
+
+    // Sharded directories stay sharded when they shrink; they are only
+    // converted back by a full `write_listing`.
+    fn remove_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let header = self.shard_header(&dir_path).await?;
+            let path = match &header {
+                Some(header) => header.bucket_path(&dir_path, name),
+                None => dir_path.join(LISTING_FILE),
+            };
+            let mut map = read_map(&path).await?;
+            let removed = map.entries.remove(name);
+            if removed.is_some() {
+                let writes = if header.is_some() { 0 } else { map.writes + 1 };
+                write_map(&path, writes, &map.entries).await?;
+                if let Some(header) = header {
+                    write_header(&dir_path, &ShardHeader { writes: header.writes + 1, ..header }).await?;
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    // Only the bucket the folded name falls into is read.
+    fn find_folded<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        folded: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, Entry)>, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let listing = match self.shard_header(&dir_path).await? {
+                Some(header) => read_map(&header.bucket_path(&dir_path, folded)).await?.entries,
+                None => self.read_listing(dir).await?,
+            };
+            Ok(store::find_folded_in(&listing, folded))
+        })
+    }
+
+    // Reads the detailed FileMetadata (block map) from its {cid}.json file.
+    fn read_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<FileMetadata, MetadataError>> {
+        Box::pin(async move {
+            let content = fs::read(self.dir_path(dir).join(format!("{}.json", cid))).await?;
+            Ok(serde_json::from_slice(&content)?)
+        })
+    }
+
+    // Writes the detailed FileMetadata (block map) to its {cid}.json file.
+    fn write_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+        metadata: &'a FileMetadata,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            let content = serde_json::to_vec_pretty(metadata)?;
+            write_replacing(&self.dir_path(dir).join(format!("{}.json", cid)), &content).await
+        })
+    }
+
+    fn remove_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move { remove_if_exists(&self.dir_path(dir).join(format!("{}.json", cid))).await })
+    }
+
+    fn create_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            fs::create_dir_all(self.dir_path(dir)).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move { remove_dir_if_exists(&self.dir_path(dir)).await })
+    }
+
+    // Both files are stamped: a directory being converted between formats
+    // briefly has both, and either may be the one read. The write count is
+    // the header's while there is one.
+    fn listing_stamp<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<Option<ListingStamp>, MetadataError>> {
+        Box::pin(async move {
+            let dir_path = self.dir_path(dir);
+            let listing_path = dir_path.join(LISTING_FILE);
+            let listing = stat_if_exists(&listing_path).await?;
+            let header = stat_if_exists(&dir_path.join(SHARD_HEADER_FILE)).await?;
+            let writes = match self.shard_header(&dir_path).await? {
+                Some(header) => header.writes,
+                None => read_writes(&listing_path).await?,
+            };
+            Ok(Some(ListingStamp::of_files(writes, &[listing, header])))
+        })
+    }
+}
+
+fn bucket_file(shard_dir: &Path, bucket: u32) -> PathBuf {
+    shard_dir.join(format!("{:04x}.json", bucket))
+}
+
+// Reads one listing file. A missing file reads as empty.
+async fn read_map(path: &Path) -> Result<ListingFile, MetadataError> {
+    match fs::read(path).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ListingFile::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Bucket files are written with a write count of 0, which is left out.
+async fn write_map(path: &Path, writes: u64, listing: &DirectoryListing) -> Result<(), MetadataError> {
+    write_replacing(path, &serde_json::to_vec_pretty(&ListingFileRef { writes, entries: listing })?).await
+}
+
+// Reads the write count from the head of a metadata.json without parsing the
+// listing. A missing file, or one written before writes were counted, reads as 0.
+async fn read_writes(path: &Path) -> Result<u64, MetadataError> {
+    let file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut head = Vec::with_capacity(WRITES_HEAD.len() + 20);
+    file.take((WRITES_HEAD.len() + 20) as u64).read_to_end(&mut head).await?;
+    let Some(rest) = head.strip_prefix(WRITES_HEAD.as_bytes()) else {
+        return Ok(0);
+    };
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    Ok(std::str::from_utf8(&rest[..digits]).ok().and_then(|n| n.parse().ok()).unwrap_or(0))
+}
+
+async fn write_header(dir_path: &Path, header: &ShardHeader) -> Result<(), MetadataError> {
+    write_replacing(&dir_path.join(SHARD_HEADER_FILE), &serde_json::to_vec_pretty(header)?).await
+}
+
+// Listing files are replaced rather than rewritten in place, so that every
+// write gives them a new inode and changes their stamp, even within the
+// resolution of their modification time. Block maps are replaced so that a
+// crash part way through a write leaves the previous one.
+async fn write_replacing(path: &Path, content: &[u8]) -> Result<(), MetadataError> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, content).await?;
+    fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+async fn stat_if_exists(path: &Path) -> Result<Option<std::fs::Metadata>, MetadataError> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn remove_dir_if_exists(path: &Path) -> Result<(), MetadataError> {
+    match fs::remove_dir_all(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), MetadataError> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::model::SymlinkInfo;
+    use crate::metadata::test_util::TestPool;
+    use chrono::Utc;
+
+    fn listing(prefix: &str, len: usize) -> DirectoryListing {
+        let entry = Entry::Symlink(SymlinkInfo { target: "t".into(), created_at: Utc::now(), modified_at: Utc::now() });
+        (0..len).map(|i| (format!("{}{}", prefix, i), entry.clone())).collect()
+    }
+
+    fn names(listing: &DirectoryListing) -> Vec<String> {
+        let mut names: Vec<String> = listing.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn full_rewrites_switch_bucket_sets_at_once() {
+        let pool = TestPool::new("json-rewrite", "{}");
+        let store = JsonStore::new(&pool.root);
+        let dir = DirKey::root().child("abcde");
+        let dir_path = store.dir_path(&dir);
+        store.create_dir(&dir).await.unwrap();
+
+        let first = listing("a", SHARD_THRESHOLD + 1);
+        store.write_listing(&dir, &first).await.unwrap();
+        assert!(store.shard_header(&dir_path).await.unwrap().is_some());
+        assert!(!dir_path.join(LISTING_FILE).exists());
+
+        // An interrupted rewrite leaves buckets the header never switched to.
+        let stray = ShardHeader { buckets: SHARD_BUCKETS, generation: 1, writes: 0 };
+        fs::create_dir_all(stray.shard_dir(&dir_path)).await.unwrap();
+        write_map(&stray.bucket_path(&dir_path, "stray"), 0, &listing("stray", 1)).await.unwrap();
+        assert_eq!(names(&store.read_listing(&dir).await.unwrap()), names(&first));
+
+        let second = listing("b", SHARD_THRESHOLD + 5);
+        store.write_listing(&dir, &second).await.unwrap();
+        assert_eq!(names(&store.read_listing(&dir).await.unwrap()), names(&second));
+        assert!(store.get_entry(&dir, "b3").await.unwrap().is_some());
+        assert!(!dir_path.join(SHARD_DIR).exists());
+
+        store.put_entry(&dir, "c", &second["b1"]).await.unwrap();
+        assert!(store.remove_entry(&dir, "b1").await.unwrap().is_some());
+        assert_eq!(store.read_listing(&dir).await.unwrap().len(), SHARD_THRESHOLD + 5);
+
+        let small = listing("s", 3);
+        store.write_listing(&dir, &small).await.unwrap();
+        assert_eq!(names(&store.read_listing(&dir).await.unwrap()), names(&small));
+        assert!(store.shard_header(&dir_path).await.unwrap().is_none());
+        let leftovers: Vec<_> = std::fs::read_dir(&dir_path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(SHARD_DIR))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+}
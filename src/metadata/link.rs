@@ -0,0 +1,163 @@
+// src/metadata/link.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::FileEntry;
+use crate::metadata::path_utils;
+use crate::metadata::store::{DirKey, DirLock, MetadataStore};
+use crate::metadata::version;
+
+// Block maps shared by hard links live under this pseudo-directory of the metadata store.
+const LINKS_DIR: &str = path_utils::reserved_dir("hardlinks");
+
+pub fn links_root() -> DirKey {
+    DirKey::root().child(LINKS_DIR)
+}
+
+// The directory holding the block map (and versions) of `entry`, found in `dir`.
+pub fn home(dir: &DirKey, entry: &FileEntry) -> DirKey {
+    if entry.linked { links_root() } else { dir.clone() }
+}
+
+// Locks the shared block map of a linked entry. Callers take it after the
+// lock on the entry's own directory, so the order is always the same.
+pub(crate) async fn lock_home(store: &dyn MetadataStore, entry: &FileEntry) -> Result<Option<DirLock>, MetadataError> {
+    if !entry.linked {
+        return Ok(None);
+    }
+    Ok(Some(store.lock_dir(&links_root()).await?))
+}
+
+// Adds a hard link in `link_dir` to the file of `entry` (found in `dir`,
+// which the caller has locked). The first link moves the block map into the
+// link directory under a new CID, as CIDs are only unique within one
+// directory; the caller must then store the updated entry.
+pub(crate) async fn add_link(
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    entry: &mut FileEntry,
+    link_dir: &DirKey,
+) -> Result<(), MetadataError> {
+    let root = links_root();
+    store.create_dir(&root).await?;
+    let _lock = store.lock_dir(&root).await?;
+
+    let mut metadata = store.read_file_metadata(&home(dir, entry), &entry.cid).await?;
+    metadata.links += 1;
+    if entry.linked {
+        metadata.link_dirs.push(link_dir.as_string());
+        return store.write_file_metadata(&root, &entry.cid, &metadata).await;
+    }
+    metadata.link_dirs = vec![dir.as_string(), link_dir.as_string()];
+
+    // Versions are keyed by the file's CID and move along with it.
+    let mut cid = path_utils::generate_cid();
+    while is_taken(store, &root, &cid).await? {
+        cid = path_utils::generate_cid();
+    }
+    for v in &metadata.versions {
+        let old_id = version::version_id(&entry.cid, v.version);
+        let version_metadata = store.read_file_metadata(dir, &old_id).await?;
+        store.write_file_metadata(&root, &version::version_id(&cid, v.version), &version_metadata).await?;
+        store.remove_file_metadata(dir, &old_id).await?;
+    }
+    store.write_file_metadata(&root, &cid, &metadata).await?;
+    store.remove_file_metadata(dir, &entry.cid).await?;
+    entry.cid = cid;
+    entry.linked = true;
+    Ok(())
+}
+
+async fn is_taken(store: &dyn MetadataStore, root: &DirKey, cid: &str) -> Result<bool, MetadataError> {
+    match store.read_file_metadata(root, cid).await {
+        Ok(_) => Ok(true),
+        Err(MetadataError::NotFound(_)) => Ok(false),
+        Err(MetadataError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Drops the hard link to a linked file found in `dir`. Returns true if it
+// was the last one, in which case the caller frees the block map.
+pub(crate) async fn remove_link(store: &dyn MetadataStore, dir: &DirKey, entry: &FileEntry) -> Result<bool, MetadataError> {
+    let root = links_root();
+    let _lock = store.lock_dir(&root).await?;
+    let mut metadata = store.read_file_metadata(&root, &entry.cid).await?;
+    metadata.links = metadata.links.saturating_sub(1);
+    if metadata.links == 0 {
+        return Ok(true);
+    }
+    let dir = dir.as_string();
+    if let Some(position) = metadata.link_dirs.iter().position(|d| *d == dir) {
+        metadata.link_dirs.remove(position);
+    }
+    store.write_file_metadata(&root, &entry.cid, &metadata).await?;
+    Ok(false)
+}
+
+// Records that the hard link to a linked file has moved from directory
+// `from` to `to`, e.g. because a directory above it was renamed.
+pub(crate) async fn move_link(
+    store: &dyn MetadataStore,
+    entry: &FileEntry,
+    from: &DirKey,
+    to: &DirKey,
+) -> Result<(), MetadataError> {
+    let root = links_root();
+    let _lock = store.lock_dir(&root).await?;
+    let mut metadata = store.read_file_metadata(&root, &entry.cid).await?;
+    let from = from.as_string();
+    match metadata.link_dirs.iter_mut().find(|d| **d == from) {
+        Some(dir) => *dir = to.as_string(),
+        None => return Ok(()),
+    }
+    store.write_file_metadata(&root, &entry.cid, &metadata).await
+}
+
+// The directories holding links to the linked file `cid`, each once.
+pub(crate) async fn link_dirs(store: &dyn MetadataStore, cid: &str) -> Result<Vec<DirKey>, MetadataError> {
+    let root = links_root();
+    let _lock = store.lock_dir(&root).await?;
+    let mut dirs = store.read_file_metadata(&root, cid).await?.link_dirs;
+    dirs.sort();
+    dirs.dedup();
+    Ok(dirs.iter().map(|d| DirKey::from_string(d)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::manager;
+    use crate::metadata::model::Entry;
+    use crate::metadata::test_util::{self, TestPool};
+
+    async fn size(pool_root: &str, path: &str) -> u64 {
+        match manager::get_entry(pool_root, path).await.unwrap() {
+            Entry::File(file_entry) => file_entry.size,
+            Entry::Directory(info) => info.size,
+            Entry::Symlink(_) => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn every_link_follows_a_change_in_size() {
+        let pool = TestPool::new("link-size", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/a", "f", test_util::file_metadata("f", 10)).await.unwrap();
+        manager::create_hard_link(root, "/a/f", "/b/x/g").await.unwrap();
+        manager::create_hard_link(root, "/a/f", "/b/x/h").await.unwrap();
+
+        manager::replace_file(root, "/a", "f", test_util::file_metadata("f", 30)).await.unwrap();
+        for (path, expected) in [("/a/f", 30), ("/b/x/g", 30), ("/b/x/h", 30), ("/a", 30), ("/b/x", 60), ("/b", 60)] {
+            assert_eq!(size(root, path).await, expected, "size of {}", path);
+        }
+
+        // Links moved along with a directory are still found.
+        manager::rename_entry(root, "/b", "/c").await.unwrap();
+        manager::delete_entry(root, "/a/f").await.unwrap();
+        manager::replace_file(root, "/c/x", "g", test_util::file_metadata("g", 5)).await.unwrap();
+        for (path, expected) in [("/c/x/g", 5), ("/c/x/h", 5), ("/c", 10), ("/a", 0)] {
+            assert_eq!(size(root, path).await, expected, "size of {}", path);
+        }
+    }
+}
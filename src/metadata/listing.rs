@@ -0,0 +1,367 @@
+// src/metadata/listing.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{DirectoryListing, Entry};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    // Directories before files.
+    Type,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+// How to order, filter and page a directory listing. Entries that tie on the
+// sort key are ordered by name, so the order is total and stable across calls.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ListOptions {
+    pub sort: SortKey,
+    pub order: SortOrder,
+    // `next_cursor` of the previous page; the listing continues after it.
+    pub cursor: Option<String>,
+    // Maximum number of entries per page, at least 1. All remaining entries if unset.
+    pub limit: Option<usize>,
+    // Only return entries of this kind.
+    pub kind: Option<EntryKind>,
+    // Only return entries whose name matches this glob (`*`, `?`, `[...]`).
+    pub pattern: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedEntry {
+    pub name: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPage {
+    pub entries: Vec<ListedEntry>,
+    // Set when more entries follow; pass it back as `ListOptions::cursor`.
+    pub next_cursor: Option<String>,
+}
+
+// The position of an entry in a sorted listing. Cursors are this, as hex-encoded JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Position {
+    sort: SortKey,
+    order: SortOrder,
+    key: u64,
+    name: String,
+}
+
+// An entry ranked by its position, for picking a page out of a heap.
+struct Ranked(Position, Entry);
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+// Filters, sorts and pages the entries of a directory listing. Only the page
+// is sorted: the entries after the cursor pass through a heap that keeps the
+// first `limit + 1` of them, the last of which only tells that more follow.
+pub fn list_page(listing: DirectoryListing, options: &ListOptions) -> Result<ListPage, MetadataError> {
+    if options.limit == Some(0) {
+        return Err(MetadataError::InvalidLimit(0));
+    }
+    let pattern = options.pattern.as_deref().map(glob_to_regex).transpose()?;
+    let after = options.cursor.as_deref().map(decode_cursor).transpose()?;
+    if let Some(after) = &after
+        && (after.sort != options.sort || after.order != options.order)
+    {
+        return Err(MetadataError::InvalidCursor(
+            "cursor was issued for a different sort order".to_string(),
+        ));
+    }
+
+    let keep = options.limit.map_or(usize::MAX, |limit| limit.saturating_add(1));
+    let mut heap = BinaryHeap::new();
+    for (name, entry) in listing {
+        if options.kind.is_some_and(|kind| kind != kind_of(&entry))
+            || pattern.as_ref().is_some_and(|p| !p.is_match(&name))
+        {
+            continue;
+        }
+        let position = position_of(options, name, &entry);
+        if after.as_ref().is_some_and(|after| compare(&position, after) != Ordering::Greater) {
+            continue;
+        }
+        heap.push(Ranked(position, entry));
+        if heap.len() > keep {
+            heap.pop();
+        }
+    }
+
+    let mut entries = heap.into_sorted_vec();
+    let next_cursor = options.limit.filter(|&limit| entries.len() > limit).map(|limit| {
+        entries.truncate(limit);
+        encode_cursor(&entries[limit - 1].0)
+    });
+    let entries =
+        entries.into_iter().map(|Ranked(position, entry)| ListedEntry { name: position.name, entry }).collect();
+    Ok(ListPage { entries, next_cursor })
+}
+
+pub(crate) fn kind_of(entry: &Entry) -> EntryKind {
+    match entry {
+        Entry::File(_) => EntryKind::File,
+        Entry::Directory(_) => EntryKind::Directory,
+        Entry::Symlink(_) => EntryKind::Symlink,
+    }
+}
+
+fn position_of(options: &ListOptions, name: String, entry: &Entry) -> Position {
+    let (size, modified_at) = match entry {
+        Entry::File(file) => (file.size, file.modified_at),
+        Entry::Directory(dir) => (dir.size, dir.modified_at),
+        Entry::Symlink(link) => (0, link.modified_at),
+    };
+    let key = match options.sort {
+        SortKey::Name => 0,
+        SortKey::Size => size,
+        SortKey::Mtime => modified_at.timestamp_nanos_opt().unwrap_or(0).max(0) as u64,
+        SortKey::Type => (kind_of(entry) != EntryKind::Directory) as u64,
+    };
+    Position { sort: options.sort, order: options.order, key, name }
+}
+
+// Orders by the sort key, then by name, in the requested direction.
+fn compare(a: &Position, b: &Position) -> Ordering {
+    let ordering = a.key.cmp(&b.key).then_with(|| a.name.cmp(&b.name));
+    match a.order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+fn encode_cursor(position: &Position) -> String {
+    hex::encode(serde_json::to_vec(position).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Result<Position, MetadataError> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| MetadataError::InvalidCursor(cursor.to_string()))
+}
+
+// Translates a glob into an anchored regex. `*` matches any run of
+// characters, `?` any single character, and `[...]` / `[!...]` a set. As in
+// POSIX globs, a `]` right after the opening bracket is part of the set.
+pub(crate) fn glob_to_regex(glob: &str) -> Result<Regex, MetadataError> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+                let mut closed = false;
+                let mut first = true;
+                for c in chars.by_ref() {
+                    if c == ']' && !first {
+                        closed = true;
+                        break;
+                    }
+                    first = false;
+                    // `&&` and `~~` are set operations in a regex class.
+                    if matches!(c, '\\' | '[' | ']' | '&' | '~') {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                if !closed {
+                    return Err(MetadataError::InvalidPattern(glob.to_string()));
+                }
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|_| MetadataError::InvalidPattern(glob.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::model::{DirectoryInfo, FileEntry, SymlinkInfo};
+    use chrono::{DateTime, Utc};
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn file(size: u64, mtime: i64) -> Entry {
+        let (created_at, modified_at) = (at(mtime), at(mtime));
+        Entry::File(FileEntry { cid: String::new(), size, created_at, modified_at, linked: false, revision: 0 })
+    }
+
+    fn sample() -> DirectoryListing {
+        let dir = Entry::Directory(DirectoryInfo {
+            cid: String::new(),
+            size: 20,
+            created_at: at(5),
+            modified_at: at(5),
+            xattrs: Default::default(),
+            quota: None,
+            revision: 0,
+        });
+        let symlink = Entry::Symlink(SymlinkInfo { target: "t".into(), created_at: at(4), modified_at: at(4) });
+        [("b", file(30, 3)), ("a", file(10, 2)), ("c", file(10, 1)), ("d", dir), ("e", symlink)]
+            .into_iter()
+            .map(|(name, entry)| (name.to_string(), entry))
+            .collect()
+    }
+
+    fn names(page: &ListPage) -> Vec<&str> {
+        page.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    fn sorted(sort: SortKey, order: SortOrder) -> ListOptions {
+        ListOptions { sort, order, ..ListOptions::default() }
+    }
+
+    #[test]
+    fn entries_sort_by_key_then_name() {
+        for (sort, asc) in [
+            (SortKey::Name, ["a", "b", "c", "d", "e"]),
+            (SortKey::Size, ["e", "a", "c", "d", "b"]),
+            (SortKey::Mtime, ["c", "a", "b", "e", "d"]),
+            (SortKey::Type, ["d", "a", "b", "c", "e"]),
+        ] {
+            let page = list_page(sample(), &sorted(sort, SortOrder::Asc)).unwrap();
+            assert_eq!(names(&page), asc, "{:?}", sort);
+            assert!(page.next_cursor.is_none());
+            let page = list_page(sample(), &sorted(sort, SortOrder::Desc)).unwrap();
+            assert_eq!(names(&page), asc.iter().rev().copied().collect::<Vec<_>>(), "{:?}", sort);
+        }
+    }
+
+    #[test]
+    fn pages_continue_after_their_cursor() {
+        let mut options = ListOptions { limit: Some(2), ..sorted(SortKey::Size, SortOrder::Desc) };
+        let mut pages = Vec::new();
+        loop {
+            let page = list_page(sample(), &options).unwrap();
+            pages.push(names(&page).join(""));
+            match page.next_cursor {
+                Some(cursor) => options.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(pages, ["bd", "ca", "e"]);
+
+        // A page that ends with the last entry has no cursor.
+        let options = ListOptions { limit: Some(5), ..ListOptions::default() };
+        assert!(list_page(sample(), &options).unwrap().next_cursor.is_none());
+    }
+
+    #[test]
+    fn cursors_survive_changes_to_the_listing() {
+        let mut options = ListOptions { limit: Some(2), ..ListOptions::default() };
+        let page = list_page(sample(), &options).unwrap();
+        assert_eq!(names(&page), ["a", "b"]);
+
+        // The entry the cursor points at and the one after it are gone; a new one sorts in between.
+        let mut listing = sample();
+        listing.remove("b");
+        listing.remove("c");
+        listing.insert("bb".to_string(), file(1, 1));
+        options.cursor = page.next_cursor;
+        let page = list_page(listing.clone(), &options).unwrap();
+        assert_eq!(names(&page), ["bb", "d"]);
+        options.cursor = page.next_cursor;
+        let page = list_page(listing, &options).unwrap();
+        assert_eq!(names(&page), ["e"]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn bad_limits_and_cursors_are_rejected() {
+        let options = ListOptions { limit: Some(0), ..ListOptions::default() };
+        assert!(matches!(list_page(sample(), &options), Err(MetadataError::InvalidLimit(0))));
+
+        let options = ListOptions { limit: Some(1), ..ListOptions::default() };
+        let cursor = list_page(sample(), &options).unwrap().next_cursor;
+        let options = ListOptions { cursor, ..sorted(SortKey::Name, SortOrder::Desc) };
+        assert!(matches!(list_page(sample(), &options), Err(MetadataError::InvalidCursor(_))));
+        let options = ListOptions { cursor: Some("zz".into()), ..ListOptions::default() };
+        assert!(matches!(list_page(sample(), &options), Err(MetadataError::InvalidCursor(_))));
+    }
+
+    #[test]
+    fn entries_filter_by_kind_and_pattern() {
+        for (kind, expected) in
+            [(EntryKind::File, vec!["a", "b", "c"]), (EntryKind::Directory, vec!["d"]), (EntryKind::Symlink, vec!["e"])]
+        {
+            let options = ListOptions { kind: Some(kind), ..ListOptions::default() };
+            assert_eq!(names(&list_page(sample(), &options).unwrap()), expected);
+        }
+        let options =
+            ListOptions { kind: Some(EntryKind::File), pattern: Some("[!a]".into()), ..ListOptions::default() };
+        assert_eq!(names(&list_page(sample(), &options).unwrap()), ["b", "c"]);
+    }
+
+    #[test]
+    fn globs_translate_like_posix_ones() {
+        let matches = |glob: &str, name: &str| glob_to_regex(glob).unwrap().is_match(name);
+        for glob in ["[]", "[!]", "[a", "[]a"] {
+            assert!(matches!(glob_to_regex(glob), Err(MetadataError::InvalidPattern(_))), "{}", glob);
+        }
+        assert!(matches("[]a]", "]") && matches("[]a]", "a") && !matches("[]a]", "b"));
+        assert!(matches("[!x]", "y") && !matches("[!x]", "x") && !matches("[!x]", "xy") && !matches("[!x]", ""));
+        assert!(matches("[!]x]", "y") && !matches("[!]x]", "]") && !matches("[!]x]", "x"));
+        assert!(matches("[a&&b]", "&") && matches("[a&&b]", "b") && matches("[~~]", "~"));
+        assert!(matches("*.[ch]", "x.c") && !matches("*.[ch]", "x.c.o") && matches("a?c", "a.c"));
+        assert!(matches("a.c", "a.c") && !matches("a.c", "abc") && matches("[a-c]", "b") && !matches("[a-c]", "-"));
+    }
+}
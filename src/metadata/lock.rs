@@ -3,17 +3,22 @@
 // Copyright (c) 2025 Canmi
 
 use rfs_utils::{log, LogLevel};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tokio::time::{sleep, Duration};
 
 // A file-based lock guard. The lock is released when this struct is dropped.
+// The lock file holds the ID of the process that took it, so that a lock left
+// behind by a process that died can be broken.
 pub struct FileLock {
     lock_path: PathBuf,
 }
 
 impl FileLock {
     // Acquires a lock on a target path by creating a `.lock` file.
-    // It will wait indefinitely if the lock is already held.
+    // It will wait indefinitely if the lock is already held by a live process.
     pub async fn acquire(target_path: &Path) -> std::io::Result<Self> {
         let lock_path = target_path.with_extension(
             target_path
@@ -24,8 +29,28 @@ impl FileLock {
                 + ".lock",
         );
 
+        // Creating the lock file only if it does not exist claims the lock
+        // atomically, even between processes.
         let mut attempts = 0;
-        while lock_path.exists() {
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lock_path)
+                .await
+            {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())
+                        .await?;
+                    file.flush().await?;
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            if break_if_stale(&lock_path).await? {
+                continue;
+            }
             if attempts == 0 {
                 log(
                     LogLevel::Debug,
@@ -35,9 +60,6 @@ impl FileLock {
             attempts += 1;
             sleep(Duration::from_millis(100)).await;
         }
-
-        // Create the lock file to claim the lock.
-        tokio::fs::File::create(&lock_path).await?;
         log(
             LogLevel::Debug,
             &format!("Acquired lock: {}", lock_path.display()),
@@ -47,12 +69,68 @@ impl FileLock {
     }
 }
 
+// The process that holds the lock at `path`. None while the lock is being
+// taken and the ID is not written yet, or if the lock file is gone.
+async fn holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).await.ok()?.parse().ok()
+}
+
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 checks for the process without signalling it. A process of
+    // another user is alive but cannot be signalled.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Removes the lock at `lock_path` if the process holding it has died, and
+// returns whether it did. The lock file is moved aside before it is checked
+// again, so that of two waiters breaking the same lock, the second cannot
+// remove the lock the first has taken meanwhile.
+async fn break_if_stale(lock_path: &Path) -> std::io::Result<bool> {
+    let Some(pid) = holder(lock_path).await.filter(|pid| !is_alive(*pid)) else {
+        return Ok(false);
+    };
+    let mut aside = OsString::from(lock_path.as_os_str());
+    aside.push(format!(
+        ".{}.{:08x}.stale",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let aside = PathBuf::from(aside);
+    match fs::rename(lock_path, &aside).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    }
+    if holder(&aside).await == Some(pid) {
+        log(
+            LogLevel::Warn,
+            &format!(
+                "Broke lock {} left by process {}, which is gone.",
+                lock_path.display(),
+                pid
+            ),
+        );
+    } else if let Err(e) = fs::hard_link(&aside, lock_path).await {
+        // The lock was taken again in between; give it back unless yet
+        // another waiter has taken it since.
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    fs::remove_file(&aside).await?;
+    Ok(true)
+}
+
 // The Drop implementation ensures the lock file is removed when the guard
 // goes out of scope, releasing the lock.
 impl Drop for FileLock {
     fn drop(&mut self) {
         // This is a synchronous operation but is acceptable for this use case.
-        if let Err(e) = std::fs::remove_file(&self.lock_path) {
+        // A directory removed while locked takes the lock file with it.
+        if let Err(e) = std::fs::remove_file(&self.lock_path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
             // We can't do much about an error here, but we should log it.
             eprintln!(
                 "Failed to remove lock file {}: {}",
@@ -69,3 +147,43 @@ impl Drop for FileLock {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::test_util::TestPool;
+
+    #[tokio::test]
+    async fn locks_left_by_dead_processes_are_broken() {
+        let pool = TestPool::new("lock-stale", "{}");
+        let target = Path::new(&pool.root).join("listing.json");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        std::fs::write(target.with_extension("json.lock"), child.id().to_string()).unwrap();
+
+        let lock = tokio::time::timeout(Duration::from_secs(5), FileLock::acquire(&target))
+            .await
+            .unwrap()
+            .unwrap();
+        let held_by = std::fs::read_to_string(&lock.lock_path).unwrap();
+        assert_eq!(held_by, std::process::id().to_string());
+        drop(lock);
+        assert!(!target.with_extension("json.lock").exists());
+    }
+
+    #[tokio::test]
+    async fn locks_of_live_processes_and_locks_being_taken_are_waited_for() {
+        let pool = TestPool::new("lock-live", "{}");
+        let target = Path::new(&pool.root).join("listing.json");
+        let lock = FileLock::acquire(&target).await.unwrap();
+        let wait = || tokio::time::timeout(Duration::from_millis(300), FileLock::acquire(&target));
+        assert!(wait().await.is_err());
+        drop(lock);
+
+        // A lock file without an ID yet is still being taken.
+        std::fs::write(target.with_extension("json.lock"), "").unwrap();
+        assert!(wait().await.is_err());
+        std::fs::remove_file(target.with_extension("json.lock")).unwrap();
+        assert!(wait().await.is_ok());
+    }
+}
@@ -2,31 +2,85 @@
 // SPDX-License-Identifier: AGPL-3.0
 // Copyright (c) 2025 Canmi
 
+use crate::common::config;
+use crate::metadata::batch::Batch;
 use crate::metadata::error::MetadataError;
-use crate::metadata::lock::FileLock;
+use crate::metadata::journal::{self, ChangeKind};
+use crate::metadata::link;
+use crate::metadata::listing::{self, ListOptions, ListPage};
+use crate::metadata::naming;
 use crate::metadata::model::{
-    DirectoryInfo, DirectoryListing, Entry, FileEntry, FileMetadata,
+    BlockInfo, DirQuota, DirectoryInfo, DirectoryListing, Entry, FileEntry, FileMetadata, SymlinkInfo, VersionInfo,
+    XattrMap,
 };
 use crate::metadata::path_utils;
+use crate::metadata::precondition::Precondition;
+use crate::metadata::quota::{self, QuotaUsage};
+use crate::metadata::snapshot::{self, SnapshotInfo};
+use crate::metadata::store::{self, DirKey, DirLock, MetadataStore};
+use crate::metadata::trash::{self, TrashItem};
+use crate::metadata::tree::{self, TreeClone};
+use crate::metadata::version;
+use crate::metadata::walk::{self, WalkOptions};
+use crate::metadata::xattr;
 use chrono::Utc;
 use futures::future::BoxFuture;
-use std::path::{Path, PathBuf};
-use tokio::fs;
-
-const METADATA_DIR: &str = "metadata";
-const LISTING_FILE: &str = "metadata.json";
+use futures::StreamExt;
+use rfs_utils::{log, LogLevel};
+use std::collections::{BTreeSet, HashSet};
+use std::pin::pin;
+use std::sync::Arc;
 
 // New function to read the contents of a directory.
 pub async fn list_directory(
     pool_root: &str,
     rfs_dir_path: &str,
 ) -> Result<DirectoryListing, MetadataError> {
-    let dir_components = path_utils::validate_and_split_path(rfs_dir_path)?;
-    let target_dir_path = resolve_dir_path(pool_root, &dir_components).await?;
-    let listing = read_listing(&target_dir_path).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    let dir_components = naming::split_path(pool_root, store.as_ref(), rfs_dir_path).await?;
+    let target_dir = resolve_dir_path(pool_root, store.as_ref(), &dir_components).await?;
+    let listing = store.read_listing(&target_dir).await?;
     Ok(listing)
 }
 
+// Reads one page of a directory, sorted and filtered per `options`.
+pub async fn list_directory_page(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    options: &ListOptions,
+) -> Result<ListPage, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let dir_components = naming::split_path(pool_root, store.as_ref(), rfs_dir_path).await?;
+    let target_dir = find_dir_path(store.as_ref(), &dir_components).await?;
+    let listing = store.read_listing(&target_dir).await?;
+    listing::list_page(listing, options)
+}
+
+// Reads the FileMetadata (block map) of the file at `rfs_file_path`.
+pub async fn get_file_metadata(
+    pool_root: &str,
+    rfs_file_path: &str,
+) -> Result<FileMetadata, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    file_metadata_in(pool_root, store.as_ref(), DirKey::root(), rfs_file_path).await
+}
+
+async fn file_metadata_in(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    base: DirKey,
+    rfs_file_path: &str,
+) -> Result<FileMetadata, MetadataError> {
+    let mut components = naming::split_path_in(pool_root, store, base.clone(), rfs_file_path).await?;
+    let filename = components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+    let dir = find_dir_path_in(store, base, &components).await?;
+
+    match store.get_entry(&dir, &filename).await? {
+        Some(Entry::File(file_entry)) => store.read_file_metadata(&link::home(&dir, &file_entry), &file_entry.cid).await,
+        Some(_) => Err(MetadataError::NotAFile(rfs_file_path.to_string())),
+        None => Err(MetadataError::NotFound(rfs_file_path.to_string())),
+    }
+}
 
 // Creates a file with its associated metadata and triggers a recursive update.
 pub async fn create_file(
@@ -35,88 +89,1314 @@ pub async fn create_file(
     filename: &str,
     file_metadata: FileMetadata,
 ) -> Result<(), MetadataError> {
-    // Resolve the target directory's physical path.
-    let mut dir_components = path_utils::validate_and_split_path(rfs_dir_path)?;
-    let target_dir_path = resolve_dir_path(pool_root, &dir_components).await?;
-    let listing_path = target_dir_path.join(LISTING_FILE);
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
 
-    // Acquire a lock on the directory's metadata file.
-    let _lock = FileLock::acquire(&listing_path).await?;
-    let mut listing = read_listing(&target_dir_path).await?;
+    // Resolve the target directory.
+    let mut dir_components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    check_quotas(pool_root, store, &dir_components, file_metadata.size as i64, 1).await?;
+    let target_dir = resolve_dir_path(pool_root, store, &dir_components).await?;
+    let filename = &naming::child_name(pool_root, store, &target_dir, filename).await?;
 
-    if listing.contains_key(filename) {
+    // Acquire a lock on the directory's listing.
+    let _lock = store.lock_dir(&target_dir).await?;
+    if store.get_entry(&target_dir, filename).await?.is_some() {
         return Err(MetadataError::EntryAlreadyExists(filename.to_string()));
     }
+    create_file_locked(pool_root, store, &mut dir_components, &target_dir, filename, file_metadata).await
+}
 
+// Creates the file `filename`, which does not exist yet, in `target_dir`,
+// whose lock the caller holds.
+async fn create_file_locked(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir_components: &mut [String],
+    target_dir: &DirKey,
+    filename: &str,
+    file_metadata: FileMetadata,
+) -> Result<(), MetadataError> {
     // Create the new file's metadata and entry.
     let new_cid = path_utils::generate_cid();
-    write_file_block_map(&target_dir_path, &new_cid, &file_metadata).await?;
+    store.write_file_metadata(target_dir, &new_cid, &file_metadata).await?;
 
     let new_entry = Entry::File(FileEntry {
-        cid: new_cid,
+        cid: new_cid.clone(),
         size: file_metadata.size,
         created_at: file_metadata.created_at,
         modified_at: file_metadata.modified_at,
+        linked: false,
+        revision: 0,
     });
-    listing.insert(filename.to_string(), new_entry);
-    write_listing(&target_dir_path, &listing).await?;
+    store.put_entry(target_dir, filename, &new_entry).await?;
+    let path = journal::entry_path(dir_components, filename);
+    journal::record(pool_root, ChangeKind::Create, path, Some(&new_cid)).await;
 
     // Propagate the size and timestamp changes up the directory tree.
-    propagate_update(pool_root, &mut dir_components, file_metadata.size as i64).await?;
+    propagate_update(pool_root, store, dir_components, file_metadata.size as i64, 1).await
+}
+
+// Like `create_file`, but an existing file of the same name is replaced. Its
+// previous contents are kept as a version if the pool's retention policy
+// allows, and released otherwise.
+pub async fn replace_file(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    file_metadata: FileMetadata,
+) -> Result<(), MetadataError> {
+    replace_file_if(pool_root, rfs_dir_path, filename, file_metadata, &Precondition::default()).await
+}
+
+// Like `replace_file`, but only if the existing entry, or its absence,
+// satisfies `precondition`. With `if_none_match` set to `*` this creates
+// the file only if it does not exist yet.
+pub async fn replace_file_if(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    file_metadata: FileMetadata,
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+
+    let mut dir_components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    let target_dir = resolve_dir_path(pool_root, store, &dir_components).await?;
+    let filename = &naming::child_name(pool_root, store, &target_dir, filename).await?;
+
+    let lock = store.lock_dir(&target_dir).await?;
+    let existing = store.get_entry(&target_dir, filename).await?;
+    precondition.check(&journal::entry_path(&dir_components, filename), existing.as_ref())?;
+    let mut file_entry = match existing {
+        Some(Entry::File(file_entry)) => file_entry,
+        Some(_) => return Err(MetadataError::NotAFile(filename.to_string())),
+        // Created under the lock the precondition was checked under.
+        None => {
+            check_quotas(pool_root, store, &dir_components, file_metadata.size as i64, 1).await?;
+            let (dir_components, target_dir) = (&mut dir_components, &target_dir);
+            return create_file_locked(pool_root, store, dir_components, target_dir, filename, file_metadata).await;
+        }
+    };
+
+    // The new contents are seen through every hard link of the file.
+    let home_lock = link::lock_home(store, &file_entry).await?;
+    let home = link::home(&target_dir, &file_entry);
+    let current = store.read_file_metadata(&home, &file_entry.cid).await?;
+    let old_size = current.size;
+    check_quotas(pool_root, store, &dir_components, file_metadata.size as i64 - old_size as i64, 0).await?;
+    let file_metadata = FileMetadata { links: current.links, link_dirs: current.link_dirs.clone(), ..file_metadata };
+    let (replacement, superseded) =
+        version::push_version(pool_root, store, &home, &file_entry.cid, current, file_metadata).await?;
+    store.write_file_metadata(&home, &file_entry.cid, &replacement).await?;
+
+    file_entry.size = replacement.size;
+    file_entry.modified_at = replacement.modified_at;
+    file_entry.revision += 1;
+    store.put_entry(&target_dir, filename, &Entry::File(file_entry.clone())).await?;
+    let path = journal::entry_path(&dir_components, filename);
+    journal::record(pool_root, ChangeKind::Modify, path, Some(&file_entry.cid)).await;
+
+    propagate_update(pool_root, store, &mut dir_components, replacement.size as i64 - old_size as i64, 0).await?;
+    if file_entry.linked {
+        drop((home_lock, lock));
+        update_links(pool_root, store, &file_entry.cid, (&target_dir, filename)).await?;
+    }
+    // Only now that nothing refers to them any more.
+    superseded.release(pool_root, store).await
+}
+
+// Lists the prior versions of a file, oldest first.
+pub async fn list_versions(pool_root: &str, rfs_file_path: &str) -> Result<Vec<VersionInfo>, MetadataError> {
+    Ok(get_file_metadata(pool_root, rfs_file_path).await?.versions)
+}
+
+// Reads the block map of a prior version of a file, e.g. to read its
+// contents with `block::read`.
+pub async fn get_version_metadata(
+    pool_root: &str,
+    rfs_file_path: &str,
+    version: u32,
+) -> Result<FileMetadata, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let (_, dir, _, file_entry) = find_file(pool_root, store.as_ref(), rfs_file_path).await?;
+    let home = link::home(&dir, &file_entry);
+    let current = store.read_file_metadata(&home, &file_entry.cid).await?;
+    if !current.versions.iter().any(|v| v.version == version) {
+        return Err(MetadataError::VersionNotFound(version));
+    }
+    store.read_file_metadata(&home, &version::version_id(&file_entry.cid, version)).await
+}
+
+// Makes a prior version of a file current again. The contents it replaces
+// become the newest version, so a restore can itself be undone.
+pub async fn restore_version(pool_root: &str, rfs_file_path: &str, version: u32) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let (mut dir_components, dir, filename, mut file_entry) = find_file(pool_root, store, rfs_file_path).await?;
+
+    let lock = store.lock_dir(&dir).await?;
+    let home_lock = link::lock_home(store, &file_entry).await?;
+    let home = link::home(&dir, &file_entry);
+    let mut current = store.read_file_metadata(&home, &file_entry.cid).await?;
+    let Some(position) = current.versions.iter().position(|v| v.version == version) else {
+        return Err(MetadataError::VersionNotFound(version));
+    };
+    current.versions.remove(position);
+
+    let version_id = version::version_id(&file_entry.cid, version);
+    let mut restored = store.read_file_metadata(&home, &version_id).await?;
+    restored.modified_at = Utc::now();
+    restored.links = current.links;
+    restored.link_dirs = current.link_dirs.clone();
+    let old_size = current.size;
+    check_quotas(pool_root, store, &dir_components, restored.size as i64 - old_size as i64, 0).await?;
+    let (restored, superseded) =
+        version::push_version(pool_root, store, &home, &file_entry.cid, current, restored).await?;
+    store.write_file_metadata(&home, &file_entry.cid, &restored).await?;
+    store.remove_file_metadata(&home, &version_id).await?;
+
+    file_entry.size = restored.size;
+    file_entry.modified_at = restored.modified_at;
+    file_entry.revision += 1;
+    store.put_entry(&dir, &filename, &Entry::File(file_entry.clone())).await?;
+    let path = journal::entry_path(&dir_components, &filename);
+    journal::record(pool_root, ChangeKind::Modify, path, Some(&file_entry.cid)).await;
+
+    propagate_update(pool_root, store, &mut dir_components, restored.size as i64 - old_size as i64, 0).await?;
+    if file_entry.linked {
+        drop((home_lock, lock));
+        update_links(pool_root, store, &file_entry.cid, (&dir, &filename)).await?;
+    }
+    superseded.release(pool_root, store).await
+}
+
+// Resolves a file path to its parent's components and key, its name, and its entry.
+async fn find_file(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    rfs_file_path: &str,
+) -> Result<(Vec<String>, DirKey, String, FileEntry), MetadataError> {
+    let mut components = naming::split_path(pool_root, store, rfs_file_path).await?;
+    let filename = components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+    let dir = find_dir_path(store, &components).await?;
+    match store.get_entry(&dir, &filename).await? {
+        Some(Entry::File(file_entry)) => Ok((components, dir, filename, file_entry)),
+        Some(_) => Err(MetadataError::NotAFile(rfs_file_path.to_string())),
+        None => Err(MetadataError::NotFound(rfs_file_path.to_string())),
+    }
+}
+
+// Reads the directory entry at `rfs_path` without following symlinks.
+pub async fn get_entry(pool_root: &str, rfs_path: &str) -> Result<Entry, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let mut components = naming::split_path(pool_root, store.as_ref(), rfs_path).await?;
+    let name = components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+    let dir = find_dir_path(store.as_ref(), &components).await?;
+    store
+        .get_entry(&dir, &name)
+        .await?
+        .ok_or_else(|| MetadataError::NotFound(rfs_path.to_string()))
+}
+
+// Creates a symbolic link named `filename` in `rfs_dir_path` pointing at
+// `target`. The target is stored verbatim; it may be relative or dangling.
+pub async fn create_symlink(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    target: &str,
+) -> Result<(), MetadataError> {
+    create_symlink_if(pool_root, rfs_dir_path, filename, target, &Precondition::default()).await
+}
+
+// Like `create_symlink`, but only if the entry the link would take the place
+// of, or its absence, satisfies `precondition`.
+pub async fn create_symlink_if(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    target: &str,
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+
+    let mut dir_components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    check_quotas(pool_root, store, &dir_components, 0, 1).await?;
+    let target_dir = resolve_dir_path(pool_root, store, &dir_components).await?;
+    let filename = &naming::child_name(pool_root, store, &target_dir, filename).await?;
+
+    let _lock = store.lock_dir(&target_dir).await?;
+    let existing = store.get_entry(&target_dir, filename).await?;
+    precondition.check(&journal::entry_path(&dir_components, filename), existing.as_ref())?;
+    if existing.is_some() {
+        return Err(MetadataError::EntryAlreadyExists(filename.to_string()));
+    }
+    let now = Utc::now();
+    let link = SymlinkInfo { target: target.to_string(), created_at: now, modified_at: now };
+    store.put_entry(&target_dir, filename, &Entry::Symlink(link)).await?;
+    journal::record(pool_root, ChangeKind::Create, journal::entry_path(&dir_components, filename), None).await;
+    propagate_update(pool_root, store, &mut dir_components, 0, 1).await
+}
+
+// Reads the target of the symbolic link at `rfs_path`.
+pub async fn read_symlink(pool_root: &str, rfs_path: &str) -> Result<String, MetadataError> {
+    match get_entry(pool_root, rfs_path).await? {
+        Entry::Symlink(link) => Ok(link.target),
+        _ => Err(MetadataError::NotASymlink(rfs_path.to_string())),
+    }
+}
+
+// Creates `new_path` as a hard link to the file at `existing_path`. Both
+// entries then share one block map, which is freed with the last link.
+pub async fn create_hard_link(pool_root: &str, existing_path: &str, new_path: &str) -> Result<(), MetadataError> {
+    create_hard_link_if(pool_root, existing_path, new_path, &Precondition::default()).await
+}
+
+// Like `create_hard_link`, but only if the file at `existing_path` satisfies `precondition`.
+pub async fn create_hard_link_if(
+    pool_root: &str,
+    existing_path: &str,
+    new_path: &str,
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+
+    let (src_dir_components, src_dir, src_name, src_entry) = find_file(pool_root, store, existing_path).await?;
+    let mut dst_dir_components = naming::split_path(pool_root, store, new_path).await?;
+    let dst_name = dst_dir_components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+    // The directories above the new link count its size again, as they do for the original.
+    check_quotas(pool_root, store, &dst_dir_components, src_entry.size as i64, 1).await?;
+    let dst_dir = resolve_dir_path(pool_root, store, &dst_dir_components).await?;
+    if store.get_entry(&dst_dir, &dst_name).await?.is_some() {
+        return Err(MetadataError::EntryAlreadyExists(dst_name));
+    }
 
+    // Count the new link first, so the block map can never be freed under it.
+    let file_entry = {
+        let _lock = store.lock_dir(&src_dir).await?;
+        let existing = store.get_entry(&src_dir, &src_name).await?;
+        precondition.check(&journal::entry_path(&src_dir_components, &src_name), existing.as_ref())?;
+        let Some(Entry::File(mut file_entry)) = existing else {
+            return Err(MetadataError::NotFound(existing_path.to_string()));
+        };
+        link::add_link(store, &src_dir, &mut file_entry, &dst_dir).await?;
+        store.put_entry(&src_dir, &src_name, &Entry::File(file_entry.clone())).await?;
+        file_entry
+    };
+
+    let _lock = store.lock_dir(&dst_dir).await?;
+    if store.get_entry(&dst_dir, &dst_name).await?.is_some() {
+        link::remove_link(store, &dst_dir, &file_entry).await?;
+        return Err(MetadataError::EntryAlreadyExists(dst_name));
+    }
+    store.put_entry(&dst_dir, &dst_name, &Entry::File(file_entry.clone())).await?;
+    let path = journal::entry_path(&dst_dir_components, &dst_name);
+    journal::record(pool_root, ChangeKind::Create, path, Some(&file_entry.cid)).await;
+    propagate_update(pool_root, store, &mut dst_dir_components, file_entry.size as i64, 1).await
+}
+
+// A file whose directory is locked while its block map is rewritten, e.g. by
+// `block::write`. Dropping it without `commit` leaves the file untouched.
+pub(crate) struct FileUpdate {
+    pool_root: String,
+    store: Arc<dyn MetadataStore>,
+    _home_lock: Option<DirLock>,
+    _lock: DirLock,
+    dir_components: Vec<String>,
+    dir: DirKey,
+    home: DirKey,
+    filename: String,
+    entry: FileEntry,
+    pub metadata: FileMetadata,
+}
+
+impl FileUpdate {
+    // Fails with `QuotaExceeded` if resizing the file to `size` would exceed
+    // a quota, so a write can be refused before it stores any blocks.
+    pub async fn check_quota(&self, size: u64) -> Result<(), MetadataError> {
+        let size_delta = size as i64 - self.metadata.size as i64;
+        check_quotas(&self.pool_root, self.store.as_ref(), &self.dir_components, size_delta, 0).await
+    }
+
+    // Swaps in the new block map and size, and updates the entry and its parents.
+    pub async fn commit(mut self, metadata: FileMetadata) -> Result<(), MetadataError> {
+        let store = self.store.as_ref();
+        store.write_file_metadata(&self.home, &self.entry.cid, &metadata).await?;
+
+        let size_delta = metadata.size as i64 - self.metadata.size as i64;
+        self.entry.size = metadata.size;
+        self.entry.modified_at = metadata.modified_at;
+        self.entry.revision += 1;
+        store.put_entry(&self.dir, &self.filename, &Entry::File(self.entry.clone())).await?;
+        let path = journal::entry_path(&self.dir_components, &self.filename);
+        journal::record(&self.pool_root, ChangeKind::Modify, path, Some(&self.entry.cid)).await;
+
+        propagate_update(&self.pool_root, store, &mut self.dir_components, size_delta, 0).await?;
+        if self.entry.linked {
+            drop((self._home_lock, self._lock));
+            let changed = (&self.dir, self.filename.as_str());
+            update_links(&self.pool_root, self.store.as_ref(), &self.entry.cid, changed).await?;
+        }
+        Ok(())
+    }
+}
+
+// Locks the directory of the file at `rfs_file_path` and reads its block map,
+// provided the file satisfies `precondition`.
+pub(crate) async fn open_file_for_update(
+    pool_root: &str,
+    rfs_file_path: &str,
+    precondition: &Precondition,
+) -> Result<FileUpdate, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let (dir_components, dir, filename, _) = find_file(pool_root, store.as_ref(), rfs_file_path).await?;
+
+    // Look the entry up again under the lock, in case it changed meanwhile.
+    let lock = store.lock_dir(&dir).await?;
+    let existing = store.get_entry(&dir, &filename).await?;
+    precondition.check(&journal::entry_path(&dir_components, &filename), existing.as_ref())?;
+    let entry = match existing {
+        Some(Entry::File(file_entry)) => file_entry,
+        Some(_) => return Err(MetadataError::NotAFile(rfs_file_path.to_string())),
+        None => return Err(MetadataError::NotFound(rfs_file_path.to_string())),
+    };
+    let home_lock = link::lock_home(store.as_ref(), &entry).await?;
+    let home = link::home(&dir, &entry);
+    let metadata = store.read_file_metadata(&home, &entry.cid).await?;
+    Ok(FileUpdate {
+        pool_root: pool_root.to_string(),
+        store,
+        _home_lock: home_lock,
+        _lock: lock,
+        dir_components,
+        dir,
+        home,
+        filename,
+        entry,
+        metadata,
+    })
+}
+
+// Reads the value of the extended attribute `name` of a file or directory.
+pub async fn get_xattr(pool_root: &str, rfs_path: &str, name: &str) -> Result<Vec<u8>, MetadataError> {
+    let xattrs = read_xattrs(pool_root, rfs_path).await?;
+    let value = xattrs.get(name).ok_or_else(|| MetadataError::XattrNotFound(name.to_string()))?;
+    xattr::decode(pool_root, value).await
+}
+
+// Lists the names of the extended attributes of a file or directory, sorted.
+pub async fn list_xattrs(pool_root: &str, rfs_path: &str) -> Result<Vec<String>, MetadataError> {
+    Ok(read_xattrs(pool_root, rfs_path).await?.into_keys().collect())
+}
+
+// Sets the extended attribute `name` of a file or directory, replacing any
+// previous value. Values larger than `xattr::INLINE_MAX` are stored as blocks.
+pub async fn set_xattr(pool_root: &str, rfs_path: &str, name: &str, value: &[u8]) -> Result<(), MetadataError> {
+    set_xattr_if(pool_root, rfs_path, name, value, &Precondition::default()).await
+}
+
+// Like `set_xattr`, but only if the file or directory satisfies `precondition`.
+pub async fn set_xattr_if(
+    pool_root: &str,
+    rfs_path: &str,
+    name: &str,
+    value: &[u8],
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    xattr::validate_name(name)?;
+    let value = xattr::encode(pool_root, value).await?;
+    let insert = |xattrs: &mut XattrMap| xattrs.insert(name.to_string(), value.clone());
+    match update_xattrs(pool_root, rfs_path, precondition, insert).await {
+        Ok(Some(old)) => xattr::release(pool_root, &old).await,
+        Ok(None) => Ok(()),
+        Err(e) => {
+            xattr::release(pool_root, &value).await?;
+            Err(e)
+        }
+    }
+}
+
+// Removes the extended attribute `name` of a file or directory.
+pub async fn remove_xattr(pool_root: &str, rfs_path: &str, name: &str) -> Result<(), MetadataError> {
+    match update_xattrs(pool_root, rfs_path, &Precondition::default(), |xattrs| xattrs.remove(name)).await? {
+        Some(old) => xattr::release(pool_root, &old).await,
+        None => Err(MetadataError::XattrNotFound(name.to_string())),
+    }
+}
+
+async fn read_xattrs(pool_root: &str, rfs_path: &str) -> Result<XattrMap, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let (_, dir, _, entry) = find_xattr_owner(pool_root, store.as_ref(), rfs_path).await?;
+    match entry {
+        Entry::File(file_entry) => {
+            Ok(store.read_file_metadata(&link::home(&dir, &file_entry), &file_entry.cid).await?.xattrs)
+        }
+        Entry::Directory(info) => Ok(info.xattrs),
+        Entry::Symlink(_) => unreachable!("find_xattr_owner rejects symlinks"),
+    }
+}
+
+// Applies `change` to the extended attributes of a file or directory under
+// the relevant locks, provided it satisfies `precondition`, and stores the
+// result. A file's attributes live in its block map, shared by its hard
+// links; a directory's in its entry in the parent listing.
+async fn update_xattrs<T>(
+    pool_root: &str,
+    rfs_path: &str,
+    precondition: &Precondition,
+    change: impl FnOnce(&mut XattrMap) -> T,
+) -> Result<T, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let (components, dir, name, _) = find_xattr_owner(pool_root, store, rfs_path).await?;
+    let path = journal::entry_path(&components, &name);
+
+    let lock = store.lock_dir(&dir).await?;
+    let existing = store.get_entry(&dir, &name).await?;
+    precondition.check(&path, existing.as_ref())?;
+    match existing {
+        Some(Entry::File(mut file_entry)) => {
+            let home_lock = link::lock_home(store, &file_entry).await?;
+            let home = link::home(&dir, &file_entry);
+            let mut metadata = store.read_file_metadata(&home, &file_entry.cid).await?;
+            let result = change(&mut metadata.xattrs);
+            store.write_file_metadata(&home, &file_entry.cid, &metadata).await?;
+            file_entry.revision += 1;
+            store.put_entry(&dir, &name, &Entry::File(file_entry.clone())).await?;
+            journal::record(pool_root, ChangeKind::Modify, path, Some(&file_entry.cid)).await;
+            if file_entry.linked {
+                drop((home_lock, lock));
+                update_links(pool_root, store, &file_entry.cid, (&dir, &name)).await?;
+            }
+            Ok(result)
+        }
+        Some(Entry::Directory(mut info)) => {
+            let result = change(&mut info.xattrs);
+            info.revision += 1;
+            store.put_entry(&dir, &name, &Entry::Directory(info.clone())).await?;
+            journal::record(pool_root, ChangeKind::Modify, path, Some(&info.cid)).await;
+            Ok(result)
+        }
+        Some(Entry::Symlink(_)) => Err(xattrs_unsupported(rfs_path)),
+        None => Err(MetadataError::NotFound(rfs_path.to_string())),
+    }
+}
+
+// Resolves the file or directory whose extended attributes `rfs_path` names
+// to its parent's components and key, its name, and its entry. The pool root
+// and symlinks have no place to keep attributes.
+async fn find_xattr_owner(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    rfs_path: &str,
+) -> Result<(Vec<String>, DirKey, String, Entry), MetadataError> {
+    let mut components = naming::split_path(pool_root, store, rfs_path).await?;
+    let name = components.pop().ok_or_else(|| xattrs_unsupported(rfs_path))?;
+    let dir = find_dir_path(store, &components).await?;
+    match store.get_entry(&dir, &name).await? {
+        Some(Entry::Symlink(_)) => Err(xattrs_unsupported(rfs_path)),
+        Some(entry) => Ok((components, dir, name, entry)),
+        None => Err(MetadataError::NotFound(rfs_path.to_string())),
+    }
+}
+
+fn xattrs_unsupported(rfs_path: &str) -> MetadataError {
+    MetadataError::InvalidOperation(format!("'{}' cannot have extended attributes", rfs_path))
+}
+
+// Checks whether a file of `size` bytes named `filename` could be written to
+// `rfs_dir_path`, replacing any file of that name, without exceeding a quota.
+// Lets callers such as ingest fail before storing any blocks.
+pub async fn check_quota(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    filename: &str,
+    size: u64,
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let dir_components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    let existing = match find_dir_path(store, &dir_components).await {
+        Ok(dir) => {
+            let filename = naming::child_name(pool_root, store, &dir, filename).await?;
+            store.get_entry(&dir, &filename).await?
+        }
+        Err(MetadataError::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    match existing {
+        Some(Entry::File(file_entry)) => {
+            check_quotas(pool_root, store, &dir_components, size as i64 - file_entry.size as i64, 0).await
+        }
+        _ => check_quotas(pool_root, store, &dir_components, size as i64, 1).await,
+    }
+}
+
+// Limits the bytes and the number of entries (files, directories and
+// symlinks, at any depth) below the directory at `rfs_dir_path`; None lifts a
+// limit. The pool root cannot have a quota. Copies and snapshots of the
+// directory do not inherit it.
+pub async fn set_dir_quota(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    max_bytes: Option<u64>,
+    max_entries: Option<u64>,
+) -> Result<(), MetadataError> {
+    update_dir_quota(pool_root, rfs_dir_path, |quota| {
+        quota.max_bytes = max_bytes;
+        quota.max_entries = max_entries;
+    })
+    .await?;
     Ok(())
 }
 
-// Recursively updates the size and modification time of parent directories.
+// Charges everything below the directory at `rfs_dir_path` to `owner`, or
+// to no one if None. A change is charged to each owner once, so directories
+// of the same owner may not be nested.
+pub async fn set_dir_owner(pool_root: &str, rfs_dir_path: &str, owner: Option<&str>) -> Result<(), MetadataError> {
+    if let Some(owner) = owner {
+        let store = store::get_metadata_store(pool_root).await?;
+        let components = naming::split_path(pool_root, store.as_ref(), rfs_dir_path).await?;
+        let Some((_, ancestors)) = components.split_last() else {
+            return Err(quota_unsupported(rfs_dir_path));
+        };
+        let owned_by = |entry: &Entry| {
+            matches!(entry, Entry::Directory(info) if info.quota.as_ref().is_some_and(|q| q.owner.as_deref() == Some(owner)))
+        };
+
+        let mut ancestor = DirKey::root();
+        for component in ancestors {
+            match store.get_entry(&ancestor, component).await? {
+                Some(entry) if owned_by(&entry) => return Err(nested_owner(owner, rfs_dir_path)),
+                Some(Entry::Directory(info)) => ancestor = ancestor.child(&info.cid),
+                _ => break,
+            }
+        }
+        let dir = find_dir_path(store.as_ref(), &components).await?;
+        let mut entries = pin!(walk::walk_dir(pool_root, dir, "/", WalkOptions::default()));
+        while let Some(item) = entries.next().await {
+            if owned_by(&item?.entry) {
+                return Err(nested_owner(owner, rfs_dir_path));
+            }
+        }
+    }
+
+    let (dir, ()) = update_dir_quota(pool_root, rfs_dir_path, |quota| quota.owner = owner.map(String::from)).await?;
+    quota::charge_dir(pool_root, store::get_metadata_store(pool_root).await?.as_ref(), &dir).await
+}
+
+fn nested_owner(owner: &str, rfs_dir_path: &str) -> MetadataError {
+    MetadataError::InvalidOperation(format!(
+        "'{}' is inside or contains another directory charged to '{}'",
+        rfs_dir_path, owner
+    ))
+}
+
+// Limits the bytes and entries of all directories charged to `owner`
+// together; None lifts a limit.
+pub async fn set_owner_quota(
+    pool_root: &str,
+    owner: &str,
+    max_bytes: Option<u64>,
+    max_entries: Option<u64>,
+) -> Result<(), MetadataError> {
+    let _registry_lock = quota::REGISTRY.lock(pool_root).await?;
+    let mut registry = quota::REGISTRY.read(pool_root).await?;
+    let quota = registry.entry(owner.to_string()).or_default();
+    quota.max_bytes = max_bytes;
+    quota.max_entries = max_entries;
+    if quota.max_bytes.is_none() && quota.max_entries.is_none() && quota.dirs.is_empty() {
+        registry.remove(owner);
+    }
+    quota::REGISTRY.write(pool_root, &registry).await
+}
+
+// Reads the usage of the directory at `rfs_dir_path` against its quota. A
+// directory without one has its entries counted and reports no limits.
+pub async fn get_dir_quota(pool_root: &str, rfs_dir_path: &str) -> Result<QuotaUsage, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let mut components = naming::split_path(pool_root, store.as_ref(), rfs_dir_path).await?;
+    let Some(name) = components.pop() else {
+        return Err(quota_unsupported(rfs_dir_path));
+    };
+    let parent = find_dir_path(store.as_ref(), &components).await?;
+    let info = match store.get_entry(&parent, &name).await? {
+        Some(Entry::Directory(info)) => info,
+        Some(_) => return Err(MetadataError::NotADirectory(rfs_dir_path.to_string())),
+        None => return Err(MetadataError::NotFound(rfs_dir_path.to_string())),
+    };
+    match QuotaUsage::of_dir(&info) {
+        Some(usage) => Ok(usage),
+        None => Ok(QuotaUsage {
+            bytes: info.size,
+            entries: count_entries(pool_root, parent.child(&info.cid)).await?,
+            ..Default::default()
+        }),
+    }
+}
+
+// Reads the usage of all directories charged to `owner` against its limits.
+pub async fn get_owner_quota(pool_root: &str, owner: &str) -> Result<QuotaUsage, MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    quota::owner_usage(pool_root, store.as_ref(), owner).await
+}
+
+// Applies `change` to the quota of the directory at `rfs_dir_path` under its
+// parent's lock, records it as a modification of the directory, and returns
+// the directory's key along with the result. A directory gaining a quota has
+// its entries counted; one left without limits or owner drops it.
+async fn update_dir_quota<T>(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    change: impl FnOnce(&mut DirQuota) -> T,
+) -> Result<(DirKey, T), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let mut components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    let name = components.pop().ok_or_else(|| quota_unsupported(rfs_dir_path))?;
+    let parent = find_dir_path(store, &components).await?;
+
+    let _lock = store.lock_dir(&parent).await?;
+    let mut info = match store.get_entry(&parent, &name).await? {
+        Some(Entry::Directory(info)) => info,
+        Some(_) => return Err(MetadataError::NotADirectory(rfs_dir_path.to_string())),
+        None => return Err(MetadataError::NotFound(rfs_dir_path.to_string())),
+    };
+    let dir = parent.child(&info.cid);
+    let mut quota = match info.quota.take() {
+        Some(quota) => quota,
+        None => DirQuota { entries: count_entries(pool_root, dir.clone()).await?, ..Default::default() },
+    };
+    let result = change(&mut quota);
+    info.quota = (!quota.is_unset()).then_some(quota);
+    info.revision += 1;
+    let cid = info.cid.clone();
+    store.put_entry(&parent, &name, &Entry::Directory(info)).await?;
+    journal::record(pool_root, ChangeKind::Modify, journal::entry_path(&components, &name), Some(&cid)).await;
+    Ok((dir, result))
+}
+
+fn quota_unsupported(rfs_dir_path: &str) -> MetadataError {
+    MetadataError::InvalidOperation(format!("'{}' cannot have a quota", rfs_dir_path))
+}
+
+// Copies a file or directory tree from `src_path` to `dst_path` without
+// touching any data: block maps are duplicated under new CIDs and the
+// reference count of every block they point at is bumped. The copy is only
+// linked into its destination once it is complete.
+pub async fn copy_entry(pool_root: &str, src_path: &str, dst_path: &str) -> Result<(), MetadataError> {
+    copy_entry_if(pool_root, src_path, dst_path, &Precondition::default()).await
+}
+
+// Like `copy_entry`, but only if the entry at `src_path` satisfies
+// `precondition` when the copy starts.
+pub async fn copy_entry_if(
+    pool_root: &str,
+    src_path: &str,
+    dst_path: &str,
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+
+    let mut src_components = naming::split_path(pool_root, store, src_path).await?;
+    let mut dst_dir_components = naming::split_path(pool_root, store, dst_path).await?;
+    if dst_dir_components.starts_with(&src_components) {
+        return Err(MetadataError::InvalidOperation(format!(
+            "cannot copy '{}' into itself",
+            src_path
+        )));
+    }
+    let src_name = src_components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+    let dst_name = dst_dir_components.pop().ok_or(MetadataError::EmptyPathComponent)?;
+
+    let src_dir = find_dir_path(store, &src_components).await?;
+    let src_entry = {
+        let _lock = store.lock_dir(&src_dir).await?;
+        let existing = store.get_entry(&src_dir, &src_name).await?;
+        precondition.check(&journal::entry_path(&src_components, &src_name), existing.as_ref())?;
+        existing.ok_or_else(|| MetadataError::NotFound(src_path.to_string()))?
+    };
+    let dst_dir = resolve_dir_path(pool_root, store, &dst_dir_components).await?;
+
+    let _lock = store.lock_dir(&dst_dir).await?;
+    if store.get_entry(&dst_dir, &dst_name).await?.is_some() {
+        return Err(MetadataError::EntryAlreadyExists(dst_name));
+    }
+
+    let mut clone = TreeClone::new(pool_root, store, false);
+    let copied = match clone.clone_entry(&src_dir, &src_entry, &dst_dir).await {
+        Ok(entry) => entry,
+        Err(e) => {
+            clone.rollback().await;
+            return Err(e);
+        }
+    };
+    let size = tree::entry_size(&copied);
+    if let Err(e) = check_quotas(pool_root, store, &dst_dir_components, size as i64, clone.entries() as i64).await {
+        clone.rollback().await;
+        return Err(e);
+    }
+    if let Err(e) = store.put_entry(&dst_dir, &dst_name, &copied).await {
+        clone.rollback().await;
+        return Err(e);
+    }
+    let path = journal::entry_path(&dst_dir_components, &dst_name);
+    journal::record(pool_root, ChangeKind::Create, path, journal::cid_of(&copied)).await;
+
+    let entries = clone.entries() as i64;
+    if let Err(e) = propagate_update(pool_root, store, &mut dst_dir_components, size as i64, entries).await {
+        // Take the copy out again before its blocks are released.
+        if store.remove_entry(&dst_dir, &dst_name).await.is_ok() {
+            clone.rollback().await;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Deletes the file, directory tree or symlink at `rfs_path`. With the pool's
+// trash enabled it is moved there and can be restored until it is purged;
+// otherwise its blocks are released at once.
+pub async fn delete_entry(pool_root: &str, rfs_path: &str) -> Result<(), MetadataError> {
+    delete_entry_if(pool_root, rfs_path, &Precondition::default()).await
+}
+
+// Like `delete_entry`, but only if the entry satisfies `precondition`.
+pub async fn delete_entry_if(pool_root: &str, rfs_path: &str, precondition: &Precondition) -> Result<(), MetadataError> {
+    let use_trash = config::get_pool_config(pool_root).await?.trash.enabled;
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let mut components = naming::split_path(pool_root, store, rfs_path).await?;
+    let path = format!("/{}", components.join("/"));
+    let name = components
+        .pop()
+        .ok_or_else(|| MetadataError::InvalidOperation("cannot delete the pool root".to_string()))?;
+    let dir = find_dir_path(store, &components).await?;
+
+    let _registry_lock = match use_trash {
+        true => Some(trash::REGISTRY.lock(pool_root).await?),
+        false => None,
+    };
+    let _lock = store.lock_dir(&dir).await?;
+    let existing = store.get_entry(&dir, &name).await?;
+    precondition.check(&path, existing.as_ref())?;
+    let entry = existing.ok_or_else(|| MetadataError::NotFound(path.clone()))?;
+    let entries = match &entry {
+        Entry::Directory(info) => 1 + count_entries(pool_root, dir.child(&info.cid)).await?,
+        _ => 1,
+    };
+
+    if use_trash {
+        let mut registry = trash::REGISTRY.read(pool_root).await?;
+        let id = trash::keep(pool_root, store, &mut registry, &dir, &name, &entry, &path).await?;
+        if let Err(e) = store.remove_entry(&dir, &name).await {
+            if let Err(e) = trash::put_back(pool_root, store, &mut registry, &id).await {
+                log(LogLevel::Error, &format!("Failed to move '{}' back out of the trash: {}", path, e));
+            }
+            return Err(e);
+        }
+        trash::settle(pool_root, &mut registry, &[id]).await?;
+    } else {
+        store.remove_entry(&dir, &name).await?;
+        tree::release_entry(pool_root, store, &dir, &entry).await?;
+    }
+    journal::record(pool_root, ChangeKind::Delete, path.clone(), journal::cid_of(&entry)).await;
+    propagate_update(pool_root, store, &mut components, -(tree::entry_size(&entry) as i64), -(entries as i64)).await?;
+    log(LogLevel::Info, &format!("Deleted '{}'{}.", path, if use_trash { " to the trash" } else { "" }));
+    Ok(())
+}
+
+// Moves the entry at `from` to `to`, which must not exist yet; missing
+// directories on the way to `to` are created. The entry keeps its CID
+// unless another entry in its new directory already has it.
+pub async fn rename_entry(pool_root: &str, from: &str, to: &str) -> Result<(), MetadataError> {
+    rename_entry_if(pool_root, from, to, &Precondition::default()).await
+}
+
+// Like `rename_entry`, but only if the entry at `from` satisfies `precondition`.
+pub async fn rename_entry_if(
+    pool_root: &str,
+    from: &str,
+    to: &str,
+    precondition: &Precondition,
+) -> Result<(), MetadataError> {
+    let mut batch = Batch::new();
+    batch.rename_if(from, to, precondition);
+    batch.commit(pool_root).await
+}
+
+// Lists the entries in the trash, oldest deletion first.
+pub async fn list_trash(pool_root: &str) -> Result<Vec<TrashItem>, MetadataError> {
+    let registry = trash::REGISTRY.read(pool_root).await?;
+    let mut items: Vec<TrashItem> = registry.into_values().filter(|item| item.moving.is_none()).collect();
+    items.sort_by_key(|item| item.deleted_at);
+    Ok(items)
+}
+
+// Moves a trashed entry back to the directory it was deleted from, or to
+// `rfs_dir_path` if given. Missing directories on the way are created.
+pub async fn restore_trash(pool_root: &str, id: &str, rfs_dir_path: Option<&str>) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let _registry_lock = trash::REGISTRY.lock(pool_root).await?;
+    let mut registry = trash::REGISTRY.read(pool_root).await?;
+    let item = registry
+        .remove(id)
+        .filter(|item| item.moving.is_none())
+        .ok_or_else(|| MetadataError::TrashItemNotFound(id.to_string()))?;
+    let (name, entry) = store
+        .read_listing(&item.root())
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| MetadataError::NotFound(item.path.clone()))?;
+
+    let mut components = match rfs_dir_path {
+        Some(rfs_dir_path) => naming::split_path(pool_root, store, rfs_dir_path).await?,
+        None => {
+            let mut components = naming::split_path(pool_root, store, &item.path).await?;
+            components.pop();
+            components
+        }
+    };
+    let entries = match &entry {
+        Entry::Directory(info) => 1 + count_entries(pool_root, item.root().child(&info.cid)).await?,
+        _ => 1,
+    };
+    check_quotas(pool_root, store, &components, item.size as i64, entries as i64).await?;
+    let dir = resolve_dir_path(pool_root, store, &components).await?;
+    let name = naming::child_name(pool_root, store, &dir, &name).await?;
+
+    let _lock = store.lock_dir(&dir).await?;
+    if store.get_entry(&dir, &name).await?.is_some() {
+        return Err(MetadataError::EntryAlreadyExists(name));
+    }
+    tree::move_entry(pool_root, store, &item.root(), &entry, &dir).await?;
+    store.put_entry(&dir, &name, &entry).await?;
+    store.remove_dir(&item.root()).await?;
+    trash::REGISTRY.write(pool_root, &registry).await?;
+    let path = journal::entry_path(&components, &name);
+    journal::record(pool_root, ChangeKind::Create, path, journal::cid_of(&entry)).await;
+    propagate_update(pool_root, store, &mut components, item.size as i64, entries as i64).await?;
+    log(LogLevel::Info, &format!("Restored '{}' from the trash.", item.path));
+    Ok(())
+}
+
+// Permanently removes an entry from the trash, releasing its blocks.
+pub async fn purge_trash(pool_root: &str, id: &str) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let _registry_lock = trash::REGISTRY.lock(pool_root).await?;
+    let mut registry = trash::REGISTRY.read(pool_root).await?;
+    let item = registry
+        .remove(id)
+        .filter(|item| item.moving.is_none())
+        .ok_or_else(|| MetadataError::TrashItemNotFound(id.to_string()))?;
+    // The item stays registered until it is purged, so a failed purge can be retried.
+    trash::purge(pool_root, store.as_ref(), &item).await?;
+    trash::REGISTRY.write(pool_root, &registry).await
+}
+
+// Takes a read-only snapshot of the directory at `rfs_dir_path` ("/" for the
+// whole pool). Only the top directory is locked while the tree is copied, so
+// concurrent writes deeper down may or may not be captured.
+pub async fn create_snapshot(
+    pool_root: &str,
+    rfs_dir_path: &str,
+    name: &str,
+) -> Result<SnapshotInfo, MetadataError> {
+    let name = &naming::normalize_name(pool_root, name).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let components = naming::split_path(pool_root, store, rfs_dir_path).await?;
+    let source = find_dir_path(store, &components).await?;
+
+    let _registry_lock = snapshot::REGISTRY.lock(pool_root).await?;
+    let mut registry = snapshot::REGISTRY.read(pool_root).await?;
+    if registry.contains_key(name) {
+        return Err(MetadataError::EntryAlreadyExists(name.to_string()));
+    }
+
+    let _lock = store.lock_dir(&source).await?;
+    let mut info = SnapshotInfo {
+        name: name.to_string(),
+        source: format!("/{}", components.join("/")),
+        cid: path_utils::generate_cid(),
+        size: 0,
+        created_at: Utc::now(),
+        deleting: false,
+    };
+    store.create_dir(&snapshot::snapshots_root()).await?;
+    let mut clone = TreeClone::new(pool_root, store, true);
+    info.size = match clone.clone_dir(&source, &info.root()).await {
+        Ok(size) => size,
+        Err(e) => {
+            clone.rollback().await;
+            return Err(e);
+        }
+    };
+
+    registry.insert(name.to_string(), info.clone());
+    snapshot::REGISTRY.write(pool_root, &registry).await?;
+    log(LogLevel::Info, &format!("Created snapshot '{}' of '{}'.", name, info.source));
+    Ok(info)
+}
+
+// Lists the snapshots of a pool, oldest first.
+pub async fn list_snapshots(pool_root: &str) -> Result<Vec<SnapshotInfo>, MetadataError> {
+    let mut snapshots: Vec<SnapshotInfo> =
+        snapshot::REGISTRY.read(pool_root).await?.into_values().filter(|s| !s.deleting).collect();
+    snapshots.sort_by_key(|s| s.created_at);
+    Ok(snapshots)
+}
+
+// Reads a directory inside a snapshot. `rfs_dir_path` is relative to the
+// directory the snapshot was taken of.
+pub async fn list_snapshot_directory(
+    pool_root: &str,
+    snapshot_name: &str,
+    rfs_dir_path: &str,
+) -> Result<DirectoryListing, MetadataError> {
+    let snapshot_name = &naming::normalize_name(pool_root, snapshot_name).await?;
+    let info = snapshot::get_snapshot(pool_root, snapshot_name).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    let components = naming::split_path_in(pool_root, store.as_ref(), info.root(), rfs_dir_path).await?;
+    let dir = find_dir_path_in(store.as_ref(), info.root(), &components).await?;
+    store.read_listing(&dir).await
+}
+
+// Reads the block map of a file inside a snapshot, e.g. to read its contents
+// with `block::read`.
+pub async fn get_snapshot_file_metadata(
+    pool_root: &str,
+    snapshot_name: &str,
+    rfs_file_path: &str,
+) -> Result<FileMetadata, MetadataError> {
+    let snapshot_name = &naming::normalize_name(pool_root, snapshot_name).await?;
+    let info = snapshot::get_snapshot(pool_root, snapshot_name).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    file_metadata_in(pool_root, store.as_ref(), info.root(), rfs_file_path).await
+}
+
+// Rolls the directory at `rfs_dir_path` (the snapshot's source if None) back
+// to the snapshot: its current contents are replaced by a copy of the
+// snapshot's, and blocks only the replaced contents used are released. The
+// snapshot itself is kept.
+pub async fn restore_snapshot(
+    pool_root: &str,
+    snapshot_name: &str,
+    rfs_dir_path: Option<&str>,
+) -> Result<(), MetadataError> {
+    let snapshot_name = &naming::normalize_name(pool_root, snapshot_name).await?;
+    let info = snapshot::get_snapshot(pool_root, snapshot_name).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let mut components = naming::split_path(pool_root, store, rfs_dir_path.unwrap_or(&info.source)).await?;
+    let target = resolve_dir_path(pool_root, store, &components).await?;
+
+    let _lock = store.lock_dir(&target).await?;
+    let mut clone = TreeClone::new(pool_root, store, true);
+    let mut restored = DirectoryListing::new();
+    for (name, entry) in store.read_listing(&info.root()).await? {
+        match clone.clone_entry(&info.root(), &entry, &target).await {
+            Ok(copied) => restored.insert(name, copied),
+            Err(e) => {
+                clone.rollback().await;
+                return Err(e);
+            }
+        };
+    }
+
+    let replaced = store.read_listing(&target).await?;
+    let old_size: u64 = replaced.values().map(tree::entry_size).sum();
+    let new_size: u64 = restored.values().map(tree::entry_size).sum();
+    let entries_delta = clone.entries() as i64 - count_entries(pool_root, target.clone()).await? as i64;
+    if let Err(e) = check_quotas(pool_root, store, &components, new_size as i64 - old_size as i64, entries_delta).await {
+        clone.rollback().await;
+        return Err(e);
+    }
+
+    store.write_listing(&target, &restored).await?;
+    for (name, entry) in &replaced {
+        tree::release_entry(pool_root, store, &target, entry).await?;
+        journal::record(pool_root, ChangeKind::Delete, journal::entry_path(&components, name), journal::cid_of(entry))
+            .await;
+    }
+    for (name, entry) in &restored {
+        journal::record(pool_root, ChangeKind::Create, journal::entry_path(&components, name), journal::cid_of(entry))
+            .await;
+    }
+    propagate_update(pool_root, store, &mut components, new_size as i64 - old_size as i64, entries_delta).await?;
+    log(
+        LogLevel::Info,
+        &format!("Restored snapshot '{}' to '{}'.", snapshot_name, rfs_dir_path.unwrap_or(&info.source)),
+    );
+    Ok(())
+}
+
+// Deletes a snapshot, releasing the blocks only it still referenced. The
+// snapshot stays registered, marked as deleting, until its tree is released,
+// so that its blocks keep being accounted for and a failed deletion can be
+// finished by deleting it again.
+pub async fn delete_snapshot(pool_root: &str, snapshot_name: &str) -> Result<(), MetadataError> {
+    let snapshot_name = &naming::normalize_name(pool_root, snapshot_name).await?;
+    let store = store::get_metadata_store(pool_root).await?;
+    let _registry_lock = snapshot::REGISTRY.lock(pool_root).await?;
+    let mut registry = snapshot::REGISTRY.read(pool_root).await?;
+    let info =
+        registry.get_mut(snapshot_name).ok_or_else(|| MetadataError::SnapshotNotFound(snapshot_name.to_string()))?;
+    if !info.deleting {
+        info.deleting = true;
+        snapshot::REGISTRY.write(pool_root, &registry).await?;
+    }
+
+    tree::release_dir(pool_root, store.as_ref(), &registry[snapshot_name].root()).await?;
+    registry.remove(snapshot_name);
+    snapshot::REGISTRY.write(pool_root, &registry).await
+}
+
+// Calls `visit` once for every reference the pool's metadata holds on a
+// stored block: file contents, file versions, the files held by snapshots
+// and the trash, and extended attribute values of files and directories.
+pub async fn visit_block_refs(
+    pool_root: &str,
+    mut visit: impl FnMut(&BlockInfo),
+) -> Result<(), MetadataError> {
+    let store = store::get_metadata_store(pool_root).await?;
+    let mut roots = vec![DirKey::root()];
+    roots.extend(snapshot::REGISTRY.read(pool_root).await?.values().map(SnapshotInfo::root));
+    roots.extend(trash::REGISTRY.read(pool_root).await?.values().map(TrashItem::root));
+
+    // A hard-linked block map is reached once per link, but visited only once.
+    let mut linked = HashSet::new();
+    for root in roots {
+        let mut entries = pin!(walk::walk_dir(pool_root, root, "/", WalkOptions::default()));
+        while let Some(item) = entries.next().await {
+            let item = item?;
+            let file_entry = match &item.entry {
+                Entry::File(file_entry) => file_entry,
+                Entry::Directory(info) => {
+                    xattr::blocks(&info.xattrs).for_each(&mut visit);
+                    continue;
+                }
+                Entry::Symlink(_) => continue,
+            };
+            if file_entry.linked && !linked.insert(file_entry.cid.clone()) {
+                continue;
+            }
+            visit_file_refs(store.as_ref(), &link::home(&item.dir, file_entry), &file_entry.cid, &mut visit).await?;
+        }
+    }
+    Ok(())
+}
+
+// Calls `visit` for every block the file `cid` stored in `home` references:
+// its contents, its prior versions and its extended attribute values.
+// Returns the file's block map.
+pub(crate) async fn visit_file_refs(
+    store: &dyn MetadataStore,
+    home: &DirKey,
+    cid: &str,
+    mut visit: impl FnMut(&BlockInfo),
+) -> Result<FileMetadata, MetadataError> {
+    let metadata = store.read_file_metadata(home, cid).await?;
+    for v in &metadata.versions {
+        let version = store.read_file_metadata(home, &version::version_id(cid, v.version)).await?;
+        version.blocks.values().filter(|b| !b.hole).for_each(&mut visit);
+    }
+    metadata.blocks.values().filter(|b| !b.hole).for_each(&mut visit);
+    xattr::blocks(&metadata.xattrs).for_each(&mut visit);
+    Ok(metadata)
+}
+
+// Fails with `QuotaExceeded` if adding `bytes` and `entries` below the
+// directory at `dir_components` would break the quota of that directory, of
+// one of its ancestors, or of an owner they are charged to. Directories on
+// the path that do not exist yet count as new entries. Shrinking always
+// passes. Limits are checked before a change is made, so concurrent writers
+// may overshoot them slightly.
+pub(crate) async fn check_quotas(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir_components: &[String],
+    bytes: i64,
+    entries: i64,
+) -> Result<(), MetadataError> {
+    let mut existing = Vec::new();
+    let mut dir = DirKey::root();
+    for component in dir_components {
+        match store.get_entry(&dir, component).await? {
+            Some(Entry::Directory(info)) => {
+                dir = dir.child(&info.cid);
+                existing.push(info);
+            }
+            _ => break,
+        }
+    }
+    let entries = entries + (dir_components.len() - existing.len()) as i64;
+    if bytes <= 0 && entries <= 0 {
+        return Ok(());
+    }
+
+    let mut owners = BTreeSet::new();
+    for (depth, info) in existing.iter().enumerate() {
+        if let Some(usage) = QuotaUsage::of_dir(info) {
+            let path = format!("'/{}'", dir_components[..=depth].join("/"));
+            usage.check(&path, bytes, entries)?;
+        }
+        owners.extend(info.quota.as_ref().and_then(|q| q.owner.clone()));
+    }
+    for owner in owners {
+        let usage = quota::owner_usage(pool_root, store, &owner).await?;
+        usage.check(&format!("owner '{}'", owner), bytes, entries)?;
+    }
+    Ok(())
+}
+
+// Counts the files, directories and symlinks below `dir`.
+pub(crate) async fn count_entries(pool_root: &str, dir: DirKey) -> Result<u64, MetadataError> {
+    let mut count = 0;
+    let mut entries = pin!(walk::walk_dir(pool_root, dir, "/", WalkOptions::default()));
+    while let Some(item) = entries.next().await {
+        item?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Brings the entries of every hard link to the linked file `cid` up to date
+// with its block map, along with the directories above them. Each link but
+// `changed`, the one the caller updated, gets a new revision, so the ETags of
+// all links change with the file. The links may be anywhere in the pool, so
+// the caller must not hold any locks.
+async fn update_links(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    cid: &str,
+    changed: (&DirKey, &str),
+) -> Result<(), MetadataError> {
+    for dir in link::link_dirs(store, cid).await? {
+        let _lock = store.lock_dir(&dir).await?;
+        let metadata = {
+            let _home_lock = store.lock_dir(&link::links_root()).await?;
+            store.read_file_metadata(&link::links_root(), cid).await?
+        };
+
+        let mut size_delta = 0;
+        for (name, entry) in store.read_listing(&dir).await? {
+            let Entry::File(mut file_entry) = entry else {
+                continue;
+            };
+            if !file_entry.linked || file_entry.cid != cid || (&dir, name.as_str()) == changed {
+                continue;
+            }
+            size_delta += metadata.size as i64 - file_entry.size as i64;
+            file_entry.size = metadata.size;
+            file_entry.modified_at = metadata.modified_at;
+            file_entry.revision += 1;
+            store.put_entry(&dir, &name, &Entry::File(file_entry)).await?;
+        }
+        // Links in the trash count towards no directory.
+        if size_delta != 0
+            && let Some(mut components) = dir_components(store, &dir).await?
+        {
+            propagate_update(pool_root, store, &mut components, size_delta, 0).await?;
+        }
+    }
+    Ok(())
+}
+
+// The path of the directory `dir`, found by looking each CID of its key up in
+// its parent's listing. None for a directory outside the tree, e.g. in the trash.
+async fn dir_components(store: &dyn MetadataStore, dir: &DirKey) -> Result<Option<Vec<String>>, MetadataError> {
+    let mut components = Vec::new();
+    let mut current = DirKey::root();
+    for cid in dir.cids() {
+        let listing = store.read_listing(&current).await?;
+        let name = listing.into_iter().find_map(|(name, entry)| match entry {
+            Entry::Directory(info) if info.cid == *cid => Some(name),
+            _ => None,
+        });
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        components.push(name);
+        current = current.child(cid);
+    }
+    Ok(Some(components))
+}
+
+// Recursively updates the size and modification time of parent directories,
+// and the entry counts of those with a quota.
 fn propagate_update<'a>(
     pool_root: &'a str,
+    store: &'a dyn MetadataStore,
     dir_components: &'a mut [String],
     size_delta: i64,
+    entries_delta: i64,
 ) -> BoxFuture<'a, Result<(), MetadataError>> {
     Box::pin(async move {
         if dir_components.is_empty() {
             return Ok(()); // Reached the root of the pool.
         }
 
-        if let Some((child_name, parent_components)) = dir_components.split_last_mut() {
-            let parent_path = resolve_dir_path(pool_root, parent_components).await?;
-            let parent_listing_path = parent_path.join(LISTING_FILE);
+        let _lock = update_dir_entry(pool_root, store, dir_components, size_delta, entries_delta).await?;
 
-            let _lock = FileLock::acquire(&parent_listing_path).await?;
-            let mut parent_listing = read_listing(&parent_path).await?;
+        // Recurse to the next level up.
+        let parent_len = dir_components.len() - 1;
+        propagate_update(pool_root, store, &mut dir_components[..parent_len], size_delta, entries_delta).await
+    })
+}
 
-            // Find the entry for the child directory and update it.
-            if let Some(Entry::Directory(dir_info)) = parent_listing.get_mut(child_name) {
-                dir_info.size = (dir_info.size as i64 + size_delta) as u64;
-                dir_info.modified_at = Utc::now();
-            }
+// Applies a change below the directory at `dir_components` (not the root) to
+// its entry in its parent: size, modification time, revision and quota entry
+// count. Returns the lock on the parent, which the caller may hold on to.
+pub(crate) async fn update_dir_entry(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir_components: &[String],
+    size_delta: i64,
+    entries_delta: i64,
+) -> Result<DirLock, MetadataError> {
+    let (child_name, parent_components) = dir_components.split_last().ok_or(MetadataError::EmptyPathComponent)?;
+    let parent_dir = resolve_dir_path(pool_root, store, parent_components).await?;
 
-            write_listing(&parent_path, &parent_listing).await?;
+    let lock = store.lock_dir(&parent_dir).await?;
 
-            // Recurse to the next level up.
-            propagate_update(pool_root, parent_components, size_delta).await?;
+    // Find the entry for the child directory and update it.
+    if let Some(Entry::Directory(mut dir_info)) = store.get_entry(&parent_dir, child_name).await? {
+        dir_info.size = (dir_info.size as i64 + size_delta) as u64;
+        dir_info.modified_at = Utc::now();
+        dir_info.revision += 1;
+        if let Some(quota) = &mut dir_info.quota {
+            quota.entries = quota.entries.saturating_add_signed(entries_delta);
         }
-
-        Ok(())
-    })
+        store.put_entry(&parent_dir, child_name, &Entry::Directory(dir_info)).await?;
+    }
+    Ok(lock)
 }
 
-// Resolves a virtual path to its physical metadata directory.
-async fn resolve_dir_path(
+// Resolves a virtual path to its directory key, creating missing directories.
+pub(crate) async fn resolve_dir_path(
     pool_root: &str,
+    store: &dyn MetadataStore,
     rfs_dir_components: &[String],
-) -> Result<PathBuf, MetadataError> {
-    let mut current_path = Path::new(pool_root).join(METADATA_DIR);
-    fs::create_dir_all(&current_path).await?;
+) -> Result<DirKey, MetadataError> {
+    if let Some(dir) = store.cached_dir(&DirKey::root(), rfs_dir_components).await? {
+        return Ok(dir);
+    }
+    let mut current_dir = DirKey::root();
+    store.create_dir(&current_dir).await?;
 
-    for component in rfs_dir_components {
-        let listing_path = current_path.join(LISTING_FILE);
-        let _lock = FileLock::acquire(&listing_path).await?;
-        let mut listing = read_listing(&current_path).await?;
+    for (depth, component) in rfs_dir_components.iter().enumerate() {
+        let _lock = store.lock_dir(&current_dir).await?;
 
-        let entry_info = match listing.get(component) {
-            Some(Entry::Directory(info)) => info.clone(),
-            Some(Entry::File(_)) => return Err(MetadataError::NotADirectory(component.clone())),
+        let entry_info = match store.get_entry(&current_dir, component).await? {
+            Some(Entry::Directory(info)) => info,
+            Some(_) => return Err(MetadataError::NotADirectory(component.clone())),
             None => {
                 let now = Utc::now();
                 let new_dir_info = DirectoryInfo {
@@ -124,46 +1404,52 @@ async fn resolve_dir_path(
                     size: 0,
                     created_at: now,
                     modified_at: now,
+                    xattrs: XattrMap::new(),
+                    quota: None,
+                    revision: 0,
                 };
-                listing.insert(component.clone(), Entry::Directory(new_dir_info.clone()));
-                write_listing(&current_path, &listing).await?;
+                store.put_entry(&current_dir, component, &Entry::Directory(new_dir_info.clone())).await?;
+                let path = journal::entry_path(&rfs_dir_components[..depth], component);
+                journal::record(pool_root, ChangeKind::Create, path, Some(&new_dir_info.cid)).await;
+                // The new directory counts as an entry of the directories above it.
+                propagate_update(pool_root, store, &mut rfs_dir_components[..depth].to_vec(), 0, 1).await?;
                 new_dir_info
             }
         };
-        current_path.push(entry_info.cid);
-        fs::create_dir_all(&current_path).await?;
+        current_dir = current_dir.child(&entry_info.cid);
+        store.create_dir(&current_dir).await?;
     }
-    Ok(current_path)
+    store.remember_dir(&DirKey::root(), rfs_dir_components, &current_dir);
+    Ok(current_dir)
 }
 
-// Reads and parses a metadata.json file.
-async fn read_listing(dir_path: &Path) -> Result<DirectoryListing, MetadataError> {
-    let listing_path = dir_path.join(LISTING_FILE);
-    if !listing_path.exists() {
-        return Ok(DirectoryListing::new());
-    }
-    let content = fs::read(listing_path).await?;
-    Ok(serde_json::from_slice(&content)?)
+// Resolves a virtual path to its directory key without creating missing
+// directories along the way.
+pub(crate) async fn find_dir_path(
+    store: &dyn MetadataStore,
+    rfs_dir_components: &[String],
+) -> Result<DirKey, MetadataError> {
+    find_dir_path_in(store, DirKey::root(), rfs_dir_components).await
 }
 
-// Writes a DirectoryListing to a metadata.json file.
-async fn write_listing(
-    dir_path: &Path,
-    listing: &DirectoryListing,
-) -> Result<(), MetadataError> {
-    let content = serde_json::to_vec_pretty(listing)?;
-    fs::write(dir_path.join(LISTING_FILE), content).await?;
-    Ok(())
-}
+// Like `find_dir_path`, but resolves relative to `base`, e.g. a snapshot root.
+pub(crate) async fn find_dir_path_in(
+    store: &dyn MetadataStore,
+    base: DirKey,
+    rfs_dir_components: &[String],
+) -> Result<DirKey, MetadataError> {
+    if let Some(dir) = store.cached_dir(&base, rfs_dir_components).await? {
+        return Ok(dir);
+    }
+    let mut current_dir = base.clone();
 
-// Writes the detailed FileMetadata (block map) to its {cid}.json file.
-async fn write_file_block_map(
-    dir_path: &Path,
-    cid: &str,
-    metadata: &FileMetadata,
-) -> Result<(), MetadataError> {
-    let content = serde_json::to_vec_pretty(metadata)?;
-    let meta_path = dir_path.join(format!("{}.json", cid));
-fs::write(meta_path, &content).await?;
-    Ok(())
+    for component in rfs_dir_components {
+        match store.get_entry(&current_dir, component).await? {
+            Some(Entry::Directory(info)) => current_dir = current_dir.child(&info.cid),
+            Some(_) => return Err(MetadataError::NotADirectory(component.clone())),
+            None => return Err(MetadataError::NotFound(rfs_dir_components.join("/"))),
+        }
+    }
+    store.remember_dir(&base, rfs_dir_components, &current_dir);
+    Ok(current_dir)
 }
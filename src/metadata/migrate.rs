@@ -0,0 +1,247 @@
+// src/metadata/migrate.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config::{self, MetadataBackend};
+use crate::metadata::error::MetadataError;
+use crate::metadata::link;
+use crate::metadata::model::Entry;
+use crate::metadata::search_index;
+use crate::metadata::snapshot::{self, SnapshotInfo};
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use crate::metadata::trash::{self, TrashItem};
+use crate::metadata::version;
+use rfs_utils::{log, LogLevel};
+use std::path::Path;
+
+// Counts of what a migration copied.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MigrationStats {
+    pub directories: u64,
+    pub files: u64,
+}
+
+// Converts the metadata of the pool at `pool_root` to the `target` backend and
+// switches the pool's config.json over to it. The pool must not be mounted
+// while this runs. The source metadata is left in place, so a migration can be
+// undone by migrating back.
+pub async fn migrate(pool_root: &str, target: MetadataBackend) -> Result<MigrationStats, MetadataError> {
+    let pool_config = config::get_pool_config(pool_root).await?;
+    let source_backend = pool_config.metadata_backend;
+    if source_backend == target {
+        return Err(MetadataError::Migration(format!(
+            "pool already uses the {:?} metadata backend",
+            target
+        )));
+    }
+
+    let source = store::get_metadata_store(pool_root).await?;
+    let roots = metadata_roots(pool_root).await?;
+    let destination = store::open_backend(pool_root, target).await?;
+    // Metadata left behind by an earlier migration away from `target` is stale.
+    // An interrupted migration copies the pool root last, so it may be empty
+    // while the other roots are not.
+    let mut stale = false;
+    for root in &roots {
+        if !destination.read_listing(root).await?.is_empty() {
+            stale = true;
+            break;
+        }
+    }
+    if stale {
+        log(
+            LogLevel::Warn,
+            &format!("Discarding stale {:?} metadata of '{}'.", target, pool_root),
+        );
+        clear_tree(destination.as_ref(), &roots).await?;
+    }
+
+    log(
+        LogLevel::Info,
+        &format!("Migrating metadata of '{}' from {:?} to {:?}.", pool_root, source_backend, target),
+    );
+    let stats = copy_tree(source.as_ref(), destination.as_ref(), &roots).await?;
+    set_metadata_backend(pool_root, target).await?;
+    config::invalidate_pool_config(pool_root);
+    store::close_metadata_store(pool_root);
+    // The search index has not seen the changes made while it was unused.
+    search_index::discard(pool_root).await?;
+    log(
+        LogLevel::Info,
+        &format!(
+            "Migrated {} directories and {} files of '{}'.",
+            stats.directories, stats.files, pool_root
+        ),
+    );
+    Ok(stats)
+}
+
+// The directories the metadata of a pool hangs off: the root, and those of the
+// hard-link directory, snapshots and trashed entries.
+pub(crate) async fn metadata_roots(pool_root: &str) -> Result<Vec<DirKey>, MetadataError> {
+    // The link directory holds the block maps shared by hard links.
+    let mut roots = vec![DirKey::root(), link::links_root()];
+    roots.extend(snapshot::REGISTRY.read(pool_root).await?.values().map(SnapshotInfo::root));
+    roots.extend(trash::REGISTRY.read(pool_root).await?.values().map(TrashItem::root));
+    Ok(roots)
+}
+
+// Copies every listing and block map below `roots` from one store to another.
+pub async fn copy_tree(
+    from: &dyn MetadataStore,
+    to: &dyn MetadataStore,
+    roots: &[DirKey],
+) -> Result<MigrationStats, MetadataError> {
+    let mut stats = MigrationStats::default();
+    // Create every root up front: hard-linked block maps are copied into the
+    // link directory while the other roots are walked.
+    for root in roots {
+        to.create_dir(root).await?;
+    }
+    let mut pending = roots.to_vec();
+    while let Some(dir) = pending.pop() {
+        to.create_dir(&dir).await?;
+        let listing = from.read_listing(&dir).await?;
+        for entry in listing.values() {
+            match entry {
+                Entry::File(file_entry) => {
+                    // A hard-linked block map is copied again for each link; that is harmless.
+                    let home = link::home(&dir, file_entry);
+                    let metadata = from.read_file_metadata(&home, &file_entry.cid).await?;
+                    for v in &metadata.versions {
+                        let id = version::version_id(&file_entry.cid, v.version);
+                        to.write_file_metadata(&home, &id, &from.read_file_metadata(&home, &id).await?).await?;
+                    }
+                    to.write_file_metadata(&home, &file_entry.cid, &metadata).await?;
+                    stats.files += 1;
+                }
+                Entry::Directory(info) => pending.push(dir.child(&info.cid)),
+                Entry::Symlink(_) => {}
+            }
+        }
+        to.write_listing(&dir, &listing).await?;
+        stats.directories += 1;
+    }
+    Ok(stats)
+}
+
+// Removes every directory below `roots`, deepest first.
+async fn clear_tree(store: &dyn MetadataStore, roots: &[DirKey]) -> Result<(), MetadataError> {
+    let mut dirs = Vec::new();
+    let mut pending = roots.to_vec();
+    while let Some(dir) = pending.pop() {
+        for entry in store.read_listing(&dir).await?.values() {
+            if let Entry::Directory(info) = entry {
+                pending.push(dir.child(&info.cid));
+            }
+        }
+        dirs.push(dir);
+    }
+    for dir in dirs.iter().rev() {
+        store.remove_dir(dir).await?;
+    }
+    Ok(())
+}
+
+// Rewrites the `metadataBackend` key of config.json, keeping every other key as written.
+async fn set_metadata_backend(pool_root: &str, backend: MetadataBackend) -> Result<(), MetadataError> {
+    let config_path = Path::new(pool_root).join(config::POOL_CONFIG_FILE);
+    let mut value = match tokio::fs::read(&config_path).await {
+        Ok(content) => serde_json::from_slice(&content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => serde_json::json!({}),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(object) = value.as_object_mut() else {
+        return Err(MetadataError::Migration(format!("{} is not a JSON object", config_path.display())));
+    };
+    object.insert("metadataBackend".to_string(), serde_json::to_value(backend)?);
+    tokio::fs::write(&config_path, serde_json::to_vec_pretty(&value)?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+
+    const CONFIG: &str =
+        r#"{"metadataBackend":"json","trash":{"enabled":true},"versionRetention":{"maxVersions":5}}"#;
+
+    // What the tests compare across backends.
+    async fn describe(root: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        for path in ["/d/f", "/d/g"] {
+            let metadata = manager::get_file_metadata(root, path).await.unwrap();
+            lines.push(format!("{} {} {}", path, metadata.size, metadata.links));
+        }
+        for v in manager::list_versions(root, "/d/f").await.unwrap() {
+            let metadata = manager::get_version_metadata(root, "/d/f", v.version).await.unwrap();
+            lines.push(format!("version {} {}", v.version, metadata.size));
+        }
+        let snapshot = manager::get_snapshot_file_metadata(root, "s", "/g").await.unwrap();
+        lines.push(format!("snapshot /g {} {}", snapshot.size, snapshot.links));
+        let store = store::get_metadata_store(root).await.unwrap();
+        for item in manager::list_trash(root).await.unwrap() {
+            let Some(Entry::Directory(info)) = store.get_entry(&item.root(), "t").await.unwrap() else {
+                panic!("the item holds no directory 't'");
+            };
+            let names = store.read_listing(&item.root().child(&info.cid)).await.unwrap().into_keys();
+            lines.push(format!("trash {} {:?}", item.path, names.collect::<Vec<_>>()));
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn a_round_trip_keeps_snapshots_trash_links_and_versions() {
+        let pool = TestPool::new("migrate-round-trip", CONFIG);
+        let root = pool.root.as_str();
+        manager::replace_file(root, "/d", "f", test_util::file_metadata("f", 1)).await.unwrap();
+        manager::replace_file(root, "/d", "f", test_util::file_metadata("f", 2)).await.unwrap();
+        manager::create_hard_link(root, "/d/f", "/d/g").await.unwrap();
+        manager::create_snapshot(root, "/d", "s").await.unwrap();
+        manager::create_file(root, "/t", "x", test_util::file_metadata("x", 3)).await.unwrap();
+        manager::delete_entry(root, "/t").await.unwrap();
+        let before = describe(root).await;
+        assert_eq!(before.len(), 5);
+
+        migrate(root, MetadataBackend::Db).await.unwrap();
+        assert_eq!(describe(root).await, before);
+        migrate(root, MetadataBackend::Json).await.unwrap();
+        assert_eq!(config::get_pool_config(root).await.unwrap().metadata_backend, MetadataBackend::Json);
+        assert_eq!(describe(root).await, before);
+    }
+
+    #[tokio::test]
+    async fn stale_metadata_below_any_root_is_discarded() {
+        let pool = TestPool::new("migrate-stale", CONFIG);
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d/e", "f", test_util::file_metadata("f", 1)).await.unwrap();
+        let snapshot_root = manager::create_snapshot(root, "/d", "s").await.unwrap().root();
+        manager::delete_entry(root, "/d").await.unwrap();
+        manager::purge_trash(root, &manager::list_trash(root).await.unwrap()[0].id).await.unwrap();
+
+        // Leftovers of an interrupted migration, below the snapshot only.
+        let json = store::get_metadata_store(root).await.unwrap();
+        let mut listing = json.read_listing(&snapshot_root).await.unwrap();
+        let Some(Entry::Directory(info)) = listing.get_mut("e") else {
+            panic!("the snapshot holds no directory 'e'");
+        };
+        let fresh = snapshot_root.child(&info.cid);
+        info.cid = "stale".to_string();
+        let stale = snapshot_root.child("stale");
+        {
+            let db = store::open_backend(root, MetadataBackend::Db).await.unwrap();
+            db.create_dir(&snapshot_root).await.unwrap();
+            db.write_listing(&snapshot_root, &listing).await.unwrap();
+            db.create_dir(&stale).await.unwrap();
+            db.write_listing(&stale, &json.read_listing(&fresh).await.unwrap()).await.unwrap();
+        }
+
+        migrate(root, MetadataBackend::Db).await.unwrap();
+        let db = store::get_metadata_store(root).await.unwrap();
+        assert!(db.read_listing(&stale).await.unwrap().is_empty());
+        assert_eq!(manager::list_snapshot_directory(root, "s", "/").await.unwrap().len(), 1);
+        assert_eq!(manager::get_snapshot_file_metadata(root, "s", "/e/f").await.unwrap().size, 1);
+    }
+}
@@ -2,8 +2,33 @@
 // SPDX-License-Identifier: AGPL-3.0
 // Copyright (c) 2025 Canmi
 
+pub mod batch;
+pub mod cache;
+pub mod db;
+pub mod du;
 pub mod error;
+pub mod format;
+pub mod journal;
+pub mod json;
+pub mod link;
+pub mod listing;
 pub mod lock;
 pub mod manager;
+pub mod migrate;
 pub mod model;
+pub mod naming;
 pub mod path_utils;
+pub mod precondition;
+pub mod quota;
+pub mod registry;
+pub mod search;
+pub mod search_index;
+pub mod snapshot;
+pub mod store;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod trash;
+pub mod tree;
+pub mod version;
+pub mod walk;
+pub mod xattr;
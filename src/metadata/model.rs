@@ -11,18 +11,95 @@ use std::collections::{BTreeMap, HashMap};
 pub struct BlockInfo {
     pub xxh3: u128,
     pub index: u32,
+    // An all-zero chunk that was never written to the block store.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hole: bool,
+}
+
+impl BlockInfo {
+    // A block stored in the pool under `{xxh3}-{index}`.
+    pub fn stored(xxh3: u128, index: u32) -> Self {
+        BlockInfo { xxh3, index, hole: false }
+    }
+
+    // A chunk of zeros that is synthesized on read instead of stored.
+    pub fn hole() -> Self {
+        BlockInfo { xxh3: 0, index: 0, hole: true }
+    }
 }
 
 // Represents the full metadata for a single file, stored in its {cid}.json file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct FileMetadata {
-    pub filename: String, // The original filename is now stored here.
+    // The original filename. Block maps written before format 2 lack it.
+    #[serde(default)]
+    pub filename: String,
     pub size: u64,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
     // BTreeMap ensures blocks are ordered by their sequence number.
     pub blocks: BTreeMap<u64, BlockInfo>,
+    // Prior contents of the file, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<VersionInfo>,
+    // The number of directory entries (hard links) sharing this block map.
+    #[serde(default = "single_link", skip_serializing_if = "is_single_link")]
+    pub links: u32,
+    // The directories holding those links, as `DirKey` strings, once per
+    // link. Changes to the file are carried over to every link through them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_dirs: Vec<String>,
+    // User-defined extended attributes. They belong to the file rather than
+    // its contents, so they carry over when the contents are replaced.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: XattrMap,
+}
+
+fn single_link() -> u32 {
+    1
+}
+
+fn is_single_link(links: &u32) -> bool {
+    *links == 1
+}
+
+// The value of an extended attribute.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum XattrValue {
+    // A small value kept in the metadata itself, hex-encoded.
+    Inline(#[serde(with = "hex_bytes")] Vec<u8>),
+    // A large value stored as data blocks, chunked like file contents.
+    Blocks { size: u64, blocks: Vec<BlockInfo> },
+}
+
+// Extended attributes by name.
+pub type XattrMap = BTreeMap<String, XattrValue>;
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+// A prior version of a file. Its block map is stored next to the file's own,
+// under `{cid}.v{version}`, and keeps its blocks referenced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub version: u32,
+    pub size: u64,
+    // When this content was last modified.
+    pub modified_at: DateTime<Utc>,
+    // When this content stopped being the current one.
+    pub replaced_at: DateTime<Utc>,
 }
 
 // Represents a file's entry within a directory's metadata.json.
@@ -33,6 +110,14 @@ pub struct FileEntry {
     pub size: u64,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
+    // The block map is shared with other hard links and is stored in the
+    // pool's link directory instead of next to this entry.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub linked: bool,
+    // Counts the changes to the file's contents and attributes made through
+    // this entry. Together with the CID it forms the entry's ETag.
+    #[serde(default)]
+    pub revision: u64,
 }
 
 // Represents a directory's entry within its parent's metadata.json.
@@ -43,14 +128,72 @@ pub struct DirectoryInfo {
     pub size: u64,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
+    // User-defined extended attributes of the directory.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: XattrMap,
+    // Limits on the tree below the directory, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<DirQuota>,
+    // Counts the changes to the entry, including those of its size and
+    // modification time caused by changes below the directory.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+// The quota of a directory, kept on its entry. Usage covers everything below
+// the directory at any depth: bytes are the directory's aggregated size, and
+// entries are counted here as they are added and removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DirQuota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<u64>,
+    // The owner everything below the directory is charged to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    // Files, directories and symlinks below the directory.
+    #[serde(default)]
+    pub entries: u64,
+}
+
+impl DirQuota {
+    // A quota without limits or owner has nothing left to enforce.
+    pub fn is_unset(&self) -> bool {
+        self.max_bytes.is_none() && self.max_entries.is_none() && self.owner.is_none()
+    }
 }
 
-// An enum representing either a File or a Directory in a listing.
+// Represents a symbolic link. The target is stored as given and is never
+// resolved by the metadata layer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SymlinkInfo {
+    pub target: String,
+    pub created_at: DateTime<Utc>,
+    pub modified_at: DateTime<Utc>,
+}
+
+// An enum representing a File, a Directory or a Symlink in a listing.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum Entry {
     File(FileEntry),
     Directory(DirectoryInfo),
+    Symlink(SymlinkInfo),
+}
+
+impl Entry {
+    // Identifies the current state of a file or directory entry, for
+    // conditional operations. Symlinks have none.
+    pub fn etag(&self) -> Option<String> {
+        match self {
+            Entry::File(file_entry) => Some(format!("{}-{}", file_entry.cid, file_entry.revision)),
+            Entry::Directory(info) => Some(format!("{}-{}", info.cid, info.revision)),
+            Entry::Symlink(_) => None,
+        }
+    }
 }
 
 // Represents the content of a metadata.json file, mapping names to entries.
@@ -0,0 +1,123 @@
+// src/metadata/naming.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config;
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::Entry;
+use crate::metadata::path_utils;
+use crate::metadata::store::{DirKey, MetadataStore};
+
+// Splits and validates a path under the pool's naming policy. With
+// case-insensitive lookup, each component naming an existing entry is
+// replaced by that entry's stored spelling.
+pub(crate) async fn split_path(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    path: &str,
+) -> Result<Vec<String>, MetadataError> {
+    split_path_in(pool_root, store, DirKey::root(), path).await
+}
+
+// Like `split_path`, but for a path relative to `base`, e.g. a snapshot root.
+pub(crate) async fn split_path_in(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    base: DirKey,
+    path: &str,
+) -> Result<Vec<String>, MetadataError> {
+    let naming = config::get_pool_config(pool_root).await?.naming;
+    let mut components = path_utils::validate_and_split_path(path, naming.mode)?;
+    if !naming.case_insensitive {
+        return Ok(components);
+    }
+
+    let mut dir = base;
+    for component in components.iter_mut() {
+        let Some((name, entry)) = find_entry(store, &dir, component).await? else {
+            break;
+        };
+        *component = name;
+        match entry {
+            Entry::Directory(info) => dir = dir.child(&info.cid),
+            _ => break,
+        }
+    }
+    Ok(components)
+}
+
+// Validates and normalizes a name that is not part of a path, e.g. a snapshot name.
+pub(crate) async fn normalize_name(pool_root: &str, name: &str) -> Result<String, MetadataError> {
+    let naming = config::get_pool_config(pool_root).await?.naming;
+    path_utils::validate_component(name, naming.mode)
+}
+
+// Validates and normalizes the name of an entry in `dir`, resolving it to the
+// stored spelling of an existing entry when lookups are case-insensitive.
+pub(crate) async fn child_name(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    name: &str,
+) -> Result<String, MetadataError> {
+    let naming = config::get_pool_config(pool_root).await?.naming;
+    let name = path_utils::validate_component(name, naming.mode)?;
+    if !naming.case_insensitive {
+        return Ok(name);
+    }
+    Ok(find_entry(store, dir, &name).await?.map_or(name, |(stored, _)| stored))
+}
+
+// Finds the entry `name` in `dir`, falling back to one whose name differs only
+// in case. Names are compared by full Unicode case folding, see
+// `path_utils::fold_case`, so "STRASSE" finds "Straße", which lowercasing
+// alone would not.
+async fn find_entry(
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    name: &str,
+) -> Result<Option<(String, Entry)>, MetadataError> {
+    if let Some(entry) = store.get_entry(dir, name).await? {
+        return Ok(Some((name.to_string(), entry)));
+    }
+    store.find_folded(dir, &path_utils::fold_case(name)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::model::SymlinkInfo;
+    use crate::metadata::store;
+    use crate::metadata::test_util::TestPool;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn names_resolve_regardless_of_case_in_large_directories() {
+        for backend in ["json", "db"] {
+            let config = format!(r#"{{"metadataBackend":"{}","naming":{{"caseInsensitive":true}}}}"#, backend);
+            let pool = TestPool::new(&format!("naming-fold-{}", backend), &config);
+            let store = store::get_metadata_store(&pool.root).await.unwrap();
+            let dir = DirKey::root().child("abcde");
+            store.create_dir(&dir).await.unwrap();
+
+            // Enough entries to shard a JSON listing.
+            let entry =
+                Entry::Symlink(SymlinkInfo { target: "t".into(), created_at: Utc::now(), modified_at: Utc::now() });
+            let listing = (0..3000).map(|i| (format!("Entry{}", i), entry.clone())).collect();
+            store.write_listing(&dir, &listing).await.unwrap();
+            store.put_entry(&dir, "Stra\u{df}e", &entry).await.unwrap();
+
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "ENTRY2999").await.unwrap(), "Entry2999");
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "entry17").await.unwrap(), "Entry17");
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "STRA\u{df}E").await.unwrap(), "Stra\u{df}e");
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "strasse").await.unwrap(), "Stra\u{df}e");
+            // A decomposed spelling is normalized before it is looked up.
+            let decomposed = "Cafe\u{301}";
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, decomposed).await.unwrap(), "Caf\u{e9}");
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "missing").await.unwrap(), "missing");
+
+            store.remove_entry(&dir, "Entry17").await.unwrap();
+            assert_eq!(child_name(&pool.root, store.as_ref(), &dir, "entry17").await.unwrap(), "entry17");
+        }
+    }
+}
@@ -2,16 +2,26 @@
 // SPDX-License-Identifier: AGPL-3.0
 // Copyright (c) 2025 Canmi
 
+use crate::common::config::NamingMode;
 use crate::metadata::error::MetadataError;
+use icu_normalizer::ComposingNormalizer;
 use once_cell::sync::Lazy;
 use rand::Rng;
 use regex::Regex;
 use unicode_segmentation::UnicodeSegmentation;
 
 const CID_LENGTH: usize = 5;
+// Pseudo-directories of the metadata store, such as the trash, sit under the
+// root key next to the CIDs of top-level directories. Their names are longer
+// than a CID, so they never collide with a real directory; `reserved_dir`
+// checks this when the name is defined.
+pub(crate) const fn reserved_dir(name: &'static str) -> &'static str {
+    assert!(name.len() > CID_LENGTH, "reserved directory names must be longer than a CID");
+    name
+}
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
 
-// Regex for validating safe characters in file/directory names.
+// Regex for validating safe characters in file/directory names (`NamingMode::Safe`).
 static SAFE_NAME_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^[\p{L}\p{N}_\-\.\@\~\(\)\[\]]+$").unwrap());
 
@@ -27,36 +37,66 @@ pub fn generate_cid() -> String {
         .collect()
 }
 
-// Validates a single component of a path (a file or directory name).
-pub fn validate_component(name: &str) -> Result<(), MetadataError> {
+// The form names are stored in, whatever form they were given in.
+pub fn normalize(name: &str) -> String {
+    ComposingNormalizer::new_nfc().normalize(name).into_owned()
+}
+
+// The form names are compared in when lookups are case-insensitive: the full
+// Unicode case folding of the name, so that e.g. "STRASSE" matches "Straße",
+// normalized again as folding may decompose characters. Sharded JSON buckets
+// and the db's folded index are keyed by it, so changing it needs a format step.
+pub fn fold_case(name: &str) -> String {
+    normalize(&caseless::default_case_fold_str(name))
+}
+
+// Validates a single component of a path (a file or directory name) under
+// the naming `mode` and returns its NFC-normalized form.
+pub fn validate_component(name: &str, mode: NamingMode) -> Result<String, MetadataError> {
     if name.is_empty() {
         return Err(MetadataError::EmptyPathComponent);
     }
-    // Check for allowed character set.
-    if !SAFE_NAME_REGEX.is_match(name) {
-        return Err(MetadataError::InvalidPathComponent(name.to_string()));
+    let name = normalize(name);
+    // '.' and '..' would shadow path navigation in every mode. A '..' inside
+    // a longer name is harmless: components never span a '/'.
+    let valid = name != "."
+        && name != ".."
+        && match mode {
+            NamingMode::Posix => !name.contains(['/', '\0']),
+            // Check for allowed character set.
+            NamingMode::Safe => SAFE_NAME_REGEX.is_match(&name) && !name.ends_with('.'),
+            NamingMode::Windows => is_windows_name(&name),
+        };
+    if !valid {
+        return Err(MetadataError::InvalidPathComponent(name));
     }
-    // Handle special cases for '.'.
-    if name == "." || name == ".." || name.ends_with('.') || name.contains("..") {
-        return Err(MetadataError::InvalidPathComponent(name.to_string()));
+    Ok(name)
+}
+
+fn is_windows_name(name: &str) -> bool {
+    if name.chars().any(|c| c.is_control() || "<>:\"/\\|?*".contains(c)) || name.ends_with(['.', ' ']) {
+        return false;
     }
-    Ok(())
+    // Device names are reserved with any extension, e.g. "nul.txt".
+    let stem = name.split('.').next().unwrap_or(name).trim_end().to_ascii_uppercase();
+    let device = match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit()
+            && stem.as_bytes()[3] != b'0',
+    };
+    !device
 }
 
 // Splits a virtual path into its components and validates each one.
-// Returns a Vec of owned Strings to avoid lifetime issues.
-pub fn validate_and_split_path(path: &str) -> Result<Vec<String>, MetadataError> {
-    let components: Vec<String> = path
-        .trim_matches('/')
+// Returns a Vec of owned, normalized Strings to avoid lifetime issues.
+pub fn validate_and_split_path(path: &str, mode: NamingMode) -> Result<Vec<String>, MetadataError> {
+    path.trim_matches('/')
         .graphemes(true)
         .collect::<String>()
         .split('/')
         .filter(|s| !s.is_empty())
-        .map(String::from)
-        .collect();
-
-    for component in &components {
-        validate_component(component)?;
-    }
-    Ok(components)
+        .map(|component| validate_component(component, mode))
+        .collect()
 }
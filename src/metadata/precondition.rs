@@ -0,0 +1,123 @@
+// src/metadata/precondition.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::Entry;
+
+// Conditions on the current state of an entry for a change to go ahead,
+// modelled on the HTTP `If-Match` and `If-None-Match` headers. Each holds
+// `*` or a comma-separated list of ETags, as returned by `Entry::etag`;
+// quotes and weak `W/` prefixes are ignored. Unset conditions always pass.
+#[derive(Debug, Clone, Default)]
+pub struct Precondition {
+    // Proceed only if the entry exists and, unless `*`, has one of these ETags.
+    pub if_match: Option<String>,
+    // Proceed only if the entry does not exist or, unless `*`, has none of these ETags.
+    pub if_none_match: Option<String>,
+}
+
+impl Precondition {
+    pub fn if_match(etags: &str) -> Self {
+        Precondition { if_match: Some(etags.to_string()), if_none_match: None }
+    }
+
+    pub fn if_none_match(etags: &str) -> Self {
+        Precondition { if_match: None, if_none_match: Some(etags.to_string()) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    // Fails with `PreconditionFailed` unless the entry at `path`, None if
+    // there is none, satisfies both conditions.
+    pub(crate) fn check(&self, path: &str, entry: Option<&Entry>) -> Result<(), MetadataError> {
+        let etag = entry.and_then(Entry::etag);
+        if let Some(etags) = &self.if_match
+            && !(entry.is_some() && matches(etags, etag.as_deref()))
+        {
+            return Err(MetadataError::PreconditionFailed(format!("'{}' does not match {}", path, etags)));
+        }
+        if let Some(etags) = &self.if_none_match
+            && entry.is_some()
+            && matches(etags, etag.as_deref())
+        {
+            return Err(MetadataError::PreconditionFailed(format!("'{}' matches {}", path, etags)));
+        }
+        Ok(())
+    }
+}
+
+// Whether an existing entry with `etag` matches the list `etags`.
+fn matches(etags: &str, etag: Option<&str>) -> bool {
+    etags.split(',').map(|t| t.trim().trim_start_matches("W/").trim_matches('"')).any(|t| t == "*" || Some(t) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+
+    async fn etag(pool_root: &str, rfs_path: &str) -> String {
+        manager::get_entry(pool_root, rfs_path).await.unwrap().etag().unwrap()
+    }
+
+    fn failed<T: std::fmt::Debug>(result: Result<T, MetadataError>) -> bool {
+        matches!(result, Err(MetadataError::PreconditionFailed(_)))
+    }
+
+    #[tokio::test]
+    async fn only_one_conditional_create_wins() {
+        let pool = TestPool::new("precondition-create", "{}");
+        let root = pool.root.as_str();
+        let create = Precondition::if_none_match("*");
+        let creates =
+            (0..4).map(|i| manager::replace_file_if(root, "/d", "f", test_util::file_metadata("f", i), &create));
+        let results = futures::future::join_all(creates).await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.into_iter().filter(Result::is_err).all(failed));
+    }
+
+    #[tokio::test]
+    async fn every_hard_link_gets_a_new_etag() {
+        let pool = TestPool::new("precondition-links", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/a", "f", test_util::file_metadata("f", 1)).await.unwrap();
+        manager::create_hard_link(root, "/a/f", "/b/g").await.unwrap();
+
+        let before = etag(root, "/b/g").await;
+        manager::replace_file(root, "/a", "f", test_util::file_metadata("f", 2)).await.unwrap();
+        let replaced = etag(root, "/b/g").await;
+        assert_ne!(replaced, before);
+        manager::set_xattr(root, "/a/f", "user.k", b"v").await.unwrap();
+        assert_ne!(etag(root, "/b/g").await, replaced);
+        assert!(failed(manager::set_xattr_if(root, "/b/g", "user.k", b"w", &Precondition::if_match(&replaced)).await));
+
+        let current = Precondition::if_match(&etag(root, "/b/g").await);
+        manager::set_xattr_if(root, "/b/g", "user.k", b"w", &current).await.unwrap();
+        assert_eq!(manager::get_xattr(root, "/a/f", "user.k").await.unwrap(), b"w");
+    }
+
+    #[tokio::test]
+    async fn conditional_renames_copies_and_links() {
+        let pool = TestPool::new("precondition-ops", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d", "f", test_util::file_metadata("f", 1)).await.unwrap();
+        let stale = Precondition::if_match("\"stale-0\"");
+        let current = Precondition::if_match(&etag(root, "/d/f").await);
+
+        assert!(failed(manager::rename_entry_if(root, "/d/f", "/d/g", &stale).await));
+        assert!(failed(manager::copy_entry_if(root, "/d/f", "/d/c", &stale).await));
+        assert!(failed(manager::create_hard_link_if(root, "/d/f", "/d/l", &stale).await));
+        assert_eq!(manager::list_directory(root, "/d").await.unwrap().len(), 1);
+
+        manager::copy_entry_if(root, "/d/f", "/d/c", &current).await.unwrap();
+        manager::rename_entry_if(root, "/d/f", "/d/g", &current).await.unwrap();
+        let create = Precondition::if_none_match("*");
+        assert!(failed(manager::create_symlink_if(root, "/d", "g", "c", &create).await));
+        manager::create_symlink_if(root, "/d", "s", "c", &create).await.unwrap();
+        assert_eq!(manager::list_directory(root, "/d").await.unwrap().len(), 3);
+    }
+}
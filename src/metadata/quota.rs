@@ -0,0 +1,202 @@
+// src/metadata/quota.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{DirectoryInfo, Entry};
+use crate::metadata::registry::JsonRegistry;
+use crate::metadata::store::{DirKey, MetadataStore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+const QUOTAS_FILE: &str = "quotas.json";
+
+// The limits of one owner, applied to the sum of the directories charged to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerQuota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<u64>,
+    // Keys of the directories charged to the owner, as in `DirKey::as_string`.
+    #[serde(default)]
+    pub dirs: BTreeSet<String>,
+}
+
+// The registry of a pool's owner quotas, `{pool}/quotas.json`, keyed by owner.
+pub type QuotaRegistry = BTreeMap<String, OwnerQuota>;
+
+// Current usage of a directory or owner against its limits.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaUsage {
+    pub bytes: u64,
+    pub entries: u64,
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+}
+
+impl QuotaUsage {
+    // The usage of a directory against its quota, if it has one.
+    pub(crate) fn of_dir(info: &DirectoryInfo) -> Option<Self> {
+        info.quota.as_ref().map(|quota| QuotaUsage {
+            bytes: info.size,
+            entries: quota.entries,
+            max_bytes: quota.max_bytes,
+            max_entries: quota.max_entries,
+        })
+    }
+
+    // Fails if growing by `bytes` and `entries` would exceed a limit.
+    // `subject` names the directory or owner in the error.
+    pub(crate) fn check(&self, subject: &str, bytes: i64, entries: i64) -> Result<(), MetadataError> {
+        if bytes > 0
+            && let Some(max) = self.max_bytes
+            && self.bytes.saturating_add(bytes as u64) > max
+        {
+            return Err(MetadataError::QuotaExceeded(format!(
+                "{} uses {} of {} bytes, {} more requested",
+                subject, self.bytes, max, bytes
+            )));
+        }
+        if entries > 0
+            && let Some(max) = self.max_entries
+            && self.entries.saturating_add(entries as u64) > max
+        {
+            return Err(MetadataError::QuotaExceeded(format!(
+                "{} holds {} of {} entries, {} more requested",
+                subject, self.entries, max, entries
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub const REGISTRY: JsonRegistry<QuotaRegistry> = JsonRegistry::new(QUOTAS_FILE);
+
+// Re-keys the directories charged to owners at or below `from` after their
+// tree moved to `to`, or forgets them if it was released. Every move or
+// release of a directory tree goes through here, so the registry lock is
+// taken while directory locks are held, and no directory is locked under it.
+pub(crate) async fn move_dirs(pool_root: &str, from: &DirKey, to: Option<&DirKey>) -> Result<(), MetadataError> {
+    let from = from.as_string();
+    let below = |key: &String| key == &from || key.starts_with(&format!("{}/", from));
+    // Most pools charge no owners; skip the lock when nothing moves.
+    if !REGISTRY.read(pool_root).await?.values().any(|q| q.dirs.iter().any(below)) {
+        return Ok(());
+    }
+
+    let _lock = REGISTRY.lock(pool_root).await?;
+    let mut registry = REGISTRY.read(pool_root).await?;
+    for quota in registry.values_mut() {
+        let moved: Vec<String> = quota.dirs.iter().filter(|key| below(key)).cloned().collect();
+        for key in moved {
+            quota.dirs.remove(&key);
+            if let Some(to) = to {
+                quota.dirs.insert(format!("{}{}", to.as_string(), &key[from.len()..]));
+            }
+        }
+    }
+    REGISTRY.write(pool_root, &registry).await
+}
+
+// Records the directory `dir` under the owner its quota now names, and under
+// no other. It reads the owner back, so concurrent changes to it converge.
+pub(crate) async fn charge_dir(pool_root: &str, store: &dyn MetadataStore, dir: &DirKey) -> Result<(), MetadataError> {
+    let _lock = REGISTRY.lock(pool_root).await?;
+    let mut registry = REGISTRY.read(pool_root).await?;
+    let owner = dir_info(store, dir).await?.and_then(|info| info.quota).and_then(|quota| quota.owner);
+    let key = dir.as_string();
+    for quota in registry.values_mut() {
+        quota.dirs.remove(&key);
+    }
+    if let Some(owner) = owner {
+        registry.entry(owner).or_default().dirs.insert(key);
+    }
+    REGISTRY.write(pool_root, &registry).await
+}
+
+// Sums the usage of the directories charged to `owner`. A directory that is
+// gone or charged to someone else, e.g. while a move is re-keyed, is skipped.
+pub(crate) async fn owner_usage(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    owner: &str,
+) -> Result<QuotaUsage, MetadataError> {
+    let Some(quota) = REGISTRY.read(pool_root).await?.remove(owner) else {
+        return Ok(QuotaUsage::default());
+    };
+    let mut usage = QuotaUsage { max_bytes: quota.max_bytes, max_entries: quota.max_entries, ..Default::default() };
+    for key in &quota.dirs {
+        let Some(info) = dir_info(store, &DirKey::from_string(key)).await? else {
+            continue;
+        };
+        if let Some(dir_quota) = info.quota.filter(|q| q.owner.as_deref() == Some(owner)) {
+            usage.bytes += info.size;
+            usage.entries += dir_quota.entries;
+        }
+    }
+    Ok(usage)
+}
+
+// Finds the entry of the directory `dir` in its parent's listing.
+async fn dir_info(store: &dyn MetadataStore, dir: &DirKey) -> Result<Option<DirectoryInfo>, MetadataError> {
+    let Some((cid, parent)) = dir.cids().split_last() else {
+        return Ok(None);
+    };
+    let parent = DirKey::from_string(&parent.join("/"));
+    Ok(store.read_listing(&parent).await?.into_values().find_map(|entry| match entry {
+        Entry::Directory(info) if &info.cid == cid => Some(info),
+        _ => None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::store;
+    use crate::metadata::test_util::{self, TestPool};
+
+    async fn charged_dirs(pool_root: &str) -> Vec<String> {
+        REGISTRY.read(pool_root).await.unwrap().remove("u").unwrap_or_default().dirs.into_iter().collect()
+    }
+
+    async fn dir_key(pool_root: &str, path: &[&str]) -> String {
+        let store = store::get_metadata_store(pool_root).await.unwrap();
+        let components: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+        manager::find_dir_path(store.as_ref(), &components).await.unwrap().as_string()
+    }
+
+    #[tokio::test]
+    async fn charged_directories_follow_moves_and_releases() {
+        let pool = TestPool::new("quota-owner-keys", r#"{"trash":{"enabled":true}}"#);
+        let root = pool.root.as_str();
+        manager::create_file(root, "/a/b", "f", test_util::file_metadata("f", 10)).await.unwrap();
+        manager::create_file(root, "/c", "g", test_util::file_metadata("g", 1)).await.unwrap();
+        manager::set_dir_owner(root, "/a/b", Some("u")).await.unwrap();
+        assert_eq!(charged_dirs(root).await, [dir_key(root, &["a", "b"]).await]);
+
+        manager::rename_entry(root, "/a/b", "/c/b").await.unwrap();
+        assert_eq!(charged_dirs(root).await, [dir_key(root, &["c", "b"]).await]);
+        assert_eq!(manager::get_owner_quota(root, "u").await.unwrap().bytes, 10);
+
+        manager::delete_entry(root, "/c").await.unwrap();
+        let item = manager::list_trash(root).await.unwrap().remove(0);
+        assert!(charged_dirs(root).await[0].starts_with(&item.root().as_string()));
+        manager::restore_trash(root, &item.id, None).await.unwrap();
+        assert_eq!(charged_dirs(root).await, [dir_key(root, &["c", "b"]).await]);
+
+        // Copies are not charged, and restoring a snapshot replaces the charged directory.
+        manager::create_snapshot(root, "/c", "s").await.unwrap();
+        manager::copy_entry(root, "/c/b", "/a/b").await.unwrap();
+        manager::restore_snapshot(root, "s", None).await.unwrap();
+        assert!(charged_dirs(root).await.is_empty());
+        assert_eq!(manager::get_owner_quota(root, "u").await.unwrap().bytes, 0);
+
+        manager::set_dir_owner(root, "/a/b", Some("u")).await.unwrap();
+        manager::set_dir_owner(root, "/a/b", None).await.unwrap();
+        assert!(charged_dirs(root).await.is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+// src/metadata/registry.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::lock::FileLock;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// A registry of pool-wide records kept as one JSON file in the pool root,
+// e.g. snapshots.json. A pool without the file has an empty registry.
+pub struct JsonRegistry<T> {
+    file: &'static str,
+    _records: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> JsonRegistry<T> {
+    pub const fn new(file: &'static str) -> Self {
+        JsonRegistry { file, _records: PhantomData }
+    }
+
+    fn path(&self, pool_root: &str) -> PathBuf {
+        Path::new(pool_root).join(self.file)
+    }
+
+    // Serializes changes to the registry.
+    pub async fn lock(&self, pool_root: &str) -> Result<FileLock, MetadataError> {
+        Ok(FileLock::acquire(&self.path(pool_root)).await?)
+    }
+
+    pub async fn read(&self, pool_root: &str) -> Result<T, MetadataError> {
+        match fs::read(self.path(pool_root)).await {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Replaces the registry through a temporary file, so that a crash leaves
+    // either the old or the new one.
+    pub async fn write(&self, pool_root: &str, registry: &T) -> Result<(), MetadataError> {
+        let path = self.path(pool_root);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(registry)?).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+// src/metadata/search.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::listing::{self, EntryKind};
+use crate::metadata::model::Entry;
+use crate::metadata::naming;
+use crate::metadata::search_index;
+use crate::metadata::store;
+use crate::metadata::walk::{self, WalkOptions};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::pin::pin;
+
+// Criteria for `search`. Every criterion that is set must match.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SearchQuery {
+    // Only entries below this directory are searched. Defaults to the pool root.
+    pub path: Option<String>,
+    // Glob over the entry name (`*`, `?`, `[...]`).
+    pub name: Option<String>,
+    // Regular expression over the entry name.
+    pub regex: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    pub kind: Option<EntryKind>,
+    pub cid: Option<String>,
+    // Return only the first this many hits in path order.
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub path: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+// The compiled form of a query.
+struct Matcher {
+    name: Option<Regex>,
+    regex: Option<Regex>,
+    query: SearchQuery,
+}
+
+impl Matcher {
+    fn new(query: &SearchQuery) -> Result<Self, MetadataError> {
+        let name = query.name.as_deref().map(listing::glob_to_regex).transpose()?;
+        let regex = query
+            .regex
+            .as_deref()
+            .map(|r| Regex::new(r).map_err(|_| MetadataError::InvalidPattern(r.to_string())))
+            .transpose()?;
+        Ok(Matcher { name, regex, query: query.clone() })
+    }
+
+    fn matches(&self, name: &str, entry: &Entry) -> bool {
+        let (cid, size, modified_at) = match entry {
+            Entry::File(file) => (Some(&file.cid), file.size, file.modified_at),
+            Entry::Directory(dir) => (Some(&dir.cid), dir.size, dir.modified_at),
+            Entry::Symlink(link) => (None, 0, link.modified_at),
+        };
+        let q = &self.query;
+        q.kind.is_none_or(|kind| kind == listing::kind_of(entry))
+            && q.cid.as_ref().is_none_or(|c| cid == Some(c))
+            && q.min_size.is_none_or(|min| size >= min)
+            && q.max_size.is_none_or(|max| size <= max)
+            && q.modified_after.is_none_or(|after| modified_at >= after)
+            && q.modified_before.is_none_or(|before| modified_at < before)
+            && self.name.as_ref().is_none_or(|r| r.is_match(name))
+            && self.regex.as_ref().is_none_or(|r| r.is_match(name))
+    }
+}
+
+// Collects hits, keeping only the first `limit` of them in path order, so
+// that both search paths return the same hits however many entries match.
+struct Hits {
+    limit: Option<usize>,
+    heap: BinaryHeap<ByPath>,
+}
+
+struct ByPath(SearchHit);
+
+impl PartialEq for ByPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.path == other.0.path
+    }
+}
+
+impl Eq for ByPath {}
+
+impl PartialOrd for ByPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.path.cmp(&other.0.path)
+    }
+}
+
+impl Hits {
+    fn new(limit: Option<usize>) -> Self {
+        Hits { limit, heap: BinaryHeap::new() }
+    }
+
+    fn push(&mut self, hit: SearchHit) {
+        self.heap.push(ByPath(hit));
+        if self.limit.is_some_and(|limit| self.heap.len() > limit) {
+            self.heap.pop();
+        }
+    }
+
+    fn into_sorted(self) -> Vec<SearchHit> {
+        self.heap.into_sorted_vec().into_iter().map(|ByPath(hit)| hit).collect()
+    }
+}
+
+// Finds entries matching `query`. Pools that keep a search index answer from
+// it; other pools are walked from the search root.
+// Hits are returned in path order.
+pub async fn search(pool_root: &str, query: &SearchQuery) -> Result<Vec<SearchHit>, MetadataError> {
+    let matcher = Matcher::new(query)?;
+    // Opening the store also loads the pool's search index, if it keeps one.
+    let store = store::get_metadata_store(pool_root).await?;
+    let components = naming::split_path(pool_root, store.as_ref(), query.path.as_deref().unwrap_or("/")).await?;
+    let root = format!("/{}", components.join("/"));
+    let mut hits = Hits::new(query.limit);
+    match search_index::get(pool_root) {
+        Some(index) => {
+            let index = index.lock().await;
+            let prefix = if root == "/" { root.clone() } else { format!("{}/", root) };
+            for (dir, name, entry) in index.iter() {
+                if matcher.matches(name, entry)
+                    && let Some(path) = index.path_of(dir, name)
+                    && path.starts_with(&prefix)
+                {
+                    hits.push(SearchHit { path, entry: entry.clone() });
+                }
+            }
+        }
+        None => {
+            // The walk does not visit entries in path order, so it cannot stop early.
+            let mut entries = pin!(walk::walk(pool_root, &root, WalkOptions::default()));
+            while let Some(item) = entries.next().await {
+                let item = item?;
+                if matcher.matches(&item.name, &item.entry) {
+                    hits.push(SearchHit { path: item.path, entry: item.entry });
+                }
+            }
+        }
+    }
+    Ok(hits.into_sorted())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{file_metadata, TestPool};
+
+    async fn limited_paths(pool: &TestPool) -> Vec<String> {
+        for (dir, name) in [("/z", "f"), ("/a/b", "f"), ("/a", "f2"), ("/m", "f"), ("/a-c", "f"), ("/", "f")] {
+            manager::create_file(&pool.root, dir, name, file_metadata(name, 1)).await.unwrap();
+        }
+        let query = SearchQuery { name: Some("f*".into()), limit: Some(4), ..SearchQuery::default() };
+        search(&pool.root, &query).await.unwrap().into_iter().map(|hit| hit.path).collect()
+    }
+
+    #[tokio::test]
+    async fn limit_keeps_the_first_hits_in_path_order_on_both_paths() {
+        let expected = vec!["/a-c/f", "/a/b/f", "/a/f2", "/f"];
+        let walked = TestPool::new("search-walk", "{}");
+        assert_eq!(limited_paths(&walked).await, expected);
+        let indexed = TestPool::new("search-index", r#"{"metadataBackend":"db","searchIndex":true}"#);
+        assert_eq!(limited_paths(&indexed).await, expected);
+        assert!(search_index::get(&indexed.root).is_some());
+        // A JSON pool may be changed by other processes, which an index would miss.
+        let unindexed = TestPool::new("search-json", r#"{"metadataBackend":"json","searchIndex":true}"#);
+        assert_eq!(limited_paths(&unindexed).await, expected);
+        assert!(search_index::get(&unindexed.root).is_none());
+    }
+}
@@ -0,0 +1,453 @@
+// src/metadata/search_index.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::block::log_file::{self, Commit, LogSync};
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{DirectoryListing, Entry, FileMetadata};
+use crate::metadata::store::{DirKey, DirLock, ListingStamp, MetadataStore};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+const SEARCH_INDEX_FILE: &str = "search.log";
+
+// Open search indexes, one per pool root.
+static SEARCH_INDEXES: Lazy<StdMutex<HashMap<String, Arc<Mutex<SearchIndex>>>>> =
+    Lazy::new(|| StdMutex::new(HashMap::new()));
+
+// One line of the append-only search index log, keyed like the metadata store
+// itself: by directory key and entry name.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum SearchRecord {
+    Put { dir: String, name: String, entry: Entry },
+    Remove { dir: String, name: String },
+    // Drops every entry of a directory.
+    Clear { dir: String },
+    // Replaces every entry of a directory.
+    Listing { dir: String, entries: HashMap<String, Entry> },
+    // Written and synced before a directory is changed in the store. Until a
+    // later record of the directory settles it, the index may be missing the
+    // change, and loading the index reads the directory from the store again.
+    Touch { dir: String },
+}
+
+// A resident copy of every directory entry in a pool, so searches do not read
+// listings from the metadata store. Paths are rebuilt from the parent links
+// of directory entries. Only pools on the db backend keep one: their store is
+// opened by one process at a time, and the index is loaded again whenever the
+// store is, so no other process's writes can slip past it.
+pub struct SearchIndex {
+    dirs: HashMap<String, HashMap<String, Entry>>,
+    // Directory key -> (parent directory key, name in parent).
+    parents: HashMap<String, (String, String)>,
+    log_path: PathBuf,
+    log: fs::File,
+    sync: Arc<LogSync>,
+    log_records: usize,
+}
+
+// Returns the search index of a pool, if the pool's metadata store keeps one.
+pub fn get(pool_root: &str) -> Option<Arc<Mutex<SearchIndex>>> {
+    SEARCH_INDEXES.lock().unwrap().get(pool_root).cloned()
+}
+
+// Forgets the loaded search index of a pool along with its store, which
+// another process may change before it is opened here again.
+pub(crate) fn close(pool_root: &str) {
+    SEARCH_INDEXES.lock().unwrap().remove(pool_root);
+}
+
+// Drops the search index of a pool, to be built anew from the metadata store
+// when it is next opened. For changes made to the store directly rather than
+// through an `IndexedStore`, as by a format upgrade or a migration.
+pub(crate) async fn discard(pool_root: &str) -> Result<(), MetadataError> {
+    SEARCH_INDEXES.lock().unwrap().remove(pool_root);
+    match fs::remove_file(Path::new(pool_root).join(SEARCH_INDEX_FILE)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl SearchIndex {
+    // Loads the index from its log, or builds it from `store` if there is none.
+    async fn load(pool_root: &str, store: &dyn MetadataStore) -> Result<Self, MetadataError> {
+        let log_path = Path::new(pool_root).join(SEARCH_INDEX_FILE);
+        let log_exists = log_path.exists();
+        let file = fs::OpenOptions::new().create(true).append(true).open(&log_path).await?;
+        let mut index = SearchIndex {
+            dirs: HashMap::new(),
+            parents: HashMap::new(),
+            sync: LogSync::new(&file).await?,
+            log: file,
+            log_path,
+            log_records: 0,
+        };
+
+        let replayed = log_exists && index.replay(store).await?;
+        if !replayed {
+            if !log_exists {
+                log(LogLevel::Info, &format!("No search index found in '{}', building it.", pool_root));
+            }
+            index.dirs.clear();
+            index.parents.clear();
+            let mut pending = vec![DirKey::root()];
+            while let Some(dir) = pending.pop() {
+                let listing = store.read_listing(&dir).await?;
+                for entry in listing.values() {
+                    if let Entry::Directory(info) = entry {
+                        pending.push(dir.child(&info.cid));
+                    }
+                }
+                index.apply(SearchRecord::Listing { dir: dir.as_string(), entries: listing });
+            }
+        }
+
+        let live = index.dirs.values().map(HashMap::len).sum::<usize>();
+        if !replayed || index.log_records > 2 * live + 1024 {
+            index.compact().await?;
+        }
+        Ok(index)
+    }
+
+    // Replays the log, then reads the directories whose changes it may be
+    // missing from the store. Returns false if the log is unreadable.
+    async fn replay(&mut self, store: &dyn MetadataStore) -> Result<bool, MetadataError> {
+        let content = log_file::read_records(&self.log_path).await?;
+        let mut unsettled = HashSet::new();
+        for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let Ok(record) = serde_json::from_slice::<SearchRecord>(line) else {
+                let message = format!("Rebuilding the search index: {} is unreadable.", self.log_path.display());
+                log(LogLevel::Warn, &message);
+                return Ok(false);
+            };
+            match &record {
+                SearchRecord::Touch { dir } => unsettled.insert(dir.clone()),
+                SearchRecord::Put { dir, .. }
+                | SearchRecord::Remove { dir, .. }
+                | SearchRecord::Clear { dir }
+                | SearchRecord::Listing { dir, .. } => unsettled.remove(dir),
+            };
+            self.apply(record);
+            self.log_records += 1;
+        }
+
+        for dir in unsettled {
+            let entries = store.read_listing(&DirKey::from_string(&dir)).await?;
+            self.record(SearchRecord::Listing { dir, entries }).await?;
+        }
+        Ok(true)
+    }
+
+    // Iterates over every indexed entry as (directory key, name, entry).
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &Entry)> {
+        self.dirs.iter().flat_map(|(dir, entries)| {
+            entries.iter().map(move |(name, entry)| (dir.as_str(), name.as_str(), entry))
+        })
+    }
+
+    // Rebuilds the virtual path of an entry, or None if one of its ancestors
+    // is no longer linked into the tree.
+    pub fn path_of(&self, dir: &str, name: &str) -> Option<String> {
+        let mut components = vec![name];
+        let mut current = dir;
+        while !current.is_empty() {
+            let (parent, dir_name) = self.parents.get(current)?;
+            components.push(dir_name);
+            current = parent;
+        }
+        components.reverse();
+        Some(format!("/{}", components.join("/")))
+    }
+
+    fn apply(&mut self, record: SearchRecord) {
+        match record {
+            SearchRecord::Put { dir, name, entry } => {
+                if let Entry::Directory(info) = &entry {
+                    self.parents.insert(child_key(&dir, &info.cid), (dir.clone(), name.clone()));
+                }
+                let replaced = self.dirs.entry(dir.clone()).or_default().insert(name, entry.clone());
+                if let Some(Entry::Directory(old)) = replaced
+                    && !matches!(&entry, Entry::Directory(new) if new.cid == old.cid)
+                {
+                    self.parents.remove(&child_key(&dir, &old.cid));
+                }
+            }
+            SearchRecord::Remove { dir, name } => {
+                if let Some(Entry::Directory(info)) = self.dirs.get_mut(&dir).and_then(|e| e.remove(&name)) {
+                    self.parents.remove(&child_key(&dir, &info.cid));
+                }
+            }
+            SearchRecord::Clear { dir } => {
+                for entry in self.dirs.remove(&dir).into_iter().flat_map(HashMap::into_values) {
+                    if let Entry::Directory(info) = entry {
+                        self.parents.remove(&child_key(&dir, &info.cid));
+                    }
+                }
+            }
+            SearchRecord::Listing { dir, entries } => {
+                self.apply(SearchRecord::Clear { dir: dir.clone() });
+                self.dirs.insert(dir.clone(), HashMap::new());
+                for (name, entry) in entries {
+                    self.apply(SearchRecord::Put { dir: dir.clone(), name, entry });
+                }
+            }
+            SearchRecord::Touch { .. } => {}
+        }
+    }
+
+    // Records a change made to the store. It is not synced: if it is lost, the
+    // `Touch` before it has the directory read again.
+    async fn record(&mut self, record: SearchRecord) -> Result<(), MetadataError> {
+        log_file::write_record(&mut self.log, &record).await?;
+        self.sync.wrote();
+        self.log_records += 1;
+        self.apply(record);
+        Ok(())
+    }
+
+    // Records that a directory is about to change. The change may only be
+    // made once the returned commit is durable.
+    async fn touch(&mut self, dir: &DirKey) -> Result<Commit, MetadataError> {
+        log_file::write_record(&mut self.log, &SearchRecord::Touch { dir: dir.as_string() }).await?;
+        self.sync.wrote();
+        self.log_records += 1;
+        Ok(Commit::of(Some(&self.sync)))
+    }
+
+    // Rewrites the log as one `Put` record per live entry.
+    async fn compact(&mut self) -> Result<(), MetadataError> {
+        let mut content = Vec::new();
+        for (dir, name, entry) in self.iter() {
+            let record = SearchRecord::Put { dir: dir.to_string(), name: name.to_string(), entry: entry.clone() };
+            content.extend(serde_json::to_vec(&record)?);
+            content.push(b'\n');
+        }
+        log_file::replace(&self.log_path, &content).await?;
+
+        self.log = fs::OpenOptions::new().append(true).open(&self.log_path).await?;
+        self.sync = LogSync::new(&self.log).await?;
+        self.log_records = self.dirs.values().map(HashMap::len).sum();
+        Ok(())
+    }
+}
+
+fn child_key(dir: &str, cid: &str) -> String {
+    if dir.is_empty() { cid.to_string() } else { format!("{}/{}", dir, cid) }
+}
+
+// Wraps a metadata store and mirrors every entry change into the pool's search index.
+pub struct IndexedStore {
+    inner: Arc<dyn MetadataStore>,
+    index: Arc<Mutex<SearchIndex>>,
+}
+
+impl IndexedStore {
+    pub async fn open(pool_root: &str, inner: Arc<dyn MetadataStore>) -> Result<Self, MetadataError> {
+        let index = match get(pool_root) {
+            Some(index) => index,
+            None => {
+                let loaded = Arc::new(Mutex::new(SearchIndex::load(pool_root, inner.as_ref()).await?));
+                let mut indexes = SEARCH_INDEXES.lock().unwrap();
+                indexes.entry(pool_root.to_string()).or_insert(loaded).clone()
+            }
+        };
+        Ok(IndexedStore { inner, index })
+    }
+
+    // Records durably that `dir` is about to change, sharing the sync with
+    // other writers.
+    async fn touch(&self, dir: &DirKey) -> Result<(), MetadataError> {
+        let commit = self.index.lock().await.touch(dir).await?;
+        Ok(commit.wait().await?)
+    }
+
+    // Records a change to `dir` once the store has made it. After a failed
+    // write the store may hold part of it, so the directory is read again.
+    async fn settle(
+        &self,
+        dir: &DirKey,
+        written: Result<(), MetadataError>,
+        record: SearchRecord,
+    ) -> Result<(), MetadataError> {
+        let mut index = self.index.lock().await;
+        if let Err(e) = written {
+            if let Ok(entries) = self.inner.read_listing(dir).await {
+                index.record(SearchRecord::Listing { dir: dir.as_string(), entries }).await?;
+            }
+            return Err(e);
+        }
+        index.record(record).await
+    }
+}
+
+impl MetadataStore for IndexedStore {
+    fn lock_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirLock, MetadataError>> {
+        self.inner.lock_dir(dir)
+    }
+
+    fn read_listing<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirectoryListing, MetadataError>> {
+        self.inner.read_listing(dir)
+    }
+
+    fn write_listing<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        listing: &'a DirectoryListing,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.touch(dir).await?;
+            let written = self.inner.write_listing(dir, listing).await;
+            self.settle(dir, written, SearchRecord::Listing { dir: dir.as_string(), entries: listing.clone() }).await
+        })
+    }
+
+    fn get_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        self.inner.get_entry(dir, name)
+    }
+
+    fn put_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+        entry: &'a Entry,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.touch(dir).await?;
+            let written = self.inner.put_entry(dir, name, entry).await;
+            let record = SearchRecord::Put { dir: dir.as_string(), name: name.to_string(), entry: entry.clone() };
+            self.settle(dir, written, record).await
+        })
+    }
+
+    fn remove_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move {
+            self.touch(dir).await?;
+            let (written, removed) = match self.inner.remove_entry(dir, name).await {
+                Ok(removed) => (Ok(()), removed),
+                Err(e) => (Err(e), None),
+            };
+            let record = SearchRecord::Remove { dir: dir.as_string(), name: name.to_string() };
+            self.settle(dir, written, record).await?;
+            Ok(removed)
+        })
+    }
+
+    fn find_folded<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        folded: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, Entry)>, MetadataError>> {
+        self.inner.find_folded(dir, folded)
+    }
+
+    fn read_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<FileMetadata, MetadataError>> {
+        self.inner.read_file_metadata(dir, cid)
+    }
+
+    fn write_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+        metadata: &'a FileMetadata,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.write_file_metadata(dir, cid, metadata)
+    }
+
+    fn remove_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.remove_file_metadata(dir, cid)
+    }
+
+    fn create_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        self.inner.create_dir(dir)
+    }
+
+    fn remove_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            self.touch(dir).await?;
+            let written = self.inner.remove_dir(dir).await;
+            self.settle(dir, written, SearchRecord::Clear { dir: dir.as_string() }).await
+        })
+    }
+
+    fn listing_stamp<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<Option<ListingStamp>, MetadataError>> {
+        self.inner.listing_stamp(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::json::JsonStore;
+    use crate::metadata::model::SymlinkInfo;
+    use crate::metadata::test_util::TestPool;
+    use chrono::Utc;
+
+    fn symlink() -> Entry {
+        Entry::Symlink(SymlinkInfo { target: "t".into(), created_at: Utc::now(), modified_at: Utc::now() })
+    }
+
+    fn names(index: &SearchIndex) -> Vec<&str> {
+        let mut names: Vec<_> = index.iter().map(|(_, name, _)| name).collect();
+        names.sort();
+        names
+    }
+
+    fn line(record: &SearchRecord) -> Vec<u8> {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    // A pool whose root holds "a", and a search log that lists "old" there,
+    // followed by `tail`.
+    async fn stale_pool(name: &str, tail: &[u8]) -> (TestPool, JsonStore) {
+        let pool = TestPool::new(name, "{}");
+        let store = JsonStore::new(&pool.root);
+        store.create_dir(&DirKey::root()).await.unwrap();
+        store.put_entry(&DirKey::root(), "a", &symlink()).await.unwrap();
+
+        let mut content = line(&SearchRecord::Put { dir: String::new(), name: "old".into(), entry: symlink() });
+        content.extend(tail);
+        std::fs::write(Path::new(&pool.root).join(SEARCH_INDEX_FILE), content).unwrap();
+        (pool, store)
+    }
+
+    #[tokio::test]
+    async fn directories_touched_before_a_crash_are_read_again() {
+        let (pool, store) = stale_pool("search-index-touch", &line(&SearchRecord::Touch { dir: String::new() })).await;
+        let index = SearchIndex::load(&pool.root, &store).await.unwrap();
+        assert_eq!(names(&index), ["a"]);
+        drop(index);
+
+        // Settled by then, the directory is not read from the store again.
+        store.remove_entry(&DirKey::root(), "a").await.unwrap();
+        let index = SearchIndex::load(&pool.root, &store).await.unwrap();
+        assert_eq!(names(&index), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn settled_directories_are_taken_from_the_log() {
+        let mut tail = line(&SearchRecord::Touch { dir: String::new() });
+        tail.extend(line(&SearchRecord::Remove { dir: String::new(), name: "x".into() }));
+        let (pool, store) = stale_pool("search-index-settled", &tail).await;
+        let index = SearchIndex::load(&pool.root, &store).await.unwrap();
+        assert_eq!(names(&index), ["old"]);
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_log_is_rebuilt_from_the_store() {
+        let mut tail = b"{\"put\":\n".to_vec();
+        tail.extend(line(&SearchRecord::Put { dir: String::new(), name: "b".into(), entry: symlink() }));
+        let (pool, store) = stale_pool("search-index-unreadable", &tail).await;
+        let index = SearchIndex::load(&pool.root, &store).await.unwrap();
+        assert_eq!(names(&index), ["a"]);
+    }
+}
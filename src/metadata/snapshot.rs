@@ -0,0 +1,92 @@
+// src/metadata/snapshot.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::path_utils;
+use crate::metadata::registry::JsonRegistry;
+use crate::metadata::store::DirKey;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const SNAPSHOTS_FILE: &str = "snapshots.json";
+// Snapshot trees live under this pseudo-directory of the metadata store.
+const SNAPSHOTS_DIR: &str = path_utils::reserved_dir("snapshots");
+
+// A read-only copy of a directory tree, frozen when it was taken. Its block
+// maps hold references on their blocks, so the blocks outlive later changes
+// to the live tree.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub name: String,
+    // The directory the snapshot was taken of.
+    pub source: String,
+    pub cid: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+    // Set while the snapshot's tree is being released. It can no longer be
+    // read, and deleting it again finishes the release.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleting: bool,
+}
+
+impl SnapshotInfo {
+    // The metadata store directory holding the snapshot's copy of `source`.
+    pub fn root(&self) -> DirKey {
+        snapshots_root().child(&self.cid)
+    }
+}
+
+pub fn snapshots_root() -> DirKey {
+    DirKey::root().child(SNAPSHOTS_DIR)
+}
+
+// The registry of a pool's snapshots, `{pool}/snapshots.json`, keyed by name.
+pub type SnapshotRegistry = BTreeMap<String, SnapshotInfo>;
+
+pub const REGISTRY: JsonRegistry<SnapshotRegistry> = JsonRegistry::new(SNAPSHOTS_FILE);
+
+pub async fn get_snapshot(pool_root: &str, name: &str) -> Result<SnapshotInfo, MetadataError> {
+    REGISTRY.read(pool_root)
+        .await?
+        .remove(name)
+        .filter(|info| !info.deleting)
+        .ok_or_else(|| MetadataError::SnapshotNotFound(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::model::Entry;
+    use crate::metadata::store;
+    use crate::metadata::test_util::{self, TestPool};
+
+    #[tokio::test]
+    async fn deleting_again_finishes_an_interrupted_deletion() {
+        let pool = TestPool::new("snapshot-delete", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d", "one", test_util::file_metadata("one", 1)).await.unwrap();
+        manager::create_file(root, "/d/sub", "two", test_util::file_metadata("two", 2)).await.unwrap();
+        let info = manager::create_snapshot(root, "/d", "s").await.unwrap();
+
+        // A deletion that stopped after releasing the first file.
+        let store = store::get_metadata_store(root).await.unwrap();
+        let mut registry = REGISTRY.read(root).await.unwrap();
+        registry.get_mut("s").unwrap().deleting = true;
+        REGISTRY.write(root, &registry).await.unwrap();
+        let Some(Entry::File(one)) = store.remove_entry(&info.root(), "one").await.unwrap() else {
+            panic!("the snapshot has no file 'one'");
+        };
+        store.remove_file_metadata(&info.root(), &one.cid).await.unwrap();
+
+        assert!(matches!(get_snapshot(root, "s").await, Err(MetadataError::SnapshotNotFound(_))));
+        assert!(manager::list_snapshots(root).await.unwrap().is_empty());
+        manager::delete_snapshot(root, "s").await.unwrap();
+        assert!(REGISTRY.read(root).await.unwrap().is_empty());
+        assert!(store.read_listing(&info.root()).await.unwrap().is_empty());
+        assert_eq!(manager::list_directory(root, "/d/sub").await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,251 @@
+// src/metadata/store.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config::{self, MetadataBackend};
+use crate::metadata::cache::{self, CachedStore};
+use crate::metadata::db::DbStore;
+use crate::metadata::error::MetadataError;
+use crate::metadata::format;
+use crate::metadata::json::JsonStore;
+use crate::metadata::model::{DirectoryListing, Entry, FileMetadata};
+use crate::metadata::path_utils;
+use crate::metadata::search_index::{self, IndexedStore};
+use crate::metadata::trash;
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use rfs_utils::{log, LogLevel};
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::OnceCell;
+
+// Metadata stores in use, one per pool root. The cell is set once the store
+// is open; concurrent first users wait for the one opening it.
+static METADATA_STORES: Lazy<Mutex<HashMap<String, StoreCell>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+type StoreCell = Arc<OnceCell<Arc<dyn MetadataStore>>>;
+
+// Identifies a directory by the chain of directory CIDs leading to it from the
+// pool root. The root directory has an empty chain.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct DirKey(Vec<String>);
+
+impl DirKey {
+    pub fn root() -> Self {
+        DirKey(Vec::new())
+    }
+
+    pub fn child(&self, cid: &str) -> Self {
+        let mut cids = self.0.clone();
+        cids.push(cid.to_string());
+        DirKey(cids)
+    }
+
+    pub fn cids(&self) -> &[String] {
+        &self.0
+    }
+
+    // The chain joined with '/', e.g. "" for the root or "aB3x9/Qz81k".
+    pub fn as_string(&self) -> String {
+        self.0.join("/")
+    }
+
+    pub fn from_string(s: &str) -> Self {
+        DirKey(s.split('/').filter(|c| !c.is_empty()).map(String::from).collect())
+    }
+}
+
+// Identifies a version of a directory's listing as stored, so that a cache
+// notices writes by other processes: the count of writes to the listing,
+// which every writer bumps under the directory lock, and the inode, length
+// and modification time of each file holding it, or None for a file that
+// does not exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingStamp {
+    generation: u64,
+    files: Vec<Option<(u64, u64, SystemTime)>>,
+}
+
+impl ListingStamp {
+    pub fn of_files(generation: u64, files: &[Option<std::fs::Metadata>]) -> Self {
+        let stamp = |m: &std::fs::Metadata| (m.ino(), m.len(), m.modified().unwrap_or(UNIX_EPOCH));
+        ListingStamp { generation, files: files.iter().map(|file| file.as_ref().map(stamp)).collect() }
+    }
+}
+
+// Held while a directory is being modified; the lock is released on drop.
+pub type DirLock = Box<dyn Send + Sync>;
+
+// Storage for the metadata tree of one pool. The operations in `manager` are
+// built on these primitives, so every backend gets the same semantics.
+//
+// Writers must hold the directory's `lock_dir` guard around read-modify-write
+// sequences on its listing.
+pub trait MetadataStore: Send + Sync {
+    // Takes the exclusive lock on a directory, waiting for other holders.
+    fn lock_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirLock, MetadataError>>;
+
+    // Reads every entry of a directory. A directory never written reads as empty.
+    fn read_listing<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<DirectoryListing, MetadataError>>;
+
+    // Replaces every entry of a directory.
+    fn write_listing<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        listing: &'a DirectoryListing,
+    ) -> BoxFuture<'a, Result<(), MetadataError>>;
+
+    fn get_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move { Ok(self.read_listing(dir).await?.remove(name)) })
+    }
+
+    fn put_entry<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        name: &'a str,
+        entry: &'a Entry,
+    ) -> BoxFuture<'a, Result<(), MetadataError>> {
+        Box::pin(async move {
+            let mut listing = self.read_listing(dir).await?;
+            listing.insert(name.to_string(), entry.clone());
+            self.write_listing(dir, &listing).await
+        })
+    }
+
+    // Removes an entry and returns it, if it existed.
+    fn remove_entry<'a>(&'a self, dir: &'a DirKey, name: &'a str) -> BoxFuture<'a, Result<Option<Entry>, MetadataError>> {
+        Box::pin(async move {
+            let mut listing = self.read_listing(dir).await?;
+            let removed = listing.remove(name);
+            if removed.is_some() {
+                self.write_listing(dir, &listing).await?;
+            }
+            Ok(removed)
+        })
+    }
+
+    // Finds the entry of `dir` whose name folds to `folded` under
+    // `path_utils::fold_case`, the least such name if there are several.
+    fn find_folded<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        folded: &'a str,
+    ) -> BoxFuture<'a, Result<Option<(String, Entry)>, MetadataError>> {
+        Box::pin(async move { Ok(find_folded_in(&self.read_listing(dir).await?, folded)) })
+    }
+
+    // Reads the FileMetadata (block map) of a file stored in `dir`.
+    fn read_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<FileMetadata, MetadataError>>;
+
+    fn write_file_metadata<'a>(
+        &'a self,
+        dir: &'a DirKey,
+        cid: &'a str,
+        metadata: &'a FileMetadata,
+    ) -> BoxFuture<'a, Result<(), MetadataError>>;
+
+    fn remove_file_metadata<'a>(&'a self, dir: &'a DirKey, cid: &'a str) -> BoxFuture<'a, Result<(), MetadataError>>;
+
+    // Prepares storage for a new, empty directory.
+    fn create_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>>;
+
+    // Deletes an empty directory's storage, including its listing.
+    fn remove_dir<'a>(&'a self, dir: &'a DirKey) -> BoxFuture<'a, Result<(), MetadataError>>;
+
+    // Stamps the stored version of a directory's listing. Backends that no
+    // other process can write while this one has them open return None.
+    fn listing_stamp<'a>(&'a self, _dir: &'a DirKey) -> BoxFuture<'a, Result<Option<ListingStamp>, MetadataError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    // Looks up a still valid resolution of directory names below `base`, if
+    // the store caches them; see `remember_dir`.
+    fn cached_dir<'a>(
+        &'a self,
+        _base: &'a DirKey,
+        _components: &'a [String],
+    ) -> BoxFuture<'a, Result<Option<DirKey>, MetadataError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    // Offers the resolution of directory names below `base` to `dir`, just
+    // made through `get_entry`, to the store's cache.
+    fn remember_dir(&self, _base: &DirKey, _components: &[String], _dir: &DirKey) {}
+}
+
+// Looks up an entry by its folded name in a listing read as a whole.
+pub(crate) fn find_folded_in(listing: &DirectoryListing, folded: &str) -> Option<(String, Entry)> {
+    listing
+        .iter()
+        .find(|(name, _)| path_utils::fold_case(name) == folded)
+        .map(|(name, entry)| (name.clone(), entry.clone()))
+}
+
+// Returns the metadata store of a pool, creating it from the pool's config on
+// first use. The pool's metadata is upgraded to the current format then, and
+// deletes to the trash that a crash interrupted are settled.
+pub async fn get_metadata_store(pool_root: &str) -> Result<Arc<dyn MetadataStore>, MetadataError> {
+    let cell = METADATA_STORES.lock().unwrap().entry(pool_root.to_string()).or_default().clone();
+    // Only one caller opens the backend: redb refuses a second open, and the
+    // listing cache is registered per pool.
+    cell.get_or_try_init(|| open_store(pool_root)).await.cloned()
+}
+
+async fn open_store(pool_root: &str) -> Result<Arc<dyn MetadataStore>, MetadataError> {
+    let pool_config = config::get_pool_config(pool_root).await?;
+    let mut created = open_backend(pool_root, pool_config.metadata_backend).await?;
+    format::open(pool_root, created.as_ref()).await?;
+    trash::recover(pool_root, created.as_ref()).await?;
+    match (pool_config.search_index, pool_config.metadata_backend) {
+        (true, MetadataBackend::Db) => created = Arc::new(IndexedStore::open(pool_root, created).await?),
+        (true, MetadataBackend::Json) => log(
+            LogLevel::Warn,
+            &format!("Not keeping a search index for '{}', which needs the db metadata backend.", pool_root),
+        ),
+        (false, _) => {}
+    }
+    if pool_config.listing_cache.enabled {
+        created = Arc::new(CachedStore::open(pool_root, created, &pool_config.listing_cache));
+    }
+    Ok(created)
+}
+
+// Opens a specific backend of a pool, regardless of which one its config selects.
+pub async fn open_backend(
+    pool_root: &str,
+    backend: MetadataBackend,
+) -> Result<Arc<dyn MetadataStore>, MetadataError> {
+    Ok(match backend {
+        MetadataBackend::Json => Arc::new(JsonStore::new(pool_root)),
+        MetadataBackend::Db => Arc::new(DbStore::open(pool_root).await?),
+    })
+}
+
+// Forgets the cached store of a pool, e.g. after its backend was switched.
+pub fn close_metadata_store(pool_root: &str) {
+    METADATA_STORES.lock().unwrap().remove(pool_root);
+    cache::close(pool_root);
+    search_index::close(pool_root);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_first_opens_share_one_store() {
+        let dir = std::env::temp_dir().join(format!("rfs-store-open-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(config::POOL_CONFIG_FILE), r#"{"metadataBackend":"db"}"#).unwrap();
+        let root = dir.to_string_lossy().into_owned();
+
+        let opens: Vec<_> = (0..8).map(|_| get_metadata_store(&root)).collect();
+        let stores = futures::future::try_join_all(opens).await.unwrap();
+        assert!(stores.iter().all(|store| Arc::ptr_eq(store, &stores[0])));
+        close_metadata_store(&root);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+// src/metadata/test_util.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::model::FileMetadata;
+use crate::metadata::store;
+use chrono::Utc;
+use std::collections::BTreeMap;
+
+// A scratch pool in the temporary directory, removed again on drop.
+pub(crate) struct TestPool {
+    pub root: String,
+}
+
+impl TestPool {
+    // Creates an empty pool with `config` as its config.json.
+    pub(crate) fn new(name: &str, config: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rfs-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(crate::common::config::POOL_CONFIG_FILE), config).unwrap();
+        TestPool { root: dir.to_string_lossy().into_owned() }
+    }
+}
+
+impl Drop for TestPool {
+    fn drop(&mut self) {
+        store::close_metadata_store(&self.root);
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+// The block map of a file without blocks that claims to be `size` bytes long.
+pub(crate) fn file_metadata(filename: &str, size: u64) -> FileMetadata {
+    FileMetadata {
+        filename: filename.to_string(),
+        size,
+        created_at: Utc::now(),
+        modified_at: Utc::now(),
+        blocks: BTreeMap::new(),
+        versions: Vec::new(),
+        links: 1,
+        link_dirs: Vec::new(),
+        xattrs: BTreeMap::new(),
+    }
+}
@@ -0,0 +1,257 @@
+// src/metadata/trash.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::common::config;
+use crate::metadata::error::MetadataError;
+use crate::metadata::journal;
+use crate::metadata::model::{DirectoryListing, Entry};
+use crate::metadata::path_utils;
+use crate::metadata::registry::JsonRegistry;
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use crate::metadata::tree;
+use chrono::{DateTime, Duration, Utc};
+use rfs_utils::{log, LogLevel};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const TRASH_FILE: &str = "trash.json";
+// Deleted entries wait under this pseudo-directory of the metadata store.
+const TRASH_DIR: &str = path_utils::reserved_dir("trashbin");
+
+// An entry moved to the trash by a delete. It is kept under its original
+// name as the only entry of its own directory below the trash root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashItem {
+    pub id: String,
+    // Where the entry was deleted from.
+    pub path: String,
+    pub size: u64,
+    pub deleted_at: DateTime<Utc>,
+    // Set while the entry's metadata is moved into the trash. An item that
+    // still has it after a crash is settled by `recover`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moving: Option<TrashOrigin>,
+}
+
+// The listing a trashed entry is being taken out of.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashOrigin {
+    // The directory's key, as in `DirKey::as_string`.
+    pub dir: String,
+    pub name: String,
+    pub entry: Entry,
+}
+
+impl TrashItem {
+    // The metadata store directory holding the deleted entry.
+    pub fn root(&self) -> DirKey {
+        trash_root().child(&self.id)
+    }
+}
+
+pub fn trash_root() -> DirKey {
+    DirKey::root().child(TRASH_DIR)
+}
+
+// The registry of a pool's trash, `{pool}/trash.json`, keyed by item ID.
+pub type TrashRegistry = BTreeMap<String, TrashItem>;
+
+pub const REGISTRY: JsonRegistry<TrashRegistry> = JsonRegistry::new(TRASH_FILE);
+
+// Moves the metadata of `entry`, deleted as `name` from `dir` at `path`, into
+// a new item of `registry`. The caller holds the registry lock and then
+// removes the entry from `dir`, and calls `settle` once it has. The item is
+// written as moving first, so a crash part way is undone or finished by
+// `recover`; a failed move is undone at once. Returns the item's ID.
+pub(crate) async fn keep(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    registry: &mut TrashRegistry,
+    dir: &DirKey,
+    name: &str,
+    entry: &Entry,
+    path: &str,
+) -> Result<String, MetadataError> {
+    let mut id = path_utils::generate_cid();
+    while registry.contains_key(&id) {
+        id = path_utils::generate_cid();
+    }
+    let origin = TrashOrigin { dir: dir.as_string(), name: name.to_string(), entry: entry.clone() };
+    let item = TrashItem {
+        id: id.clone(),
+        path: path.to_string(),
+        size: tree::entry_size(entry),
+        deleted_at: Utc::now(),
+        moving: Some(origin),
+    };
+    registry.insert(id.clone(), item.clone());
+    if let Err(e) = REGISTRY.write(pool_root, registry).await {
+        registry.remove(&id);
+        return Err(e);
+    }
+    if let Err(e) = move_in(pool_root, store, &item).await {
+        if let Err(e) = put_back(pool_root, store, registry, &id).await {
+            log(LogLevel::Error, &format!("Failed to move '{}' back out of the trash: {}", path, e));
+        }
+        return Err(e);
+    }
+    Ok(id)
+}
+
+// Marks the items `ids`, whose entries have been removed from their listings, as kept.
+pub(crate) async fn settle(pool_root: &str, registry: &mut TrashRegistry, ids: &[String]) -> Result<(), MetadataError> {
+    for id in ids {
+        if let Some(item) = registry.get_mut(id) {
+            item.moving = None;
+        }
+    }
+    REGISTRY.write(pool_root, registry).await
+}
+
+// Undoes `keep` for an item whose entry is still in its listing: its metadata
+// is moved back and the item dropped.
+pub(crate) async fn put_back(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    registry: &mut TrashRegistry,
+    id: &str,
+) -> Result<(), MetadataError> {
+    let Some(origin) = registry.get(id).and_then(|item| item.moving.clone()) else {
+        return Ok(());
+    };
+    let root = trash_root().child(id);
+    tree::move_entry(pool_root, store, &root, &origin.entry, &DirKey::from_string(&origin.dir)).await?;
+    store.remove_dir(&root).await?;
+    registry.remove(id);
+    REGISTRY.write(pool_root, registry).await
+}
+
+// Moves an item's metadata into its directory below the trash root. Moves
+// can be run again, so this finishes one that was interrupted.
+async fn move_in(pool_root: &str, store: &dyn MetadataStore, item: &TrashItem) -> Result<(), MetadataError> {
+    let Some(origin) = &item.moving else {
+        return Ok(());
+    };
+    store.create_dir(&item.root()).await?;
+    tree::move_entry(pool_root, store, &DirKey::from_string(&origin.dir), &origin.entry, &item.root()).await?;
+    store.write_listing(&item.root(), &DirectoryListing::from([(origin.name.clone(), origin.entry.clone())])).await
+}
+
+// Settles the items a crash left moving: one whose entry is still in its
+// listing is put back, as the delete never happened; the others are moved
+// into the trash the rest of the way. Run when the pool's store is opened.
+pub(crate) async fn recover(pool_root: &str, store: &dyn MetadataStore) -> Result<(), MetadataError> {
+    // Most pools have nothing to recover; skip the lock then.
+    if !REGISTRY.read(pool_root).await?.values().any(|item| item.moving.is_some()) {
+        return Ok(());
+    }
+    let _registry_lock = REGISTRY.lock(pool_root).await?;
+    let mut registry = REGISTRY.read(pool_root).await?;
+    let moving: Vec<TrashItem> = registry.values().filter(|item| item.moving.is_some()).cloned().collect();
+    for item in moving {
+        let Some(origin) = &item.moving else {
+            continue;
+        };
+        let dir = DirKey::from_string(&origin.dir);
+        let _dir_lock = store.lock_dir(&dir).await?;
+        let listed = store.get_entry(&dir, &origin.name).await?;
+        if listed.is_some_and(|entry| journal::cid_of(&entry) == journal::cid_of(&origin.entry)) {
+            log(LogLevel::Warn, &format!("Undoing the interrupted delete of '{}'.", item.path));
+            put_back(pool_root, store, &mut registry, &item.id).await?;
+        } else {
+            log(LogLevel::Warn, &format!("Finishing the interrupted delete of '{}'.", item.path));
+            move_in(pool_root, store, &item).await?;
+            settle(pool_root, &mut registry, std::slice::from_ref(&item.id)).await?;
+        }
+    }
+    Ok(())
+}
+
+// Frees a trashed entry before it is dropped from the registry: its blocks
+// are released and its metadata removed. A purge that failed part way can be
+// run again.
+pub(crate) async fn purge(pool_root: &str, store: &dyn MetadataStore, item: &TrashItem) -> Result<(), MetadataError> {
+    tree::release_dir(pool_root, store, &item.root()).await
+}
+
+// Purges the entries deleted longer ago than the pool's retention. Entries
+// left from when the trash was enabled expire as well. Each item stays
+// registered until it is purged, so a purge that fails is retried the next
+// time. Returns the number purged.
+pub async fn purge_expired(pool_root: &str) -> Result<u64, MetadataError> {
+    let retention_days = config::get_pool_config(pool_root).await?.trash.retention_days;
+    let cutoff = Utc::now() - Duration::days(retention_days as i64);
+    let store = store::get_metadata_store(pool_root).await?;
+
+    let _registry_lock = REGISTRY.lock(pool_root).await?;
+    let mut registry = REGISTRY.read(pool_root).await?;
+    let expired: Vec<TrashItem> =
+        registry.values().filter(|item| item.deleted_at < cutoff && item.moving.is_none()).cloned().collect();
+    for item in &expired {
+        purge(pool_root, store.as_ref(), item).await?;
+        registry.remove(&item.id);
+        REGISTRY.write(pool_root, &registry).await?;
+    }
+    Ok(expired.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+
+    // Keeps `/d` in the trash as a delete would, stopping before its entry
+    // is removed from the root listing if `removed` is false.
+    async fn interrupted_delete(pool_root: &str, removed: bool) {
+        let store = store::get_metadata_store(pool_root).await.unwrap();
+        let entry = store.get_entry(&DirKey::root(), "d").await.unwrap().unwrap();
+        let mut registry = REGISTRY.read(pool_root).await.unwrap();
+        keep(pool_root, store.as_ref(), &mut registry, &DirKey::root(), "d", &entry, "/d").await.unwrap();
+        if removed {
+            store.remove_entry(&DirKey::root(), "d").await.unwrap();
+        }
+        store::close_metadata_store(pool_root);
+    }
+
+    #[tokio::test]
+    async fn interrupted_deletes_are_settled_on_open() {
+        let pool = TestPool::new("trash-recover", r#"{"trash":{"enabled":true}}"#);
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d/sub", "f", test_util::file_metadata("f", 3)).await.unwrap();
+
+        interrupted_delete(root, false).await;
+        assert_eq!(manager::list_directory(root, "/d/sub").await.unwrap().len(), 1);
+        assert_eq!(manager::get_file_metadata(root, "/d/sub/f").await.unwrap().size, 3);
+        assert!(REGISTRY.read(root).await.unwrap().is_empty());
+
+        interrupted_delete(root, true).await;
+        assert!(manager::get_entry(root, "/d").await.is_err());
+        let items = manager::list_trash(root).await.unwrap();
+        assert_eq!(items.len(), 1);
+        manager::restore_trash(root, &items[0].id, None).await.unwrap();
+        assert_eq!(manager::get_file_metadata(root, "/d/sub/f").await.unwrap().size, 3);
+    }
+
+    #[tokio::test]
+    async fn purging_keeps_the_item_until_it_is_released() {
+        let pool = TestPool::new("trash-purge", r#"{"trash":{"enabled":true,"retentionDays":0}}"#);
+        let root = pool.root.as_str();
+        manager::create_file(root, "/d", "f", test_util::file_metadata("f", 3)).await.unwrap();
+        manager::delete_entry(root, "/d").await.unwrap();
+
+        // A purge that stopped after releasing part of the item is finished by the next one.
+        let item = manager::list_trash(root).await.unwrap().remove(0);
+        let store = store::get_metadata_store(root).await.unwrap();
+        let Some(Entry::Directory(info)) = store.get_entry(&item.root(), "d").await.unwrap() else {
+            panic!("the item holds no directory 'd'");
+        };
+        store.remove_entry(&item.root().child(&info.cid), "f").await.unwrap();
+        assert_eq!(purge_expired(root).await.unwrap(), 1);
+        assert!(manager::list_trash(root).await.unwrap().is_empty());
+        assert!(store.read_listing(&item.root()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+// src/metadata/tree.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::block::store as block_store;
+use crate::metadata::error::MetadataError;
+use crate::metadata::journal;
+use crate::metadata::link;
+use crate::metadata::model::{BlockInfo, DirectoryInfo, Entry, FileEntry};
+use crate::metadata::path_utils;
+use crate::metadata::quota;
+use crate::metadata::store::{DirKey, MetadataStore};
+use crate::metadata::version;
+use crate::metadata::xattr;
+use chrono::Utc;
+
+// Clones metadata trees without touching block data: block maps are
+// duplicated under new CIDs and every block they point at gains a reference.
+// Tracks what it has created, so a failed clone can be undone.
+pub(crate) struct TreeClone<'a> {
+    pool_root: &'a str,
+    store: &'a dyn MetadataStore,
+    // Keep the original timestamps instead of stamping the copies with now.
+    preserve_times: bool,
+    retained: Vec<(u128, u32)>,
+    files: Vec<(DirKey, String)>,
+    dirs: Vec<DirKey>,
+    // Entries cloned so far, counted for quotas.
+    entries: u64,
+}
+
+impl<'a> TreeClone<'a> {
+    pub fn new(pool_root: &'a str, store: &'a dyn MetadataStore, preserve_times: bool) -> Self {
+        TreeClone {
+            pool_root,
+            store,
+            preserve_times,
+            retained: Vec::new(),
+            files: Vec::new(),
+            dirs: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    // Clones `entry` (found in `src_dir`) into `dst_dir` and returns the new,
+    // not yet linked entry.
+    pub async fn clone_entry(&mut self, src_dir: &DirKey, entry: &Entry, dst_dir: &DirKey) -> Result<Entry, MetadataError> {
+        self.entries += 1;
+        let info = match entry {
+            Entry::File(file_entry) => return self.clone_file(src_dir, file_entry, dst_dir).await.map(Entry::File),
+            Entry::Directory(info) => info,
+            Entry::Symlink(link) => {
+                let mut link = link.clone();
+                self.stamp(&mut link.created_at, &mut link.modified_at);
+                return Ok(Entry::Symlink(link));
+            }
+        };
+
+        // Quotas stay with the original directory.
+        let mut root = DirectoryInfo { cid: path_utils::generate_cid(), quota: None, revision: 0, ..info.clone() };
+        self.stamp(&mut root.created_at, &mut root.modified_at);
+        self.retain(xattr::blocks(&root.xattrs)).await?;
+        self.clone_dir(&src_dir.child(&info.cid), &dst_dir.child(&root.cid)).await?;
+        Ok(Entry::Directory(root))
+    }
+
+    // Clones the contents of directory `from` into a new directory `to` and
+    // returns the total size of its entries.
+    pub async fn clone_dir(&mut self, from: &DirKey, to: &DirKey) -> Result<u64, MetadataError> {
+        let mut size = None;
+        let mut pending = vec![(from.clone(), to.clone())];
+        while let Some((from, to)) = pending.pop() {
+            self.store.create_dir(&to).await?;
+            self.dirs.push(to.clone());
+
+            let mut listing = self.store.read_listing(&from).await?;
+            self.entries += listing.len() as u64;
+            for entry in listing.values_mut() {
+                match entry {
+                    Entry::File(file_entry) => *file_entry = self.clone_file(&from, file_entry, &to).await?,
+                    Entry::Directory(info) => {
+                        let new_cid = path_utils::generate_cid();
+                        pending.push((from.child(&info.cid), to.child(&new_cid)));
+                        info.cid = new_cid;
+                        info.quota = None;
+                        info.revision = 0;
+                        self.stamp(&mut info.created_at, &mut info.modified_at);
+                        self.retain(xattr::blocks(&info.xattrs)).await?;
+                    }
+                    Entry::Symlink(link) => self.stamp(&mut link.created_at, &mut link.modified_at),
+                }
+            }
+            self.store.write_listing(&to, &listing).await?;
+            size.get_or_insert_with(|| listing.values().map(entry_size).sum());
+        }
+        Ok(size.unwrap_or(0))
+    }
+
+    // The number of files, directories and symlinks cloned so far.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    async fn clone_file(&mut self, src_dir: &DirKey, file_entry: &FileEntry, dst_dir: &DirKey) -> Result<FileEntry, MetadataError> {
+        let mut metadata = self.store.read_file_metadata(&link::home(src_dir, file_entry), &file_entry.cid).await?;
+        // Versions and hard links belong to the original; the clone is a
+        // separate file with its current contents only.
+        metadata.versions.clear();
+        metadata.links = 1;
+        metadata.link_dirs.clear();
+        self.retain(metadata.blocks.values().chain(xattr::blocks(&metadata.xattrs))).await?;
+
+        let (mut created_at, mut modified_at) = (metadata.created_at, metadata.modified_at);
+        self.stamp(&mut created_at, &mut modified_at);
+        metadata.created_at = created_at;
+        metadata.modified_at = modified_at;
+        let cid = path_utils::generate_cid();
+        self.store.write_file_metadata(dst_dir, &cid, &metadata).await?;
+        self.files.push((dst_dir.clone(), cid.clone()));
+        Ok(FileEntry { cid, size: metadata.size, created_at, modified_at, linked: false, revision: 0 })
+    }
+
+    async fn retain(&mut self, blocks: impl Iterator<Item = &BlockInfo>) -> Result<(), MetadataError> {
+        for block in blocks.filter(|b| !b.hole) {
+            block_store::retain_block(self.pool_root, block.xxh3, block.index).await?;
+            self.retained.push((block.xxh3, block.index));
+        }
+        Ok(())
+    }
+
+    fn stamp(&self, created_at: &mut chrono::DateTime<Utc>, modified_at: &mut chrono::DateTime<Utc>) {
+        if !self.preserve_times {
+            let now = Utc::now();
+            *created_at = now;
+            *modified_at = now;
+        }
+    }
+
+    // Best-effort removal of everything created so far.
+    pub async fn rollback(&self) {
+        for (xxh3, index) in &self.retained {
+            let _ = block_store::release_block(self.pool_root, *xxh3, *index).await;
+        }
+        for (dir, cid) in &self.files {
+            let _ = self.store.remove_file_metadata(dir, cid).await;
+        }
+        for dir in self.dirs.iter().rev() {
+            let _ = self.store.remove_dir(dir).await;
+        }
+    }
+}
+
+pub(crate) fn entry_size(entry: &Entry) -> u64 {
+    match entry {
+        Entry::File(file_entry) => file_entry.size,
+        Entry::Directory(info) => info.size,
+        Entry::Symlink(_) => 0,
+    }
+}
+
+// Moves the metadata of `entry` from `src_dir` to `dst_dir` under the same
+// CIDs: a file's block map and versions, or a directory's whole subtree, whose
+// keys all change with its parent, as do those of the directories charged to
+// owners. Linking the entry into `dst_dir` is left to the caller; block
+// references are untouched. Only the caller's lock is held, so the subtree
+// must not be written to concurrently. A move that was interrupted can be run
+// again to finish it, or run the other way to undo it.
+pub(crate) async fn move_entry(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    src_dir: &DirKey,
+    entry: &Entry,
+    dst_dir: &DirKey,
+) -> Result<(), MetadataError> {
+    match journal::cid_of(entry) {
+        Some(cid) => move_entry_as(pool_root, store, src_dir, entry, dst_dir, cid).await,
+        None => Ok(()),
+    }
+}
+
+// Like `move_entry`, but the entry's metadata takes the CID `cid` in `dst_dir`,
+// for when its own is taken there. A linked file's block map stays where it is.
+pub(crate) async fn move_entry_as(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    src_dir: &DirKey,
+    entry: &Entry,
+    dst_dir: &DirKey,
+    cid: &str,
+) -> Result<(), MetadataError> {
+    match entry {
+        Entry::File(file_entry) => move_file(store, src_dir, file_entry, dst_dir, cid).await,
+        Entry::Directory(info) => {
+            let (from, to) = (src_dir.child(&info.cid), dst_dir.child(cid));
+            move_dir(store, &from, &to).await?;
+            quota::move_dirs(pool_root, &from, Some(&to)).await
+        }
+        Entry::Symlink(_) => Ok(()),
+    }
+}
+
+async fn move_dir(store: &dyn MetadataStore, from: &DirKey, to: &DirKey) -> Result<(), MetadataError> {
+    let mut moved = Vec::new();
+    let mut pending = vec![(from.clone(), to.clone())];
+    while let Some((from, to)) = pending.pop() {
+        store.create_dir(&to).await?;
+        // An interrupted move may have removed the source already; its
+        // entries were listed at the destination before.
+        let mut listing = store.read_listing(&from).await?;
+        if listing.is_empty() {
+            listing = store.read_listing(&to).await?;
+        }
+        for entry in listing.values() {
+            match entry {
+                Entry::File(file_entry) => move_file(store, &from, file_entry, &to, &file_entry.cid).await?,
+                Entry::Directory(info) => pending.push((from.child(&info.cid), to.child(&info.cid))),
+                Entry::Symlink(_) => {}
+            }
+        }
+        store.write_listing(&to, &listing).await?;
+        moved.push(from);
+    }
+    for dir in moved.iter().rev() {
+        store.remove_dir(dir).await?;
+    }
+    Ok(())
+}
+
+// A hard-linked block map stays in the link directory; only where the link is
+// gets recorded. The file's block map is moved last, so a move that finds it
+// at the destination only has nothing left to do.
+async fn move_file(
+    store: &dyn MetadataStore,
+    src_dir: &DirKey,
+    file_entry: &FileEntry,
+    dst_dir: &DirKey,
+    dst_cid: &str,
+) -> Result<(), MetadataError> {
+    if file_entry.linked {
+        return link::move_link(store, file_entry, src_dir, dst_dir).await;
+    }
+    let metadata = match store.read_file_metadata(src_dir, &file_entry.cid).await {
+        Ok(metadata) => metadata,
+        Err(_) if store.read_file_metadata(dst_dir, dst_cid).await.is_ok() => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for v in &metadata.versions {
+        let id = version::version_id(&file_entry.cid, v.version);
+        let dst_id = version::version_id(dst_cid, v.version);
+        let version = match store.read_file_metadata(src_dir, &id).await {
+            Ok(version) => version,
+            Err(_) if store.read_file_metadata(dst_dir, &dst_id).await.is_ok() => continue,
+            Err(e) => return Err(e),
+        };
+        store.write_file_metadata(dst_dir, &dst_id, &version).await?;
+        store.remove_file_metadata(src_dir, &id).await?;
+    }
+    store.write_file_metadata(dst_dir, dst_cid, &metadata).await?;
+    store.remove_file_metadata(src_dir, &file_entry.cid).await
+}
+
+// Frees an entry that has already been unlinked from its directory: drops the
+// block references of every file below it and removes its metadata.
+pub(crate) async fn release_entry(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    entry: &Entry,
+) -> Result<(), MetadataError> {
+    match entry {
+        Entry::File(file_entry) => release_file(pool_root, store, dir, file_entry).await,
+        Entry::Directory(info) => {
+            xattr::release_all(pool_root, &info.xattrs).await?;
+            release_dir(pool_root, store, &dir.child(&info.cid)).await
+        }
+        Entry::Symlink(_) => Ok(()),
+    }
+}
+
+// Frees the contents of a directory and then the directory itself, which no
+// owner is charged for any more. Every entry is unlinked from its listing
+// before its references are dropped, so a release that fails part way can be
+// run again: it finishes what is left and never drops a reference twice, at
+// worst leaking the file it failed on.
+pub(crate) async fn release_dir(pool_root: &str, store: &dyn MetadataStore, dir: &DirKey) -> Result<(), MetadataError> {
+    // Subdirectories with the listing that links them, parents first.
+    let mut subdirs = Vec::new();
+    let mut pending = vec![dir.clone()];
+    while let Some(dir) = pending.pop() {
+        for (name, entry) in store.read_listing(&dir).await? {
+            match entry {
+                Entry::File(file_entry) => {
+                    store.remove_entry(&dir, &name).await?;
+                    release_file(pool_root, store, &dir, &file_entry).await?;
+                }
+                Entry::Directory(info) => {
+                    pending.push(dir.child(&info.cid));
+                    subdirs.push((dir.clone(), name, info));
+                }
+                Entry::Symlink(_) => {}
+            }
+        }
+    }
+    for (parent, name, info) in subdirs.iter().rev() {
+        store.remove_entry(parent, name).await?;
+        xattr::release_all(pool_root, &info.xattrs).await?;
+        store.remove_dir(&parent.child(&info.cid)).await?;
+    }
+    store.remove_dir(dir).await?;
+    quota::move_dirs(pool_root, dir, None).await
+}
+
+// A hard-linked file is only freed along with its last link. The block maps
+// are removed before their references are dropped.
+async fn release_file(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    file_entry: &FileEntry,
+) -> Result<(), MetadataError> {
+    if file_entry.linked && !link::remove_link(store, dir, file_entry).await? {
+        return Ok(());
+    }
+    let (home, cid) = (link::home(dir, file_entry), &file_entry.cid);
+    let metadata = store.read_file_metadata(&home, cid).await?;
+    store.remove_file_metadata(&home, cid).await?;
+    version::release_versions(pool_root, store, &home, cid, &metadata.versions).await?;
+    xattr::release_all(pool_root, &metadata.xattrs).await?;
+    for block in metadata.blocks.values().filter(|b| !b.hole) {
+        block_store::release_block(pool_root, block.xxh3, block.index).await?;
+    }
+    Ok(())
+}
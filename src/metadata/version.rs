@@ -0,0 +1,199 @@
+// src/metadata/version.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::block::store as block_store;
+use crate::common::config::{self, VersionRetention};
+use crate::metadata::error::MetadataError;
+use crate::metadata::link;
+use crate::metadata::model::{Entry, FileMetadata, VersionInfo};
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use crate::metadata::walk::{self, WalkOptions};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use std::pin::pin;
+
+// The ID a version's block map is stored under, next to the file's own.
+pub fn version_id(cid: &str, version: u32) -> String {
+    format!("{}.v{}", cid, version)
+}
+
+// Moves the current contents of a file into its version list and makes
+// `replacement` current. Returns the new current block map, carrying the
+// version list, and what it no longer refers to: the old contents when
+// versioning is off, and the versions that were pruned. The caller releases
+// those once the new block map is written.
+pub(crate) async fn push_version(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    cid: &str,
+    mut current: FileMetadata,
+    mut replacement: FileMetadata,
+) -> Result<(FileMetadata, Superseded), MetadataError> {
+    let policy = config::get_pool_config(pool_root).await?.version_retention;
+    let mut versions = std::mem::take(&mut current.versions);
+    // Extended attributes belong to the file, not to one version of its contents.
+    replacement.xattrs = std::mem::take(&mut current.xattrs);
+
+    let mut superseded = Superseded { dir: dir.clone(), cid: cid.to_string(), contents: None, versions: Vec::new() };
+    if policy.max_versions == 0 {
+        superseded.contents = Some(current);
+    } else {
+        let version = versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+        store.write_file_metadata(dir, &version_id(cid, version), &current).await?;
+        versions.push(VersionInfo {
+            version,
+            size: current.size,
+            modified_at: current.modified_at,
+            replaced_at: Utc::now(),
+        });
+    }
+
+    replacement.versions = versions;
+    superseded.versions = prune(&mut replacement, &policy, Utc::now());
+    Ok((replacement, superseded))
+}
+
+// The contents a file's new block map has superseded. Their blocks are only
+// released after the new block map is written, so that a failure in between
+// leaves the file as it was rather than pointing at released blocks.
+#[must_use]
+pub(crate) struct Superseded {
+    dir: DirKey,
+    cid: String,
+    contents: Option<FileMetadata>,
+    versions: Vec<u32>,
+}
+
+impl Superseded {
+    pub async fn release(self, pool_root: &str, store: &dyn MetadataStore) -> Result<(), MetadataError> {
+        for version in &self.versions {
+            release_version(pool_root, store, &self.dir, &self.cid, *version).await?;
+        }
+        match &self.contents {
+            Some(contents) => release_block_map(pool_root, contents).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// Drops the versions of a file that `policy` no longer allows from its block
+// map, oldest first. Returns the versions dropped, to be released once the
+// block map has been written.
+pub(crate) fn prune(metadata: &mut FileMetadata, policy: &VersionRetention, now: DateTime<Utc>) -> Vec<u32> {
+    let cutoff = policy.max_age_days.map(|days| now - Duration::days(days as i64));
+    let mut kept_bytes: u64 = metadata.versions.iter().map(|v| v.size).sum();
+    let mut excess = metadata.versions.len().saturating_sub(policy.max_versions as usize);
+
+    let mut dropped = Vec::new();
+    metadata.versions.sort_by_key(|v| v.version);
+    metadata.versions.retain(|v| {
+        let expired = cutoff.is_some_and(|cutoff| v.replaced_at < cutoff);
+        let oversized = policy.max_bytes.is_some_and(|max| kept_bytes > max);
+        if excess > 0 || expired || oversized {
+            excess = excess.saturating_sub(1);
+            kept_bytes -= v.size;
+            dropped.push(v.version);
+            return false;
+        }
+        true
+    });
+    dropped
+}
+
+// Releases the blocks of every version of a file and removes their block maps.
+pub(crate) async fn release_versions(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    cid: &str,
+    versions: &[VersionInfo],
+) -> Result<(), MetadataError> {
+    for version in versions {
+        release_version(pool_root, store, dir, cid, version.version).await?;
+    }
+    Ok(())
+}
+
+async fn release_version(
+    pool_root: &str,
+    store: &dyn MetadataStore,
+    dir: &DirKey,
+    cid: &str,
+    version: u32,
+) -> Result<(), MetadataError> {
+    let id = version_id(cid, version);
+    let metadata = store.read_file_metadata(dir, &id).await?;
+    store.remove_file_metadata(dir, &id).await?;
+    release_block_map(pool_root, &metadata).await
+}
+
+async fn release_block_map(pool_root: &str, metadata: &FileMetadata) -> Result<(), MetadataError> {
+    for block in metadata.blocks.values().filter(|b| !b.hole) {
+        block_store::release_block(pool_root, block.xxh3, block.index).await?;
+    }
+    Ok(())
+}
+
+// Applies the pool's retention policy to every file, so versions also expire
+// on files that are no longer being replaced. Returns the number of versions dropped.
+pub async fn prune_pool(pool_root: &str) -> Result<u64, MetadataError> {
+    let policy = config::get_pool_config(pool_root).await?.version_retention;
+    let store = store::get_metadata_store(pool_root).await?;
+    let store = store.as_ref();
+    let now = Utc::now();
+
+    let mut pruned = 0;
+    let mut entries = pin!(walk::walk(pool_root, "/", WalkOptions::default()));
+    while let Some(item) = entries.next().await {
+        let item = item?;
+        if !matches!(item.entry, Entry::File(_)) {
+            continue;
+        }
+
+        // Look the file up again under the lock: it may have been deleted or
+        // replaced since the walk listed it.
+        let _lock = store.lock_dir(&item.dir).await?;
+        let Some(Entry::File(file_entry)) = store.get_entry(&item.dir, &item.name).await? else {
+            continue;
+        };
+        let _home_lock = link::lock_home(store, &file_entry).await?;
+        let home = link::home(&item.dir, &file_entry);
+        let mut metadata = store.read_file_metadata(&home, &file_entry.cid).await?;
+        let dropped = prune(&mut metadata, &policy, now);
+        if !dropped.is_empty() {
+            store.write_file_metadata(&home, &file_entry.cid, &metadata).await?;
+            for version in &dropped {
+                release_version(pool_root, store, &home, &file_entry.cid, *version).await?;
+            }
+            pruned += dropped.len() as u64;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+
+    #[tokio::test]
+    async fn pruned_versions_are_removed_after_the_file_is_written() {
+        let pool = TestPool::new("version-prune", r#"{"versionRetention":{"maxVersions":1}}"#);
+        let root = pool.root.as_str();
+        for size in 1..=3 {
+            manager::replace_file(root, "/", "f", test_util::file_metadata("f", size)).await.unwrap();
+        }
+
+        let versions = manager::list_versions(root, "/f").await.unwrap();
+        assert_eq!(versions.iter().map(|v| (v.version, v.size)).collect::<Vec<_>>(), vec![(2, 2)]);
+        let Entry::File(file_entry) = manager::get_entry(root, "/f").await.unwrap() else {
+            panic!("'/f' is not a file");
+        };
+        let store = store::get_metadata_store(root).await.unwrap();
+        assert!(store.read_file_metadata(&DirKey::root(), &version_id(&file_entry.cid, 1)).await.is_err());
+        assert_eq!(store.read_file_metadata(&DirKey::root(), &version_id(&file_entry.cid, 2)).await.unwrap().size, 2);
+    }
+}
@@ -0,0 +1,289 @@
+// src/metadata/walk.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::metadata::error::MetadataError;
+use crate::metadata::manager;
+use crate::metadata::model::Entry;
+use crate::metadata::naming;
+use crate::metadata::store::{self, DirKey, MetadataStore};
+use futures::stream::{self, Stream};
+use std::sync::Arc;
+
+// Decides whether an entry is skipped. Returning true drops the entry and,
+// for a directory, everything below it.
+pub type PruneFn = Arc<dyn Fn(&WalkItem) -> bool + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalkOrder {
+    // A directory is yielded before its contents.
+    #[default]
+    Pre,
+    // A directory is yielded after its contents, e.g. for deleting a tree.
+    Post,
+}
+
+#[derive(Clone, Default)]
+pub struct WalkOptions {
+    // Deepest level to yield; direct children of the start path are depth 1.
+    pub max_depth: Option<usize>,
+    pub order: WalkOrder,
+    pub prune: Option<PruneFn>,
+}
+
+// An entry found by `walk`.
+#[derive(Debug, Clone)]
+pub struct WalkItem {
+    // Full virtual path of the entry, e.g. "/photos/2024/a.jpg".
+    pub path: String,
+    pub name: String,
+    pub entry: Entry,
+    pub depth: usize,
+    // The directory holding the entry, for reading its block map.
+    pub dir: DirKey,
+}
+
+// A directory being walked. Its listing is read when it is first reached.
+struct Frame {
+    dir: DirKey,
+    path: String,
+    depth: usize,
+    entries: Option<std::vec::IntoIter<(String, Entry)>>,
+    // In post order, the directory's own item, yielded once it is exhausted.
+    post: Option<WalkItem>,
+}
+
+struct WalkState {
+    pool_root: String,
+    start: String,
+    // Set when walking from a known directory rather than resolving `start`.
+    base: Option<DirKey>,
+    options: WalkOptions,
+    store: Option<Arc<dyn MetadataStore>>,
+    started: bool,
+    stack: Vec<Frame>,
+}
+
+// Walks the tree below `rfs_path` depth-first, yielding every entry as a
+// stream. Entries of a directory come in name order. Only the listings of the
+// directories on the current path are held in memory.
+//
+// A directory that cannot be read yields an error in place of its contents;
+// the walk then carries on with its siblings. In post order the directory's
+// own item still follows the error.
+pub fn walk(
+    pool_root: &str,
+    rfs_path: &str,
+    options: WalkOptions,
+) -> impl Stream<Item = Result<WalkItem, MetadataError>> + Send + 'static {
+    walk_state(pool_root, rfs_path, None, options)
+}
+
+// Walks below a directory of the metadata store that may not be reachable
+// from the pool root, such as a snapshot. Yielded paths start at `rfs_path`.
+pub(crate) fn walk_dir(
+    pool_root: &str,
+    dir: DirKey,
+    rfs_path: &str,
+    options: WalkOptions,
+) -> impl Stream<Item = Result<WalkItem, MetadataError>> + Send + 'static {
+    walk_state(pool_root, rfs_path, Some(dir), options)
+}
+
+fn walk_state(
+    pool_root: &str,
+    rfs_path: &str,
+    base: Option<DirKey>,
+    options: WalkOptions,
+) -> impl Stream<Item = Result<WalkItem, MetadataError>> + Send + 'static {
+    let state = WalkState {
+        pool_root: pool_root.to_string(),
+        start: rfs_path.to_string(),
+        base,
+        options,
+        store: None,
+        started: false,
+        stack: Vec::new(),
+    };
+    stream::unfold(state, |mut state| async move {
+        let item = state.next().await?;
+        Some((item, state))
+    })
+}
+
+impl WalkState {
+    async fn next(&mut self) -> Option<Result<WalkItem, MetadataError>> {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None if self.started => return None,
+            None => {
+                self.started = true;
+                match self.start().await {
+                    Ok(store) => store,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        };
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            if frame.entries.is_none() {
+                match store.read_listing(&frame.dir).await {
+                    Ok(listing) => {
+                        let mut entries: Vec<(String, Entry)> = listing.into_iter().collect();
+                        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                        frame.entries = Some(entries.into_iter());
+                    }
+                    Err(e) => {
+                        // Left without entries, the frame is popped next time round.
+                        frame.entries = Some(Vec::new().into_iter());
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            let Some((name, entry)) = frame.entries.as_mut().and_then(|entries| entries.next()) else {
+                let done = self.stack.pop()?;
+                match done.post {
+                    Some(item) => return Some(Ok(item)),
+                    None => continue,
+                }
+            };
+
+            let item = WalkItem {
+                path: join_path(&frame.path, &name),
+                name,
+                entry,
+                depth: frame.depth + 1,
+                dir: frame.dir.clone(),
+            };
+            if self.options.prune.as_ref().is_some_and(|prune| prune(&item)) {
+                continue;
+            }
+
+            let descend = self.options.max_depth.is_none_or(|max| item.depth < max);
+            if let (Entry::Directory(info), true) = (&item.entry, descend) {
+                let post = self.options.order == WalkOrder::Post;
+                self.stack.push(Frame {
+                    dir: item.dir.child(&info.cid),
+                    path: item.path.clone(),
+                    depth: item.depth,
+                    entries: None,
+                    post: post.then(|| item.clone()),
+                });
+                if post {
+                    continue;
+                }
+            }
+            return Some(Ok(item));
+        }
+    }
+
+    // Resolves the start path and pushes it as the first frame.
+    async fn start(&mut self) -> Result<Arc<dyn MetadataStore>, MetadataError> {
+        let store = store::get_metadata_store(&self.pool_root).await?;
+        let components = naming::split_path(&self.pool_root, store.as_ref(), &self.start).await?;
+        let dir = match self.base.take() {
+            Some(dir) => dir,
+            None => manager::find_dir_path(store.as_ref(), &components).await?,
+        };
+        self.stack.push(Frame {
+            dir,
+            path: format!("/{}", components.join("/")),
+            depth: 0,
+            entries: None,
+            post: None,
+        });
+        self.store = Some(store.clone());
+        Ok(store)
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{}{}", parent, name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::json::{LISTING_FILE, METADATA_DIR};
+    use crate::metadata::test_util::{self, TestPool};
+    use futures::StreamExt;
+    use std::path::Path;
+
+    // /a/x, /a/y, /b/c/z and /f.
+    async fn tree(name: &str) -> TestPool {
+        let pool = TestPool::new(name, r#"{"metadataBackend":"json"}"#);
+        for (dir, file) in [("/a", "x"), ("/a", "y"), ("/b/c", "z"), ("/", "f")] {
+            manager::create_file(&pool.root, dir, file, test_util::file_metadata(file, 1)).await.unwrap();
+        }
+        pool
+    }
+
+    // The paths of the walk, with "!" for an error.
+    async fn paths(pool: &TestPool, rfs_path: &str, options: WalkOptions) -> Vec<String> {
+        let items: Vec<_> = walk(&pool.root, rfs_path, options).collect().await;
+        items.into_iter().map(|item| item.map_or_else(|_| "!".to_string(), |item| item.path)).collect()
+    }
+
+    fn ordered(order: WalkOrder) -> WalkOptions {
+        WalkOptions { order, ..WalkOptions::default() }
+    }
+
+    #[tokio::test]
+    async fn directories_come_before_or_after_their_contents() {
+        let pool = tree("walk-order").await;
+        assert_eq!(
+            paths(&pool, "/", ordered(WalkOrder::Pre)).await,
+            ["/a", "/a/x", "/a/y", "/b", "/b/c", "/b/c/z", "/f"]
+        );
+        assert_eq!(
+            paths(&pool, "/", ordered(WalkOrder::Post)).await,
+            ["/a/x", "/a/y", "/a", "/b/c/z", "/b/c", "/b", "/f"]
+        );
+        assert_eq!(paths(&pool, "/b", ordered(WalkOrder::Post)).await, ["/b/c/z", "/b/c"]);
+    }
+
+    #[tokio::test]
+    async fn walks_stop_at_max_depth() {
+        let pool = tree("walk-depth").await;
+        for order in [WalkOrder::Pre, WalkOrder::Post] {
+            let options = WalkOptions { max_depth: Some(1), ..ordered(order) };
+            assert_eq!(paths(&pool, "/", options).await, ["/a", "/b", "/f"]);
+        }
+        let options = WalkOptions { max_depth: Some(2), ..WalkOptions::default() };
+        let items: Vec<_> = walk(&pool.root, "/", options).map(|item| item.unwrap()).collect().await;
+        let depths: Vec<_> = items.iter().map(|item| (item.path.as_str(), item.depth)).collect();
+        assert_eq!(depths, [("/a", 1), ("/a/x", 2), ("/a/y", 2), ("/b", 1), ("/b/c", 2), ("/f", 1)]);
+    }
+
+    #[tokio::test]
+    async fn pruned_directories_take_their_subtree_along() {
+        let pool = tree("walk-prune").await;
+        let prune: PruneFn = Arc::new(|item: &WalkItem| item.name == "b");
+        for (order, expected) in
+            [(WalkOrder::Pre, ["/a", "/a/x", "/a/y", "/f"]), (WalkOrder::Post, ["/a/x", "/a/y", "/a", "/f"])]
+        {
+            let options = WalkOptions { prune: Some(prune.clone()), ..ordered(order) };
+            assert_eq!(paths(&pool, "/", options).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_directories_are_reported_and_passed() {
+        let pool = tree("walk-unreadable").await;
+        let Entry::Directory(info) = manager::get_entry(&pool.root, "/a").await.unwrap() else {
+            panic!("'/a' is not a directory");
+        };
+        let listing = Path::new(&pool.root).join(METADATA_DIR).join(&info.cid).join(LISTING_FILE);
+        std::fs::write(listing, b"not a listing").unwrap();
+        store::close_metadata_store(&pool.root);
+
+        assert_eq!(paths(&pool, "/", ordered(WalkOrder::Pre)).await, ["/a", "!", "/b", "/b/c", "/b/c/z", "/f"]);
+        assert_eq!(paths(&pool, "/", ordered(WalkOrder::Post)).await, ["!", "/a", "/b/c/z", "/b/c", "/b", "/f"]);
+    }
+}
@@ -0,0 +1,158 @@
+// src/metadata/xattr.rs
+// SPDX-License-Identifier: AGPL-3.0
+// Copyright (c) 2025 Canmi
+
+use crate::block::{digest, store as block_store, CHUNK_SIZE};
+use crate::metadata::error::MetadataError;
+use crate::metadata::model::{BlockInfo, XattrMap, XattrValue};
+
+// Values up to this size are kept inline in the metadata.
+pub const INLINE_MAX: usize = 4096;
+// The longest attribute name accepted, in bytes, as on Linux.
+pub const NAME_MAX: usize = 255;
+
+pub fn validate_name(name: &str) -> Result<(), MetadataError> {
+    if name.is_empty() || name.len() > NAME_MAX || name.contains('\0') {
+        return Err(MetadataError::InvalidXattrName(name.to_string()));
+    }
+    Ok(())
+}
+
+// Turns a value into its stored form. Large values are written as blocks,
+// each holding one reference until the value is released.
+pub(crate) async fn encode(pool_root: &str, value: &[u8]) -> Result<XattrValue, MetadataError> {
+    if value.len() <= INLINE_MAX {
+        return Ok(XattrValue::Inline(value.to_vec()));
+    }
+
+    let mut blocks = Vec::new();
+    for chunk in value.chunks(CHUNK_SIZE) {
+        let xxh3_hash = digest::calculate_xxh3_128(chunk);
+        match block_store::write_block(pool_root, xxh3_hash, chunk).await {
+            Ok(stored) => blocks.push(BlockInfo::stored(xxh3_hash, stored.index)),
+            Err(e) => {
+                release_blocks(pool_root, &blocks).await?;
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(XattrValue::Blocks { size: value.len() as u64, blocks })
+}
+
+pub(crate) async fn decode(pool_root: &str, value: &XattrValue) -> Result<Vec<u8>, MetadataError> {
+    let (size, blocks) = match value {
+        XattrValue::Inline(bytes) => return Ok(bytes.clone()),
+        XattrValue::Blocks { size, blocks } => (*size, blocks),
+    };
+
+    let mut data = Vec::with_capacity(size as usize);
+    for block in blocks {
+        data.extend_from_slice(&block_store::read_block(pool_root, block.xxh3, block.index).await?);
+    }
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+// The stored blocks the attributes in `xattrs` reference.
+pub(crate) fn blocks(xattrs: &XattrMap) -> impl Iterator<Item = &BlockInfo> {
+    xattrs.values().flat_map(|value| match value {
+        XattrValue::Inline(_) => &[][..],
+        XattrValue::Blocks { blocks, .. } => &blocks[..],
+    })
+}
+
+// Drops the block references held by one attribute value.
+pub(crate) async fn release(pool_root: &str, value: &XattrValue) -> Result<(), MetadataError> {
+    if let XattrValue::Blocks { blocks, .. } = value {
+        release_blocks(pool_root, blocks).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn release_all(pool_root: &str, xattrs: &XattrMap) -> Result<(), MetadataError> {
+    for value in xattrs.values() {
+        release(pool_root, value).await?;
+    }
+    Ok(())
+}
+
+async fn release_blocks(pool_root: &str, blocks: &[BlockInfo]) -> Result<(), MetadataError> {
+    for block in blocks {
+        block_store::release_block(pool_root, block.xxh3, block.index).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::index;
+    use crate::metadata::manager;
+    use crate::metadata::test_util::{self, TestPool};
+
+    async fn stored(pool_root: &str, name: &str) -> XattrValue {
+        manager::get_file_metadata(pool_root, "/f").await.unwrap().xattrs.remove(name).unwrap()
+    }
+
+    async fn referenced_blocks(pool_root: &str) -> usize {
+        let index = index::open(pool_root).await.unwrap();
+        let index = index.lock().await;
+        index.iter().filter(|(_, entry)| entry.refs > 0).count()
+    }
+
+    fn value(len: usize) -> Vec<u8> {
+        (0..len as u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn values_past_the_inline_limit_go_to_blocks() {
+        let pool = TestPool::new("xattr-inline", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "f", test_util::file_metadata("f", 0)).await.unwrap();
+
+        manager::set_xattr(root, "/f", "user.small", &value(INLINE_MAX)).await.unwrap();
+        manager::set_xattr(root, "/f", "user.large", &value(INLINE_MAX + 1)).await.unwrap();
+        assert!(matches!(stored(root, "user.small").await, XattrValue::Inline(bytes) if bytes.len() == INLINE_MAX));
+        match stored(root, "user.large").await {
+            XattrValue::Blocks { size, blocks } => {
+                assert_eq!(size, INLINE_MAX as u64 + 1);
+                assert_eq!(blocks.len(), 1);
+            }
+            XattrValue::Inline(_) => panic!("a value past INLINE_MAX was kept inline"),
+        }
+        assert_eq!(manager::get_xattr(root, "/f", "user.small").await.unwrap(), value(INLINE_MAX));
+        assert_eq!(manager::get_xattr(root, "/f", "user.large").await.unwrap(), value(INLINE_MAX + 1));
+    }
+
+    #[tokio::test]
+    async fn values_spanning_several_chunks_read_back_whole() {
+        let pool = TestPool::new("xattr-chunks", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "f", test_util::file_metadata("f", 0)).await.unwrap();
+
+        let data = value(2 * CHUNK_SIZE + 7);
+        manager::set_xattr(root, "/f", "user.big", &data).await.unwrap();
+        assert!(matches!(stored(root, "user.big").await, XattrValue::Blocks { blocks, .. } if blocks.len() == 3));
+        assert_eq!(manager::get_xattr(root, "/f", "user.big").await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn blocks_are_released_with_the_attribute_or_its_file() {
+        let pool = TestPool::new("xattr-release", "{}");
+        let root = pool.root.as_str();
+        manager::create_file(root, "/", "f", test_util::file_metadata("f", 0)).await.unwrap();
+
+        // Replacing a value releases the old blocks, removing it the new ones.
+        manager::set_xattr(root, "/f", "user.a", &value(CHUNK_SIZE + 1)).await.unwrap();
+        assert_eq!(referenced_blocks(root).await, 2);
+        manager::set_xattr(root, "/f", "user.a", &value(INLINE_MAX + 1)).await.unwrap();
+        assert_eq!(referenced_blocks(root).await, 1);
+        manager::remove_xattr(root, "/f", "user.a").await.unwrap();
+        assert_eq!(referenced_blocks(root).await, 0);
+
+        manager::set_xattr(root, "/f", "user.b", &value(CHUNK_SIZE + 1)).await.unwrap();
+        assert_eq!(referenced_blocks(root).await, 2);
+        manager::delete_entry(root, "/f").await.unwrap();
+        assert_eq!(referenced_blocks(root).await, 0);
+    }
+}